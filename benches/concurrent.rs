@@ -0,0 +1,63 @@
+/*!
+Measures key-checking throughput with several threads sharing one
+`KeyAuth`, with the keys in a single shard (equivalent to one big lock)
+and with the default number of shards.
+
+Run with `cargo bench --bench concurrent`.
+*/
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use authlite::KeyAuth;
+
+const THREADS: usize = 8;
+const KEYS_PER_THREAD: usize = 1000;
+const RUN_TIME: Duration = Duration::from_secs(2);
+
+/**
+Runs `THREADS` threads against a fresh `KeyAuth` split into `shards`
+shards for `RUN_TIME`, each repeatedly checking, and every tenth time
+refreshing, its own keys; returns the total operations per second.
+*/
+fn run(shards: Option<usize>) -> f64 {
+    let path = std::env::temp_dir().join(format!("authlite_bench_{}.csv", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    let mut a = KeyAuth::new(&path).unwrap();
+    if let Some(n) = shards { a.shards(n); }
+    let a = Arc::new(a);
+    
+    let handles: Vec<_> = (0..THREADS).map(|t| {
+        let a = Arc::clone(&a);
+        std::thread::spawn(move || {
+            let uname = format!("user{}", t);
+            let keys: Vec<String> = (0..KEYS_PER_THREAD)
+                .map(|_| a.issue_key(&uname).unwrap())
+                .collect();
+            
+            let start = Instant::now();
+            let mut ops: u64 = 0;
+            while start.elapsed() < RUN_TIME {
+                for key in keys.iter() {
+                    if ops.is_multiple_of(10) {
+                        a.refresh_key(key).unwrap();
+                    } else {
+                        a.check_key(key, &uname).unwrap();
+                    }
+                    ops += 1;
+                }
+            }
+            ops
+        })
+    }).collect();
+    
+    let total: u64 = handles.into_iter().map(|h| h.join().unwrap()).sum();
+    let _ = std::fs::remove_file(&path);
+    total as f64 / RUN_TIME.as_secs_f64()
+}
+
+fn main() {
+    let single = run(Some(1));
+    println!("1 shard:          {:>12.0} ops/s", single);
+    let sharded = run(None);
+    println!("default shards:   {:>12.0} ops/s ({:.2}x)", sharded, sharded / single);
+}
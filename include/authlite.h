@@ -0,0 +1,80 @@
+#ifndef AUTHLITE_H
+#define AUTHLITE_H
+
+/* Generated by cbindgen from src/ffi.rs; don't edit. */
+
+#include <stdarg.h>
+#include <stdbool.h>
+#include <stddef.h>
+#include <stdint.h>
+#include <stdlib.h>
+
+/*
+ The check succeeded. 
+ */
+#define AUTHLITE_OK 0
+
+/*
+ An argument was null or not valid UTF-8. 
+ */
+#define AUTHLITE_BAD_ARGUMENT -1
+
+/*
+ The library panicked; this is a bug. 
+ */
+#define AUTHLITE_PANIC -2
+
+/*
+ An open database, from `authlite_open()`; release it with `authlite_free()`. 
+ */
+typedef struct Authlite Authlite;
+
+/*
+ Opens (creating them if necessary) the given password and key files,
+ with every change written through to them. Returns null, after printing
+ the error to stderr, if they can't be opened.
+
+ # Safety
+
+ Both arguments must be null or point to NUL-terminated strings.
+ */
+struct Authlite *authlite_open(const char *users_file, const char *keys_file);
+
+/*
+ Checks the given user's password, with `salt_len` bytes of salt at
+ `salt` (which may be null if `salt_len` is 0).
+
+ # Safety
+
+ `auth` must have been returned by `authlite_open()` and not yet freed;
+ `uname` and `password` must be null or point to NUL-terminated strings;
+ and `salt` must point to at least `salt_len` bytes.
+ */
+int authlite_check_password(const struct Authlite *auth,
+                            const char *uname,
+                            const char *password,
+                            const uint8_t *salt,
+                            size_t salt_len);
+
+/*
+ Checks that `key` is a valid key for the given user.
+
+ # Safety
+
+ `auth` must have been returned by `authlite_open()` and not yet freed,
+ and `key` and `uname` must be null or point to NUL-terminated strings.
+ */
+int authlite_check_key(const struct Authlite *auth, const char *key, const char *uname);
+
+/*
+ Closes a database opened by `authlite_open()`. Does nothing if `auth` is
+ null.
+
+ # Safety
+
+ `auth` must have been returned by `authlite_open()`, and must not be used
+ (or freed) again afterward.
+ */
+void authlite_free(struct Authlite *auth);
+
+#endif  /* AUTHLITE_H */
@@ -0,0 +1,181 @@
+use std::fs;
+use std::io::{self, BufRead, BufReader, ErrorKind, Write};
+use std::os::unix::fs::{DirBuilderExt, PermissionsExt};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use crate::{BothAuth, DataError};
+
+/* How long a connected client may sit idle before it's dropped, so one
+   forgotten session can't tie up the server. */
+const IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+
+/** A tiny administration server, listening on a Unix domain socket, for
+    managing the users of a running service without restarting it (or
+    editing its files behind its back).
+    
+    The protocol is line-based: each line sent is a command, and each
+    command is answered by a line starting with `OK` or `ERR` (followed by
+    a message). The commands are
+    
+    * `adduser NAME PASSWORD [SALT]` adds a user (with an empty salt if
+      none is given);
+    * `deluser NAME` deletes a user, and any keys issued to them;
+    * `list` sends each user name on its own line, then `OK` and the
+      number of users;
+    * `cull` removes expired keys;
+    * `save` saves whichever databases are dirty;
+    * `quit` closes the connection.
+    
+    So, for example, `echo "list" | nc -U /run/myapp/admin.sock`.
+    
+    The socket is only readable and writable by its owner, which is the
+    only access control there is. It's bound in a private directory and
+    made so before it's moved to its path, so there's never a moment when
+    anyone else can connect. Clients are served one at a time.
+    
+    Only available on Unix, with the `admin-socket` feature.
+*/
+#[derive(Debug)]
+pub struct AdminServer {
+    path: PathBuf,
+    stop: Arc<AtomicBool>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl AdminServer {
+    /**
+    Starts serving the given database on a new socket at `path`, in a
+    background thread.
+    
+    If there's already a socket at `path` that nothing is listening on
+    (left behind by a crash, say), it's replaced; if something _is_
+    listening on it, this returns an error of kind `ErrorKind::AddrInUse`.
+    */
+    pub fn start(auth: Arc<BothAuth>, path: impl AsRef<Path>) -> io::Result<Self> {
+        let path = path.as_ref();
+        if path.exists() {
+            if UnixStream::connect(path).is_ok() {
+                return Err(io::Error::new(ErrorKind::AddrInUse,
+                    format!("{} is already being served", path.to_string_lossy())));
+            }
+            fs::remove_file(path)?;
+        }
+        let listener = bind_private(path)?;
+        
+        let stop = Arc::new(AtomicBool::new(false));
+        let stopping = stop.clone();
+        let handle = thread::spawn(move || {
+            for stream in listener.incoming() {
+                if stopping.load(Ordering::SeqCst) { break; }
+                match stream {
+                    Ok(stream) => {
+                        if let Err(e) = serve(&auth, stream) {
+                            eprintln!("WARNING: admin connection: {}", &e);
+                        }
+                    },
+                    Err(e) => { eprintln!("WARNING: admin socket: {}", &e); },
+                }
+            }
+        });
+        
+        return Ok(AdminServer { path: PathBuf::from(path), stop, handle: Some(handle) });
+    }
+    
+    /** Returns the path of the socket. */
+    pub fn path(&self) -> &Path { &self.path }
+    
+    /**
+    Stops the server (after the current client, if any, is done) and
+    removes its socket. This also happens when it's dropped.
+    */
+    pub fn stop(mut self) { self.shutdown(); }
+    
+    fn shutdown(&mut self) {
+        let handle = match self.handle.take() {
+            Some(handle) => handle,
+            None => { return; },
+        };
+        self.stop.store(true, Ordering::SeqCst);
+        /* Wake the listener up so it sees the flag. */
+        let _ = UnixStream::connect(&self.path);
+        let _ = handle.join();
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+impl Drop for AdminServer {
+    fn drop(&mut self) { self.shutdown(); }
+}
+
+/**
+Binds a socket at `path` that only its owner can connect to. A socket
+bound in place would briefly have the umask's permissions before it could
+be changed, so it's bound in a new directory (next to `path`, so it's on
+the same filesystem) that only the owner can enter, then moved.
+*/
+fn bind_private(path: &Path) -> io::Result<UnixListener> {
+    let mut dir = path.as_os_str().to_owned();
+    dir.push(format!(".{}.d", std::process::id()));
+    let dir = PathBuf::from(dir);
+    fs::DirBuilder::new().mode(0o700).create(&dir)?;
+    
+    let tmp = dir.join("s");
+    let result = UnixListener::bind(&tmp).and_then(|listener| {
+        fs::set_permissions(&tmp, fs::Permissions::from_mode(0o600))?;
+        fs::rename(&tmp, path)?;
+        Ok(listener)
+    });
+    let _ = fs::remove_file(&tmp);
+    let _ = fs::remove_dir(&dir);
+    return result;
+}
+
+/** Handles one client's commands until it disconnects or quits. */
+fn serve(auth: &BothAuth, stream: UnixStream) -> io::Result<()> {
+    stream.set_read_timeout(Some(IDLE_TIMEOUT))?;
+    let mut w = stream.try_clone()?;
+    for line in BufReader::new(stream).lines() {
+        let line = line?;
+        let words: Vec<&str> = line.split_whitespace().collect();
+        let reply = match words.as_slice() {
+            [] => { continue; },
+            ["quit"] => { break; },
+            ["adduser", uname, password] => result(auth.add_user(uname, password, b"")),
+            ["adduser", uname, password, salt] => {
+                result(auth.add_user(uname, password, salt.as_bytes()))
+            },
+            ["deluser", uname] => {
+                result(auth.delete_user(uname).and_then(|_| auth.remove_user_keys(uname).map(|_| ())))
+            },
+            ["list"] => {
+                let unames = auth.usernames();
+                for uname in unames.iter() { writeln!(w, "{}", uname)?; }
+                format!("OK {}", unames.len())
+            },
+            ["cull"] => match auth.cull_keys() {
+                Ok(()) => String::from("OK"),
+                Err(e) => format!("ERR {}", e),
+            },
+            ["save"] => match auth.save_if_dirty() {
+                Ok(report) => format!("OK {:?}", report),
+                Err(e) => format!("ERR {}", e),
+            },
+            [cmd, ..] => format!("ERR bad command or arguments: {}", cmd),
+        };
+        writeln!(w, "{}", reply)?;
+    }
+    return Ok(());
+}
+
+/** Formats the reply to a command that changes the users. */
+fn result(r: Result<(), DataError>) -> String {
+    match r {
+        Ok(()) => String::from("OK"),
+        Err(e) => format!("ERR {:?}", e),
+    }
+}
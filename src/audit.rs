@@ -0,0 +1,55 @@
+use std::fmt;
+use std::path::PathBuf;
+
+/** A weakness in an authorization system's configuration, as reported by
+    `BothAuth::security_report()`.
+    
+    The `Display` implementation describes the problem and what to do
+    about it, in a form suitable for logging at startup.
+*/
+#[derive(Debug, Clone, PartialEq)]
+#[non_exhaustive]
+pub enum Finding {
+    /** Keys are shorter than 16 characters (see `KeyAuth::length()`). */
+    ShortKeys { length: usize },
+    /** Keys are drawn from fewer than 16 distinct characters (see
+        `KeyAuth::charset()`). */
+    SmallAlphabet { size: usize },
+    /** Keys have less than 128 bits of entropy (see
+        `KeyAuth::key_entropy_bits()`). */
+    LowKeyEntropy { bits: f64 },
+    /** No `UsernamePolicy` has been set, so any string at all (including
+        an empty one) can be a user name. */
+    NoUsernamePolicy,
+    /** No pepper has been set (see `PwdAuth::pepper()`), so a stolen copy
+        of the password file is enough to start guessing passwords. */
+    NoPepper,
+    /** The file can be read by users other than its owner. */
+    WorldReadable { path: PathBuf },
+    /** Keys are stored in the key file as they are, so anyone who can
+        read it can impersonate any logged-in user. */
+    PlaintextKeys { path: PathBuf },
+}
+
+impl fmt::Display for Finding {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Finding::ShortKeys { length } => write!(f,
+                "keys are only {} characters long; use at least 16", length),
+            Finding::SmallAlphabet { size } => write!(f,
+                "keys use only {} distinct characters; use a larger KeyCharset", size),
+            Finding::LowKeyEntropy { bits } => write!(f,
+                "keys have only {:.0} bits of entropy; make them longer", bits),
+            Finding::NoUsernamePolicy => write!(f,
+                "no username policy is set; set one to reject empty or odd user names"),
+            Finding::NoPepper => write!(f,
+                "no password pepper is set; set one so the password file alone is useless"),
+            Finding::WorldReadable { path } => write!(f,
+                "{} is readable by other users; restrict it to its owner (chmod 600)",
+                path.to_string_lossy()),
+            Finding::PlaintextKeys { path } => write!(f,
+                "keys are stored in plain text in {}; protect it like a password file",
+                path.to_string_lossy()),
+        }
+    }
+}
@@ -0,0 +1,174 @@
+/*!
+A small HTTP sidecar that exposes a `BothAuth` to applications that
+aren't written in Rust. It listens only on the loopback interface.
+
+Usage: `authlite-server USERS_FILE KEYS_FILE [PORT]` (the port defaults to
+8900). The files are created if they don't exist, and every change is
+written through to them.
+
+All request and response bodies are JSON. A `"salt"` may be included
+wherever a password is; it is empty if left out.
+
+  * `POST /login` with `{"uname", "password"}` returns `{"key"}`.
+  * `POST /check` with `{"uname", "key"}` returns `{"uname"}` if the key
+    is valid for the user, refreshing it.
+  * `POST /logout` with `{"key"}` revokes the key.
+  * `GET /users` returns `{"users": [...]}`, and `POST /users` with
+    `{"uname", "password"}` adds a user; both require an administrator's
+    credentials in the `X-Auth-User` and `X-Auth-Key` headers.
+
+Errors are returned as `{"error"}`, with the status suggested by
+`DataError::suggested_status()`.
+
+Build with `cargo build --features server`.
+*/
+use std::sync::Arc;
+
+use serde_json::{json, Value};
+use tiny_http::{Header, Method, Request, Response, Server};
+
+use authlite::{BothAuth, DataError};
+
+const DEFAULT_PORT: u16 = 8900;
+
+/** A response status and JSON body. */
+type Reply = (u16, Value);
+
+fn error(e: DataError) -> Reply {
+    (e.suggested_status(), json!({ "error": format!("{:?}", e) }))
+}
+
+fn bad_request(msg: &str) -> Reply {
+    (400, json!({ "error": msg }))
+}
+
+/** Returns the string field `name` of the JSON object `body`. */
+fn field<'a>(body: &'a Value, name: &str) -> Option<&'a str> {
+    body.get(name).and_then(Value::as_str)
+}
+
+/** Returns the value of the named request header, if present. */
+fn header<'a>(req: &'a Request, name: &'static str) -> Option<&'a str> {
+    req.headers().iter()
+        .find(|h| h.field.equiv(name))
+        .map(|h| h.value.as_str())
+}
+
+/** Checks that the request carries an administrator's credentials. */
+fn require_admin(auth: &BothAuth, req: &Request) -> Result<(), Reply> {
+    match (header(req, "X-Auth-User"), header(req, "X-Auth-Key")) {
+        (Some(uname), Some(key)) => auth.require_admin(key, uname).map_err(error),
+        _ => Err(bad_request("missing X-Auth-User or X-Auth-Key header")),
+    }
+}
+
+fn handle(auth: &BothAuth, req: &mut Request) -> Reply {
+    let mut text = String::new();
+    if req.as_reader().read_to_string(&mut text).is_err() {
+        return bad_request("body is not UTF-8");
+    }
+    let body: Value = if text.is_empty() {
+        Value::Null
+    } else {
+        match serde_json::from_str(&text) {
+            Ok(v) => v,
+            Err(_) => { return bad_request("body is not JSON"); },
+        }
+    };
+    let salt = field(&body, "salt").unwrap_or("").as_bytes();
+    
+    match (req.method(), req.url()) {
+        (Method::Post, "/login") => {
+            match (field(&body, "uname"), field(&body, "password")) {
+                (Some(uname), Some(password)) => {
+                    match auth.check_password_and_issue_key(uname, password, salt) {
+                        Ok(key) => (200, json!({ "key": key })),
+                        Err(e) => error(e),
+                    }
+                },
+                _ => bad_request("expected uname and password"),
+            }
+        },
+        (Method::Post, "/check") => {
+            match (field(&body, "uname"), field(&body, "key")) {
+                (Some(uname), Some(key)) => match auth.check_and_refresh_key(key, uname) {
+                    Ok(()) => (200, json!({ "uname": uname })),
+                    Err(e) => error(e),
+                },
+                _ => bad_request("expected uname and key"),
+            }
+        },
+        (Method::Post, "/logout") => match field(&body, "key") {
+            Some(key) => match auth.remove_key(key) {
+                Ok(()) => (200, json!({})),
+                Err(e) => error(e),
+            },
+            None => bad_request("expected key"),
+        },
+        (Method::Get, "/users") => {
+            if let Err(reply) = require_admin(auth, req) { return reply; }
+            (200, json!({ "users": auth.usernames() }))
+        },
+        (Method::Post, "/users") => {
+            if let Err(reply) = require_admin(auth, req) { return reply; }
+            match (field(&body, "uname"), field(&body, "password")) {
+                (Some(uname), Some(password)) => match auth.add_user(uname, password, salt) {
+                    Ok(()) => (201, json!({ "uname": uname })),
+                    Err(e) => error(e),
+                },
+                _ => bad_request("expected uname and password"),
+            }
+        },
+        _ => (404, json!({ "error": "no such endpoint" })),
+    }
+}
+
+fn main() {
+    let args: Vec<String> = std::env::args().collect();
+    if args.len() < 3 || args.len() > 4 {
+        eprintln!("usage: {} USERS_FILE KEYS_FILE [PORT]", &args[0]);
+        std::process::exit(2);
+    }
+    let port: u16 = match args.get(3) {
+        None => DEFAULT_PORT,
+        Some(p) => match p.parse() {
+            Ok(port) => port,
+            Err(_) => {
+                eprintln!("bad port: {}", p);
+                std::process::exit(2);
+            },
+        },
+    };
+    
+    let auth = match BothAuth::builder()
+        .pwd_file(&args[1])
+        .key_file(&args[2])
+        .write_through(true)
+        .build()
+    {
+        Ok(auth) => Arc::new(auth),
+        Err(e) => {
+            eprintln!("{}", &e);
+            std::process::exit(1);
+        },
+    };
+    let server = match Server::http(("127.0.0.1", port)) {
+        Ok(server) => server,
+        Err(e) => {
+            eprintln!("can't listen on port {}: {}", port, &e);
+            std::process::exit(1);
+        },
+    };
+    eprintln!("listening on 127.0.0.1:{}", port);
+    
+    let content_type = Header::from_bytes("Content-Type", "application/json").unwrap();
+    for mut req in server.incoming_requests() {
+        let (status, body) = handle(&auth, &mut req);
+        let response = Response::from_string(body.to_string())
+            .with_status_code(status)
+            .with_header(content_type.clone());
+        if let Err(e) = req.respond(response) {
+            eprintln!("WARNING: responding to request: {}", &e);
+        }
+    }
+}
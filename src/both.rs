@@ -1,7 +1,35 @@
-use std::path::Path;
-use std::time::Duration;
+use std::collections::HashMap;
+use std::fmt;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, SystemTime};
 
-use crate::{KeyAuth, PwdAuth, FileError, DataError};
+use serde_json::Value;
+
+use crate::{AuthOk, Finding, Identifier, KeyAuth, KeyCharset, KeyHash, KeyInfo, PwdAuth, GroupAuth, InviteAuth, CertAuth, ExternalAuth,
+            RememberAuth,
+            UsernamePolicy, InputLimits, ImportReport, SaltPolicy, SaltEncoding, UserBundle, AuthEvent, CapacityPolicy, Compression,
+            FileError, DataError, Op, SaveOrder};
+use crate::event::{EventHook, SecurityEvent};
+use crate::hasher::{HashEncoding, HashFn, PasswordHasher};
+use crate::checker::{CheckerFn, CredentialChecker};
+use crate::secret::{Secret, SecretProvider};
+use crate::shard::UserLocks;
+use crate::health::{self, HealthIssue};
+use crate::validate::ValidationReport;
+#[cfg(feature = "bundle")]
+use crate::bundle;
+#[cfg(feature = "pubkey")]
+use crate::PubkeyAuth;
+#[cfg(feature = "bundle")]
+use crate::key::not_before_path;
+#[cfg(feature = "bundle")]
+use crate::pwd::sum_path;
+#[cfg(feature = "http-hooks")]
+use crate::webhook::Webhook;
 
 /** A combined authorization system that offers all the features of a
     `PwdAuth` and a `Keyauth` as well as some combined functionality unique
@@ -20,6 +48,19 @@ use crate::{KeyAuth, PwdAuth, FileError, DataError};
 pub struct BothAuth {
     pwdauth: PwdAuth,
     keyauth: KeyAuth,
+    signing_key: Secret,
+    groups: Option<GroupAuth>,
+    invites: Option<InviteAuth>,
+    certs: Option<CertAuth>,
+    external: Option<ExternalAuth>,
+    #[cfg(feature = "pubkey")]
+    pubkeys: Option<PubkeyAuth>,
+    remember: Option<RememberAuth>,
+    rotation: RotationPolicy,
+    class_lives: HashMap<String, Duration>,
+    /* Serializes `.rotate_credentials()` with itself and with refreshing
+       the same user's keys. */
+    ulocks: UserLocks,
 }
 
 impl BothAuth {
@@ -28,18 +69,27 @@ impl BothAuth {
     information in the supplied pathnames.
     */
     pub fn new(
-        pwd_file: &dyn AsRef<Path>,
-        key_file: &dyn AsRef<Path>
+        pwd_file: impl AsRef<Path>,
+        key_file: impl AsRef<Path>
     )-> Result<Self, FileError> {
         let new_pa = PwdAuth::new(pwd_file)?;
         let new_ka = KeyAuth::new(key_file)?;
         
-        let ba = BothAuth {
-            pwdauth: new_pa,
-            keyauth: new_ka,
-        };
+        return Ok(BothAuth::from_parts(new_pa, new_ka));
+    }
+    
+    /**
+    Like `.new()`, but first creates any missing directories in the paths
+    to both files (readable only by their owner, on Unix).
+    */
+    pub fn new_with_dirs(
+        pwd_file: impl AsRef<Path>,
+        key_file: impl AsRef<Path>
+    ) -> Result<Self, FileError> {
+        let new_pa = PwdAuth::new_with_dirs(pwd_file)?;
+        let new_ka = KeyAuth::new_with_dirs(key_file)?;
         
-        return Ok(ba);
+        return Ok(BothAuth::from_parts(new_pa, new_ka));
     }
     
     /**
@@ -47,64 +97,355 @@ impl BothAuth {
     key files.
     */
     pub fn open(
-        pwd_file: &dyn AsRef<Path>,
-        key_file: &dyn AsRef<Path>
+        pwd_file: impl AsRef<Path>,
+        key_file: impl AsRef<Path>
     ) -> Result<Self, FileError> {
         let pa = PwdAuth::open(pwd_file)?;
         let ka = KeyAuth::open(key_file)?;
         
-        let ba = BothAuth {
-            pwdauth: pa,
-            keyauth: ka,
-        };
+        return Ok(BothAuth::from_parts(pa, ka));
+    }
+    
+    /**
+    Open a joint authorization system using the given password and key
+    files, creating either (or both) of them if they don't exist.
+    */
+    pub fn open_or_new(
+        pwd_file: impl AsRef<Path>,
+        key_file: impl AsRef<Path>
+    ) -> Result<Self, FileError> {
+        let pa = PwdAuth::open_or_new(pwd_file)?;
+        let ka = KeyAuth::open_or_new(key_file)?;
         
-        return Ok(ba);
+        return Ok(BothAuth::from_parts(pa, ka));
     }
     
+    /**
+    Wraps the given databases, with nothing attached and the default
+    settings; every constructor (and the builder) goes through here.
+    */
+    fn from_parts(pwdauth: PwdAuth, keyauth: KeyAuth) -> BothAuth {
+        return BothAuth {
+            pwdauth,
+            keyauth,
+            signing_key: Secret::random(),
+            groups: None,
+            invites: None,
+            certs: None,
+            external: None,
+            #[cfg(feature = "pubkey")]
+            pubkeys: None,
+            remember: None,
+            rotation: RotationPolicy::default(),
+            class_lives: HashMap::new(),
+            ulocks: UserLocks::new(),
+        };
+    }
+    
+    /**
+    Start configuring a joint authorization system; see `BothAuthBuilder`.
+    */
+    pub fn builder() -> BothAuthBuilder { BothAuthBuilder::default() }
+    
     /* PwdAuth methods */
     
-    pub fn add_user(&mut self, uname: &str, password: &str, salt: &[u8])
+    pub fn add_user(&self, uname: &str, password: &str, salt: &[u8])
     -> Result<(), DataError> { self.pwdauth.add_user(uname, password, salt) }
     
-    pub fn delete_user(&mut self, uname: &str)
+    pub fn bootstrap_admin(&self, uname: &str, salt: &[u8])
+    -> Result<String, DataError> { self.pwdauth.bootstrap_admin(uname, salt) }
+    
+    pub fn import_plaintext_csv(&self, path: impl AsRef<Path>, salt_policy: SaltPolicy)
+    -> Result<ImportReport, FileError> { self.pwdauth.import_plaintext_csv(path, salt_policy) }
+    
+    pub fn delete_user(&self, uname: &str)
     -> Result<(), DataError> { self.pwdauth.delete_user(uname) }
     
-    pub fn change_password(&mut self, uname: &str, password: &str, salt: &[u8])
+    pub fn change_password(&self, uname: &str, password: &str, salt: &[u8])
     -> Result<(), DataError> { self.pwdauth.change_password(uname, password, salt) }
     
     pub fn check_password(&self, uname: &str, password: &str, salt: &[u8])
-    -> Result<(), DataError> { self.pwdauth.check_password(uname, password, salt) }
+    -> Result<AuthOk, DataError> { self.pwdauth.check_password(uname, password, salt) }
+    
+    pub fn check_password_by(&self, id: Identifier<'_>, password: &str, salt: &[u8])
+    -> Result<AuthOk, DataError> { self.pwdauth.check_password_by(id, password, salt) }
+    
+    pub fn find_user(&self, id: Identifier<'_>)
+    -> Result<String, DataError> { self.pwdauth.find_user(id) }
+    
+    pub fn rehash_needed(&self) -> Vec<String> { self.pwdauth.rehash_needed() }
+    
+    pub fn rehash_user(&self, uname: &str, password: &str, salt: &[u8])
+    -> Result<bool, DataError> { self.pwdauth.rehash_user(uname, password, salt) }
+    
+    pub fn hash_schemes(&self) -> HashMap<String, usize> { self.pwdauth.hash_schemes() }
+    
+    pub fn try_check_password(&self, uname: &str, password: &str, salt: &[u8], timeout: Duration)
+    -> Result<AuthOk, DataError> { self.pwdauth.try_check_password(uname, password, salt, timeout) }
     
     pub fn user_exists(&self, uname: &str)
     -> Result<(), DataError> { self.pwdauth.user_exists(uname) }
     
+    pub fn max_users(&mut self, max: Option<usize>) { self.pwdauth.max_users(max) }
+    
+    /** Calls `PwdAuth::max_file_size()`. */
+    pub fn max_pwd_file_size(&mut self, max: Option<u64>) { self.pwdauth.max_file_size(max) }
+    
+    /** Calls `PwdAuth::estimated_file_size()`. */
+    pub fn estimated_pwd_file_size(&self) -> u64 { self.pwdauth.estimated_file_size() }
+    
+    /** Returns how many users there are. */
+    pub fn user_count(&self) -> usize { self.pwdauth.row_count() }
+    
+    pub fn username_policy(&mut self, policy: UsernamePolicy) {
+        self.pwdauth.username_policy(policy)
+    }
+    
+    pub fn input_limits(&mut self, limits: InputLimits) { self.pwdauth.input_limits(limits) }
+    
+    pub fn pepper(&mut self, provider: &dyn SecretProvider)
+    -> std::io::Result<()> { self.pwdauth.pepper(provider) }
+    
+    pub fn password_hasher<H>(&mut self, hasher: H)
+    where H: PasswordHasher + 'static { self.pwdauth.password_hasher(hasher) }
+    
+    pub fn credential_checker<C>(&mut self, checker: C)
+    where C: CredentialChecker + 'static { self.pwdauth.credential_checker(checker) }
+    
+    pub fn user_id(&self, uname: &str)
+    -> Result<u64, DataError> { self.pwdauth.user_id(uname) }
+    
+    pub fn user_by_id(&self, uid: u64)
+    -> Result<String, DataError> { self.pwdauth.user_by_id(uid) }
+    
+    pub fn set_admin(&self, uname: &str, is_admin: bool)
+    -> Result<(), DataError> { self.pwdauth.set_admin(uname, is_admin) }
+    
+    pub fn is_admin(&self, uname: &str)
+    -> Result<bool, DataError> { self.pwdauth.is_admin(uname) }
+    
+    pub fn usernames(&self) -> Vec<String> { self.pwdauth.usernames() }
+    
+    pub fn case_insensitive(&mut self, on: bool) { self.pwdauth.case_insensitive(on) }
+    
+    pub fn normalize_passwords(&mut self, on: bool) { self.pwdauth.normalize_passwords(on) }
+    
+    pub fn hash_encoding(&mut self, encoding: HashEncoding) { self.pwdauth.hash_encoding(encoding) }
+    
+    pub fn salt_encoding(&mut self, encoding: SaltEncoding) { self.pwdauth.salt_encoding(encoding) }
+    
+    pub fn challenge_life(&mut self, life: Duration) { self.pwdauth.challenge_life(life) }
+    
+    pub fn set_class(&self, uname: &str, class: Option<&str>)
+    -> Result<(), DataError> { self.pwdauth.set_class(uname, class) }
+    
+    pub fn set_email(&self, uname: &str, email: Option<&str>)
+    -> Result<(), DataError> { self.pwdauth.set_email(uname, email) }
+    
+    pub fn email_of(&self, uname: &str)
+    -> Result<Option<String>, DataError> { self.pwdauth.email_of(uname) }
+    
+    pub fn set_salt(&self, uname: &str, salt: Option<&[u8]>)
+    -> Result<(), DataError> { self.pwdauth.set_salt(uname, salt) }
+    
+    pub fn salt_of(&self, uname: &str)
+    -> Result<Option<Vec<u8>>, DataError> { self.pwdauth.salt_of(uname) }
+    
+    pub fn password_challenge(&self, uname: &str)
+    -> Result<(Vec<u8>, [u8; 32]), DataError> { self.pwdauth.password_challenge(uname) }
+    
+    pub fn verify_challenge_response(&self, uname: &str, nonce: &[u8], response: &[u8])
+    -> Result<AuthOk, DataError> { self.pwdauth.verify_challenge_response(uname, nonce, response) }
+    
+    pub fn class_of(&self, uname: &str)
+    -> Result<Option<String>, DataError> { self.pwdauth.class_of(uname) }
+    
+    pub fn check_password_admin(&self, uname: &str, password: &str, salt: &[u8])
+    -> Result<(), DataError> { self.pwdauth.check_password_admin(uname, password, salt) }
+    
+    pub fn export_verifier(&self, path: impl AsRef<Path>)
+    -> Result<(), FileError> { self.pwdauth.export_verifier(path) }
+    
+    pub fn export_report(&self, path: impl AsRef<Path>)
+    -> Result<(), FileError> { self.pwdauth.export_report(path) }
+    
     /* KeyAuth methods */
     
     pub fn length(&mut self, key_length: usize) { self.keyauth.length(key_length) }
     
-    pub fn chars(&mut self, key_chars: &dyn AsRef<str>) { self.keyauth.chars(key_chars) }
+    pub fn chars(&mut self, key_chars: impl AsRef<str>) { self.keyauth.chars(key_chars) }
+    
+    pub fn charset(&mut self, charset: KeyCharset) { self.keyauth.charset(charset) }
     
     pub fn life(&mut self, key_life: Duration) { self.keyauth.life(key_life) }
     
-    pub fn issue_key(&mut self, uname: &str)
-    -> String { self.keyauth.issue_key(uname) }
+    pub fn life_str(&mut self, key_life: &str)
+    -> Result<(), humantime::DurationError> { self.keyauth.life_str(key_life) }
+    
+    pub fn save_key_config(&self) -> Result<(), FileError> { self.keyauth.save_config() }
+    
+    pub fn key_entropy_bits(&self) -> f64 { self.keyauth.key_entropy_bits() }
+    
+    pub fn compression(&mut self, compression: Compression) {
+        self.keyauth.compression(compression)
+    }
+    
+    pub fn max_keys(&mut self, max: Option<usize>) { self.keyauth.max_keys(max) }
+    
+    /** Calls `KeyAuth::max_file_size()`. */
+    pub fn max_key_file_size(&mut self, max: Option<u64>) { self.keyauth.max_file_size(max) }
+    
+    /** Calls `KeyAuth::estimated_file_size()`. */
+    pub fn estimated_key_file_size(&self) -> u64 { self.keyauth.estimated_file_size() }
+    
+    /** Calls `KeyAuth::row_count()`. */
+    pub fn key_count(&self) -> usize { self.keyauth.row_count() }
+    
+    pub fn shards(&mut self, n: usize) { self.keyauth.shards(n) }
+    
+    pub fn issue_rate_limit(&mut self, max: Option<usize>, per: Duration) {
+        self.keyauth.issue_rate_limit(max, per)
+    }
+    
+    pub fn uniform_errors(&mut self, on: bool) { self.pwdauth.uniform_errors(on) }
+    
+    /**
+    Turn "write-through" mode on or off for both databases; see
+    `PwdAuth::write_through()` and `KeyAuth::write_through()`.
+    */
+    pub fn write_through(&mut self, on: bool) {
+        self.pwdauth.write_through(on);
+        self.keyauth.write_through(on);
+    }
+    
+    pub fn capacity_policy(&mut self, policy: CapacityPolicy) {
+        self.keyauth.capacity_policy(policy)
+    }
+    
+    /**
+    Set the order in which both databases write their records when saved;
+    see `SaveOrder`.
+    */
+    pub fn save_order(&mut self, order: SaveOrder) {
+        self.pwdauth.save_order(order);
+        self.keyauth.save_order(order);
+    }
+    
+    pub fn issue_key(&self, uname: &str)
+    -> Result<String, DataError> { self.keyauth.issue_key(uname) }
+    
+    pub fn issue_key_with_context(&self, uname: &str, context: Value)
+    -> Result<String, DataError> { self.keyauth.issue_key_with_context(uname, context) }
+    
+    pub fn key_info(&self, key: &str)
+    -> Result<KeyInfo, DataError> { self.keyauth.key_info(key) }
+    
+    pub fn user_keys(&self, uname: &str) -> Vec<KeyInfo> { self.keyauth.user_keys(uname) }
     
-    pub fn invalidate_key(&mut self, key: &str)
+    pub fn rotate_key(&self, old_key: &str, uname: &str)
+    -> Result<String, DataError> { self.keyauth.rotate_key(old_key, uname) }
+    
+    pub fn invalidate_key(&self, key: &str)
     -> Result<(), DataError> { self.keyauth.invalidate_key(key) }
     
-    pub fn remove_key(&mut self, key: &str)
+    /**
+    See `KeyAuth::revocations_since()`; `since` is a generation of the key
+    database, from `.key_generation()`.
+    */
+    pub fn revocations_since(&self, since: u64)
+    -> Vec<KeyHash> { self.keyauth.revocations_since(since) }
+    
+    pub fn remove_key(&self, key: &str)
     -> Result<(), DataError> { self.keyauth.remove_key(key) }
     
+    pub fn remove_user_keys(&self, uname: &str)
+    -> Result<usize, DataError> { self.keyauth.remove_user_keys(uname) }
+    
+    pub fn refresh_user_keys(&self, uname: &str)
+    -> Result<usize, DataError> { self.keyauth.refresh_user_keys(uname) }
+    
     pub fn check_key(&self, key:&str, uname: &str)
-    -> Result<(), DataError> { self.keyauth.check_key(key, uname) }
+    -> Result<AuthOk, DataError> { self.keyauth.check_key(key, uname) }
+    
+    pub fn try_check_key(&self, key: &str, uname: &str, timeout: Duration)
+    -> Result<AuthOk, DataError> { self.keyauth.try_check_key(key, uname, timeout) }
+    
+    pub fn check_key_at(&self, key: &str, uname: &str, at: SystemTime)
+    -> Result<AuthOk, DataError> { self.keyauth.check_key_at(key, uname, at) }
+    
+    pub fn authenticate(&self, key: &str)
+    -> Result<String, DataError> { self.keyauth.authenticate(key) }
+    
+    pub fn export_user(&self, uname: &str)
+    -> Result<UserBundle, DataError> { self.pwdauth.export_user(uname) }
     
-    pub fn refresh_key(&mut self, key: &str)
+    pub fn import_user(&self, bundle: UserBundle)
+    -> Result<(), DataError> { self.pwdauth.import_user(bundle) }
+    
+    pub fn time_remaining(&self, key: &str)
+    -> Result<Duration, DataError> { self.keyauth.time_remaining(key) }
+    
+    pub fn refresh_key(&self, key: &str)
     -> Result<(), DataError> { self.keyauth.refresh_key(key) }
     
-    pub fn check_and_refresh_key(&mut self, key: &str, uname: &str)
-    -> Result<(), DataError> { self.keyauth.check_and_refresh_key(key, uname) }
+    /**
+    Calls `KeyAuth::check_and_refresh_key()`, holding the user's lock, so
+    it can't interleave with `.rotate_credentials()` for the same user.
+    */
+    pub fn check_and_refresh_key(&self, key: &str, uname: &str) -> Result<(), DataError> {
+        let _ulock = self.ulocks.lock(uname);
+        self.keyauth.check_and_refresh_key(key, uname)
+    }
+    
+    pub fn add_key_namespace(&mut self, name: impl Into<String>, key_file: impl AsRef<Path>,
+                             life: Duration)
+    -> Result<(), FileError> { self.keyauth.add_namespace(name, key_file, life) }
+    
+    pub fn key_namespace(&self, name: &str)
+    -> Result<&KeyAuth, DataError> { self.keyauth.namespace(name) }
+    
+    pub fn issue_key_in(&self, namespace: &str, uname: &str)
+    -> Result<String, DataError> { self.keyauth.issue_key_in(namespace, uname) }
+    
+    pub fn check_key_in(&self, namespace: &str, key: &str, uname: &str)
+    -> Result<AuthOk, DataError> { self.keyauth.check_key_in(namespace, key, uname) }
+    
+    pub fn remove_key_in(&self, namespace: &str, key: &str)
+    -> Result<(), DataError> { self.keyauth.remove_key_in(namespace, key) }
+    
+    pub fn touch_threshold(&mut self, fraction: f64) { self.keyauth.touch_threshold(fraction) }
+    
+    pub fn idle_timeout(&mut self, max_idle: Option<Duration>) { self.keyauth.idle_timeout(max_idle) }
+    
+    /** Calls `KeyAuth::touch()`, holding the user's lock, as `.check_and_refresh_key()` does. */
+    pub fn touch(&self, key: &str, uname: &str) -> Result<bool, DataError> {
+        let _ulock = self.ulocks.lock(uname);
+        self.keyauth.touch(key, uname)
+    }
+    
+    pub fn cull_keys(&self)
+    -> Result<(), FileError> { self.keyauth.cull_keys() }
+    
+    pub fn cull_keys_at(&self, at: SystemTime)
+    -> Result<(), FileError> { self.keyauth.cull_keys_at(at) }
     
-    pub fn cull_keys(&mut self) { self.keyauth.cull_keys() }
+    pub fn cull_idle(&self, max_idle: Duration)
+    -> Result<usize, FileError> { self.keyauth.cull_idle(max_idle) }
+    
+    /**
+    Forcibly logs out every session by invalidating all keys issued up
+    until now; see `KeyAuth::invalidate_all()`.
+    */
+    pub fn invalidate_all_sessions(&self) -> Result<(), FileError> {
+        self.keyauth.invalidate_all()
+    }
+    
+    /**
+    Logs out every session of the given user that began before `before`
+    (for "log out other devices"); see `KeyAuth::invalidate_user_before()`.
+    */
+    pub fn invalidate_sessions_before(&self, uname: &str, before: SystemTime)
+    -> Result<(), FileError> { self.keyauth.invalidate_user_before(uname, before) }
     
     /* Unique methods */
     
@@ -112,40 +453,1301 @@ impl BothAuth {
     Issue a key only if the given username is in the password authorization
     database.
     */
-    pub fn issue_user_key(&mut self, uname: &str) -> Result<String, DataError> {
+    pub fn issue_user_key(&self, uname: &str) -> Result<String, DataError> {
         self.pwdauth.user_exists(uname)?;
-        Ok(self.keyauth.issue_key(uname))
+        self.issue_class_key(uname)
     }
     
     /**
     Checks to see whether the username/password/salt combo is valid, and
     if so, issue a key associated with that user name.
+    
+    Like `.issue_user_key()`, the key's life depends on the user's class
+    (see `.class_life()`).
     */
     pub fn check_password_and_issue_key(
-        &mut self,
+        &self,
         uname: &str,
         password: &str,
         salt: &[u8]
     ) -> Result<String, DataError> {
         self.pwdauth.check_password(uname, password, salt)?;
-        Ok(self.keyauth.issue_key(uname))
+        self.issue_class_key(uname)
+    }
+    
+    /**
+    Sets how long keys issued to users in the given class should last,
+    overriding the usual key life (see `.life()`) when issuing keys with
+    `.issue_user_key()`, `.check_password_and_issue_key()`, `.login()`,
+    and `.rotate_credentials()`. Users with no class, or with a class that
+    has no life set, get the usual key life. Keys keep their class's life
+    when they're refreshed or rotated, even if the class is changed later.
+    */
+    pub fn class_life(&mut self, class: impl Into<String>, life: Duration) {
+        let _ = self.class_lives.insert(class.into(), life);
+    }
+    
+    /** Issues a key to `uname` with the life set for their class, if any. */
+    fn issue_class_key(&self, uname: &str) -> Result<String, DataError> {
+        let life = match self.pwdauth.class_of(uname) {
+            Ok(Some(class)) => self.class_lives.get(&class).copied(),
+            _ => None,
+        };
+        match life {
+            Some(life) => self.keyauth.issue_key_with_life(uname, life),
+            None => self.keyauth.issue_key(uname),
+        }
     }
 
+    /**
+    Returns `Ok(())` if the given key is valid for the supplied user _and_
+    that user has administrator privileges; otherwise returns the same
+    errors as `.check_key()`, `DataError::NoSuchUser`, or
+    `DataError::NotAdmin`.
+    */
+    pub fn check_key_admin(&self, key: &str, uname: &str) -> Result<(), DataError> {
+        self.keyauth.check_key(key, uname)?;
+        if self.pwdauth.is_admin(uname)? {
+            Ok(())
+        } else {
+            Err(DataError::NotAdmin)
+        }
+    }
+    
+    /**
+    Guard for administrative request handlers: like `.check_key_admin()`,
+    but also refreshes the key's life (as `.check_and_refresh_key()` does)
+    if the check succeeds.
+    */
+    pub fn require_admin(&self, key: &str, uname: &str) -> Result<(), DataError> {
+        self.check_key_admin(key, uname)?;
+        self.keyauth.refresh_key(key)
+    }
+    
+    /**
+    Register a function to be called whenever either underlying database
+    emits an `AuthEvent`, replacing any previously-registered handler.
+    */
+    pub fn on_event<F>(&mut self, handler: F)
+    where F: Fn(&AuthEvent) + Send + Sync + 'static
+    {
+        let hook = EventHook::new(handler);
+        for event in self.pwdauth.duplicate_events() { hook.emit(event); }
+        self.keyauth.set_event_hook(hook);
+    }
+    
+    /**
+    Register a function to be called whenever a `SecurityEvent` occurs in
+    either underlying database (a lockout, a password change, or the
+    deletion of an admin), replacing any previously-registered handler.
+    This lets an application send a notification without polling.
+    */
+    pub fn on_security_event<F>(&mut self, handler: F)
+    where F: Fn(&SecurityEvent) + Send + Sync + 'static
+    {
+        let hook = EventHook::new(handler);
+        self.pwdauth.set_security_hook(hook.clone());
+        self.keyauth.set_security_hook(hook);
+    }
+    
+    /**
+    Checks the current configuration for weaknesses (short keys, small
+    key alphabets, no username policy or pepper, data files readable by
+    other users, and so on), returning a list of findings, each of which
+    describes (via `Display`) what's wrong and how to fix it. Nothing is
+    logged or sent anywhere; it's up to the application to report them,
+    say at startup.
+    */
+    pub fn security_report(&self) -> Vec<Finding> {
+        let mut findings: Vec<Finding> = Vec::new();
+        self.pwdauth.audit(&mut findings);
+        self.keyauth.audit(&mut findings);
+        return findings;
+    }
+    
+    /**
+    Returns a summary of the database's state and configuration (how many
+    users and sessions there are, which files are dirty and when they were
+    last saved, and so on), for a health endpoint or a `status` command to
+    show as it is; its `Display` form is a few lines of plain text.
+    */
+    pub fn status(&self) -> StatusReport {
+        let mut report = StatusReport::default();
+        self.pwdauth.status(&mut report);
+        self.keyauth.status(&mut report);
+        report.pwd_saved = modified(&report.pwd_file);
+        report.key_saved = modified(&report.key_file);
+        return report;
+    }
+    
+    /**
+    Checks that the database's files are all there, can be read, and could
+    be saved (by writing, then removing, a temporary file alongside each,
+    as saving does), and that the password and key files have no rows that
+    opening them would skip or misread, returning a list of the problems
+    found; an empty list means all is well. This is meant for a readiness
+    probe, so it doesn't change anything.
+    
+    Attached databases' files (groups, invites, and so on) are checked too,
+    except for their contents. Each check reads the whole file, so don't
+    call this too often on a big database.
+    */
+    pub fn health_check(&self) -> Vec<HealthIssue> {
+        let mut issues: Vec<HealthIssue> = Vec::new();
+        health::check_file(self.pwdauth.path(), |p| PwdAuth::validate_file(p), &mut issues);
+        health::check_file(self.keyauth.path(), |p| KeyAuth::validate_file(p), &mut issues);
+        
+        let mut attached: Vec<&Path> = Vec::new();
+        if let Some(groups) = &self.groups { attached.push(groups.path()); }
+        if let Some(invites) = &self.invites { attached.push(invites.path()); }
+        if let Some(certs) = &self.certs { attached.push(certs.path()); }
+        if let Some(external) = &self.external { attached.push(external.path()); }
+        #[cfg(feature = "pubkey")]
+        if let Some(pubkeys) = &self.pubkeys { attached.push(pubkeys.path()); }
+        if let Some(remember) = &self.remember { attached.push(remember.path()); }
+        for path in attached.into_iter() {
+            let readable = |path: &Path| {
+                std::fs::File::open(path)
+                    .map(|_| ValidationReport::empty(path))
+                    .map_err(|e| FileError::from_io(path, Op::Read, &e))
+            };
+            health::check_file(path, readable, &mut issues);
+        }
+        
+        return issues;
+    }
+    
+    /**
+    Looks for keys issued to user names that aren't in the password
+    database, as can happen after hand-editing the .csv files or restoring
+    only one of them from a backup.
+    
+    If `repair` is `true`, any such keys are also removed from the key
+    database (which is then dirty, or saved in write-through mode).
+    */
+    pub fn verify_consistency(&self, repair: bool)
+    -> Result<ConsistencyReport, FileError> {
+        let mut orphaned_keys: Vec<(String, String)> = self.keyauth.key_users()
+            .into_iter()
+            .filter(|(_, uname)| self.pwdauth.user_exists(uname).is_err())
+            .collect();
+        orphaned_keys.sort_by(|a, b| (&a.1, &a.0).cmp(&(&b.1, &b.0)));
+        
+        if repair && !orphaned_keys.is_empty() {
+            let keys: Vec<String> = orphaned_keys.iter()
+                .map(|(key, _)| key.clone())
+                .collect();
+            self.keyauth.remove_keys(&keys)?;
+        }
+        
+        let report = ConsistencyReport { orphaned_keys, repaired: repair };
+        return Ok(report);
+    }
+    
+    /**
+    Set the secret used to derive CSRF tokens (see `.login()`) and to sign
+    values (see `.sign_value()`), instead of the random one generated when
+    this `BothAuth` was created. Unless this is set, tokens and signatures
+    are only valid until the program restarts (even though their keys
+    remain valid), and only in the process that made them.
+    
+    The secret is retrieved from `provider` once, right away.
+    */
+    pub fn signing_secret(&mut self, provider: &dyn SecretProvider) -> std::io::Result<()> {
+        self.signing_key = Secret::from_provider(provider)?;
+        return Ok(());
+    }
+    
+    /**
+    Checks the password like `.check_password_and_issue_key()` and, if it
+    is valid, issues a session key along with a CSRF token to go with it.
+    
+    The token is a keyed hash of the session key, so it needn't be stored
+    anywhere; embed it in forms (or have scripts send it in a header) and
+    check it with `.check_csrf()` when they're submitted.
+    */
+    pub fn login(&self, uname: &str, password: &str, salt: &[u8])
+    -> Result<LoginResult, DataError> {
+        let session_key = self.check_password_and_issue_key(uname, password, salt)?;
+        let csrf_token = self.csrf_token(&session_key);
+        return Ok(LoginResult { session_key, csrf_token });
+    }
+    
+    /** Returns the CSRF token that goes with the given session key. */
+    pub fn csrf_token(&self, key: &str) -> String {
+        self.csrf_hash(key).to_hex().to_string()
+    }
+    
+    /**
+    Returns `Ok(())` if `token` is the CSRF token for the session key `key`
+    (as returned by `.login()` or `.csrf_token()`), and
+    `DataError::BadToken` otherwise. The comparison takes constant time.
+    
+    This doesn't check that the key itself is valid; do that with
+    `.check_key()` first.
+    */
+    pub fn check_csrf(&self, key: &str, token: &str) -> Result<(), DataError> {
+        match blake3::Hash::from_hex(token) {
+            Ok(hash) if hash == self.csrf_hash(key) => Ok(()),
+            _ => Err(DataError::BadToken),
+        }
+    }
+    
+    /**
+    Signs `data` (a flash message or other cookie value, say) with a keyed
+    hash derived from the signing secret (see `.signing_secret()`), so it
+    can be handed to the client and trusted when it comes back. The result
+    is `data`, a `.`, and 64 hex digits; `data` isn't encrypted or encoded,
+    so it must already be safe to put wherever it's going.
+    */
+    pub fn sign_value(&self, data: &str) -> String {
+        format!("{}.{}", data, self.value_hash(data).to_hex())
+    }
+    
+    /**
+    Returns the data signed by `.sign_value()` if `signed` carries a valid
+    signature, and `DataError::BadToken` otherwise. The comparison takes
+    constant time.
+    */
+    pub fn verify_value(&self, signed: &str) -> Result<String, DataError> {
+        let (data, sig) = match signed.rfind('.') {
+            Some(n) => (&signed[..n], &signed[n + 1..]),
+            None => { return Err(DataError::BadToken); },
+        };
+        match blake3::Hash::from_hex(sig) {
+            Ok(hash) if hash == self.value_hash(data) => Ok(data.to_string()),
+            _ => Err(DataError::BadToken),
+        }
+    }
+    
+    /** Computes the keyed hash with which a value is signed. */
+    fn value_hash(&self, data: &str) -> blake3::Hash {
+        let hash_key = blake3::derive_key("authlite 2021 signed value", self.signing_key.as_bytes());
+        blake3::keyed_hash(&hash_key, data.as_bytes())
+    }
+    
+    /** Computes the keyed hash from which a key's CSRF token is made. */
+    fn csrf_hash(&self, key: &str) -> blake3::Hash {
+        let hash_key = blake3::derive_key("authlite 2021 CSRF token", self.signing_key.as_bytes());
+        blake3::keyed_hash(&hash_key, key.as_bytes())
+    }
+    
+    /**
+    Set what `.rotate_credentials()` does besides changing the password.
+    The default revokes the user's existing keys and issues a fresh one.
+    */
+    pub fn rotation_policy(&mut self, policy: RotationPolicy) { self.rotation = policy; }
+    
+    /**
+    Changes the user's password, then deals with their keys according to
+    the `RotationPolicy` (see `.rotation_policy()`): their existing keys
+    are kept, refreshed, or revoked, and a fresh key is issued and
+    returned if the policy says so. This covers the common "change
+    password while logged in" flow, where the session making the change
+    should survive it (with a new key) but any others shouldn't.
+    
+    Returns `DataError::NoSuchUser` (without changing anything) if the user
+    doesn't exist. If issuing the new key fails, the password has still
+    been changed.
+    
+    Concurrent rotations for the same user happen one after the other, so
+    the key that survives is always the one issued with the password that
+    sticks; rotations for different users proceed in parallel.
+    */
+    pub fn rotate_credentials(&self, uname: &str, new_password: &str, salt: &[u8])
+    -> Result<Option<String>, DataError> {
+        let _ulock = self.ulocks.lock(uname);
+        self.pwdauth.change_password(uname, new_password, salt)?;
+        match self.rotation.existing_keys {
+            ExistingKeys::Keep => {},
+            ExistingKeys::Refresh => { let _ = self.keyauth.refresh_user_keys(uname)?; },
+            ExistingKeys::Revoke => { let _ = self.keyauth.remove_user_keys(uname)?; },
+        }
+        if self.rotation.issue_key {
+            return self.issue_class_key(uname).map(Some);
+        }
+        return Ok(None);
+    }
+    
+    /**
+    Deletes everything stored about the user, for a data-deletion request:
+    their row in the password file (with their password hash, salt, and
+    email address), all their keys (revoked ones too, and those in key
+    namespaces), and their memberships, invites, certificates, external
+    identities, public keys, and remember-me series in whichever of those
+    databases are attached; then saves every file that changed, right
+    away.
+    
+    Nothing else is kept about users (events are only passed to handlers),
+    so this emits a `SecurityEvent::UserPurged` for the application to
+    scrub its own logs. Backups made earlier still hold the user's data.
+    
+    This works whether or not the user still exists, so a purge that
+    failed partway (to save, say) can just be retried. Returns what was
+    deleted, or `DataError::SaveFailed` if saving fails.
+    */
+    pub fn purge_user(&self, uname: &str) -> Result<PurgeReport, DataError> {
+        let _ulock = self.ulocks.lock(uname);
+        let user = match self.pwdauth.delete_user(uname) {
+            Ok(()) => true,
+            Err(DataError::NoSuchUser) => false,
+            Err(e) => { return Err(e); },
+        };
+        let keys = self.keyauth.purge_user(uname).map_err(DataError::SaveFailed)?;
+        let groups = self.groups.as_ref().map_or(0, |groups| groups.remove_user(uname));
+        let invites = self.invites.as_ref().map_or(0, |invites| invites.remove_user(uname));
+        let certs = self.certs.as_ref().map_or(0, |certs| certs.remove_user(uname));
+        let external = self.external.as_ref().map_or(0, |external| external.remove_user(uname));
+        #[cfg(feature = "pubkey")]
+        let pubkeys = self.pubkeys.as_ref().map_or(0, |pubkeys| pubkeys.remove_user(uname));
+        #[cfg(not(feature = "pubkey"))]
+        let pubkeys = 0;
+        let remember = self.remember.as_ref().map_or(0, |remember| remember.revoke_user(uname));
+        
+        let _ = self.save_if_dirty().map_err(DataError::SaveFailed)?;
+        self.pwdauth.emit_security(SecurityEvent::UserPurged { uname: uname.to_string() });
+        return Ok(PurgeReport { user, keys, groups, invites, certs, external, pubkeys, remember });
+    }
+    
+    /**
+    Attach a group database, enabling `.check_key_and_group()` and making
+    `.save_if_dirty()` save it too.
+    */
+    pub fn attach_groups(&mut self, groups: GroupAuth) { self.groups = Some(groups); }
+    
+    /** Returns the attached group database, if any. */
+    pub fn groups(&self) -> Option<&GroupAuth> { self.groups.as_ref() }
+    
+    /**
+    Returns `Ok(())` if the given key is valid for the supplied user _and_
+    that user is a member of `group`; otherwise returns the same errors as
+    `.check_key()` or `GroupAuth::user_in_group()`.
+    
+    Returns `DataError::NotAttached` if no group database is attached.
+    */
+    pub fn check_key_and_group(&self, key: &str, uname: &str, group: &str)
+    -> Result<(), DataError> {
+        self.keyauth.check_key(key, uname)?;
+        match &self.groups {
+            Some(groups) => groups.user_in_group(group, uname),
+            None => Err(DataError::NotAttached),
+        }
+    }
+    
+    /**
+    Attach an invite database, enabling `.create_invite()` and
+    `.redeem_invite()` and making `.save_if_dirty()` save it too.
+    */
+    pub fn attach_invites(&mut self, invites: InviteAuth) { self.invites = Some(invites); }
+    
+    /** Returns the attached invite database, if any. */
+    pub fn invites(&self) -> Option<&InviteAuth> { self.invites.as_ref() }
+    
+    /**
+    Creates an invitation to register, valid for `life`, and returns its
+    token (see `InviteAuth`). If `uname` is given, the invite can only be
+    used to register that user name.
+    
+    Returns `DataError::NotAttached` if no invite database is attached.
+    */
+    pub fn create_invite(&self, uname: Option<&str>, life: Duration)
+    -> Result<String, DataError> {
+        match &self.invites {
+            Some(invites) => Ok(invites.create_invite(uname, life)),
+            None => Err(DataError::NotAttached),
+        }
+    }
+    
+    /**
+    Uses up the invite with the given token to add a user with the given
+    name and password, as `.add_user()` does.
+    
+    Returns `DataError::NotAttached` if no invite database is attached,
+    `DataError::NoSuchKey` if there's no such invite,
+    `DataError::KeyExpired` if it has expired, `DataError::BadUsername` if
+    it's for a different user name, or any of
+    the errors `.add_user()` can return, in which case the invite can
+    still be used (unless the user was added but couldn't be saved).
+    
+    In write-through mode the invite database is saved as soon as the
+    user is, so a used invite can't be redeemed again after a restart.
+    */
+    pub fn redeem_invite(&self, token: &str, uname: &str, password: &str, salt: &[u8])
+    -> Result<(), DataError> {
+        let invites = match &self.invites {
+            Some(invites) => invites,
+            None => { return Err(DataError::NotAttached); },
+        };
+        /* Taking the invite first means no one else can redeem it while
+           the user is being added. */
+        let (hash, imeta) = invites.take(token, uname)?;
+        if let Err(e) = self.pwdauth.add_user(uname, password, salt) {
+            if !matches!(e, DataError::SaveFailed(_)) {
+                invites.restore(hash, imeta);
+            }
+            return Err(e);
+        }
+        if self.pwdauth.is_write_through() {
+            invites.save().map_err(DataError::SaveFailed)?;
+        }
+        return Ok(());
+    }
+    
+    /**
+    Attach a client certificate database, enabling
+    `.check_cert_fingerprint()` and making `.save_if_dirty()` save it too.
+    */
+    pub fn attach_certs(&mut self, certs: CertAuth) { self.certs = Some(certs); }
+    
+    /** Returns the attached client certificate database, if any. */
+    pub fn certs(&self) -> Option<&CertAuth> { self.certs.as_ref() }
+    
+    /**
+    Returns the name of the user the TLS client certificate with the given
+    fingerprint belongs to (see `CertAuth`), checking that the user still
+    exists.
+    
+    Returns `DataError::NoSuchKey` if it doesn't belong to anyone,
+    `DataError::NoSuchUser` if its user has since been deleted, or
+    `DataError::NotAttached` if no certificate database is attached.
+    */
+    pub fn check_cert_fingerprint(&self, fingerprint: &str) -> Result<String, DataError> {
+        let uname = match &self.certs {
+            Some(certs) => certs.check_cert_fingerprint(fingerprint)?,
+            None => { return Err(DataError::NotAttached); },
+        };
+        self.pwdauth.user_exists(&uname)?;
+        return Ok(uname);
+    }
+    
+    /**
+    Attach an external identity database, enabling
+    `.issue_key_for_external()` and making `.save_if_dirty()` save it too.
+    */
+    pub fn attach_external(&mut self, external: ExternalAuth) { self.external = Some(external); }
+    
+    /** Returns the attached external identity database, if any. */
+    pub fn external(&self) -> Option<&ExternalAuth> { self.external.as_ref() }
+    
+    /**
+    Attach a remember-me token database, so that `.purge_user()` ends the
+    user's series and `.save_if_dirty()` saves it too. Issue and redeem
+    tokens with `RememberAuth`'s own methods (through `.remember()`).
+    */
+    pub fn attach_remember(&mut self, remember: RememberAuth) { self.remember = Some(remember); }
+    
+    /** Returns the attached remember-me token database, if any. */
+    pub fn remember(&self) -> Option<&RememberAuth> { self.remember.as_ref() }
+    
+    /**
+    Issues a key to `uname` (as `.issue_user_key()` does) for a login that
+    the application has verified with an external identity provider,
+    linking the provider's `subject` to the user if it isn't already (see
+    `ExternalAuth`). Look the user up with `ExternalAuth::user_for()`
+    first to find out who an identity belongs to.
+    
+    Returns `DataError::NoSuchUser` if the user doesn't exist,
+    `DataError::IdentityLinked` if the identity is linked to a different
+    user, or `DataError::NotAttached` if no external identity database is
+    attached.
+    */
+    pub fn issue_key_for_external(&self, uname: &str, provider: &str, subject: &str)
+    -> Result<String, DataError> {
+        let external = match &self.external {
+            Some(external) => external,
+            None => { return Err(DataError::NotAttached); },
+        };
+        self.pwdauth.user_exists(uname)?;
+        external.link(provider, subject, uname)?;
+        self.issue_class_key(uname)
+    }
+    
     /** Return whether the password database is dirty. */
     pub fn pwd_dirty(&self) -> bool { self.pwdauth.is_dirty() }
     /** Return whether the key database is dirty. */
     pub fn key_dirty(&self) -> bool { self.keyauth.is_dirty() }
     
     /**
-    Checks independently to see if each authorization database is dirty,
-    and will write it to disk if so.
+    Attach a public key database, enabling `.login_with_signature()` and
+    making `.save_if_dirty()` save it too.
+    
+    Requires the `pubkey` feature.
+    */
+    #[cfg(feature = "pubkey")]
+    pub fn attach_pubkeys(&mut self, pubkeys: PubkeyAuth) { self.pubkeys = Some(pubkeys); }
+    
+    /** Returns the attached public key database, if any. */
+    #[cfg(feature = "pubkey")]
+    pub fn pubkeys(&self) -> Option<&PubkeyAuth> { self.pubkeys.as_ref() }
+    
+    /**
+    Checks the user's signature of the challenge they were given by
+    `PubkeyAuth::begin_challenge()` and, if it's good, issues them a key
+    as `.check_password_and_issue_key()` does.
+    
+    Returns `DataError::BadSignature` if the signature is bad,
+    `DataError::NoSuchUser` if the user has keys but has since been
+    deleted, or `DataError::NotAttached` if no public key database is
+    attached.
+    
+    Requires the `pubkey` feature.
+    */
+    #[cfg(feature = "pubkey")]
+    pub fn login_with_signature(&self, uname: &str, signature: &[u8])
+    -> Result<String, DataError> {
+        match &self.pubkeys {
+            Some(pubkeys) => { pubkeys.verify_challenge(uname, signature)?; },
+            None => { return Err(DataError::NotAttached); },
+        }
+        self.pwdauth.user_exists(uname)?;
+        self.issue_class_key(uname)
+    }
+    
+    /**
+    Like `.login_with_signature()`, but for an armored SSH signature of
+    the challenge; see `PubkeyAuth::verify_ssh_challenge()`.
+    
+    Requires the `pubkey` feature.
+    */
+    #[cfg(feature = "pubkey")]
+    pub fn login_with_ssh_signature(&self, uname: &str, armored: &str)
+    -> Result<String, DataError> {
+        match &self.pubkeys {
+            Some(pubkeys) => { pubkeys.verify_ssh_challenge(uname, armored)?; },
+            None => { return Err(DataError::NotAttached); },
+        }
+        self.pwdauth.user_exists(uname)?;
+        self.issue_class_key(uname)
+    }
+    
+    /**
+    Returns a number that goes up every time a user or key changes (the
+    sum of `PwdAuth::generation()` and `KeyAuth::generation()`), so an
+    external cache can tell whether anything has changed since it last
+    looked without saving or checking the dirty flags.
     */
-    pub fn save_if_dirty(&mut self) -> Result<(), FileError> {
+    pub fn generation(&self) -> u64 {
+        self.pwdauth.generation() + self.keyauth.generation()
+    }
+    
+    /** Returns the generation of the key database alone; see `KeyAuth::generation()`. */
+    pub fn key_generation(&self) -> u64 { self.keyauth.generation() }
+    
+    /**
+    Writes the current state of both databases into the directory `dir`,
+    using the same file names as the primary password and key files,
+    without touching the primary files or the dirty flags.
+    
+    This is meant for salvaging state when the primary files can't be
+    written (a full disk or revoked permissions, say), for example from
+    shutdown code that runs after receiving SIGTERM. Both files are
+    attempted even if the first fails; the first error is returned.
+    
+    Note that this acquires the databases' read locks like any other
+    method, so it is not safe to call from _inside_ a signal handler;
+    call it from a thread that is notified of the signal instead.
+    */
+    pub fn dump_to(&self, dir: impl AsRef<Path>) -> Result<(), FileError> {
+        let dir = dir.as_ref();
+        let (pwd_dest, key_dest) = dump_paths(
+            dir, self.pwdauth.path(), self.keyauth.path()
+        );
+        
+        let pwd_result = self.pwdauth.save_to(&pwd_dest);
+        let key_result = self.keyauth.save_to(&key_dest);
+        pwd_result?;
+        key_result
+    }
+    
+    /**
+    Packs the current state of both databases into a single .tar file at
+    `path`, for backups or moving them to another host, without touching
+    the primary files or the dirty flags.
+    
+    Besides the two .csv files (the key file uncompressed, regardless of
+    `.compression()`), the bundle holds the format version, the version of
+    authlite that wrote it, and the size and BLAKE3 hash of each file,
+    which `BothAuth::import_bundle()` checks.
+    
+    Requires the `bundle` feature.
+    */
+    #[cfg(feature = "bundle")]
+    pub fn export_bundle(&self, path: impl AsRef<Path>) -> Result<(), FileError> {
+        let pwd_data = self.pwdauth.to_csv_bytes()?;
+        let key_data = self.keyauth.to_csv_bytes()?;
+        bundle::write_bundle(path.as_ref(), &pwd_data, &key_data)
+    }
+    
+    /**
+    Unpacks a bundle written by `.export_bundle()`, writing its password
+    and key files to the given paths (replacing any files already there),
+    and opens the resulting joint authorization system.
+    
+    The bundle is checked completely before anything is written; a bundle
+    with an unknown format version, missing files, or files whose sizes or
+    hashes don't match its manifest is a `FileError` with kind
+    `ErrorKind::InvalidData`. Any not-valid-before time saved alongside the
+    key file (see `.invalidate_all_sessions()`) is removed, as the bundle
+    contains only keys that were valid when it was exported, and so is any
+    `.sum` file describing an earlier password file (see `PwdAuth::recover()`).
+    
+    Requires the `bundle` feature.
+    */
+    #[cfg(feature = "bundle")]
+    pub fn import_bundle(
+        path: impl AsRef<Path>,
+        pwd_file: impl AsRef<Path>,
+        key_file: impl AsRef<Path>
+    ) -> Result<Self, FileError> {
+        let (pwd_file, key_file) = (pwd_file.as_ref(), key_file.as_ref());
+        let (pwd_data, key_data) = bundle::read_bundle(path.as_ref())?;
+        
+        bundle::write_file(pwd_file, &pwd_data)?;
+        bundle::write_file(key_file, &key_data)?;
+        for stale in [not_before_path(key_file), sum_path(pwd_file)].iter() {
+            if let Err(e) = std::fs::remove_file(stale) {
+                if e.kind() != ErrorKind::NotFound {
+                    return Err(FileError::from_io(stale, Op::Write, &e));
+                }
+            }
+        }
+        
+        BothAuth::open(pwd_file, key_file)
+    }
+    
+    /** Write the password database to disk, whether it's dirty or not. */
+    pub fn save_passwords(&self) -> Result<(), FileError> { self.pwdauth.save() }
+    /** Write the key database to disk, whether it's dirty or not. */
+    pub fn save_keys(&self) -> Result<(), FileError> { self.keyauth.save() }
+    
+    /**
+    Starts a background thread that saves whichever files have changed
+    (as `.save_if_dirty()` does) no more often than every `min_interval`,
+    so that a burst of changes (busy key refreshes, say) is written once
+    rather than once per change. The thread stops when the returned
+    `DebouncedSaver` is stopped or dropped, saving one last time so that
+    nothing is lost at shutdown. Errors are printed as warnings.
+    
+    This is meant to be used instead of write-through mode.
+    */
+    pub fn save_debounced(self: &Arc<Self>, min_interval: Duration) -> DebouncedSaver {
+        let (stop, stopped) = mpsc::channel::<()>();
+        let auth = self.clone();
+        let handle = thread::spawn(move || {
+            loop {
+                let last = stopped.recv_timeout(min_interval) != Err(RecvTimeoutError::Timeout);
+                if let Err(e) = auth.save_if_dirty() {
+                    eprintln!("WARNING: saving: {}", &e);
+                }
+                if last { break; }
+            }
+        });
+        return DebouncedSaver { stop: Some(stop), handle: Some(handle) };
+    }
+    
+    /**
+    Checks independently to see if each authorization database (including
+    any attached group, invite, client certificate, external identity, or
+    public key database) is dirty, and will write it to disk if so,
+    reporting which files were written.
+    
+    If saving the password database fails, the key database isn't saved;
+    call `.save_passwords()` and `.save_keys()` separately to handle each
+    file's errors independently.
+    */
+    pub fn save_if_dirty(&self) -> Result<SaveReport, FileError> {
+        let mut report = SaveReport::default();
+        
         let dirty = self.pwdauth.is_dirty();
-        if dirty { self.pwdauth.save()?; }
+        if dirty {
+            self.pwdauth.save()?;
+            report.passwords = true;
+        }
         let dirty = self.keyauth.is_dirty();
-        if dirty { self.keyauth.save()?; }
+        if dirty {
+            self.keyauth.save()?;
+            report.keys = true;
+        }
+        if let Some(groups) = &self.groups {
+            if groups.is_dirty() {
+                groups.save()?;
+                report.groups = true;
+            }
+        }
+        if let Some(invites) = &self.invites {
+            if invites.is_dirty() {
+                invites.save()?;
+                report.invites = true;
+            }
+        }
+        if let Some(certs) = &self.certs {
+            if certs.is_dirty() {
+                certs.save()?;
+                report.certs = true;
+            }
+        }
+        if let Some(external) = &self.external {
+            if external.is_dirty() {
+                external.save()?;
+                report.external = true;
+            }
+        }
+        #[cfg(feature = "pubkey")]
+        if let Some(pubkeys) = &self.pubkeys {
+            if pubkeys.is_dirty() {
+                pubkeys.save()?;
+                report.pubkeys = true;
+            }
+        }
+        if let Some(remember) = &self.remember {
+            if remember.is_dirty() {
+                remember.save()?;
+                report.remember = true;
+            }
+        }
+        
+        Ok(report)
+    }
+}
+
+/** What `BothAuth::rotate_credentials()` does with a user's existing keys. */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExistingKeys {
+    /** Leave them alone. */
+    Keep,
+    /** Extend their lives, as if they were newly issued. */
+    Refresh,
+    /** Remove them, logging the user out everywhere. */
+    Revoke,
+}
+
+/** What `BothAuth::rotate_credentials()` does besides changing the
+    password (see `BothAuth::rotation_policy()`). */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RotationPolicy {
+    /** What happens to the user's existing keys. */
+    pub existing_keys: ExistingKeys,
+    /** Whether a fresh key is issued (after dealing with the old ones). */
+    pub issue_key: bool,
+}
+
+impl Default for RotationPolicy {
+    fn default() -> Self {
+        RotationPolicy { existing_keys: ExistingKeys::Revoke, issue_key: true }
+    }
+}
+
+/** The results of `BothAuth::verify_consistency()`. */
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ConsistencyReport {
+    /** `(key, user name)` pairs for keys whose users don't exist, sorted
+        by user name. */
+    pub orphaned_keys: Vec<(String, String)>,
+    /** Whether the orphaned keys were removed. */
+    pub repaired: bool,
+}
+
+impl ConsistencyReport {
+    /** Returns `true` if no problems were found. */
+    pub fn is_consistent(&self) -> bool { self.orphaned_keys.is_empty() }
+}
+
+/** A summary of a database's state and configuration, from
+    `BothAuth::status()`. */
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct StatusReport {
+    /** How many users there are. */
+    pub users: usize,
+    /** How many keys are currently valid. */
+    pub active_sessions: usize,
+    /** How many keys are stored, including revoked and expired ones that
+        haven't been culled yet. */
+    pub keys: usize,
+    /** The password file's path. */
+    pub pwd_file: PathBuf,
+    /** The key file's path. */
+    pub key_file: PathBuf,
+    /** Whether the password database has unsaved changes. */
+    pub pwd_dirty: bool,
+    /** Whether the key database has unsaved changes. */
+    pub key_dirty: bool,
+    /** When the password file was last written (by any process), or
+        `None` if that can't be found out. */
+    pub pwd_saved: Option<SystemTime>,
+    /** When the key file was last written (by any process), or `None` if
+        that can't be found out. */
+    pub key_saved: Option<SystemTime>,
+    /** The usual life of a key (see `KeyAuth::life()`). */
+    pub key_life: Duration,
+    /** See `KeyAuth::key_entropy_bits()`. */
+    pub key_entropy_bits: f64,
+    /** See `PwdAuth::max_users()`. */
+    pub max_users: Option<usize>,
+    /** See `KeyAuth::max_keys()`. */
+    pub max_keys: Option<usize>,
+    /** Whether the password database is in write-through mode (see
+        `.write_through()`). */
+    pub write_through: bool,
+}
+
+impl fmt::Display for StatusReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let file = |f: &mut fmt::Formatter<'_>, path: &Path, saved: Option<SystemTime>, dirty: bool| {
+            write!(f, "{}", path.display())?;
+            if let Some(saved) = saved {
+                write!(f, ", saved {}", humantime::format_rfc3339_seconds(saved))?;
+            }
+            writeln!(f, "{}", if dirty { ", unsaved changes" } else { "" })
+        };
+        let limit = |max: Option<usize>| match max {
+            Some(max) => max.to_string(),
+            None => "unlimited".to_string(),
+        };
+        
+        writeln!(f, "users: {} (limit {})", self.users, limit(self.max_users))?;
+        writeln!(f, "sessions: {} active, {} stored (limit {})",
+                 self.active_sessions, self.keys, limit(self.max_keys))?;
+        write!(f, "password file: ")?;
+        file(f, &self.pwd_file, self.pwd_saved, self.pwd_dirty)?;
+        write!(f, "key file: ")?;
+        file(f, &self.key_file, self.key_saved, self.key_dirty)?;
+        writeln!(f, "key life: {}, {:.0} bits of entropy",
+                 humantime::format_duration(self.key_life), self.key_entropy_bits)?;
+        write!(f, "write-through: {}", if self.write_through { "on" } else { "off" })
+    }
+}
+
+/** A successful login (see `BothAuth::login()`). */
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LoginResult {
+    /** The newly-issued session key. */
+    pub session_key: String,
+    /** The CSRF token for `session_key`. */
+    pub csrf_token: String,
+}
+
+/** Which files were written by `BothAuth::save_if_dirty()`. */
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SaveReport {
+    /** Whether the password file was written. */
+    pub passwords: bool,
+    /** Whether the key file was written. */
+    pub keys: bool,
+    /** Whether the group file (if any) was written. */
+    pub groups: bool,
+    /** Whether the invite file (if any) was written. */
+    pub invites: bool,
+    /** Whether the client certificate file (if any) was written. */
+    pub certs: bool,
+    /** Whether the external identity file (if any) was written. */
+    pub external: bool,
+    /** Whether the public key file (if any, with the `pubkey` feature)
+        was written. */
+    pub pubkeys: bool,
+    /** Whether the remember-me token file (if any) was written. */
+    pub remember: bool,
+}
+
+impl SaveReport {
+    /** Whether any file at all was written. */
+    pub fn any(&self) -> bool {
+        return self.passwords || self.keys || self.groups || self.invites
+            || self.certs || self.external || self.pubkeys || self.remember;
+    }
+}
+
+/** What `BothAuth::purge_user()` deleted. */
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PurgeReport {
+    /** Whether the user was in the password file. */
+    pub user: bool,
+    /** How many keys they had. */
+    pub keys: usize,
+    /** How many groups they were in (if a group database is attached). */
+    pub groups: usize,
+    /** How many unused invites named them (if an invite database is
+        attached). */
+    pub invites: usize,
+    /** How many client certificates they had (if a certificate database
+        is attached). */
+    pub certs: usize,
+    /** How many external identities were linked to them (if an external
+        identity database is attached). */
+    pub external: usize,
+    /** How many public keys they had (if a public key database is
+        attached, with the `pubkey` feature). */
+    pub pubkeys: usize,
+    /** How many remember-me series they had (if a remember-me token
+        database is attached). */
+    pub remember: usize,
+}
+
+/** Returns when the file at `path` was last modified, if that can be found out. */
+fn modified(path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(path).and_then(|meta| meta.modified()).ok()
+}
+
+/**
+The background thread started by `BothAuth::save_debounced()`. Stops
+(after a final save) when `.stop()` is called or it's dropped.
+*/
+#[derive(Debug)]
+pub struct DebouncedSaver {
+    stop: Option<Sender<()>>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl DebouncedSaver {
+    /** Stops the thread, waiting for its final save to finish. */
+    pub fn stop(mut self) { self.shutdown(); }
+    
+    fn shutdown(&mut self) {
+        /* Dropping the sender wakes the thread up. */
+        drop(self.stop.take());
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+impl Drop for DebouncedSaver {
+    fn drop(&mut self) { self.shutdown(); }
+}
+
+/** Collects the configuration of a `BothAuth` so that it can all be set
+    in one place before anything touches the disk.
+    
+    ```no_run
+    # use std::time::Duration;
+    # use authlite::BothAuth;
+    let auth = BothAuth::builder()
+        .pwd_file("data/users.csv")
+        .key_file("data/keys.csv")
+        .key_life(Duration::from_secs(3600))
+        .key_length(24)
+        .build()
+        .unwrap();
+    ```
+    
+    Settings that aren't specified keep the same defaults they would
+    have for a `PwdAuth` or `KeyAuth` constructed directly.
+*/
+#[derive(Debug, Default, Clone)]
+pub struct BothAuthBuilder {
+    pwd_file: Option<PathBuf>,
+    key_file: Option<PathBuf>,
+    create_dirs: bool,
+    key_life: Option<Duration>,
+    key_length: Option<usize>,
+    key_chars: Option<String>,
+    max_users: Option<usize>,
+    max_keys: Option<usize>,
+    max_pwd_file_size: Option<u64>,
+    max_key_file_size: Option<u64>,
+    capacity_policy: Option<CapacityPolicy>,
+    idle_timeout: Option<Duration>,
+    issue_rate_limit: Option<(usize, Duration)>,
+    username_policy: Option<UsernamePolicy>,
+    input_limits: Option<InputLimits>,
+    pepper: Option<Result<Secret, FileError>>,
+    hasher: Option<HashFn>,
+    hash_encoding: Option<HashEncoding>,
+    salt_encoding: Option<SaltEncoding>,
+    checker: Option<CheckerFn>,
+    signing_secret: Option<Result<Secret, FileError>>,
+    rotation_policy: Option<RotationPolicy>,
+    class_lives: Vec<(String, Duration)>,
+    case_insensitive: bool,
+    normalize_passwords: bool,
+    uniform_errors: bool,
+    write_through: bool,
+    save_order: Option<SaveOrder>,
+    #[cfg(feature = "http-hooks")]
+    webhook: Option<Webhook>,
+}
+
+impl BothAuthBuilder {
+    /** Set the path of the password file (required). */
+    pub fn pwd_file(mut self, path: impl AsRef<Path>) -> Self {
+        self.pwd_file = Some(PathBuf::from(path.as_ref()));
+        self
+    }
+    
+    /** Set the path of the key file (required). */
+    pub fn key_file(mut self, path: impl AsRef<Path>) -> Self {
+        self.key_file = Some(PathBuf::from(path.as_ref()));
+        self
+    }
+    
+    /** Create missing parent directories of new files (see `.new_with_dirs()`). */
+    pub fn create_dirs(mut self, create: bool) -> Self {
+        self.create_dirs = create;
+        self
+    }
+    
+    /** See `KeyAuth::life()`. */
+    pub fn key_life(mut self, life: Duration) -> Self {
+        self.key_life = Some(life);
+        self
+    }
+    
+    /**
+    See `KeyAuth::life_str()`; returns the error if the string can't be
+    parsed.
+    */
+    pub fn key_life_str(mut self, life: &str) -> Result<Self, humantime::DurationError> {
+        self.key_life = Some(humantime::parse_duration(life)?);
+        return Ok(self);
+    }
+    
+    /** See `KeyAuth::length()`. */
+    pub fn key_length(mut self, length: usize) -> Self {
+        self.key_length = Some(length);
+        self
+    }
+    
+    /** See `KeyAuth::chars()`. */
+    pub fn key_chars(mut self, chars: impl AsRef<str>) -> Self {
+        self.key_chars = Some(chars.as_ref().to_string());
+        self
+    }
+    
+    /** See `KeyAuth::charset()`. */
+    pub fn key_charset(self, charset: KeyCharset) -> Self {
+        self.key_chars(charset.chars())
+    }
+    
+    /** See `PwdAuth::max_users()`. */
+    pub fn max_users(mut self, max: usize) -> Self {
+        self.max_users = Some(max);
+        self
+    }
+    
+    /** See `KeyAuth::max_keys()`. */
+    pub fn max_keys(mut self, max: usize) -> Self {
+        self.max_keys = Some(max);
+        self
+    }
+    
+    /** See `PwdAuth::max_file_size()`. */
+    pub fn max_pwd_file_size(mut self, max: u64) -> Self {
+        self.max_pwd_file_size = Some(max);
+        self
+    }
+    
+    /** See `KeyAuth::max_file_size()`. */
+    pub fn max_key_file_size(mut self, max: u64) -> Self {
+        self.max_key_file_size = Some(max);
+        self
+    }
+    
+    /** See `KeyAuth::capacity_policy()`. */
+    pub fn capacity_policy(mut self, policy: CapacityPolicy) -> Self {
+        self.capacity_policy = Some(policy);
+        self
+    }
+    
+    /** See `KeyAuth::idle_timeout()`. */
+    pub fn idle_timeout(mut self, max_idle: Duration) -> Self {
+        self.idle_timeout = Some(max_idle);
+        self
+    }
+    
+    /** See `KeyAuth::issue_rate_limit()`. */
+    pub fn issue_rate_limit(mut self, max: usize, per: Duration) -> Self {
+        self.issue_rate_limit = Some((max, per));
+        self
+    }
+    
+    /** See `PwdAuth::username_policy()`. */
+    pub fn username_policy(mut self, policy: UsernamePolicy) -> Self {
+        self.username_policy = Some(policy);
+        self
+    }
+    
+    /** See `PwdAuth::input_limits()`. */
+    pub fn input_limits(mut self, limits: InputLimits) -> Self {
+        self.input_limits = Some(limits);
+        self
+    }
+    
+    /**
+    See `PwdAuth::pepper()`. The secret is retrieved from `provider`
+    immediately; if that fails, `.build()` returns the error (as a
+    `FileError` with an empty path).
+    */
+    pub fn pepper(mut self, provider: &dyn SecretProvider) -> Self {
+        let pepper = Secret::from_provider(provider)
+            .map_err(|e| FileError::from_io(Path::new(""), Op::Read, &e));
+        self.pepper = Some(pepper);
+        self
+    }
+    
+    /** See `PwdAuth::password_hasher()`. */
+    pub fn password_hasher<H>(mut self, hasher: H) -> Self
+    where H: PasswordHasher + 'static
+    {
+        self.hasher = Some(HashFn::new(hasher));
+        self
+    }
+    
+    /** See `PwdAuth::credential_checker()`. */
+    pub fn credential_checker<C>(mut self, checker: C) -> Self
+    where C: CredentialChecker + 'static
+    {
+        self.checker = Some(CheckerFn::new(checker));
+        self
+    }
+    
+    /** See `BothAuth::signing_secret()`; errors are handled as for `.pepper()`. */
+    pub fn signing_secret(mut self, provider: &dyn SecretProvider) -> Self {
+        let secret = Secret::from_provider(provider)
+            .map_err(|e| FileError::from_io(Path::new(""), Op::Read, &e));
+        self.signing_secret = Some(secret);
+        self
+    }
+    
+    /** See `BothAuth::rotation_policy()`. */
+    pub fn rotation_policy(mut self, policy: RotationPolicy) -> Self {
+        self.rotation_policy = Some(policy);
+        self
+    }
+    
+    /** See `BothAuth::class_life()`; may be called once for each class. */
+    pub fn class_life(mut self, class: impl Into<String>, life: Duration) -> Self {
+        self.class_lives.push((class.into(), life));
+        self
+    }
+    
+    /** See `PwdAuth::case_insensitive()`. */
+    pub fn case_insensitive(mut self, on: bool) -> Self {
+        self.case_insensitive = on;
+        self
+    }
+    
+    /** See `PwdAuth::normalize_passwords()`. */
+    pub fn normalize_passwords(mut self, on: bool) -> Self {
+        self.normalize_passwords = on;
+        self
+    }
+    
+    /** See `PwdAuth::hash_encoding()`. */
+    pub fn hash_encoding(mut self, encoding: HashEncoding) -> Self {
+        self.hash_encoding = Some(encoding);
+        self
+    }
+    
+    /** See `PwdAuth::salt_encoding()`. */
+    pub fn salt_encoding(mut self, encoding: SaltEncoding) -> Self {
+        self.salt_encoding = Some(encoding);
+        self
+    }
+    
+    /** See `PwdAuth::uniform_errors()`. */
+    pub fn uniform_errors(mut self, on: bool) -> Self {
+        self.uniform_errors = on;
+        self
+    }
+    
+    /** See `BothAuth::write_through()`. */
+    pub fn write_through(mut self, on: bool) -> Self {
+        self.write_through = on;
+        self
+    }
+    
+    /** See `BothAuth::save_order()`. */
+    pub fn save_order(mut self, order: SaveOrder) -> Self {
+        self.save_order = Some(order);
+        self
+    }
+    
+    /**
+    Send events to the given webhook (see `Webhook::into_handler()`).
+    
+    Requires the `http-hooks` feature.
+    */
+    #[cfg(feature = "http-hooks")]
+    pub fn webhook(mut self, hook: Webhook) -> Self {
+        self.webhook = Some(hook);
+        self
+    }
+    
+    /**
+    Open the configured password and key files (creating either if it
+    doesn't exist, as with `BothAuth::open_or_new()`) and apply all the
+    configured settings.
+    
+    Returns a `FileError` with kind `ErrorKind::InvalidInput` if either
+    file path hasn't been set.
+    */
+    pub fn build(self) -> Result<BothAuth, FileError> {
+        let pwd_file = require_path(self.pwd_file, "password")?;
+        let key_file = require_path(self.key_file, "key")?;
+        let pepper = self.pepper.transpose()?;
+        let signing_key = self.signing_secret.transpose()?;
+        
+        if self.create_dirs {
+            crate::create_parent_dirs(&pwd_file)?;
+            crate::create_parent_dirs(&key_file)?;
+        }
+        let mut ba = BothAuth::open_or_new(&pwd_file, &key_file)?;
         
-        Ok(())
+        if let Some(life) = self.key_life { ba.life(life); }
+        if let Some(length) = self.key_length { ba.length(length); }
+        if let Some(chars) = self.key_chars { ba.chars(chars); }
+        ba.max_users(self.max_users);
+        ba.max_keys(self.max_keys);
+        if self.max_pwd_file_size.is_some() { ba.max_pwd_file_size(self.max_pwd_file_size); }
+        if self.max_key_file_size.is_some() { ba.max_key_file_size(self.max_key_file_size); }
+        if let Some(policy) = self.capacity_policy { ba.capacity_policy(policy); }
+        ba.idle_timeout(self.idle_timeout);
+        if let Some((max, per)) = self.issue_rate_limit { ba.issue_rate_limit(Some(max), per); }
+        if let Some(policy) = self.username_policy { ba.username_policy(policy); }
+        if let Some(limits) = self.input_limits { ba.input_limits(limits); }
+        if let Some(pepper) = pepper { ba.pwdauth.set_pepper(pepper); }
+        if let Some(hasher) = self.hasher { ba.pwdauth.set_hasher(hasher); }
+        if let Some(checker) = self.checker { ba.pwdauth.set_checker(checker); }
+        if let Some(key) = signing_key { ba.signing_key = key; }
+        if let Some(policy) = self.rotation_policy { ba.rotation = policy; }
+        for (class, life) in self.class_lives { ba.class_life(class, life); }
+        ba.case_insensitive(self.case_insensitive);
+        ba.normalize_passwords(self.normalize_passwords);
+        if let Some(encoding) = self.hash_encoding { ba.hash_encoding(encoding); }
+        if let Some(encoding) = self.salt_encoding { ba.salt_encoding(encoding); }
+        ba.uniform_errors(self.uniform_errors);
+        ba.write_through(self.write_through);
+        if let Some(order) = self.save_order { ba.save_order(order); }
+        #[cfg(feature = "http-hooks")]
+        if let Some(hook) = self.webhook { ba.on_event(hook.into_handler()); }
+        
+        return Ok(ba);
+    }
+}
+
+/** Returns the path if it was set, or an appropriate error if not. */
+fn require_path(path: Option<PathBuf>, which: &str) -> Result<PathBuf, FileError> {
+    match path {
+        Some(p) => Ok(p),
+        None => Err(FileError::new(
+            Path::new(""), Op::Create, ErrorKind::InvalidInput,
+            format!("no {} file specified", which)
+        )),
     }
-}
\ No newline at end of file
+}
+
+/**
+Returns the paths in `dir` to which a dump of the given password and
+key files should be written.
+*/
+fn dump_paths(dir: &Path, pwd_file: &Path, key_file: &Path) -> (PathBuf, PathBuf) {
+    let pwd_name = pwd_file.file_name().unwrap_or_else(|| "users.csv".as_ref());
+    let key_name = key_file.file_name().unwrap_or_else(|| "keys.csv".as_ref());
+    
+    let pwd_dest = dir.join(pwd_name);
+    let mut key_dest = dir.join(key_name);
+    if key_dest == pwd_dest {
+        key_dest = dir.join(format!("keys-{}", key_name.to_string_lossy()));
+    }
+    
+    (pwd_dest, key_dest)
+}
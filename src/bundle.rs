@@ -0,0 +1,188 @@
+use std::collections::HashMap;
+use std::io::{ErrorKind, Read, Write};
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::{Serialize, Deserialize};
+
+use crate::{FileError, Op, open_for_read, open_for_atomic_write, abort_atomic_write,
+            commit_atomic_write};
+
+const BUNDLE_FORMAT: u32 = 1;
+const META_FILE: &str = "bundle.csv";
+const MANIFEST_FILE: &str = "manifest.csv";
+const PWD_ENTRY: &str = "passwords.csv";
+const KEY_ENTRY: &str = "keys.csv";
+
+#[derive(Debug, Serialize, Deserialize)]
+struct MetaRW {
+    format: u32,
+    authlite_version: String,
+    #[serde(with ="humantime_serde")]
+    created: SystemTime,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct ManifestRW {
+    file: String,
+    bytes: u64,
+    blake3: String,
+}
+
+/** Serializes a sequence of records as .csv data. */
+fn to_csv<T: Serialize>(path: &Path, rows: &[T]) -> Result<Vec<u8>, FileError> {
+    let mut w = csv::Writer::from_writer(Vec::new());
+    for row in rows.iter() {
+        if let Err(e) = w.serialize(row) {
+            return Err(FileError::from_csv(path, Op::Write, &e));
+        }
+    }
+    match w.into_inner() {
+        Ok(v) => Ok(v),
+        Err(e) => Err(FileError::from_io(path, Op::Write, e.error())),
+    }
+}
+
+/** Deserializes the named .csv entry from a bundle's contents. */
+fn from_csv<T>(path: &Path, entries: &HashMap<String, Vec<u8>>, name: &str)
+-> Result<Vec<T>, FileError>
+where T: for<'de> Deserialize<'de>
+{
+    let data = match entries.get(name) {
+        Some(d) => d,
+        None => { return Err(invalid(path, format!("missing {}", name))); },
+    };
+    let mut r = csv::Reader::from_reader(data.as_slice());
+    let mut rows: Vec<T> = Vec::new();
+    for result in r.deserialize::<T>() {
+        match result {
+            Ok(row) => { rows.push(row); },
+            Err(e) => { return Err(FileError::from_csv(path, Op::Read, &e)); },
+        }
+    }
+    return Ok(rows);
+}
+
+/** The error for a bundle that doesn't check out. */
+fn invalid(path: &Path, msg: String) -> FileError {
+    FileError::new(path, Op::Read, ErrorKind::InvalidData, msg)
+}
+
+/**
+Writes a bundle containing the given password and key file contents to
+`path`, atomically.
+*/
+pub(crate) fn write_bundle(path: &Path, pwd_data: &[u8], key_data: &[u8])
+-> Result<(), FileError> {
+    let meta = MetaRW {
+        format: BUNDLE_FORMAT,
+        authlite_version: String::from(env!("CARGO_PKG_VERSION")),
+        created: SystemTime::now(),
+    };
+    let manifest: Vec<ManifestRW> = [(PWD_ENTRY, pwd_data), (KEY_ENTRY, key_data)]
+        .iter()
+        .map(|(name, data)| ManifestRW {
+            file:   String::from(*name),
+            bytes:  data.len() as u64,
+            blake3: blake3::hash(data).to_hex().to_string(),
+        })
+        .collect();
+    let meta_data = to_csv(path, &[meta])?;
+    let manifest_data = to_csv(path, &manifest)?;
+    let mtime = SystemTime::now().duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    
+    let (f, tmp) = open_for_atomic_write(path)?;
+    let mut b = tar::Builder::new(f);
+    let entries: [(&str, &[u8]); 4] = [
+        (META_FILE, &meta_data),
+        (MANIFEST_FILE, &manifest_data),
+        (PWD_ENTRY, pwd_data),
+        (KEY_ENTRY, key_data),
+    ];
+    for (name, data) in entries.iter() {
+        let mut header = tar::Header::new_gnu();
+        header.set_size(data.len() as u64);
+        header.set_mode(0o600);
+        header.set_mtime(mtime);
+        header.set_cksum();
+        if let Err(e) = b.append_data(&mut header, name, *data) {
+            return Err(abort_atomic_write(&tmp, FileError::from_io(path, Op::Write, &e)));
+        }
+    }
+    let f = match b.into_inner() {
+        Ok(f) => f,
+        Err(e) => { return Err(abort_atomic_write(&tmp, FileError::from_io(path, Op::Write, &e))); },
+    };
+    
+    return commit_atomic_write(f, &tmp, path);
+}
+
+/**
+Reads the bundle at `path`, checking its format version and the sizes and
+hashes of its database files, and returns the contents of the password
+and key files.
+*/
+pub(crate) fn read_bundle(path: &Path) -> Result<(Vec<u8>, Vec<u8>), FileError> {
+    let f = open_for_read(path)?;
+    let mut archive = tar::Archive::new(f);
+    let mut entries: HashMap<String, Vec<u8>> = HashMap::new();
+    let iter = match archive.entries() {
+        Ok(x) => x,
+        Err(e) => { return Err(FileError::from_io(path, Op::Read, &e)); },
+    };
+    for entry in iter {
+        let mut entry = match entry {
+            Ok(x) => x,
+            Err(e) => { return Err(FileError::from_io(path, Op::Read, &e)); },
+        };
+        let name = match entry.path() {
+            Ok(p) => p.to_string_lossy().into_owned(),
+            Err(e) => { return Err(FileError::from_io(path, Op::Read, &e)); },
+        };
+        let mut data: Vec<u8> = Vec::new();
+        if let Err(e) = entry.read_to_end(&mut data) {
+            return Err(FileError::from_io(path, Op::Read, &e));
+        }
+        entries.insert(name, data);
+    }
+    
+    let meta: Vec<MetaRW> = from_csv(path, &entries, META_FILE)?;
+    match meta.first() {
+        Some(m) if m.format == BUNDLE_FORMAT => {},
+        Some(m) => {
+            return Err(invalid(path, format!("unsupported bundle format {}", m.format)));
+        },
+        None => { return Err(invalid(path, format!("empty {}", META_FILE))); },
+    }
+    
+    let manifest: Vec<ManifestRW> = from_csv(path, &entries, MANIFEST_FILE)?;
+    for name in [PWD_ENTRY, KEY_ENTRY].iter() {
+        let data = match entries.get(*name) {
+            Some(d) => d,
+            None => { return Err(invalid(path, format!("missing {}", name))); },
+        };
+        let mrw = match manifest.iter().find(|m| m.file == *name) {
+            Some(m) => m,
+            None => { return Err(invalid(path, format!("{} not in manifest", name))); },
+        };
+        let hash = blake3::hash(data).to_hex();
+        if mrw.bytes != data.len() as u64 || mrw.blake3 != hash.as_str() {
+            return Err(invalid(path, format!("{} failed integrity check", name)));
+        }
+    }
+    
+    let pwd_data = entries.remove(PWD_ENTRY).unwrap();
+    let key_data = entries.remove(KEY_ENTRY).unwrap();
+    return Ok((pwd_data, key_data));
+}
+
+/** Atomically writes `data` to the file at `path`. */
+pub(crate) fn write_file(path: &Path, data: &[u8]) -> Result<(), FileError> {
+    let (mut f, tmp) = open_for_atomic_write(path)?;
+    if let Err(e) = f.write_all(data) {
+        return Err(abort_atomic_write(&tmp, FileError::from_io(path, Op::Write, &e)));
+    }
+    return commit_atomic_write(f, &tmp, path);
+}
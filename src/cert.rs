@@ -0,0 +1,243 @@
+use std::collections::{BTreeMap, HashMap};
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+
+use parking_lot::RwLock;
+use serde::{Serialize, Deserialize};
+
+use crate::{FileError, DataError, Op, open_for_read, open_for_write, open_for_atomic_write,
+            abort_atomic_write, commit_atomic_write};
+
+#[derive(Debug, Serialize, Deserialize)]
+struct CertRW {
+    fingerprint: String,
+    uname: String,
+}
+
+/** A database mapping TLS client certificate fingerprints to user names,
+    which persists as a .csv file on disk.
+    
+    This is for mutual-TLS setups where a reverse proxy terminates TLS,
+    checks the client's certificate, and passes its fingerprint along
+    (nginx's `$ssl_client_fingerprint`, for example); the application then
+    looks the fingerprint up with `.check_cert_fingerprint()` to find out
+    who the client is. The fingerprint can be of any hash; fingerprints are
+    compared case-insensitively and ignoring any colons, so `AB:CD:EF` and
+    `abcdef` are the same fingerprint. A user can have any number of
+    certificates, but each certificate belongs to only one user.
+    
+    Nothing here checks the certificate itself; that's the proxy's job, and
+    the proxy must not let clients supply the fingerprint themselves.
+    
+    Attach a `CertAuth` to a `BothAuth` (with `BothAuth::attach_certs()`) to
+    check that the user still exists, too.
+    
+    This database has no write-through mode; it's flagged as "dirty" after
+    a change until saved with `.save()` (or `BothAuth::save_if_dirty()`).
+*/
+#[derive(Debug)]
+pub struct CertAuth {
+    certs:  RwLock<HashMap<String, String>>,
+    cfile:  PathBuf,
+    cdirty: RwLock<bool>,
+}
+
+impl CertAuth {
+    /**
+    Create a new certificate database that will save its data to a .csv
+    file at the supplied path.
+    */
+    pub fn new(cert_file: impl AsRef<Path>) -> Result<Self, FileError> {
+        let cert_file = cert_file.as_ref();
+        
+        if Path::exists(cert_file) {
+            return Err(FileError::new(cert_file, Op::Create, ErrorKind::AlreadyExists,
+                                      "file already exists".to_string()));
+        }
+        
+        let f = open_for_write(cert_file)?;
+        let mut w = csv::Writer::from_writer(f);
+        if let Err(e) = w.write_record(["fingerprint", "uname"]) {
+            return Err(FileError::from_csv(cert_file, Op::Create, &e));
+        }
+        if let Err(e) = w.flush() {
+            return Err(FileError::from_io(cert_file, Op::Create, &e));
+        }
+        
+        return Ok(CertAuth::from_certs(cert_file, HashMap::new()));
+    }
+    
+    /** Open a certificate database with data from the .csv file at the given path. */
+    pub fn open(cert_file: impl AsRef<Path>) -> Result<Self, FileError> {
+        let cert_file = cert_file.as_ref();
+        
+        let f = open_for_read(cert_file)?;
+        let mut certs: HashMap<String, String> = HashMap::new();
+        let mut r = csv::Reader::from_reader(f);
+        for (n, result) in r.deserialize::<CertRW>().enumerate() {
+            match result {
+                Err(e) => {
+                    eprintln!("WARNING: reading {}, record {}: {}",
+                        cert_file.to_string_lossy(), n, &e);
+                },
+                Ok(crw) => {
+                    let _ = certs.insert(normalize(&crw.fingerprint), crw.uname);
+                },
+            }
+        }
+        
+        return Ok(CertAuth::from_certs(cert_file, certs));
+    }
+    
+    /**
+    Open the certificate database at the given path if the file exists, or
+    create a new one there if it doesn't.
+    */
+    pub fn open_or_new(cert_file: impl AsRef<Path>) -> Result<Self, FileError> {
+        let cert_file = cert_file.as_ref();
+        match CertAuth::open(cert_file) {
+            Err(e) if e.kind == ErrorKind::NotFound => CertAuth::new(cert_file),
+            x => x,
+        }
+    }
+    
+    fn from_certs(cert_file: &Path, certs: HashMap<String, String>) -> Self {
+        return CertAuth {
+            certs:  RwLock::new(certs),
+            cfile:  PathBuf::from(cert_file),
+            cdirty: RwLock::new(false),
+        };
+    }
+    
+    /**
+    Registers the certificate with the given fingerprint as belonging to
+    the user. Marks the database as "dirty".
+    
+    Returns `DataError::UserExists` (naming its current owner) if the
+    certificate already belongs to someone else; remove it first to
+    reassign it.
+    */
+    pub fn add_fingerprint(&self, fingerprint: &str, uname: &str) -> Result<(), DataError> {
+        let mut certs = self.certs.write();
+        let fingerprint = normalize(fingerprint);
+        match certs.get(&fingerprint) {
+            Some(owner) if owner == uname => { return Ok(()); },
+            Some(owner) => { return Err(DataError::UserExists { uname: owner.clone() }); },
+            None => { let _ = certs.insert(fingerprint, uname.to_string()); },
+        }
+        
+        let mut dirty = self.cdirty.write();
+        *dirty = true;
+        return Ok(());
+    }
+    
+    /**
+    Forgets the certificate with the given fingerprint. Marks the database
+    as "dirty".
+    
+    Returns `DataError::NoSuchKey` if no user has that certificate.
+    */
+    pub fn remove_fingerprint(&self, fingerprint: &str) -> Result<(), DataError> {
+        let mut certs = self.certs.write();
+        if certs.remove(&normalize(fingerprint)).is_none() { return Err(DataError::NoSuchKey); }
+        
+        let mut dirty = self.cdirty.write();
+        *dirty = true;
+        return Ok(());
+    }
+    
+    /**
+    Forgets all the user's certificates, returning how many there were.
+    Marks the database as "dirty" if there were any.
+    */
+    pub fn remove_user(&self, uname: &str) -> usize {
+        let mut certs = self.certs.write();
+        let before = certs.len();
+        certs.retain(|_, owner| owner != uname);
+        let removed = before - certs.len();
+        
+        if removed > 0 {
+            let mut dirty = self.cdirty.write();
+            *dirty = true;
+        }
+        return removed;
+    }
+    
+    /**
+    Returns the name of the user the certificate with the given fingerprint
+    belongs to.
+    
+    Returns `DataError::NoSuchKey` if it doesn't belong to anyone.
+    */
+    pub fn check_cert_fingerprint(&self, fingerprint: &str) -> Result<String, DataError> {
+        let certs = self.certs.read();
+        match certs.get(&normalize(fingerprint)) {
+            Some(uname) => Ok(uname.clone()),
+            None => Err(DataError::NoSuchKey),
+        }
+    }
+    
+    /**
+    Returns the fingerprints of all the user's certificates (in lowercase
+    hex without colons), sorted.
+    */
+    pub fn fingerprints_of(&self, uname: &str) -> Vec<String> {
+        let certs = self.certs.read();
+        let mut fingerprints: Vec<String> = certs.iter()
+            .filter(|(_, owner)| owner.as_str() == uname)
+            .map(|(fingerprint, _)| fingerprint.clone())
+            .collect();
+        fingerprints.sort();
+        return fingerprints;
+    }
+    
+    /** Returns the path of the file this database saves to. */
+    pub fn path(&self) -> &Path { &self.cfile }
+    
+    /**
+    Returns whether the in-memory database is "dirty", that is, whether it's
+    out of sync with the persistent data on disk.
+    */
+    pub fn is_dirty(&self) -> bool {
+        let dirty = self.cdirty.read();
+        return *dirty;
+    }
+    
+    /**
+    Writes the current state of the database to disk (sorted by
+    fingerprint, so it diffs nicely), marking it as no longer dirty. The
+    file is replaced atomically.
+    */
+    pub fn save(&self) -> Result<(), FileError> {
+        let path = &self.cfile;
+        let certs = self.certs.write();
+        let sorted: BTreeMap<&String, &String> = certs.iter().collect();
+        
+        let (f, tmp) = open_for_atomic_write(path)?;
+        let mut w = csv::Writer::from_writer(f);
+        for (fingerprint, uname) in sorted.into_iter() {
+            let crw = CertRW { fingerprint: fingerprint.clone(), uname: uname.clone() };
+            if let Err(e) = w.serialize(crw) {
+                return Err(abort_atomic_write(&tmp, FileError::from_csv(path, Op::Write, &e)));
+            }
+        }
+        let f = match w.into_inner() {
+            Ok(f) => f,
+            Err(e) => { return Err(abort_atomic_write(&tmp, FileError::from_io(path, Op::Write, e.error()))); },
+        };
+        commit_atomic_write(f, &tmp, path)?;
+        
+        let mut dirty = self.cdirty.write();
+        *dirty = false;
+        
+        return Ok(());
+    }
+}
+
+/* Fingerprints are compared as lowercase hex without colons (or spaces). */
+fn normalize(fingerprint: &str) -> String {
+    return fingerprint.chars()
+        .filter(|c| *c != ':' && !c.is_whitespace())
+        .map(|c| c.to_ascii_lowercase())
+        .collect();
+}
@@ -0,0 +1,50 @@
+use std::fmt;
+use std::sync::Arc;
+
+use crate::DataError;
+
+/** Something other than the password file that can check a user's
+    password, like a corporate directory (see `LdapChecker`, with the
+    `ldap` feature).
+    
+    With a checker set (see `PwdAuth::credential_checker()`), passwords are
+    checked by it instead of against the stored hashes, but users must
+    still be added to the password database, which still holds everything
+    else about them (admin status, IDs, key classes, and so on), and keys
+    are still issued and checked locally. A user who isn't in the password
+    database can't log in even if the checker accepts their password
+    (though the checker is still asked, to keep the timing the same).
+*/
+pub trait CredentialChecker: Send + Sync {
+    /**
+    Returns `Ok(())` if the password is right for the user. Otherwise
+    returns `DataError::BadPassword` if it's wrong, `DataError::NoSuchUser`
+    if the checker doesn't know the user, or `DataError::Unavailable` if
+    it couldn't tell (because a server couldn't be reached, say).
+    */
+    fn check_credentials(&self, uname: &str, password: &str) -> Result<(), DataError>;
+}
+
+/** Holds the `CredentialChecker` for a database. */
+#[derive(Clone)]
+pub(crate) struct CheckerFn {
+    checker: Arc<dyn CredentialChecker>,
+}
+
+impl CheckerFn {
+    pub(crate) fn new<C>(checker: C) -> Self
+    where C: CredentialChecker + 'static
+    {
+        CheckerFn { checker: Arc::new(checker) }
+    }
+    
+    pub(crate) fn check(&self, uname: &str, password: &str) -> Result<(), DataError> {
+        self.checker.check_credentials(uname, password)
+    }
+}
+
+impl fmt::Debug for CheckerFn {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "CheckerFn(..)")
+    }
+}
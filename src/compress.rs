@@ -0,0 +1,146 @@
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::path::Path;
+
+use crate::{FileError, Op};
+
+const GZIP_MAGIC: &[u8] = &[0x1f, 0x8b];
+const ZSTD_MAGIC: &[u8] = &[0x28, 0xb5, 0x2f, 0xfd];
+
+/** Compression applied to a saved key file (see `KeyAuth::compression()`).
+
+    Each compression method requires the crate feature of the same
+    (lowercase) name; trying to read or write a compressed file without
+    it is a `FileError` with kind `ErrorKind::Unsupported`.
+*/
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Compression {
+    None,
+    Gzip,
+    Zstd,
+}
+
+impl Compression {
+    /**
+    Chooses a compression method based on the extension of the file name:
+    `.gz` for `Gzip`, `.zst` for `Zstd`, and no compression otherwise.
+    */
+    pub fn from_path(p: &Path) -> Self {
+        match p.extension().and_then(|x| x.to_str()) {
+            Some("gz") => Compression::Gzip,
+            Some("zst") => Compression::Zstd,
+            _ => Compression::None,
+        }
+    }
+    
+    /** Identifies the compression of a file from its first few bytes. */
+    fn sniff(start: &[u8]) -> Self {
+        if start.starts_with(GZIP_MAGIC) {
+            Compression::Gzip
+        } else if start.starts_with(ZSTD_MAGIC) {
+            Compression::Zstd
+        } else {
+            Compression::None
+        }
+    }
+}
+
+/** The error for a compression method whose feature isn't enabled. */
+#[cfg(not(all(feature = "gzip", feature = "zstd")))]
+fn unsupported(p: &Path, op: Op, feature: &str) -> FileError {
+    FileError::new(
+        p, op, io::ErrorKind::Unsupported,
+        format!("authlite was built without the \"{}\" feature", feature)
+    )
+}
+
+/**
+Wraps a file opened for reading in the appropriate decompressor, detecting
+the compression (if any) from the file's contents rather than its name.
+*/
+pub(crate) fn decoder(f: File, p: &Path) -> Result<Box<dyn Read>, FileError> {
+    let mut r = BufReader::new(f);
+    let start = match r.fill_buf() {
+        Ok(buf) => buf,
+        Err(e) => { return Err(FileError::from_io(p, Op::Read, &e)); },
+    };
+    
+    match Compression::sniff(start) {
+        Compression::None => Ok(Box::new(r)),
+        #[cfg(feature = "gzip")]
+        Compression::Gzip => Ok(Box::new(flate2::bufread::GzDecoder::new(r))),
+        #[cfg(not(feature = "gzip"))]
+        Compression::Gzip => Err(unsupported(p, Op::Read, "gzip")),
+        #[cfg(feature = "zstd")]
+        Compression::Zstd => match zstd::Decoder::with_buffer(r) {
+            Ok(d) => Ok(Box::new(d)),
+            Err(e) => Err(FileError::from_io(p, Op::Read, &e)),
+        },
+        #[cfg(not(feature = "zstd"))]
+        Compression::Zstd => Err(unsupported(p, Op::Read, "zstd")),
+    }
+}
+
+/** A file being written, possibly through a compressor. */
+pub(crate) enum Encoder {
+    Plain(File),
+    #[cfg(feature = "gzip")]
+    Gzip(flate2::write::GzEncoder<File>),
+    #[cfg(feature = "zstd")]
+    Zstd(zstd::Encoder<'static, File>),
+}
+
+impl Encoder {
+    /** Wraps a file opened for writing in the requested compressor. */
+    pub(crate) fn new(f: File, c: Compression, p: &Path) -> Result<Self, FileError> {
+        match c {
+            Compression::None => Ok(Encoder::Plain(f)),
+            #[cfg(feature = "gzip")]
+            Compression::Gzip => Ok(Encoder::Gzip(
+                flate2::write::GzEncoder::new(f, flate2::Compression::default())
+            )),
+            #[cfg(not(feature = "gzip"))]
+            Compression::Gzip => Err(unsupported(p, Op::Write, "gzip")),
+            #[cfg(feature = "zstd")]
+            Compression::Zstd => match zstd::Encoder::new(f, 0) {
+                Ok(e) => Ok(Encoder::Zstd(e)),
+                Err(e) => Err(FileError::from_io(p, Op::Write, &e)),
+            },
+            #[cfg(not(feature = "zstd"))]
+            Compression::Zstd => Err(unsupported(p, Op::Write, "zstd")),
+        }
+    }
+    
+    /** Finishes the compressed stream, returning the underlying file. */
+    pub(crate) fn finish(self) -> io::Result<File> {
+        match self {
+            Encoder::Plain(f) => Ok(f),
+            #[cfg(feature = "gzip")]
+            Encoder::Gzip(e) => e.finish(),
+            #[cfg(feature = "zstd")]
+            Encoder::Zstd(e) => e.finish(),
+        }
+    }
+}
+
+impl Write for Encoder {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Encoder::Plain(f) => f.write(buf),
+            #[cfg(feature = "gzip")]
+            Encoder::Gzip(e) => e.write(buf),
+            #[cfg(feature = "zstd")]
+            Encoder::Zstd(e) => e.write(buf),
+        }
+    }
+    
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Encoder::Plain(f) => f.flush(),
+            #[cfg(feature = "gzip")]
+            Encoder::Gzip(e) => e.flush(),
+            #[cfg(feature = "zstd")]
+            Encoder::Zstd(e) => e.flush(),
+        }
+    }
+}
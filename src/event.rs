@@ -0,0 +1,87 @@
+use std::fmt;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::SystemTime;
+
+/** Noteworthy things that happen inside an authorization database, which
+    can be observed by registering a handler with `.on_event()`.
+*/
+#[derive(Debug, Clone, PartialEq)]
+#[non_exhaustive]
+pub enum AuthEvent {
+    /** A still-valid key was removed to make room for a new one because
+        the key database was full. */
+    KeyEvicted { uname: String, expiry: SystemTime },
+    /** The file the database was opened from has more than one row for
+        the same user (or key); only the last was kept. `line` and
+        `first_line` are the line numbers of this row and the first one
+        with the same `id`. These are emitted (one for each duplicate found
+        when the database was opened) when a handler is registered. */
+    DuplicateEntry { path: PathBuf, line: u64, first_line: u64, id: String },
+}
+
+/** Security-relevant changes to a user's account, which can be observed
+    by registering a handler with `.on_security_event()` (to send an
+    email, say, or post to a webhook).
+*/
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum SecurityEvent {
+    /** The user was refused a new key because they had been issued too
+        many recently (see `KeyAuth::issue_rate_limit()`). */
+    Lockout { uname: String },
+    /** The user's password was changed. */
+    PasswordChanged { uname: String },
+    /** A user with admin privileges was deleted. */
+    AdminDeleted { uname: String },
+    /** Everything stored about the user was deleted with
+        `BothAuth::purge_user()`; the application should scrub (or
+        pseudonymize) whatever it has logged about them too. */
+    UserPurged { uname: String },
+}
+
+impl AuthEvent {
+    /** A short, stable name for the kind of event, like `"key_evicted"`. */
+    pub fn name(&self) -> &'static str {
+        match self {
+            AuthEvent::KeyEvicted { .. } => "key_evicted",
+            AuthEvent::DuplicateEntry { .. } => "duplicate_entry",
+        }
+    }
+}
+
+type Handler<E> = dyn Fn(&E) + Send + Sync;
+
+/** Holds the (optional) event handler callback for a database. */
+pub(crate) struct EventHook<E = AuthEvent>(Option<Arc<Handler<E>>>);
+
+impl<E> EventHook<E> {
+    pub(crate) fn new<F>(f: F) -> Self
+    where F: Fn(&E) + Send + Sync + 'static
+    {
+        EventHook(Some(Arc::new(f)))
+    }
+    
+    /** Passes the event to the handler, if there is one. */
+    pub(crate) fn emit(&self, event: E) {
+        if let Some(f) = &self.0 { f(&event); }
+    }
+}
+
+/* Not derived, because that would require `E: Clone + Default`. */
+impl<E> Clone for EventHook<E> {
+    fn clone(&self) -> Self { EventHook(self.0.clone()) }
+}
+
+impl<E> Default for EventHook<E> {
+    fn default() -> Self { EventHook(None) }
+}
+
+impl<E> fmt::Debug for EventHook<E> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.0 {
+            None => write!(f, "EventHook(None)"),
+            Some(_) => write!(f, "EventHook(Some(..))"),
+        }
+    }
+}
@@ -0,0 +1,239 @@
+use std::collections::{BTreeMap, HashMap};
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+
+use parking_lot::RwLock;
+use serde::{Serialize, Deserialize};
+
+use crate::{FileError, DataError, Op, open_for_read, open_for_write, open_for_atomic_write,
+            abort_atomic_write, commit_atomic_write};
+
+#[derive(Debug, Serialize, Deserialize)]
+struct LinkRW {
+    provider: String,
+    subject: String,
+    uname: String,
+}
+
+/** A database linking identities at external identity providers (Google,
+    GitHub, or any OAuth2/OpenID Connect provider) to local users, which
+    persists as a .csv file on disk.
+    
+    An identity is a `provider` (any name the application likes, such as
+    `google` or an OIDC issuer URL) and a `subject`, the provider's stable
+    ID for the user (the `sub` claim of an OIDC ID token, or a GitHub user
+    ID; not an email address, which can change hands). Each identity is
+    linked to one local user; a user can have any number of identities.
+    
+    This doesn't talk to the providers; the application does the OAuth
+    dance and verifies what it gets back, and then calls
+    `BothAuth::issue_key_for_external()` (with this attached, using
+    `BothAuth::attach_external()`), so everything after login uses session
+    keys and the password database's user metadata as usual. Users who
+    only ever log in externally still need adding to the password database,
+    with an unguessable password.
+    
+    Changes stay in memory, with the database flagged as "dirty", until
+    `.save()` (or `BothAuth::save_if_dirty()`) writes them; write-through
+    mode isn't supported here.
+*/
+#[derive(Debug)]
+pub struct ExternalAuth {
+    links:  RwLock<HashMap<(String, String), String>>,
+    xfile:  PathBuf,
+    xdirty: RwLock<bool>,
+}
+
+impl ExternalAuth {
+    /**
+    Create a new external identity database that will save its data to a
+    .csv file at the supplied path.
+    */
+    pub fn new(external_file: impl AsRef<Path>) -> Result<Self, FileError> {
+        let external_file = external_file.as_ref();
+        
+        if Path::exists(external_file) {
+            return Err(FileError::new(external_file, Op::Create, ErrorKind::AlreadyExists,
+                                      "file already exists".to_string()));
+        }
+        
+        let f = open_for_write(external_file)?;
+        let mut w = csv::Writer::from_writer(f);
+        if let Err(e) = w.write_record(["provider", "subject", "uname"]) {
+            return Err(FileError::from_csv(external_file, Op::Create, &e));
+        }
+        if let Err(e) = w.flush() {
+            return Err(FileError::from_io(external_file, Op::Create, &e));
+        }
+        
+        return Ok(ExternalAuth::from_links(external_file, HashMap::new()));
+    }
+    
+    /** Open an external identity database with data from the .csv file at the given path. */
+    pub fn open(external_file: impl AsRef<Path>) -> Result<Self, FileError> {
+        let external_file = external_file.as_ref();
+        
+        let f = open_for_read(external_file)?;
+        let mut links: HashMap<(String, String), String> = HashMap::new();
+        let mut r = csv::Reader::from_reader(f);
+        for (n, result) in r.deserialize::<LinkRW>().enumerate() {
+            match result {
+                Err(e) => {
+                    eprintln!("WARNING: reading {}, record {}: {}",
+                        external_file.to_string_lossy(), n, &e);
+                },
+                Ok(lrw) => {
+                    let _ = links.insert((lrw.provider, lrw.subject), lrw.uname);
+                },
+            }
+        }
+        
+        return Ok(ExternalAuth::from_links(external_file, links));
+    }
+    
+    /**
+    Open the external identity database at the given path if the file
+    exists, or create a new one there if it doesn't.
+    */
+    pub fn open_or_new(external_file: impl AsRef<Path>) -> Result<Self, FileError> {
+        let external_file = external_file.as_ref();
+        match ExternalAuth::open(external_file) {
+            Err(e) if e.kind == ErrorKind::NotFound => ExternalAuth::new(external_file),
+            x => x,
+        }
+    }
+    
+    fn from_links(external_file: &Path, links: HashMap<(String, String), String>) -> Self {
+        return ExternalAuth {
+            links:  RwLock::new(links),
+            xfile:  PathBuf::from(external_file),
+            xdirty: RwLock::new(false),
+        };
+    }
+    
+    /**
+    Links the identity to the user. Marks the database as "dirty" (if it
+    wasn't linked to them already).
+    
+    Returns `DataError::IdentityLinked` (naming the user) if the identity
+    is already linked to someone else; unlink it first to move it.
+    */
+    pub fn link(&self, provider: &str, subject: &str, uname: &str) -> Result<(), DataError> {
+        let mut links = self.links.write();
+        let identity = (provider.to_string(), subject.to_string());
+        match links.get(&identity) {
+            Some(owner) if owner == uname => { return Ok(()); },
+            Some(owner) => { return Err(DataError::IdentityLinked { uname: owner.clone() }); },
+            None => { let _ = links.insert(identity, uname.to_string()); },
+        }
+        
+        let mut dirty = self.xdirty.write();
+        *dirty = true;
+        return Ok(());
+    }
+    
+    /**
+    Unlinks the identity from whoever it's linked to. Marks the database as
+    "dirty".
+    
+    Returns `DataError::NoSuchUser` if it isn't linked to anyone.
+    */
+    pub fn unlink(&self, provider: &str, subject: &str) -> Result<(), DataError> {
+        let mut links = self.links.write();
+        let identity = (provider.to_string(), subject.to_string());
+        if links.remove(&identity).is_none() { return Err(DataError::NoSuchUser); }
+        
+        let mut dirty = self.xdirty.write();
+        *dirty = true;
+        return Ok(());
+    }
+    
+    /**
+    Unlinks all the user's identities, returning how many there were.
+    Marks the database as "dirty" if there were any.
+    */
+    pub fn remove_user(&self, uname: &str) -> usize {
+        let mut links = self.links.write();
+        let before = links.len();
+        links.retain(|_, owner| owner != uname);
+        let removed = before - links.len();
+        
+        if removed > 0 {
+            let mut dirty = self.xdirty.write();
+            *dirty = true;
+        }
+        return removed;
+    }
+    
+    /**
+    Returns the name of the user the identity is linked to.
+    
+    Returns `DataError::NoSuchUser` if it isn't linked to anyone (so the
+    application might offer to register them).
+    */
+    pub fn user_for(&self, provider: &str, subject: &str) -> Result<String, DataError> {
+        let links = self.links.read();
+        let identity = (provider.to_string(), subject.to_string());
+        match links.get(&identity) {
+            Some(uname) => Ok(uname.clone()),
+            None => Err(DataError::NoSuchUser),
+        }
+    }
+    
+    /** Returns the user's `(provider, subject)` identities, sorted. */
+    pub fn links_of(&self, uname: &str) -> Vec<(String, String)> {
+        let links = self.links.read();
+        let mut identities: Vec<(String, String)> = links.iter()
+            .filter(|(_, owner)| owner.as_str() == uname)
+            .map(|(identity, _)| identity.clone())
+            .collect();
+        identities.sort();
+        return identities;
+    }
+    
+    /** Returns the path of the file this database saves to. */
+    pub fn path(&self) -> &Path { &self.xfile }
+    
+    /**
+    Returns whether the in-memory database is "dirty", that is, whether it's
+    out of sync with the persistent data on disk.
+    */
+    pub fn is_dirty(&self) -> bool {
+        let dirty = self.xdirty.read();
+        return *dirty;
+    }
+    
+    /**
+    Writes the current state of the database to disk (sorted by provider
+    and subject, so it diffs nicely), marking it as no longer dirty. The
+    file is replaced atomically.
+    */
+    pub fn save(&self) -> Result<(), FileError> {
+        let path = &self.xfile;
+        let links = self.links.write();
+        let sorted: BTreeMap<&(String, String), &String> = links.iter().collect();
+        
+        let (f, tmp) = open_for_atomic_write(path)?;
+        let mut w = csv::Writer::from_writer(f);
+        for ((provider, subject), uname) in sorted.into_iter() {
+            let lrw = LinkRW {
+                provider: provider.clone(),
+                subject: subject.clone(),
+                uname: uname.clone(),
+            };
+            if let Err(e) = w.serialize(lrw) {
+                return Err(abort_atomic_write(&tmp, FileError::from_csv(path, Op::Write, &e)));
+            }
+        }
+        let f = match w.into_inner() {
+            Ok(f) => f,
+            Err(e) => { return Err(abort_atomic_write(&tmp, FileError::from_io(path, Op::Write, e.error()))); },
+        };
+        commit_atomic_write(f, &tmp, path)?;
+        
+        let mut dirty = self.xdirty.write();
+        *dirty = false;
+        
+        return Ok(());
+    }
+}
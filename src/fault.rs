@@ -0,0 +1,139 @@
+/*!
+The hooks through which `testing::FaultInjector` makes saves and opens
+fail. Outside of tests (and the `testing` feature), nothing is ever
+armed, and `Faulty` just passes everything through.
+*/
+use std::io::{self, Read, Write};
+use std::path::Path;
+
+use crate::Op;
+
+/** Where an armed `testing::FaultInjector` makes a save or open fail. */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(not(any(test, feature = "testing")), allow(dead_code))]
+pub enum Fault {
+    /** Right after the header line. */
+    AfterHeader,
+    /** Partway through the `n`th record (counting from 0), after its
+        first byte. */
+    MidRecord(usize),
+    /** After all the data, when it's flushed (for saves) or when the end
+        of the file is reached (for opens). */
+    OnFlush,
+}
+
+/** Returns the fault armed for the given operation on the file at `path`, if any. */
+#[cfg(any(test, feature = "testing"))]
+fn armed(path: &Path, op: Op) -> Option<Fault> { crate::testing::armed(path, op) }
+
+#[cfg(not(any(test, feature = "testing")))]
+fn armed(_path: &Path, _op: Op) -> Option<Fault> { None }
+
+/** The error every injected fault produces. */
+fn injected() -> io::Error {
+    io::Error::other("injected fault")
+}
+
+/**
+A reader or writer that fails at the point given by the `Fault` armed for
+its file, if there is one, having passed through only the data before it.
+Once it has failed, it keeps failing.
+*/
+pub(crate) struct Faulty<T> {
+    inner: T,
+    fault: Option<Fault>,
+    /* Lines and bytes of the following line passed through so far. */
+    lines: usize,
+    extra: usize,
+    tripped: bool,
+}
+
+impl<T> Faulty<T> {
+    fn new(inner: T, fault: Option<Fault>) -> Self {
+        Faulty { inner, fault, lines: 0, extra: 0, tripped: false }
+    }
+    
+    /** Wraps `w`, which is saving the file at `path`. */
+    pub(crate) fn writer(w: T, path: &Path) -> Self where T: Write {
+        Faulty::new(w, armed(path, Op::Write))
+    }
+    
+    /** Wraps `r`, which is reading the file at `path`. */
+    pub(crate) fn reader(r: T, path: &Path) -> Self where T: Read {
+        Faulty::new(r, armed(path, Op::Read))
+    }
+    
+    pub(crate) fn into_inner(self) -> T { self.inner }
+    
+    /**
+    Scans `buf`, the next data to pass through, returning how many bytes
+    from its start come before the fault, and the lines and extra bytes
+    that will have been passed through once they have been.
+    */
+    fn scan(&self, buf: &[u8]) -> (usize, usize, usize) {
+        let (lines, extra) = match self.fault {
+            Some(Fault::AfterHeader) => (1, 0),
+            Some(Fault::MidRecord(n)) => (n + 1, 1),
+            _ => { return (buf.len(), self.lines, self.extra); },
+        };
+        let (mut seen, mut past) = (self.lines, self.extra);
+        for (i, &b) in buf.iter().enumerate() {
+            if seen == lines {
+                if past == extra { return (i, seen, past); }
+                past += 1;
+            } else if b == b'\n' {
+                seen += 1;
+            }
+        }
+        return (buf.len(), seen, past);
+    }
+    
+    /** Notes that `passed` bytes of `buf` have been passed through. */
+    fn pass(&mut self, buf: &[u8], passed: usize) {
+        let (allowed, lines, extra) = self.scan(&buf[..passed]);
+        debug_assert_eq!(allowed, passed);
+        self.lines = lines;
+        self.extra = extra;
+    }
+}
+
+impl<R: Read> Read for Faulty<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.tripped { return Err(injected()); }
+        let n = self.inner.read(buf)?;
+        /* A fault that wasn't reached before the end of the file happens there. */
+        if n == 0 && self.fault.is_some() {
+            self.tripped = true;
+            return Err(injected());
+        }
+        let (allowed, _, _) = self.scan(&buf[..n]);
+        if allowed < n { self.tripped = true; }
+        if allowed == 0 && n > 0 { return Err(injected()); }
+        self.pass(buf, allowed);
+        return Ok(allowed);
+    }
+}
+
+impl<W: Write> Write for Faulty<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.tripped { return Err(injected()); }
+        let (allowed, _, _) = self.scan(buf);
+        if allowed == 0 && !buf.is_empty() {
+            self.tripped = true;
+            return Err(injected());
+        }
+        let n = self.inner.write(&buf[..allowed])?;
+        self.pass(buf, n);
+        return Ok(n);
+    }
+    
+    fn flush(&mut self) -> io::Result<()> {
+        /* Every armed fault fails the save by the time it's flushed, even
+           if there wasn't enough data to reach it. */
+        if self.fault.is_some() {
+            self.tripped = true;
+            return Err(injected());
+        }
+        self.inner.flush()
+    }
+}
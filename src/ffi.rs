@@ -0,0 +1,154 @@
+/*!
+A C interface to `BothAuth`, so C (or Python, via `ctypes`) services can
+link the library directly and share its files with Rust ones.
+
+The declarations are in `include/authlite.h`, which is generated by
+[cbindgen](https://github.com/mozilla/cbindgen):
+
+```text
+cbindgen --config cbindgen.toml --output include/authlite.h
+```
+
+Every string passed in must be a NUL-terminated UTF-8 string. The check
+functions return `AUTHLITE_OK` (0) on success, `AUTHLITE_BAD_ARGUMENT`
+(-1) if an argument is null or not UTF-8, and otherwise the
+`DataError::suggested_status()` of the error (401 for a bad password or
+key, 404 for an unknown user, and so on).
+
+Only available with the `ffi` feature.
+*/
+use std::ffi::CStr;
+use std::os::raw::{c_char, c_int};
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::ptr;
+
+use crate::{BothAuth, DataError};
+
+/** The check succeeded. */
+pub const AUTHLITE_OK: c_int = 0;
+/** An argument was null or not valid UTF-8. */
+pub const AUTHLITE_BAD_ARGUMENT: c_int = -1;
+/** The library panicked; this is a bug. */
+pub const AUTHLITE_PANIC: c_int = -2;
+
+/** An open database, from `authlite_open()`; release it with `authlite_free()`. */
+pub struct Authlite {
+    auth: BothAuth,
+}
+
+/** Borrows a C string as a `&str`, if it's non-null and UTF-8. */
+unsafe fn as_str<'a>(s: *const c_char) -> Option<&'a str> {
+    if s.is_null() { return None; }
+    CStr::from_ptr(s).to_str().ok()
+}
+
+/** Runs a check, converting its result (or a panic) to a return code. */
+fn code<F>(f: F) -> c_int
+where F: FnOnce() -> Option<Result<(), DataError>>
+{
+    match catch_unwind(AssertUnwindSafe(f)) {
+        Ok(Some(Ok(()))) => AUTHLITE_OK,
+        Ok(Some(Err(e))) => e.suggested_status() as c_int,
+        Ok(None) => AUTHLITE_BAD_ARGUMENT,
+        Err(_) => AUTHLITE_PANIC,
+    }
+}
+
+/**
+Opens (creating them if necessary) the given password and key files,
+with every change written through to them. Returns null, after printing
+the error to stderr, if they can't be opened.
+
+# Safety
+
+Both arguments must be null or point to NUL-terminated strings.
+*/
+#[no_mangle]
+pub unsafe extern "C" fn authlite_open(
+    users_file: *const c_char,
+    keys_file: *const c_char,
+) -> *mut Authlite {
+    let (users_file, keys_file) = match (as_str(users_file), as_str(keys_file)) {
+        (Some(u), Some(k)) => (u, k),
+        _ => { return ptr::null_mut(); },
+    };
+    let opened = catch_unwind(|| {
+        BothAuth::builder()
+            .pwd_file(users_file)
+            .key_file(keys_file)
+            .write_through(true)
+            .build()
+    });
+    match opened {
+        Ok(Ok(auth)) => Box::into_raw(Box::new(Authlite { auth })),
+        Ok(Err(e)) => {
+            eprintln!("authlite_open: {}", &e);
+            ptr::null_mut()
+        },
+        Err(_) => ptr::null_mut(),
+    }
+}
+
+/**
+Checks the given user's password, with `salt_len` bytes of salt at
+`salt` (which may be null if `salt_len` is 0).
+
+# Safety
+
+`auth` must have been returned by `authlite_open()` and not yet freed;
+`uname` and `password` must be null or point to NUL-terminated strings;
+and `salt` must point to at least `salt_len` bytes.
+*/
+#[no_mangle]
+pub unsafe extern "C" fn authlite_check_password(
+    auth: *const Authlite,
+    uname: *const c_char,
+    password: *const c_char,
+    salt: *const u8,
+    salt_len: usize,
+) -> c_int {
+    if auth.is_null() || (salt.is_null() && salt_len > 0) { return AUTHLITE_BAD_ARGUMENT; }
+    let salt: &[u8] = if salt_len == 0 { &[] } else { std::slice::from_raw_parts(salt, salt_len) };
+    let (uname, password) = (as_str(uname), as_str(password));
+    code(|| {
+        let (uname, password) = (uname?, password?);
+        Some((*auth).auth.check_password(uname, password, salt).map(|_| ()))
+    })
+}
+
+/**
+Checks that `key` is a valid key for the given user.
+
+# Safety
+
+`auth` must have been returned by `authlite_open()` and not yet freed,
+and `key` and `uname` must be null or point to NUL-terminated strings.
+*/
+#[no_mangle]
+pub unsafe extern "C" fn authlite_check_key(
+    auth: *const Authlite,
+    key: *const c_char,
+    uname: *const c_char,
+) -> c_int {
+    if auth.is_null() { return AUTHLITE_BAD_ARGUMENT; }
+    let (key, uname) = (as_str(key), as_str(uname));
+    code(|| {
+        let (key, uname) = (key?, uname?);
+        Some((*auth).auth.check_key(key, uname).map(|_| ()))
+    })
+}
+
+/**
+Closes a database opened by `authlite_open()`. Does nothing if `auth` is
+null.
+
+# Safety
+
+`auth` must have been returned by `authlite_open()`, and must not be used
+(or freed) again afterward.
+*/
+#[no_mangle]
+pub unsafe extern "C" fn authlite_free(auth: *mut Authlite) {
+    if auth.is_null() { return; }
+    let _ = catch_unwind(AssertUnwindSafe(|| drop(Box::from_raw(auth))));
+}
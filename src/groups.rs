@@ -0,0 +1,262 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+
+use parking_lot::RwLock;
+use serde::{Serialize, Deserialize};
+
+use crate::{FileError, DataError, Op, open_for_read, open_for_write, open_for_atomic_write,
+            abort_atomic_write, commit_atomic_write};
+
+/* Each group is stored as one row with an empty `uname` (so groups with
+   no members persist), followed by one row for each member. */
+#[derive(Debug, Serialize, Deserialize)]
+struct MemberRW {
+    group: String,
+    uname: String,
+}
+
+/** Represents a database of named groups of users (teams, tenants, roles,
+    and so on), which persists as a .csv file on disk.
+    
+    Group membership is just a set of user names; it isn't checked against
+    any password database. Attach a `GroupAuth` to a `BothAuth` (with
+    `BothAuth::attach_groups()`) to check keys and membership together.
+    
+    Unlike `PwdAuth` and `KeyAuth`, this database has no write-through
+    mode: changes are flagged as "dirty" until it's saved, by `.save()` or
+    `BothAuth::save_if_dirty()`.
+*/
+#[derive(Debug)]
+pub struct GroupAuth {
+    groups: RwLock<HashMap<String, HashSet<String>>>,
+    gfile:  PathBuf,
+    gdirty: RwLock<bool>,
+}
+
+impl GroupAuth {
+    /**
+    Create a new group database that will save its data to a .csv file at
+    the supplied path.
+    */
+    pub fn new(group_file: impl AsRef<Path>) -> Result<Self, FileError> {
+        let group_file = group_file.as_ref();
+        
+        if Path::exists(group_file) {
+            return Err(FileError::new(group_file, Op::Create, ErrorKind::AlreadyExists,
+                                      "file already exists".to_string()));
+        }
+        
+        let f = open_for_write(group_file)?;
+        let mut w = csv::Writer::from_writer(f);
+        if let Err(e) = w.write_record(["group", "uname"]) {
+            return Err(FileError::from_csv(group_file, Op::Create, &e));
+        }
+        if let Err(e) = w.flush() {
+            return Err(FileError::from_io(group_file, Op::Create, &e));
+        }
+        
+        return Ok(GroupAuth::from_groups(group_file, HashMap::new()));
+    }
+    
+    /** Open a group database with data from the .csv file at the given path. */
+    pub fn open(group_file: impl AsRef<Path>) -> Result<Self, FileError> {
+        let group_file = group_file.as_ref();
+        
+        let f = open_for_read(group_file)?;
+        let mut groups: HashMap<String, HashSet<String>> = HashMap::new();
+        let mut r = csv::Reader::from_reader(f);
+        for (n, result) in r.deserialize::<MemberRW>().enumerate() {
+            match result {
+                Err(e) => {
+                    eprintln!("WARNING: reading {}, record {}: {}",
+                        group_file.to_string_lossy(), n, &e);
+                },
+                Ok(mrw) => {
+                    let members = groups.entry(mrw.group).or_default();
+                    if !mrw.uname.is_empty() {
+                        let _ = members.insert(mrw.uname);
+                    }
+                },
+            }
+        }
+        
+        return Ok(GroupAuth::from_groups(group_file, groups));
+    }
+    
+    /**
+    Open the group database at the given path if the file exists, or
+    create a new one there if it doesn't.
+    */
+    pub fn open_or_new(group_file: impl AsRef<Path>) -> Result<Self, FileError> {
+        let group_file = group_file.as_ref();
+        match GroupAuth::open(group_file) {
+            Err(e) if e.kind == ErrorKind::NotFound => GroupAuth::new(group_file),
+            x => x,
+        }
+    }
+    
+    fn from_groups(group_file: &Path, groups: HashMap<String, HashSet<String>>) -> Self {
+        return GroupAuth {
+            groups: RwLock::new(groups),
+            gfile:  PathBuf::from(group_file),
+            gdirty: RwLock::new(false),
+        };
+    }
+    
+    /**
+    Create a new, empty group. Marks the database as "dirty".
+    
+    Returns `DataError::GroupExists` if there's already a group by that name.
+    */
+    pub fn create_group(&self, group: &str) -> Result<(), DataError> {
+        let mut groups = self.groups.write();
+        if groups.contains_key(group) { return Err(DataError::GroupExists); }
+        let _ = groups.insert(group.to_string(), HashSet::new());
+        
+        let mut dirty = self.gdirty.write();
+        *dirty = true;
+        return Ok(());
+    }
+    
+    /**
+    Delete a group (and all its memberships). Marks the database as "dirty".
+    
+    Returns `DataError::NoSuchGroup` if the group doesn't exist.
+    */
+    pub fn delete_group(&self, group: &str) -> Result<(), DataError> {
+        let mut groups = self.groups.write();
+        if groups.remove(group).is_none() { return Err(DataError::NoSuchGroup); }
+        
+        let mut dirty = self.gdirty.write();
+        *dirty = true;
+        return Ok(());
+    }
+    
+    /**
+    Add a user to a group. Marks the database as "dirty" (if the user
+    wasn't already a member).
+    
+    Returns `DataError::NoSuchGroup` if the group doesn't exist.
+    */
+    pub fn add_member(&self, group: &str, uname: &str) -> Result<(), DataError> {
+        let mut groups = self.groups.write();
+        let members = match groups.get_mut(group) {
+            Some(m) => m,
+            None => { return Err(DataError::NoSuchGroup); },
+        };
+        if members.insert(uname.to_string()) {
+            let mut dirty = self.gdirty.write();
+            *dirty = true;
+        }
+        return Ok(());
+    }
+    
+    /**
+    Remove a user from a group. Marks the database as "dirty".
+    
+    Returns `DataError::NoSuchGroup` if the group doesn't exist, or
+    `DataError::NotInGroup` if the user isn't a member.
+    */
+    pub fn remove_member(&self, group: &str, uname: &str) -> Result<(), DataError> {
+        let mut groups = self.groups.write();
+        let members = match groups.get_mut(group) {
+            Some(m) => m,
+            None => { return Err(DataError::NoSuchGroup); },
+        };
+        if !members.remove(uname) { return Err(DataError::NotInGroup); }
+        
+        let mut dirty = self.gdirty.write();
+        *dirty = true;
+        return Ok(());
+    }
+    
+    /**
+    Removes the user from every group, returning how many they were in.
+    Marks the database as "dirty" if there were any.
+    */
+    pub fn remove_user(&self, uname: &str) -> usize {
+        let mut groups = self.groups.write();
+        let mut removed = 0;
+        for members in groups.values_mut() {
+            if members.remove(uname) { removed += 1; }
+        }
+        
+        if removed > 0 {
+            let mut dirty = self.gdirty.write();
+            *dirty = true;
+        }
+        return removed;
+    }
+    
+    /**
+    Returns `Ok(())` if the user is a member of the group, and otherwise
+    `DataError::NotInGroup` (or `DataError::NoSuchGroup`).
+    */
+    pub fn user_in_group(&self, group: &str, uname: &str) -> Result<(), DataError> {
+        let groups = self.groups.read();
+        match groups.get(group) {
+            None => Err(DataError::NoSuchGroup),
+            Some(members) if members.contains(uname) => Ok(()),
+            Some(_) => Err(DataError::NotInGroup),
+        }
+    }
+    
+    /** Returns the names of all the groups the user belongs to, sorted. */
+    pub fn groups_of(&self, uname: &str) -> Vec<String> {
+        let groups = self.groups.read();
+        let mut names: Vec<String> = groups.iter()
+            .filter(|(_, members)| members.contains(uname))
+            .map(|(group, _)| group.clone())
+            .collect();
+        names.sort();
+        return names;
+    }
+    
+    /** Returns the path of the file this database saves to. */
+    pub fn path(&self) -> &Path { &self.gfile }
+    
+    /**
+    Returns whether the in-memory database is "dirty", that is, whether it's
+    out of sync with the persistent data on disk.
+    */
+    pub fn is_dirty(&self) -> bool {
+        let dirty = self.gdirty.read();
+        return *dirty;
+    }
+    
+    /**
+    Writes the current state of the database to disk (sorted by group and
+    user name, so it diffs nicely), marking it as no longer dirty. The file
+    is replaced atomically.
+    */
+    pub fn save(&self) -> Result<(), FileError> {
+        let path = &self.gfile;
+        let groups = self.groups.write();
+        let sorted: BTreeMap<&String, BTreeSet<&String>> = groups.iter()
+            .map(|(group, members)| (group, members.iter().collect()))
+            .collect();
+        
+        let (f, tmp) = open_for_atomic_write(path)?;
+        let mut w = csv::Writer::from_writer(f);
+        for (group, members) in sorted.iter() {
+            let rows = std::iter::once("").chain(members.iter().map(|m| m.as_str()));
+            for uname in rows {
+                let mrw = MemberRW { group: group.to_string(), uname: uname.to_string() };
+                if let Err(e) = w.serialize(mrw) {
+                    return Err(abort_atomic_write(&tmp, FileError::from_csv(path, Op::Write, &e)));
+                }
+            }
+        }
+        let f = match w.into_inner() {
+            Ok(f) => f,
+            Err(e) => { return Err(abort_atomic_write(&tmp, FileError::from_io(path, Op::Write, e.error()))); },
+        };
+        commit_atomic_write(f, &tmp, path)?;
+        
+        let mut dirty = self.gdirty.write();
+        *dirty = false;
+        
+        return Ok(());
+    }
+}
@@ -0,0 +1,174 @@
+/*!
+A gRPC service over a `BothAuth`, so services written in other languages
+can check passwords and keys without reading the .csv files themselves.
+
+The interface is defined in `proto/authlite.proto`, from which clients in
+other languages can be generated; `auth_client::AuthClient` is a Rust
+client. Errors are returned as gRPC statuses corresponding to
+`DataError::suggested_status()` (`Unauthenticated` for 401, `NotFound`
+for 404, and so on), with the `DataError` as the message.
+
+```no_run
+# use std::sync::Arc;
+# use authlite::BothAuth;
+# use authlite::grpc::AuthService;
+# async fn run() -> Result<(), Box<dyn std::error::Error>> {
+let auth = Arc::new(BothAuth::open("data/users.csv", "data/keys.csv")?);
+tonic::transport::Server::builder()
+    .add_service(AuthService::new(auth).into_server())
+    .serve("127.0.0.1:50051".parse()?)
+    .await?;
+# Ok(())
+# }
+```
+
+Only available with the `grpc` feature.
+*/
+use std::sync::Arc;
+
+use tonic::{Code, Request, Response, Status};
+
+use crate::{BothAuth, DataError};
+
+include!(concat!(env!("OUT_DIR"), "/authlite.Auth.rs"));
+
+/** A user name, password, and salt, for `CheckPassword` and `IssueKey`. */
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct PasswordRequest {
+    #[prost(string, tag = "1")]
+    pub uname: String,
+    #[prost(string, tag = "2")]
+    pub password: String,
+    #[prost(bytes = "vec", tag = "3")]
+    pub salt: Vec<u8>,
+}
+
+/** A user name and key, for `CheckKey`. */
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct KeyRequest {
+    #[prost(string, tag = "1")]
+    pub uname: String,
+    #[prost(string, tag = "2")]
+    pub key: String,
+}
+
+/** The key to remove, for `RevokeKey`. */
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct RevokeRequest {
+    #[prost(string, tag = "1")]
+    pub key: String,
+}
+
+/** The reply to a successful check; see `AuthOk`. */
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct CheckReply {
+    #[prost(string, tag = "1")]
+    pub uname: String,
+    #[prost(bool, tag = "2")]
+    pub needs_rehash: bool,
+}
+
+/** The key issued by `IssueKey`. */
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct KeyReply {
+    #[prost(string, tag = "1")]
+    pub key: String,
+}
+
+/** The (empty) reply to `RevokeKey`. */
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct RevokeReply {}
+
+/** Implements the `Auth` gRPC service over a shared `BothAuth`. */
+#[derive(Debug, Clone)]
+pub struct AuthService {
+    auth: Arc<BothAuth>,
+}
+
+impl AuthService {
+    pub fn new(auth: Arc<BothAuth>) -> Self { AuthService { auth } }
+    
+    /** Wraps the service for `tonic::transport::Server::add_service()`. */
+    pub fn into_server(self) -> auth_server::AuthServer<Self> {
+        auth_server::AuthServer::new(self)
+    }
+    
+    /**
+    Runs `f` on the database on tokio's blocking thread pool, since
+    hashing passwords (and saving, in write-through mode) takes a while.
+    */
+    async fn run<T, F>(&self, f: F) -> Result<Response<T>, Status>
+    where
+        T: Send + 'static,
+        F: FnOnce(&BothAuth) -> Result<T, DataError> + Send + 'static,
+    {
+        let auth = self.auth.clone();
+        match tokio::task::spawn_blocking(move || f(&auth)).await {
+            Ok(Ok(reply)) => Ok(Response::new(reply)),
+            Ok(Err(e)) => Err(status(e)),
+            Err(e) => Err(Status::internal(e.to_string())),
+        }
+    }
+}
+
+#[tonic::async_trait]
+impl auth_server::Auth for AuthService {
+    async fn check_password(
+        &self,
+        request: Request<PasswordRequest>
+    ) -> Result<Response<CheckReply>, Status> {
+        let req = request.into_inner();
+        self.run(move |auth| {
+            let ok = auth.check_password(&req.uname, &req.password, &req.salt)?;
+            Ok(CheckReply { uname: ok.uname, needs_rehash: ok.needs_rehash })
+        }).await
+    }
+    
+    async fn issue_key(
+        &self,
+        request: Request<PasswordRequest>
+    ) -> Result<Response<KeyReply>, Status> {
+        let req = request.into_inner();
+        self.run(move |auth| {
+            let key = auth.check_password_and_issue_key(&req.uname, &req.password, &req.salt)?;
+            Ok(KeyReply { key })
+        }).await
+    }
+    
+    async fn check_key(
+        &self,
+        request: Request<KeyRequest>
+    ) -> Result<Response<CheckReply>, Status> {
+        let req = request.into_inner();
+        self.run(move |auth| {
+            let ok = auth.check_key(&req.key, &req.uname)?;
+            Ok(CheckReply { uname: ok.uname, needs_rehash: ok.needs_rehash })
+        }).await
+    }
+    
+    async fn revoke_key(
+        &self,
+        request: Request<RevokeRequest>
+    ) -> Result<Response<RevokeReply>, Status> {
+        let req = request.into_inner();
+        self.run(move |auth| {
+            auth.remove_key(&req.key)?;
+            Ok(RevokeReply {})
+        }).await
+    }
+}
+
+/** Converts a `DataError` to the gRPC status closest to its HTTP status. */
+pub fn status(e: DataError) -> Status {
+    let code = match e.suggested_status() {
+        400 => Code::InvalidArgument,
+        401 => Code::Unauthenticated,
+        403 => Code::PermissionDenied,
+        404 => Code::NotFound,
+        409 => Code::AlreadyExists,
+        429 => Code::ResourceExhausted,
+        503 => Code::Unavailable,
+        _ => Code::Internal,
+    };
+    return Status::new(code, format!("{:?}", &e));
+}
@@ -0,0 +1,331 @@
+use std::any::TypeId;
+use std::borrow::Cow;
+use std::convert::TryInto;
+use std::fmt;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD_NO_PAD;
+use blake3::{Hash, Hasher, OUT_LEN};
+use unicode_normalization::{is_nfkc, UnicodeNormalization};
+
+/** A password hash function, for applications that want something other
+    than the default (`Blake3Hasher`), like bcrypt or PBKDF2 with their
+    own choice of parameters.
+    
+    A hasher turns a password and salt into a string, which is what gets
+    stored in the password file, and later checks a password and salt
+    against such a string. The `salt` passed to both methods is the salt
+    supplied by the caller with the database's pepper (if any) appended.
+    
+    The stored strings are opaque to `PwdAuth`, so they may embed whatever
+    the hash function needs (a cost factor, say), but they must not contain
+    newlines. All the passwords in a database must be hashed by the same
+    `PasswordHasher`.
+*/
+pub trait PasswordHasher: Send + Sync {
+    /** Hashes the password with the salt, returning the string to store. */
+    fn hash(&self, password: &str, salt: &[u8]) -> String;
+    
+    /**
+    Returns whether the password and salt match the `stored` string
+    previously returned by `.hash()`. This should take the same amount of
+    time whether or not they match.
+    */
+    fn verify(&self, password: &str, salt: &[u8], stored: &str) -> bool;
+    
+    /**
+    Returns whether the `stored` string (whose password has just been
+    verified) should be replaced with a fresh hash, for example because it
+    was made with a lower cost factor than the hasher now uses. The
+    default implementation always returns `false`.
+    */
+    fn needs_rehash(&self, _stored: &str) -> bool { false }
+    
+    /**
+    Returns the name of the scheme the `stored` string was hashed with,
+    for counting how far a migration between schemes has got (see
+    `PwdAuth::hash_schemes()`). The default implementation reads it the
+    way modular crypt format strings are usually laid out: the text
+    between the first two `$`s if the string starts with one (`2b` for
+    `$2b$12$...`), else the text before the first `$`, else `"blake3"`
+    (for the default hasher's bare hex).
+    */
+    fn scheme(&self, stored: &str) -> String {
+        let prefix = match stored.strip_prefix('$') {
+            Some(rest) => rest.split('$').next(),
+            None if stored.contains('$') => stored.split('$').next(),
+            None => None,
+        };
+        return prefix.unwrap_or("blake3").to_string();
+    }
+}
+
+/* The multihash code for BLAKE3, followed by the digest length. */
+const MULTIHASH_BLAKE3: [u8; 2] = [0x1e, OUT_LEN as u8];
+
+/** How the default hasher (`Blake3Hasher`) writes its digests in the
+    password file (see `PwdAuth::hash_encoding()`). All of them are
+    recognized when passwords are checked, whichever one is chosen. */
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum HashEncoding {
+    /** 64 lowercase hex digits (the default). */
+    #[default]
+    Hex,
+    /** 43 characters of standard base64, without padding. */
+    Base64,
+    /** A self-describing [multihash](https://multiformats.io/multihash/)
+        (the BLAKE3 code and the digest length, then the digest) in
+        multibase base64, which is `m` followed by 46 characters. Multibase
+        hex (`f1e20` followed by the digest in hex) is recognized too. */
+    Multihash,
+}
+
+impl HashEncoding {
+    /** Writes a digest in this encoding. */
+    fn encode(self, digest: &[u8; OUT_LEN]) -> String {
+        match self {
+            HashEncoding::Hex => Hash::from(*digest).to_hex().to_string(),
+            HashEncoding::Base64 => STANDARD_NO_PAD.encode(digest),
+            HashEncoding::Multihash => {
+                let mut multihash = MULTIHASH_BLAKE3.to_vec();
+                multihash.extend_from_slice(digest);
+                format!("m{}", STANDARD_NO_PAD.encode(&multihash))
+            },
+        }
+    }
+    
+    /**
+    Reads a stored digest, returning it along with the encoding it was
+    written in, or `None` if it isn't a digest in any of them.
+    */
+    fn decode(stored: &str) -> Option<(HashEncoding, [u8; OUT_LEN])> {
+        let multihash = |bytes: Vec<u8>| -> Option<[u8; OUT_LEN]> {
+            let digest = bytes.strip_prefix(&MULTIHASH_BLAKE3[..])?;
+            digest.try_into().ok()
+        };
+        /* The encodings' lengths all differ, so that tells them apart (a
+           base64 digest could start with `m` or `f`). */
+        let (encoding, digest) = match stored.len() {
+            64 => (HashEncoding::Hex, *Hash::from_hex(stored).ok()?.as_bytes()),
+            43 => (HashEncoding::Base64, STANDARD_NO_PAD.decode(stored).ok()?.try_into().ok()?),
+            47 => {
+                let rest = stored.strip_prefix('m')?;
+                (HashEncoding::Multihash, multihash(STANDARD_NO_PAD.decode(rest).ok()?)?)
+            },
+            69 => {
+                let rest = stored.strip_prefix('f')?;
+                (HashEncoding::Multihash, multihash(decode_hex(rest)?)?)
+            },
+            _ => { return None; },
+        };
+        return Some((encoding, digest));
+    }
+}
+
+/**
+Returns the digest in a hash stored by `Blake3Hasher` (in any
+`HashEncoding`), or `None` if it isn't one.
+*/
+pub(crate) fn blake3_digest(stored: &str) -> Option<[u8; OUT_LEN]> {
+    HashEncoding::decode(stored).map(|(_, digest)| digest)
+}
+
+/** Writes bytes as lowercase hex. */
+pub(crate) fn encode_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/** Reads hex (of either case), or returns `None` if it isn't valid. */
+pub(crate) fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+    /* An odd digit at the end makes the last slice fall short. */
+    (0..hex.len()).step_by(2)
+        .map(|i| hex.get(i..i + 2).and_then(|b| u8::from_str_radix(b, 16).ok()))
+        .collect()
+}
+
+/** The default `PasswordHasher`: a single BLAKE3 hash of the password
+    followed by the salt, stored as hex (or as chosen with
+    `PwdAuth::hash_encoding()`). It verifies stored hashes in any
+    `HashEncoding`. */
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Blake3Hasher;
+
+impl Blake3Hasher {
+    fn digest(password: &str, salt: &[u8]) -> Hash {
+        let mut hasher = Hasher::new();
+        hasher.update(password.as_bytes());
+        hasher.update(salt);
+        hasher.finalize()
+    }
+}
+
+impl PasswordHasher for Blake3Hasher {
+    fn hash(&self, password: &str, salt: &[u8]) -> String {
+        Blake3Hasher::digest(password, salt).to_hex().to_string()
+    }
+    
+    fn verify(&self, password: &str, salt: &[u8], stored: &str) -> bool {
+        match HashEncoding::decode(stored) {
+            /* Hash comparison takes constant time. */
+            Some((_, digest)) => Blake3Hasher::digest(password, salt) == Hash::from(digest),
+            None => false,
+        }
+    }
+}
+
+/** What `calibrate_hash_cost()` chose, and how long it measured it taking. */
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Calibration<P> {
+    /** The chosen parameters. */
+    pub params: P,
+    /** How long verifying a password took with them (the fastest of a few
+        tries, to discount other load on the host). */
+    pub took: Duration,
+    /** Whether that met the target; if not, these were the last (most
+        costly) parameters tried. */
+    pub met_target: bool,
+}
+
+/**
+Finds parameters for a slow `PasswordHasher` (one with a cost factor,
+like bcrypt or PBKDF2) that make verifying a password take at least
+`target` on this host, so that a deployment on a small VPS doesn't have
+to guess at them.
+
+`candidates` are tried in order, so they should go from cheapest to most
+costly (doubling iteration counts, say, or incrementing a bcrypt cost);
+`make` builds a hasher from each. The first one that meets the target is
+returned, or the last one if none does, or `None` if there are none.
+
+This takes at least a few times `target`, so it's meant to be run once
+at startup (or by an installer), not per request.
+*/
+pub fn calibrate_hash_cost<P, H, I, F>(
+    target: Duration,
+    candidates: I,
+    make: F
+) -> Option<Calibration<P>>
+where H: PasswordHasher,
+      I: IntoIterator<Item = P>,
+      F: Fn(&P) -> H
+{
+    const TRIES: usize = 3;
+    let (password, salt) = ("calibration password", b"calibration salt");
+    
+    let mut last = None;
+    for params in candidates {
+        let hasher = make(&params);
+        let stored = hasher.hash(password, salt);
+        let took = (0..TRIES).map(|_| {
+            let start = Instant::now();
+            let _ = hasher.verify(password, salt, &stored);
+            start.elapsed()
+        }).min().unwrap_or_default();
+        let met_target = took >= target;
+        last = Some(Calibration { params, took, met_target });
+        if met_target { break; }
+    }
+    
+    return last;
+}
+
+/** Holds the `PasswordHasher` for a database, along with a hash it has
+    made of a dummy password, whether passwords are normalized before
+    they're passed to it, and (if it's a `Blake3Hasher`) how its digests
+    are encoded. */
+#[derive(Clone)]
+pub(crate) struct HashFn {
+    hasher: Arc<dyn PasswordHasher>,
+    dummy: String,
+    nfkc: bool,
+    blake3: bool,
+    encoding: HashEncoding,
+}
+
+impl HashFn {
+    pub(crate) fn new<H>(hasher: H) -> Self
+    where H: PasswordHasher + 'static
+    {
+        let dummy = hasher.hash("not a password", b"not a salt");
+        let blake3 = TypeId::of::<H>() == TypeId::of::<Blake3Hasher>();
+        HashFn {
+            hasher: Arc::new(hasher), dummy, nfkc: false, blake3, encoding: HashEncoding::default()
+        }
+    }
+    
+    /** Returns this with NFKC normalization of passwords on or off. */
+    pub(crate) fn normalized(mut self, on: bool) -> Self {
+        self.nfkc = on;
+        self
+    }
+    
+    pub(crate) fn normalizes(&self) -> bool { self.nfkc }
+    
+    /** Returns this with `Blake3Hasher` digests written in the given encoding. */
+    pub(crate) fn encoded(mut self, encoding: HashEncoding) -> Self {
+        self.encoding = encoding;
+        self
+    }
+    
+    pub(crate) fn encoding(&self) -> HashEncoding { self.encoding }
+    
+    /** Returns whether the hasher is a `Blake3Hasher`. */
+    pub(crate) fn is_blake3(&self) -> bool { self.blake3 }
+    
+    /** Returns the password as it's passed to the hasher. */
+    fn prepare<'a>(&self, password: &'a str) -> Cow<'a, str> {
+        if self.nfkc && !is_nfkc(password) {
+            Cow::Owned(password.nfkc().collect())
+        } else {
+            Cow::Borrowed(password)
+        }
+    }
+    
+    pub(crate) fn hash(&self, password: &str, salt: &[u8]) -> String {
+        let stored = self.hasher.hash(&self.prepare(password), salt);
+        if self.blake3 && self.encoding != HashEncoding::Hex {
+            if let Some((_, digest)) = HashEncoding::decode(&stored) {
+                return self.encoding.encode(&digest);
+            }
+        }
+        return stored;
+    }
+    
+    pub(crate) fn verify(&self, password: &str, salt: &[u8], stored: &str) -> bool {
+        self.hasher.verify(&self.prepare(password), salt, stored)
+    }
+    
+    /**
+    Checks the password against the dummy hash (which it won't match),
+    taking about as long as checking it against a real one.
+    */
+    pub(crate) fn verify_dummy(&self, password: &str, salt: &[u8]) {
+        let _ = self.hasher.verify(&self.prepare(password), salt, &self.dummy);
+    }
+    
+    /** Digests in the wrong `HashEncoding` are rehashed to move them onto the right one. */
+    pub(crate) fn needs_rehash(&self, stored: &str) -> bool {
+        if self.blake3 {
+            if let Some((encoding, _)) = HashEncoding::decode(stored) {
+                if encoding != self.encoding { return true; }
+            }
+        }
+        self.hasher.needs_rehash(stored)
+    }
+    
+    pub(crate) fn scheme(&self, stored: &str) -> String {
+        self.hasher.scheme(stored)
+    }
+}
+
+impl Default for HashFn {
+    fn default() -> Self { HashFn::new(Blake3Hasher) }
+}
+
+impl fmt::Debug for HashFn {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "HashFn(..)")
+    }
+}
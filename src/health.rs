@@ -0,0 +1,99 @@
+/*!
+Checks on the files behind a database, for `BothAuth::health_check()`.
+*/
+use std::fmt;
+use std::io::{ErrorKind, Write};
+use std::path::{Path, PathBuf};
+
+use crate::{FileError, Op, open_for_atomic_write, abort_atomic_write};
+use crate::validate::{ValidationIssue, ValidationReport};
+
+/** A problem with one of a database's files, found by
+    `BothAuth::health_check()`. Each describes (via `Display`) what's
+    wrong.
+*/
+#[derive(Debug, Clone, PartialEq)]
+#[non_exhaustive]
+pub enum HealthIssue {
+    /** The file doesn't exist (it may have been deleted or moved since
+        the database was opened). */
+    Missing { path: PathBuf },
+    /** The file exists but can't be read. */
+    Unreadable { path: PathBuf, error: FileError },
+    /** A temporary file can't be written alongside the file, so saving
+        it would fail. */
+    NotWritable { path: PathBuf, error: FileError },
+    /** The file has rows that opening it would skip or misread; `errors`
+        is how many, and `first` is the first of them. */
+    Corrupt { path: PathBuf, errors: usize, first: ValidationIssue },
+}
+
+impl HealthIssue {
+    /** The file the problem is with. */
+    pub fn path(&self) -> &Path {
+        match self {
+            HealthIssue::Missing { path } => path,
+            HealthIssue::Unreadable { path, .. } => path,
+            HealthIssue::NotWritable { path, .. } => path,
+            HealthIssue::Corrupt { path, .. } => path,
+        }
+    }
+}
+
+impl fmt::Display for HealthIssue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HealthIssue::Missing { path } => write!(f,
+                "{} is missing", path.to_string_lossy()),
+            HealthIssue::Unreadable { path, error } => write!(f,
+                "{} can't be read: {}", path.to_string_lossy(), error),
+            HealthIssue::NotWritable { path, error } => write!(f,
+                "{} can't be saved: {}", path.to_string_lossy(), error),
+            HealthIssue::Corrupt { path, errors, first } => write!(f,
+                "{} has {} bad row(s), the first at {}", path.to_string_lossy(), errors, first),
+        }
+    }
+}
+
+/**
+Checks that the file at `path` exists and can be read (and, with
+`validate`, that none of its rows are bad), and that a temporary file
+can be written alongside it as saving does, adding whatever's wrong to
+`issues`.
+*/
+pub(crate) fn check_file<F>(path: &Path, validate: F, issues: &mut Vec<HealthIssue>)
+where F: FnOnce(&Path) -> Result<ValidationReport, FileError>
+{
+    let path_buf = PathBuf::from(path);
+    match validate(path) {
+        Err(e) if e.kind == ErrorKind::NotFound => {
+            issues.push(HealthIssue::Missing { path: path_buf.clone() });
+        },
+        Err(error) => {
+            issues.push(HealthIssue::Unreadable { path: path_buf.clone(), error });
+        },
+        Ok(report) => {
+            let mut errors = report.issues.into_iter().filter(|issue| issue.is_error());
+            if let Some(first) = errors.next() {
+                let errors = 1 + errors.count();
+                issues.push(HealthIssue::Corrupt { path: path_buf.clone(), errors, first });
+            }
+        },
+    }
+    
+    if let Err(error) = probe_write(path) {
+        issues.push(HealthIssue::NotWritable { path: path_buf, error });
+    }
+}
+
+/** Writes (and syncs, and removes) a temporary file alongside `path`. */
+fn probe_write(path: &Path) -> Result<(), FileError> {
+    let (mut f, tmp) = open_for_atomic_write(path)?;
+    let result = f.write_all(b"authlite health check\n").and_then(|_| f.sync_all());
+    drop(f);
+    if let Err(e) = result {
+        return Err(abort_atomic_write(&tmp, FileError::from_io(path, Op::Write, &e)));
+    }
+    let _ = std::fs::remove_file(&tmp);
+    return Ok(());
+}
@@ -0,0 +1,293 @@
+use std::collections::HashMap;
+use std::io::ErrorKind;
+use std::ops::Add;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+use blake3::Hash;
+use parking_lot::RwLock;
+use rand::{Rng, distributions::Alphanumeric};
+use serde::{Serialize, Deserialize};
+
+use crate::{FileError, DataError, Op, open_for_read, open_for_write, open_for_atomic_write,
+            abort_atomic_write, commit_atomic_write};
+
+const INVITE_LENGTH: usize = 32;
+
+#[derive(Debug, Serialize, Deserialize)]
+struct InviteRW {
+    hash: String,
+    /* Empty for an invite anyone can redeem. */
+    uname: String,
+    #[serde(with ="humantime_serde")]
+    expiry: SystemTime,
+}
+
+#[derive(Debug)]
+pub(crate) struct InviteMeta {
+    uname: Option<String>,
+    expiry: SystemTime,
+}
+
+impl InviteMeta {
+    fn to_rw(&self, hash: &Hash) -> InviteRW {
+        return InviteRW {
+            hash: hash.to_hex().to_string(),
+            uname: self.uname.clone().unwrap_or_default(),
+            expiry: self.expiry,
+        };
+    }
+}
+
+/** A database of invitations to register, which persists as a .csv file
+    on disk.
+    
+    An invite is a random token, handed to someone (by email, say) so they
+    can create their own account, either with a particular user name or
+    (if the invite doesn't specify one) any name they like. Each invite
+    can be redeemed only once, and expires after a set time. Only hashes
+    of the tokens are stored.
+    
+    Attach an `InviteAuth` to a `BothAuth` (with `BothAuth::attach_invites()`)
+    and use `BothAuth::create_invite()` and `BothAuth::redeem_invite()`.
+    
+    Changes aren't written to disk as they're made (there's no
+    write-through mode of its own); the database is flagged as "dirty"
+    until it's saved, by `.save()` or `BothAuth::save_if_dirty()`. The one
+    exception is `BothAuth::redeem_invite()`, which saves it straight away
+    when the `BothAuth` is in write-through mode.
+*/
+#[derive(Debug)]
+pub struct InviteAuth {
+    invites: RwLock<HashMap<Hash, InviteMeta>>,
+    ifile:   PathBuf,
+    idirty:  RwLock<bool>,
+}
+
+impl InviteAuth {
+    /**
+    Create a new invite database that will save its data to a .csv file
+    at the supplied path.
+    */
+    pub fn new(invite_file: impl AsRef<Path>) -> Result<Self, FileError> {
+        let invite_file = invite_file.as_ref();
+        
+        if Path::exists(invite_file) {
+            return Err(FileError::new(invite_file, Op::Create, ErrorKind::AlreadyExists,
+                                      "file already exists".to_string()));
+        }
+        
+        let f = open_for_write(invite_file)?;
+        let mut w = csv::Writer::from_writer(f);
+        if let Err(e) = w.write_record(["hash", "uname", "expiry"]) {
+            return Err(FileError::from_csv(invite_file, Op::Create, &e));
+        }
+        if let Err(e) = w.flush() {
+            return Err(FileError::from_io(invite_file, Op::Create, &e));
+        }
+        
+        return Ok(InviteAuth::from_invites(invite_file, HashMap::new()));
+    }
+    
+    /**
+    Open an invite database with data from the .csv file at the given
+    path. Expired invites are not added to the in-memory database.
+    */
+    pub fn open(invite_file: impl AsRef<Path>) -> Result<Self, FileError> {
+        let invite_file = invite_file.as_ref();
+        
+        let now = SystemTime::now();
+        let f = open_for_read(invite_file)?;
+        let mut invites: HashMap<Hash, InviteMeta> = HashMap::new();
+        let mut r = csv::Reader::from_reader(f);
+        for (n, result) in r.deserialize::<InviteRW>().enumerate() {
+            match result {
+                Err(e) => {
+                    eprintln!("WARNING: reading {}, record {}: {}",
+                        invite_file.to_string_lossy(), n, &e);
+                },
+                Ok(irw) => {
+                    let hash = match Hash::from_hex(&irw.hash) {
+                        Ok(x) => x,
+                        Err(e) => {
+                            eprintln!("WARNING: reading {}, record {}: can't parse \"{}\" as Hash: {}",
+                                invite_file.to_string_lossy(), n, &irw.hash, &e);
+                            continue;
+                        },
+                    };
+                    if irw.expiry < now { continue; }
+                    
+                    let uname = if irw.uname.is_empty() { None } else { Some(irw.uname) };
+                    let _ = invites.insert(hash, InviteMeta { uname, expiry: irw.expiry });
+                },
+            }
+        }
+        
+        return Ok(InviteAuth::from_invites(invite_file, invites));
+    }
+    
+    /**
+    Open the invite database at the given path if the file exists, or
+    create a new one there if it doesn't.
+    */
+    pub fn open_or_new(invite_file: impl AsRef<Path>) -> Result<Self, FileError> {
+        let invite_file = invite_file.as_ref();
+        match InviteAuth::open(invite_file) {
+            Err(e) if e.kind == ErrorKind::NotFound => InviteAuth::new(invite_file),
+            x => x,
+        }
+    }
+    
+    fn from_invites(invite_file: &Path, invites: HashMap<Hash, InviteMeta>) -> Self {
+        return InviteAuth {
+            invites: RwLock::new(invites),
+            ifile:   PathBuf::from(invite_file),
+            idirty:  RwLock::new(false),
+        };
+    }
+    
+    /**
+    Creates an invite, valid for `life`, returning its token. If `uname`
+    is given, the invite can only be used to register that user name.
+    Marks the database as dirty.
+    */
+    pub fn create_invite(&self, uname: Option<&str>, life: Duration) -> String {
+        let token: String = rand::thread_rng()
+            .sample_iter(&Alphanumeric)
+            .take(INVITE_LENGTH)
+            .map(char::from)
+            .collect();
+        let imeta = InviteMeta {
+            uname:  uname.map(|u| u.to_string()),
+            expiry: SystemTime::now().add(life),
+        };
+        
+        let mut invites = self.invites.write();
+        let _ = invites.insert(blake3::hash(token.as_bytes()), imeta);
+        let mut dirty = self.idirty.write();
+        *dirty = true;
+        
+        return token;
+    }
+    
+    /**
+    Revokes an unused invite, returning `DataError::NoSuchKey` if there's
+    no such invite. Marks the database as dirty.
+    */
+    pub fn revoke_invite(&self, token: &str) -> Result<(), DataError> {
+        let mut invites = self.invites.write();
+        if invites.remove(&blake3::hash(token.as_bytes())).is_none() {
+            return Err(DataError::NoSuchKey);
+        }
+        let mut dirty = self.idirty.write();
+        *dirty = true;
+        return Ok(());
+    }
+    
+    /**
+    Revokes every unused invite that can only be used to register `uname`,
+    returning how many there were. Marks the database as "dirty" if there
+    were any.
+    */
+    pub fn remove_user(&self, uname: &str) -> usize {
+        let mut invites = self.invites.write();
+        let before = invites.len();
+        invites.retain(|_, imeta| imeta.uname.as_deref() != Some(uname));
+        let removed = before - invites.len();
+        
+        if removed > 0 {
+            let mut dirty = self.idirty.write();
+            *dirty = true;
+        }
+        return removed;
+    }
+    
+    /**
+    Removes the invite with the given token if it can be used to register
+    `uname`, returning it (so it can be put back with `.restore()` if
+    registration fails). Marks the database as dirty.
+    
+    Returns `DataError::NoSuchKey` if there's no such invite,
+    `DataError::KeyExpired` if it has expired, or `DataError::BadUsername`
+    if it's for a different user name.
+    */
+    pub(crate) fn take(&self, token: &str, uname: &str) -> Result<(Hash, InviteMeta), DataError> {
+        let hash = blake3::hash(token.as_bytes());
+        let mut invites = self.invites.write();
+        match invites.get(&hash) {
+            None => { return Err(DataError::NoSuchKey); },
+            Some(imeta) if imeta.expiry < SystemTime::now() => {
+                let expired_for = imeta.expiry.elapsed().unwrap_or_default();
+                return Err(DataError::KeyExpired { expired_for });
+            },
+            Some(InviteMeta { uname: Some(u), .. }) if u != uname => {
+                return Err(DataError::BadUsername);
+            },
+            Some(_) => {},
+        }
+        
+        let imeta = invites.remove(&hash).unwrap();
+        let mut dirty = self.idirty.write();
+        *dirty = true;
+        return Ok((hash, imeta));
+    }
+    
+    /** Puts back an invite removed by `.take()`. */
+    pub(crate) fn restore(&self, hash: Hash, imeta: InviteMeta) {
+        let mut invites = self.invites.write();
+        let _ = invites.insert(hash, imeta);
+    }
+    
+    /** Removes expired invites, marking the database dirty if there were any. */
+    pub fn cull(&self) {
+        let now = SystemTime::now();
+        let mut invites = self.invites.write();
+        let n = invites.len();
+        invites.retain(|_, imeta| imeta.expiry >= now);
+        if invites.len() < n {
+            let mut dirty = self.idirty.write();
+            *dirty = true;
+        }
+    }
+    
+    /** Returns the path of the file this database saves to. */
+    pub fn path(&self) -> &Path { &self.ifile }
+    
+    /**
+    Returns whether the in-memory database is "dirty", that is, whether it's
+    out of sync with the persistent data on disk.
+    */
+    pub fn is_dirty(&self) -> bool {
+        let dirty = self.idirty.read();
+        return *dirty;
+    }
+    
+    /**
+    Writes all unexpired invites to disk, marking the database as no longer
+    dirty. As with the other databases, the file is replaced atomically.
+    */
+    pub fn save(&self) -> Result<(), FileError> {
+        let now = SystemTime::now();
+        let path = &self.ifile;
+        let invites = self.invites.write();
+        
+        let (f, tmp) = open_for_atomic_write(path)?;
+        let mut w = csv::Writer::from_writer(f);
+        for (hash, imeta) in invites.iter() {
+            if imeta.expiry < now { continue; }
+            if let Err(e) = w.serialize(imeta.to_rw(hash)) {
+                return Err(abort_atomic_write(&tmp, FileError::from_csv(path, Op::Write, &e)));
+            }
+        }
+        let f = match w.into_inner() {
+            Ok(f) => f,
+            Err(e) => { return Err(abort_atomic_write(&tmp, FileError::from_io(path, Op::Write, e.error()))); },
+        };
+        commit_atomic_write(f, &tmp, path)?;
+        
+        let mut dirty = self.idirty.write();
+        *dirty = false;
+        
+        return Ok(());
+    }
+}
@@ -1,20 +1,89 @@
 
 use std::collections::HashMap;
-use std::ops::{Add, Sub};
+use std::io::{ErrorKind, Read, Write};
+use std::ops::{Add, Deref};
 use std::path::{Path, PathBuf};
-use std::sync::RwLock;
-use std::time::{Duration, SystemTime};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
 use rand::{Rng, distributions};
+use parking_lot::{Mutex, RwLock};
 use serde::{Serialize, Deserialize};
+use serde_json::Value;
 
-use crate::{FileError, DataError, open_for_read, open_for_write};
+use crate::{AuthOk, FileError, DataError, Op, SaveOrder, open_for_read, open_for_write, create_parent_dirs,
+            open_for_atomic_write, abort_atomic_write, commit_atomic_write};
+use crate::audit::Finding;
+use crate::both::StatusReport;
+use crate::compress::{self, Compression, Encoder};
+use crate::event::{AuthEvent, EventHook, SecurityEvent};
+use crate::fault::Faulty;
+use crate::shard::{self, ShardedMap, WriteGuard};
+use crate::size::{self, ByteCounter, SizeLimit};
+use crate::throttle::IssueThrottle;
+use crate::validate::{self, ValidationIssue, ValidationReport};
 
 const DEFAULT_KEY_LENGTH: usize = 32;
 const DEFAULT_KEY_CHARS: &str = 
 "abcdefghijklmnopqrstuvwxyzABCDEFGHIJKLMNOPQRSTUVWXYZ0123456789/?:;[]{}|-_#^";
 const DEFAULT_KEY_LIFE_SECS: u64 = 20 * 60; 
-const ONE_YEAR: Duration = Duration::from_secs(3600 * 24 * 364);
+const DEFAULT_TOUCH_FRACTION: f64 = 0.5;
+/* Keys shorter than this (or drawn from fewer characters, or with fewer
+   bits of entropy) are flagged by `.validate_file()` and `.audit()`. */
+const MIN_SAFE_KEY_LENGTH: usize = 16;
+const MIN_SAFE_KEY_CHARS: usize = 16;
+const MIN_SAFE_KEY_BITS: f64 = 128.0;
+
+/** Preset sets of characters from which keys can be generated (see
+    `KeyAuth::charset()`).
+    
+    The default character set (used if neither `.charset()` nor `.chars()`
+    is called) includes characters like `;`, `{}`, and `|`, which need
+    escaping in URLs, cookies, and shell commands; all the presets here
+    are safe to use anywhere.
+*/
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum KeyCharset {
+    /** ASCII letters and digits (62 characters). */
+    AlphaNum,
+    /** The RFC 4648 "base64url" alphabet: letters, digits, `-`, and `_`. */
+    UrlSafe,
+    /** Lowercase hexadecimal digits. */
+    Hex,
+    /** The RFC 4648 base32 alphabet: uppercase letters and `2`-`7`. */
+    Base32,
+    /** Any other set of characters. */
+    Custom(String),
+}
+
+impl KeyCharset {
+    /** Returns the characters in this set. */
+    pub fn chars(&self) -> &str {
+        match self {
+            KeyCharset::AlphaNum =>
+                "abcdefghijklmnopqrstuvwxyzABCDEFGHIJKLMNOPQRSTUVWXYZ0123456789",
+            KeyCharset::UrlSafe =>
+                "abcdefghijklmnopqrstuvwxyzABCDEFGHIJKLMNOPQRSTUVWXYZ0123456789-_",
+            KeyCharset::Hex => "0123456789abcdef",
+            KeyCharset::Base32 => "ABCDEFGHIJKLMNOPQRSTUVWXYZ234567",
+            KeyCharset::Custom(s) => s,
+        }
+    }
+}
+
+/** What a `KeyAuth` should do when issuing a key would exceed its
+    configured maximum number of keys (see `KeyAuth::max_keys()`).
+    
+    In either case, expired keys are culled first to make room.
+*/
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CapacityPolicy {
+    /** Refuse to issue the new key, returning `DataError::CapacityExceeded`. */
+    Reject,
+    /** Remove the still-valid keys closest to expiring to make room. */
+    EvictSoonestExpiring,
+}
 
 #[derive(Debug, Serialize, Deserialize)]
 struct KeyRW {
@@ -22,18 +91,94 @@ struct KeyRW {
     #[serde(with ="humantime_serde")]
     expiry: SystemTime,
     uname: String,
+    /* Older files have no issue time column. */
+    #[serde(with ="humantime_serde", default)]
+    issued: Option<SystemTime>,
+    /* Nor a revoked column. */
+    #[serde(default)]
+    revoked: bool,
+    /* Nor a last-used column. */
+    #[serde(with ="humantime_serde", default)]
+    used: Option<SystemTime>,
+    /* Nor a context column, which holds JSON (or nothing). */
+    #[serde(default)]
+    context: Option<String>,
+    /* Nor a life column, which is empty for keys with the usual life. */
+    #[serde(with ="humantime_serde", default)]
+    life: Option<Duration>,
+}
+
+impl KeyRW {
+    fn id(&self) -> &str { &self.key }
+}
+
+/* When a key's expiry time was last set by this process, on both the
+   system clock and the monotonic clock. */
+#[derive(Debug, Clone, Copy)]
+struct Anchor {
+    wall: SystemTime,
+    mono: Instant,
+}
+
+impl Anchor {
+    fn new(wall: SystemTime) -> Self { Anchor { wall, mono: Instant::now() } }
+    
+    /**
+    Returns what the system clock would read at time `at` if it hadn't been
+    changed since the anchor was set: the anchor's time plus the (monotonic)
+    time elapsed since, shifted by however far `at` is from now.
+    */
+    fn correct(&self, at: SystemTime) -> SystemTime {
+        let now = self.wall.add(self.mono.elapsed());
+        match at.duration_since(SystemTime::now()) {
+            Ok(ahead) => now.add(ahead),
+            Err(e) => now.checked_sub(e.duration()).unwrap_or(UNIX_EPOCH),
+        }
+    }
 }
 
 #[derive(Debug)]
 struct KeyMeta {
     uname: String,
     expiry: SystemTime,
+    issued: SystemTime,
+    /* Only keys issued or refreshed by this process have one. */
+    anchor: Option<Anchor>,
+    /* Set by `KeyAuth::invalidate_key()`; the key is kept (and saved)
+       until it expires, so it can be told apart from an unknown key. */
+    revoked: bool,
+    /* The generation at which it was revoked, or 0 if it was revoked
+       before the database was opened. */
+    revoked_gen: u64,
+    /* When it was last used, in milliseconds since the epoch; atomic so
+       that checking a key (under a read lock) can update it. */
+    used: AtomicU64,
+    /* Whatever the application recorded about where the key was issued;
+       see `KeyAuth::issue_key_with_context()`. */
+    context: Option<Value>,
+    /* The life it was issued with, if not the database's usual key life;
+       see `KeyAuth::issue_key_with_life()`. */
+    life: Option<Duration>,
 }
 
 impl KeyMeta {
     fn from_rw(krw: KeyRW) -> (String, Self) {
         let (k, u, exp) = (krw.key, krw.uname, krw.expiry);
-        return (k, KeyMeta { uname: u, expiry: exp });
+        /* Keys of unknown age are treated as being as old as possible,
+           so they're rejected by any not-valid-before time. */
+        let iss = krw.issued.unwrap_or(UNIX_EPOCH);
+        /* Context that isn't valid JSON is dropped rather than losing the
+           key (it's only ever shown to the user). */
+        let context = krw.context.and_then(|json| serde_json::from_str(&json).ok());
+        let kmeta = KeyMeta {
+            uname: u, expiry: exp, issued: iss, anchor: None,
+            revoked: krw.revoked, revoked_gen: 0, used: AtomicU64::new(0), context,
+            life: krw.life,
+        };
+        /* Keys from older files count as used when they're read, so they
+           aren't culled as idle straight away. */
+        kmeta.note_use(krw.used.unwrap_or_else(SystemTime::now));
+        return (k, kmeta);
     }
     
     fn to_rw(&self, key_string: &str) -> KeyRW {
@@ -41,8 +186,299 @@ impl KeyMeta {
             uname: self.uname.clone(),
             key: key_string.to_string(),
             expiry: self.expiry,            // SystemTime is Copy
+            issued: Some(self.issued),
+            revoked: self.revoked,
+            used: Some(self.last_used()),
+            context: self.context.as_ref().map(Value::to_string),
+            life: self.life,
         };
     }
+    
+    fn info(&self, key_string: &str) -> KeyInfo {
+        return KeyInfo {
+            hash: KeyHash::of(key_string),
+            uname: self.uname.clone(),
+            issued: self.issued,
+            expiry: self.expiry,
+            last_used: self.last_used(),
+            revoked: self.revoked,
+            context: self.context.clone(),
+        };
+    }
+    
+    /**
+    Makes the key expire `life` after `now`, measuring that life on the
+    monotonic clock so that changes to the system clock don't cut it short
+    or stretch it out.
+    */
+    fn set_life(&mut self, now: SystemTime, life: Duration) {
+        self.expiry = now.add(life);
+        self.anchor = Some(Anchor::new(now));
+        self.note_use(now);
+    }
+    
+    /**
+    Makes the key expire at time `now` plus the life it was issued with,
+    or plus `usual_life` if it was issued with the usual life.
+    */
+    fn renew(&mut self, now: SystemTime, usual_life: Duration) {
+        let life = self.life.unwrap_or(usual_life);
+        self.set_life(now, life);
+    }
+    
+    /** Returns when the key was last issued, refreshed, or found valid. */
+    fn last_used(&self) -> SystemTime {
+        UNIX_EPOCH.add(Duration::from_millis(self.used.load(Ordering::Relaxed)))
+    }
+    
+    /** Notes that the key was used at time `now` (unless it was used later). */
+    fn note_use(&self, now: SystemTime) {
+        let ms = now.duration_since(UNIX_EPOCH).unwrap_or_default().as_millis();
+        let _ = self.used.fetch_max(ms as u64, Ordering::Relaxed);
+    }
+    
+    /** Whether the key has gone unused for longer than `max_idle` at time `now`. */
+    fn is_idle(&self, now: SystemTime, max_idle: Duration) -> bool {
+        now.duration_since(self.last_used()).is_ok_and(|idle| idle > max_idle)
+    }
+    
+    /**
+    Returns the time `now`, corrected (if the key has an anchor) for any
+    changes to the system clock since its expiry time was set.
+    */
+    fn corrected(&self, now: SystemTime) -> SystemTime {
+        match &self.anchor {
+            Some(anchor) => anchor.correct(now),
+            None => now,
+        }
+    }
+    
+    /** Returns how long this key has left at time `now`. */
+    fn remaining(&self, now: SystemTime) -> Duration {
+        self.expiry.duration_since(self.corrected(now)).unwrap_or_default()
+    }
+    
+    /**
+    Whether this key is no longer valid at time `now`, either because it
+    has expired or because it was issued before its user's time in
+    `not_before`.
+    */
+    fn is_expired(&self, now: SystemTime, not_before: &NotBefore) -> bool {
+        self.expired_for(now, not_before).is_some()
+    }
+    
+    /**
+    If this key is no longer valid at time `now` (see `.is_expired()`),
+    returns how long it hasn't been: since it expired, or else since the
+    time before which it was invalidated.
+    */
+    fn expired_for(&self, now: SystemTime, not_before: &NotBefore) -> Option<Duration> {
+        let now = self.corrected(now);
+        if self.expiry < now {
+            return Some(now.duration_since(self.expiry).unwrap_or_default());
+        }
+        match not_before.of(&self.uname) {
+            Some(t) if self.issued < t => Some(now.duration_since(t).unwrap_or_default()),
+            _ => None,
+        }
+    }
+}
+
+/** The BLAKE3 hash of a key, by which revoked keys are identified (see
+    `KeyAuth::revocations_since()`) without handing out the keys
+    themselves.
+*/
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct KeyHash(blake3::Hash);
+
+impl KeyHash {
+    /** Returns the hash of the given key. */
+    pub fn of(key: &str) -> Self { KeyHash(blake3::hash(key.as_bytes())) }
+    
+    /** Returns the hash as 64 lowercase hexadecimal digits. */
+    pub fn to_hex(&self) -> String { self.0.to_hex().to_string() }
+    
+    /** Returns the 32 bytes of the hash. */
+    pub fn as_bytes(&self) -> &[u8; 32] { self.0.as_bytes() }
+}
+
+impl std::fmt::Display for KeyHash {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0.to_hex())
+    }
+}
+
+/* The times before which keys are invalid: one for every key (set by
+   `KeyAuth::invalidate_all()`), and one for each user that has one (set by
+   `KeyAuth::invalidate_user_before()`). The per-user times are shared so
+   that copying this is cheap. */
+#[derive(Debug, Default, Clone)]
+struct NotBefore {
+    all: Option<SystemTime>,
+    users: Arc<HashMap<String, SystemTime>>,
+}
+
+impl NotBefore {
+    /** Returns the time before which the given user's keys are invalid. */
+    fn of(&self, uname: &str) -> Option<SystemTime> {
+        match (self.all, self.users.get(uname)) {
+            (Some(all), Some(user)) => Some(all.max(*user)),
+            (all, user) => all.or_else(|| user.copied()),
+        }
+    }
+}
+
+/** What's known about a key (see `KeyAuth::key_info()` and
+    `KeyAuth::user_keys()`), for showing a user their sessions. This is a
+    copy; changing it doesn't change the key. */
+#[derive(Debug, Clone, PartialEq)]
+pub struct KeyInfo {
+    /** The key's hash, which identifies it without giving it away. */
+    pub hash: KeyHash,
+    /** The user it was issued to. */
+    pub uname: String,
+    /** When it was issued (or rotated). */
+    pub issued: SystemTime,
+    /** When it expires, unless it's refreshed first. */
+    pub expiry: SystemTime,
+    /** When it was last issued, refreshed, or found valid. */
+    pub last_used: SystemTime,
+    /** Whether it has been revoked with `KeyAuth::invalidate_key()`. */
+    pub revoked: bool,
+    /** What was recorded about where it was issued, if anything (see
+        `KeyAuth::issue_key_with_context()`). */
+    pub context: Option<Value>,
+}
+
+/** What `KeyAuth::maintain()` got done, and what it left for next time. */
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct MaintenanceReport {
+    /** How many expired (or idle; see `KeyAuth::idle_timeout()`) keys
+        were removed. */
+    pub culled: usize,
+    /** How many shards are still to be culled in the current pass. */
+    pub shards_remaining: usize,
+    /** Whether the database was saved. */
+    pub saved: bool,
+    /** Whether the database is still dirty. */
+    pub save_pending: bool,
+}
+
+impl MaintenanceReport {
+    /** Whether there's no maintenance left to do. */
+    pub fn is_done(&self) -> bool { self.shards_remaining == 0 && !self.save_pending }
+}
+
+/* Record format of the file that persists a `KeyAuth`'s not-valid-before
+   times alongside its key file; the row without a user name applies to
+   every key. */
+#[derive(Debug, Serialize, Deserialize)]
+struct NotBeforeRW {
+    #[serde(with ="humantime_serde")]
+    not_before: SystemTime,
+    /* Older files have no user column. */
+    #[serde(default)]
+    uname: Option<String>,
+}
+
+/** Returns the path of the file storing the not-valid-before time for
+    the given key file. */
+pub(crate) fn not_before_path(key_file: &Path) -> PathBuf {
+    let mut p = key_file.as_os_str().to_owned();
+    p.push(".nvb");
+    PathBuf::from(p)
+}
+
+/** Reads the not-valid-before times for the given key file, if any
+    have been set. */
+fn read_not_before(key_file: &Path) -> Result<NotBefore, FileError> {
+    let nvb_file = not_before_path(key_file);
+    if !Path::exists(&nvb_file) { return Ok(NotBefore::default()); }
+    
+    let f = open_for_read(&nvb_file)?;
+    let mut r = csv::Reader::from_reader(f);
+    let mut not_before = NotBefore::default();
+    let mut users: HashMap<String, SystemTime> = HashMap::new();
+    for result in r.deserialize::<NotBeforeRW>() {
+        match result {
+            Err(e) => {
+                return Err(FileError::from_csv(&nvb_file, Op::Read, &e));
+            },
+            Ok(NotBeforeRW { not_before: t, uname: None }) => { not_before.all = Some(t); },
+            Ok(NotBeforeRW { not_before: t, uname: Some(u) }) => { let _ = users.insert(u, t); },
+        }
+    }
+    not_before.users = Arc::new(users);
+    
+    return Ok(not_before);
+}
+
+/** Writes the given not-valid-before times for the given key file. */
+fn write_not_before(key_file: &Path, not_before: &NotBefore) -> Result<(), FileError> {
+    let nvb_file = not_before_path(key_file);
+    let (f, tmp) = open_for_atomic_write(&nvb_file)?;
+    let mut w = csv::Writer::from_writer(f);
+    let mut rows: Vec<NotBeforeRW> = not_before.all.iter()
+        .map(|t| NotBeforeRW { not_before: *t, uname: None })
+        .collect();
+    rows.extend(not_before.users.iter()
+        .map(|(u, t)| NotBeforeRW { not_before: *t, uname: Some(u.clone()) }));
+    for nbrw in rows.iter() {
+        if let Err(e) = w.serialize(nbrw) {
+            return Err(abort_atomic_write(&tmp, FileError::from_csv(&nvb_file, Op::Write, &e)));
+        }
+    }
+    let f = match w.into_inner() {
+        Ok(f) => f,
+        Err(e) => { return Err(abort_atomic_write(&tmp, FileError::from_io(&nvb_file, Op::Write, e.error()))); },
+    };
+    return commit_atomic_write(f, &tmp, &nvb_file);
+}
+
+/* Record format of the file that persists a `KeyAuth`'s key generation
+   settings alongside its key file (see `KeyAuth::save_config()`). */
+#[derive(Debug, Serialize, Deserialize)]
+struct KeyConfigRW {
+    length: usize,
+    chars: String,
+    #[serde(with ="humantime_serde")]
+    life: Duration,
+}
+
+/** Returns the path of the file storing the key generation settings for
+    the given key file. */
+fn config_path(key_file: &Path) -> PathBuf {
+    let mut p = key_file.as_os_str().to_owned();
+    p.push(".cfg");
+    PathBuf::from(p)
+}
+
+/** Reads the saved key generation settings for the given key file, if
+    they have been saved. Settings that couldn't generate a key (a length
+    of 0, or no characters) are rejected as invalid data. */
+fn read_config(key_file: &Path) -> Result<Option<KeyConfigRW>, FileError> {
+    let cfg_file = config_path(key_file);
+    if !Path::exists(&cfg_file) { return Ok(None); }
+    
+    let f = open_for_read(&cfg_file)?;
+    let mut r = csv::Reader::from_reader(f);
+    let mut config: Option<KeyConfigRW> = None;
+    for result in r.deserialize::<KeyConfigRW>() {
+        match result {
+            Err(e) => {
+                return Err(FileError::from_csv(&cfg_file, Op::Read, &e));
+            },
+            Ok(cfgrw) => { config = Some(cfgrw); },
+        }
+    }
+    if let Some(cfgrw) = &config {
+        if cfgrw.length == 0 || cfgrw.chars.is_empty() {
+            return Err(FileError::new(&cfg_file, Op::Read, ErrorKind::InvalidData,
+                "keys must have a length and at least one character to draw from".to_string()));
+        }
+    }
+    
+    return Ok(config);
 }
 
 /** Represents a "session key" authorization database, which can persist
@@ -57,15 +493,37 @@ impl KeyMeta {
     disk; instead, the database will be internally flagged as "dirty"
     (that is, out of sync with the data on disk) until it is explicitly
     written.
+    
+    Everything that changes the keys (as opposed to the settings) takes
+    `&self`, so a single `KeyAuth` can be shared between threads in an
+    `Arc` without any extra locking.
 */
 #[derive(Debug)]
 pub struct KeyAuth {
-    keys:   RwLock<HashMap<String, KeyMeta>>,
+    keys:   ShardedMap<KeyMeta>,
     kfile:  PathBuf,
-    kdirty: RwLock<bool>,
+    kdirty: AtomicBool,
+    kgen:   AtomicU64,
     klen:   usize,
     kchars: Vec<char>,
     klife:  Duration,
+    kmax:   Option<usize>,
+    kpolicy: CapacityPolicy,
+    events: EventHook,
+    security: EventHook<SecurityEvent>,
+    not_before: RwLock<NotBefore>,
+    write_through: bool,
+    korder: SaveOrder,
+    touch_frac: f64,
+    kidle:  Option<Duration>,
+    kbytes: SizeLimit,
+    kcompress: Compression,
+    throttle: IssueThrottle,
+    load_report: ValidationReport,
+    /* Named key stores; see `.add_namespace()`. */
+    spaces: HashMap<String, KeyAuth>,
+    /* The next shard for `.maintain()` to cull. */
+    maint_next: Mutex<usize>,
 }
 
 impl KeyAuth {
@@ -73,12 +531,12 @@ impl KeyAuth {
     Create a new key authorization database that will save its data to
     a .csv file at the supplied path.
     */
-    pub fn new(key_file: &dyn AsRef<Path>) -> Result<Self, FileError> {
+    pub fn new(key_file: impl AsRef<Path>) -> Result<Self, FileError> {
         let key_file = key_file.as_ref();
         
         if Path::exists(key_file) {
-            let estr = key_file.to_string_lossy().to_string();
-            return Err(FileError::Exists(estr));
+            return Err(FileError::new(key_file, Op::Create, ErrorKind::AlreadyExists,
+                                      "file already exists".to_string()));
         }
         
         let kv: Vec<KeyMeta> = Vec::new();
@@ -91,22 +549,48 @@ impl KeyAuth {
             w.serialize(krw).unwrap();
         }
         if let Err(e) = w.flush() {
-            let estr = format!("{}: {}", key_file.to_string_lossy(), &e);
-            return Err(FileError::Write(estr));
+            return Err(FileError::from_io(key_file, Op::Create, &e));
         }
+        let not_before = read_not_before(key_file)?;
         
         let a = KeyAuth {
-            keys:   RwLock::new(HashMap::new()),
+            keys:   ShardedMap::new(shard::DEFAULT_SHARDS, HashMap::new()),
             kfile:  PathBuf::from(key_file),
-            kdirty: RwLock::new(false),
+            kdirty: AtomicBool::new(false),
+            kgen:   AtomicU64::new(0),
             klen:   DEFAULT_KEY_LENGTH,
             kchars: DEFAULT_KEY_CHARS.chars().collect(),
             klife:  Duration::from_secs(DEFAULT_KEY_LIFE_SECS),
+            kmax:   None,
+            kpolicy: CapacityPolicy::Reject,
+            events: EventHook::default(),
+            security: EventHook::default(),
+            not_before: RwLock::new(not_before),
+            write_through: false,
+            korder: SaveOrder::default(),
+            touch_frac: DEFAULT_TOUCH_FRACTION,
+            kidle:  None,
+            kbytes: SizeLimit::default(),
+            kcompress: Compression::from_path(key_file),
+            throttle: IssueThrottle::default(),
+            load_report: ValidationReport::empty(key_file),
+            spaces: HashMap::new(),
+            maint_next: Mutex::new(0),
         };
         
         return Ok(a);
     }
     
+    /**
+    Like `.new()`, but first creates any missing directories in the path
+    to the file (readable only by their owner, on Unix).
+    */
+    pub fn new_with_dirs(key_file: impl AsRef<Path>) -> Result<Self, FileError> {
+        let key_file = key_file.as_ref();
+        create_parent_dirs(key_file)?;
+        KeyAuth::new(key_file)
+    }
+    
     /**
     Open a key authorization database with data from the .csv file in the
     given path.
@@ -114,46 +598,144 @@ impl KeyAuth {
     If the database is updated and saved, this is also where the changes
     will be written to disk.
     
-    Saved keys that have expired at the time of reading will not be added
-    to the in-memory database.
+    If key generation settings have been saved alongside the file (with
+    `.save_config()`), they are restored.
+    
+    Saved keys that have expired at the time of reading (or were issued
+    before a call to `.invalidate_all()`) will not be added to the
+    in-memory database. Rows that can't be read are skipped, and if a key
+    has more than one unexpired row, the last one wins; either way, a warning is
+    printed, and the problem is recorded in the `.load_report()`.
     */
-    pub fn open(key_file: &dyn AsRef<Path>) -> Result<Self, FileError> {
+    pub fn open(key_file: impl AsRef<Path>) -> Result<Self, FileError> {
         let key_file = key_file.as_ref();
-        
-        let now = SystemTime::now();
         let f = open_for_read(key_file)?;
+        let f = Faulty::reader(compress::decoder(f, key_file)?, key_file);
+        let not_before = read_not_before(key_file)?;
+        let config = read_config(key_file)?;
+        return KeyAuth::read_from(f, key_file, not_before, config);
+    }
+    
+    /**
+    Read a key authorization database from uncompressed .csv data (in the
+    format `.save()` writes), such as an embedded asset, stdin or a
+    decrypting wrapper. Keys are handled as by `.open()`, except that
+    there are no saved invalidations or key generation settings to read.
+    
+    The database has no file of its own, so `.save()` fails with
+    `ErrorKind::InvalidInput`; write it out with `.save_to()` or
+    `.save_to_writer()` instead.
+    */
+    pub fn from_reader<R: Read>(r: R) -> Result<Self, FileError> {
+        return KeyAuth::read_from(r, Path::new(""), NotBefore::default(), None);
+    }
+    
+    /**
+    Reads a database from `f`, which will be saved to `key_file`, dropping
+    keys that have expired or were issued before `not_before`.
+    */
+    fn read_from<R: Read>(
+        f: R,
+        key_file: &Path,
+        not_before: NotBefore,
+        config: Option<KeyConfigRW>,
+    ) -> Result<Self, FileError> {
+        let now = SystemTime::now();
         let mut new_keys: HashMap<String, KeyMeta> = HashMap::new();
-        let mut r = csv::Reader::from_reader(f);
-        for (n, result) in r.deserialize().enumerate() {
-            match result {
-                Err(e) => {
-                    eprintln!("WARNING: reading {}, record {}: {}",
-                        key_file.to_string_lossy(), n, &e);
-                },
-                Ok(krw) => {
-                    let (key, kmeta) = KeyMeta::from_rw(krw);
-                    if now < kmeta.expiry {
-                        if let Some(_) = new_keys.insert(key.clone(), kmeta) {
-                            eprintln!("WARNING: duplicate key entry for \"{}\"", key);
-                        }
-                    }
-                },
+        let report = validate::validate_rows(f, key_file, KeyRW::id, |_, krw, _| {
+            let (key, kmeta) = KeyMeta::from_rw(krw);
+            if !kmeta.is_expired(now, &not_before) {
+                let _ = new_keys.insert(key, kmeta);
             }
-        }
+        })?;
+        report.warn();
         
-        let a = KeyAuth {
-            keys:   RwLock::new(new_keys),
+        let mut a = KeyAuth {
+            keys:   ShardedMap::new(shard::DEFAULT_SHARDS, new_keys),
             kfile:  PathBuf::from(key_file),
-            kdirty: RwLock::new(false),
+            kdirty: AtomicBool::new(false),
+            kgen:   AtomicU64::new(0),
             klen:   DEFAULT_KEY_LENGTH,
             kchars: DEFAULT_KEY_CHARS.chars().collect(),
             klife:  Duration::from_secs(DEFAULT_KEY_LIFE_SECS),
+            kmax:   None,
+            kpolicy: CapacityPolicy::Reject,
+            events: EventHook::default(),
+            security: EventHook::default(),
+            not_before: RwLock::new(not_before),
+            write_through: false,
+            korder: SaveOrder::default(),
+            touch_frac: DEFAULT_TOUCH_FRACTION,
+            kidle:  None,
+            kbytes: SizeLimit::default(),
+            kcompress: Compression::from_path(key_file),
+            throttle: IssueThrottle::default(),
+            load_report: report,
+            spaces: HashMap::new(),
+            maint_next: Mutex::new(0),
         };
+        if let Some(config) = config {
+            a.length(config.length);
+            a.chars(config.chars);
+            a.life(config.life);
+        }
         
         return Ok(a);
     }
     
-    /** Change the length of the generated key from the default 32. */
+    /**
+    Open the key authorization database at the given path if the file
+    exists, or create a new one there if it doesn't.
+    */
+    pub fn open_or_new(key_file: impl AsRef<Path>) -> Result<Self, FileError> {
+        let key_file = key_file.as_ref();
+        match KeyAuth::open(key_file) {
+            Err(e) if e.kind == ErrorKind::NotFound => KeyAuth::new(key_file),
+            x => x,
+        }
+    }
+    
+    /**
+    Checks the key file at the given path (and its not-valid-before time,
+    if any) without opening it as a database, reporting rows that `.open()`
+    would skip or overwrite, keys too short to be safe, and whether the
+    file is readable by other users.
+    
+    Returns `Err()` only if the file can't be read at all.
+    */
+    pub fn validate_file(key_file: impl AsRef<Path>) -> Result<ValidationReport, FileError> {
+        let key_file = key_file.as_ref();
+        
+        let now = SystemTime::now();
+        let f = open_for_read(key_file)?;
+        let f = compress::decoder(f, key_file)?;
+        let not_before = read_not_before(key_file)?;
+        let mut report = validate::validate_rows(f, key_file, KeyRW::id, |line, krw, issues| {
+            let length = krw.key.chars().count();
+            if length < MIN_SAFE_KEY_LENGTH {
+                issues.push(ValidationIssue::ShortKey { line, length });
+            }
+            let issued = krw.issued.unwrap_or(UNIX_EPOCH);
+            let expired = krw.expiry < now
+                || matches!(not_before.of(&krw.uname), Some(t) if issued < t);
+            if expired {
+                issues.push(ValidationIssue::Expired { line });
+            }
+        })?;
+        report.check_permissions();
+        return Ok(report);
+    }
+    
+    /**
+    Returns the problems found in the file when the database was opened
+    (rows that were skipped, and keys with more than one row), so they can
+    be located and fixed. This is empty for a database created with
+    `.new()`.
+    */
+    pub fn load_report(&self) -> &ValidationReport { &self.load_report }
+    
+    /** Change the length of the generated key from the default 32. With a
+        length of 0, issuing keys fails with `DataError::Unsupported`. */
     pub fn length(&mut self, key_length: usize) { self.klen = key_length; }
     
     /**
@@ -161,220 +743,1292 @@ impl KeyAuth {
     
     `"abcdefghijklmnopqrstuvwxyzABCDEFGHIJKLMNOPQRSTUVWXYZ0123456789/?:;[]{}|-_#^"`
     
-    Will panic if the new key's expiration time is unrepresentable by the
-    system.
+    Repeated characters are ignored, so that every character in the set is
+    equally likely to appear at each position in a key, whether or not the
+    size of the set is a power of two. With no characters at all, issuing
+    keys fails with `DataError::Unsupported`.
     */
-    pub fn chars(&mut self, key_chars: &dyn AsRef<str>) {
-        self.kchars = key_chars.as_ref().chars().collect();
+    pub fn chars(&mut self, key_chars: impl AsRef<str>) {
+        let mut kchars: Vec<char> = Vec::new();
+        for c in key_chars.as_ref().chars() {
+            if !kchars.contains(&c) { kchars.push(c); }
+        }
+        self.kchars = kchars;
     }
     
-    /** Change the life of issued keys from the default of 20 minutes. */
+    /**
+    Change the characters used to generate keys to one of the preset
+    `KeyCharset`s.
+    */
+    pub fn charset(&mut self, charset: KeyCharset) { self.chars(charset.chars()); }
+    
+    /**
+    Change the life of issued keys from the default of 20 minutes.
+    
+    The life of a key issued or refreshed by this process is timed on the
+    monotonic clock, so setting the system clock back (or forward) doesn't
+    extend it (or cut it short). Keys read from the file can only be timed
+    against the system clock until they're refreshed.
+    */
     pub fn life(&mut self, key_life: Duration) { self.klife = key_life; }
     
     /**
-    Generate a new key and store it in the database, associating it with
-    the supplied user name and setting it to expire at the appropriate
-    time in the future.
+    Like `.life()`, but takes the life as a human-readable string like
+    `"45m"` or `"1h 30m"` (as from a configuration file), parsed by
+    `humantime::parse_duration()`. The life is unchanged if the string
+    can't be parsed.
+    */
+    pub fn life_str(&mut self, key_life: &str) -> Result<(), humantime::DurationError> {
+        self.klife = humantime::parse_duration(key_life)?;
+        return Ok(());
+    }
+    
+    /**
+    Writes the current key length, characters, and key life to a file
+    alongside the key file (with `.cfg` appended to its name), so that
+    `.open()` restores them instead of the defaults. Otherwise, these must
+    be set again every time the database is opened.
     
-    Will panic if `self.chars()` has been set to an empty set of characters,
-    or the expiration time is far enough in the future that it can't be
-    represented by the underlying system.
+    This isn't done by `.save()`, since the settings seldom change.
     */
-    pub fn issue_key(&mut self, uname: &str) -> String {
-        let dist = distributions::Slice::new(&self.kchars).unwrap();
-        let rng = rand::thread_rng();
-        let new_key: String = rng.sample_iter(&dist).take(self.klen).collect();
-        
-        let new_kmeta = KeyMeta {
-            uname:  uname.to_string(),
-            expiry: SystemTime::now().add(self.klife),
+    pub fn save_config(&self) -> Result<(), FileError> {
+        let cfg_file = config_path(&self.kfile);
+        let cfgrw = KeyConfigRW {
+            length: self.klen,
+            chars:  self.kchars.iter().collect(),
+            life:   self.klife,
         };
-        
-        let mut keys = self.keys.write().unwrap();
-        let _ = keys.insert(new_key.clone(), new_kmeta);
-        
-        let mut dirty = self.kdirty.write().unwrap();
-        *dirty = true;
-        
-        return new_key;
+        let (f, tmp) = open_for_atomic_write(&cfg_file)?;
+        let mut w = csv::Writer::from_writer(f);
+        if let Err(e) = w.serialize(cfgrw) {
+            return Err(abort_atomic_write(&tmp, FileError::from_csv(&cfg_file, Op::Write, &e)));
+        }
+        let f = match w.into_inner() {
+            Ok(f) => f,
+            Err(e) => { return Err(abort_atomic_write(&tmp, FileError::from_io(&cfg_file, Op::Write, e.error()))); },
+        };
+        return commit_atomic_write(f, &tmp, &cfg_file);
     }
     
     /**
-    Sets the expiry time of the given key in the past, so it is no longer
-    valid.
+    Returns the number of bits of entropy in each generated key, given the
+    current length and character set: `length × log2(number of chars)`.
+    
+    As a rough guide, 128 bits is plenty for session keys; the default
+    configuration provides about 199.
     */
-    pub fn invalidate_key(&mut self, key: &str) -> Result<(), DataError> {
+    pub fn key_entropy_bits(&self) -> f64 {
+        (self.klen as f64) * (self.kchars.len() as f64).log2()
+    }
+    
+    /** Adds any weaknesses in this database's configuration to `findings`. */
+    pub(crate) fn audit(&self, findings: &mut Vec<Finding>) {
+        if self.klen < MIN_SAFE_KEY_LENGTH {
+            findings.push(Finding::ShortKeys { length: self.klen });
+        }
+        if self.kchars.len() < MIN_SAFE_KEY_CHARS {
+            findings.push(Finding::SmallAlphabet { size: self.kchars.len() });
+        }
+        let bits = self.key_entropy_bits();
+        if bits < MIN_SAFE_KEY_BITS {
+            findings.push(Finding::LowKeyEntropy { bits });
+        }
+        if validate::is_world_readable(&self.kfile) {
+            findings.push(Finding::WorldReadable { path: self.kfile.clone() });
+        }
+        findings.push(Finding::PlaintextKeys { path: self.kfile.clone() });
+    }
+    
+    /** Fills in the key database's part of a `StatusReport`. */
+    pub(crate) fn status(&self, report: &mut StatusReport) {
         let now = SystemTime::now();
-        let mut keys = self.keys.write().unwrap();
-        match keys.get_mut(key) {
-            None => Err(DataError::NoSuchKey),
-            Some(kmeta) => {
-                if kmeta.expiry < now {
-                    Err(DataError::KeyExpired)
-                } else {
-                    kmeta.expiry = now.sub(ONE_YEAR);
-                    let mut dirty = self.kdirty.write().unwrap();
-                    *dirty = true;
-                    Ok(())
+        let not_before = self.not_before();
+        {
+            let shards = self.keys.read_all();
+            let all = shards.iter().flat_map(|keys| keys.values());
+            for kmeta in all {
+                report.keys += 1;
+                if !kmeta.revoked && !kmeta.is_expired(now, &not_before) {
+                    report.active_sessions += 1;
                 }
-            },
+            }
         }
+        report.key_file = self.kfile.clone();
+        report.key_dirty = self.is_dirty();
+        report.key_life = self.klife;
+        report.key_entropy_bits = self.key_entropy_bits();
+        report.max_keys = self.kmax;
     }
     
     /**
-    Remove the given key from the database and mark it dirty, if present.
+    Set the compression used when saving the key file. By default, this
+    is chosen by the file's extension (see `Compression::from_path()`), so
+    a key file named `keys.csv.zst` is compressed with zstd.
     
-    Returns an error if the supplied key isn't present.
+    Compressed files are always recognized when opened, regardless of
+    their names or this setting.
     */
-    pub fn remove_key(&mut self, key: &str) -> Result<(), DataError> {
-        let mut keys = self.keys.write().unwrap();
-        match keys.remove(key) {
-            Some(_) => {
-                let mut dirty = self.kdirty.write().unwrap();
-                *dirty = true;
-                Ok(())
-            },
-            None => Err(DataError::NoSuchKey),
-        }
+    pub fn compression(&mut self, compression: Compression) {
+        self.kcompress = compression;
     }
     
     /**
-    Returns `Ok(())` if the given key is still valid and was issued to the
-    supplied user.
+    Limit the number of keys the database will hold. The default, `None`,
+    is unlimited.
     
-    Otherwise returns one of `DataError::{NoSuchKey, BadUsername, KeyExpired}`.
+    What happens when issuing a key would exceed this limit is determined
+    by `.capacity_policy()`. Expired keys are always culled before any
+    valid keys are evicted, and each eviction emits an
+    `AuthEvent::KeyEvicted`.
     */
-    pub fn check_key(&self, key: &str, uname: &str) -> Result<(), DataError> {
-        let keys = self.keys.read().unwrap();
-        match keys.get(key) {
-            None => Err(DataError::NoSuchKey),
-            Some(kmeta) => {
-                if kmeta.uname != uname {
-                    Err(DataError::BadUsername)
-                } else if kmeta.expiry < SystemTime::now() {
-                    Err(DataError::KeyExpired)
-                } else {
-                    Ok(())
-                }
-            }
-        }
-    }
+    pub fn max_keys(&mut self, max: Option<usize>) { self.kmax = max; }
     
     /**
-    Sets the life of the provided key as if it were newly issued.
+    Limit how big (in bytes) the key file may grow: issuing a key that
+    would take it over returns `DataError::CapacityExceeded` rather than
+    growing it further, whatever the `.capacity_policy()`, so a bug that
+    issues keys in a loop fills an error log instead of the disk. The
+    default, `None`, is unlimited.
     
-    Returns an error if the key is not found.
+    The size is that of the uncompressed .csv data (see
+    `.estimated_file_size()`), so a compressed file stays well under it.
+    Expired keys still count until they're culled.
     */
-    pub fn refresh_key(&mut self, key: &str) -> Result<(), DataError> {
-        let new_time = SystemTime::now().add(self.klife);
-        let mut keys = self.keys.write().unwrap();
-        match keys.get_mut(key) {
-            None => Err(DataError::NoSuchKey),
-            Some(kmeta) => {
-                kmeta.expiry = new_time;
-                Ok(())
-            },
-        }
+    pub fn max_file_size(&mut self, max: Option<u64>) {
+        let size = self.estimated_file_size();
+        self.kbytes.set(max, size);
     }
     
     /**
-    If the supplied key is found and valid, resets its life as if it were
-    newly issued, otherwise returns an error.
+    Returns how many bytes of .csv data `.save()` would write (before any
+    compression), for keeping an eye on growth. This serializes the whole
+    database, so it takes about as long as saving, without touching the
+    disk.
     */
-    pub fn check_and_refresh_key(
-        &mut self,
-        key: &str,
-        uname: &str
-    ) -> Result<(), DataError> {
-        let now = SystemTime::now();
-        let new_time = now.add(self.klife);
-        
-        let mut keys = self.keys.write().unwrap();
-        match keys.get_mut(key) {
-            None => Err(DataError::NoSuchKey),
-            Some(kmeta) => {
-                if kmeta.uname != uname {
-                    Err(DataError::BadUsername)
-                } else if kmeta.expiry < now {
-                    Err(DataError::KeyExpired)
-                } else {
-                    kmeta.expiry = new_time;
-                    Ok(())
-                }
-            },
+    pub fn estimated_file_size(&self) -> u64 {
+        let shards = self.keys.read_all();
+        match self.serialize_keys(ByteCounter::default(), &self.kfile, &shards) {
+            Ok(counter) => counter.0,
+            Err(_) => 0,
         }
     }
     
+    /** Returns how many keys there are, including revoked and expired ones not yet culled. */
+    pub fn row_count(&self) -> usize {
+        self.keys.read_all().iter().map(|keys| keys.len()).sum()
+    }
+    
     /**
-    Removes expired keys from the database if there are any.
+    Set how many independently-locked shards the keys are split between.
+    The default is 16.
     
-    Marks the database as dirty if any keys are removed.
+    Operations on a single key (issuing, checking, refreshing, and so on)
+    only lock that key's shard, so threads working on different keys
+    rarely wait for each other. Saving, culling, and enforcing
+    `.max_keys()` lock every shard, so they get slightly more expensive
+    as the number of shards grows.
     */
-    pub fn cull_keys(&mut self) {
-        let mut to_remove: Vec<String> = Vec::new();
-        {
-            let now = SystemTime::now();
-            let keys = self.keys.read().unwrap();
-            for (key, kmeta) in keys.iter() {
-                if kmeta.expiry < now {
-                    to_remove.push(String::from(key));
-                }
-            }
-        }
-        
-        if to_remove.len() > 0 {
-            let mut keys = self.keys.write().unwrap();
-            for key in to_remove.iter() {
-                let _ = keys.remove(key);
-            }
-            let mut dirty = self.kdirty.write().unwrap();
-            *dirty = true;
-        }
+    pub fn shards(&mut self, n: usize) {
+        let old = std::mem::replace(&mut self.keys, ShardedMap::new(1, HashMap::new()));
+        self.keys = ShardedMap::new(n, old.into_map());
+        *self.maint_next.get_mut() = 0;
     }
-
+    
     /**
-    Returns whether the in-memory database is "dirty", that is, whether it's
-    out of sync with the persistent data on disk.
+    Limit how many keys can be issued to any one user within any period of
+    length `per`; for example, `.issue_rate_limit(Some(10), one_minute)`
+    allows at most ten keys per user per minute. The default, `None`, is
+    no limit.
     
-    If this function returns `true`, you must call `.save()` before the
-    `PwdAuth` drops in order to ensure the data persists.
+    This caps how many sessions a stolen password can be used to mint,
+    independently of any limit on failed logins. Issuing a key beyond the
+    limit fails with `DataError::IssuanceThrottled`; rotating a key
+    (`.rotate_key()`) doesn't count. Recent issue times are only kept in
+    memory, and are forgotten by `.cull_keys()` once they no longer count.
     */
-    pub fn is_dirty(&self) -> bool {
-        let dirty = self.kdirty.read().unwrap();
-        return *dirty;
+    pub fn issue_rate_limit(&mut self, max: Option<usize>, per: Duration) {
+        self.throttle.set_limit(max.map(|m| (m, per)));
     }
-
-    /**
-    Writes data about all unexpired keys in the database to disk.
     
-    The state of the database written will be like that of the current
-    database after having called `.cull_keys()`, except it isn't marked
-    as dirty.
+    /**
+    Set what happens when the limit set by `.max_keys()` is reached. The
+    default is `CapacityPolicy::Reject`.
     */
-    pub fn save(&mut self) -> Result<(), FileError> {
-        let now = SystemTime::now();
+    pub fn capacity_policy(&mut self, policy: CapacityPolicy) {
+        self.kpolicy = policy;
+    }
+    
+    /**
+    Turn "write-through" mode on or off (it is off by default).
+    
+    In write-through mode, every method that changes the database saves
+    it to disk before returning, so there's never any need to think about
+    whether it's dirty. If that save fails, the method returns
+    `DataError::SaveFailed` (the change is still made in memory, and the
+    database is left dirty).
+    
+    The cost is that _every_ change (including every key issued) rewrites
+    and syncs the entire file, taking time proportional to the number of
+    keys plus the latency of an `fsync()`. That's fine for a handful of
+    users, but a busy login endpoint should stick to calling `.save()`
+    periodically.
+    */
+    pub fn write_through(&mut self, on: bool) { self.write_through = on; }
+    
+    /**
+    Set the order in which `.save()` (and `.save_to()`) write keys; see
+    `SaveOrder`. The default is `SaveOrder::Unsorted`.
+    */
+    pub fn save_order(&mut self, order: SaveOrder) { self.korder = order; }
+    
+    /**
+    Register a function to be called whenever an `AuthEvent` occurs,
+    replacing any previously-registered handler.
+    
+    The handler is called after the database's internal locks have been
+    released, so it's safe for it to call methods on this `KeyAuth`.
+    */
+    pub fn on_event<F>(&mut self, handler: F)
+    where F: Fn(&AuthEvent) + Send + Sync + 'static
+    {
+        self.set_event_hook(EventHook::new(handler));
+    }
+    
+    pub(crate) fn set_event_hook(&mut self, hook: EventHook) {
+        for event in self.load_report.duplicate_events() { hook.emit(event); }
+        self.events = hook;
+    }
+    
+    /**
+    Register a function to be called whenever a `SecurityEvent` occurs
+    (a user being refused a key by the issuance rate limit), replacing any
+    previously-registered handler.
+    */
+    pub fn on_security_event<F>(&mut self, handler: F)
+    where F: Fn(&SecurityEvent) + Send + Sync + 'static
+    {
+        self.security = EventHook::new(handler);
+    }
+    
+    pub(crate) fn set_security_hook(&mut self, hook: EventHook<SecurityEvent>) {
+        self.security = hook;
+    }
+    
+    /**
+    Generate a new key and store it in the database, associating it with
+    the supplied user name and setting it to expire at the appropriate
+    time in the future (see `.life()`).
+    
+    Returns `DataError::CapacityExceeded` if the database is full (see
+    `.max_keys()`) and its `CapacityPolicy` doesn't allow making room, or
+    `DataError::IssuanceThrottled` if the user has been issued too many
+    keys recently (see `.issue_rate_limit()`), or `DataError::Unsupported`
+    if `.chars()` has been set to an empty set of characters or `.length()`
+    to zero, so no key can be generated.
+    
+    Will panic if the expiration time is far enough in the future that it
+    can't be represented by the underlying system.
+    */
+    pub fn issue_key(&self, uname: &str) -> Result<String, DataError> {
+        self.issue(uname, None, None)
+    }
+    
+    /**
+    Like `.issue_key()`, but the key expires after `life` instead of the
+    database's usual key life. The key keeps that life: refreshing,
+    touching, or rotating it (or refreshing all of its user's keys) gives
+    it `life` again, and it's saved with the key.
+    */
+    pub fn issue_key_with_life(&self, uname: &str, life: Duration) -> Result<String, DataError> {
+        self.issue(uname, Some(life), None)
+    }
+    
+    /**
+    Like `.issue_key()`, but records `context` with the key: whatever the
+    application knows about where it's being issued (IP address, user
+    agent, app version, say), as JSON, so that a list of the user's
+    sessions can show "Firefox on Linux, since Tuesday". It's saved in
+    the key file, kept when the key is rotated, and can be read back with
+    `.key_info()` or `.user_keys()`, but not changed.
+    */
+    pub fn issue_key_with_context(&self, uname: &str, context: Value) -> Result<String, DataError> {
+        self.issue(uname, None, Some(context))
+    }
+    
+    /**
+    Issues a key with the given context and life (or the usual key life,
+    if that's `None`).
+    */
+    fn issue(&self, uname: &str, life: Option<Duration>, context: Option<Value>)
+    -> Result<String, DataError> {
+        let new_key = self.generate_key()?;
         
-        let keys = self.keys.write().unwrap();
-        let f = open_for_write(&self.kfile)?;
-        let mut w = csv::Writer::from_writer(f);
-        for (key, kmeta) in keys.iter() {
-            if kmeta.expiry > now {
-                let krw = kmeta.to_rw(key);
-                if let Err(e) = w.serialize(krw) {
-                    let estr = format!("{}: {}", self.kfile.to_string_lossy(), &e);
-                    return Err(FileError::Write(estr));
+        let now = SystemTime::now();
+        let mut new_kmeta = KeyMeta {
+            uname:  uname.to_string(),
+            expiry: now,
+            issued: now,
+            anchor: None,
+            revoked: false,
+            revoked_gen: 0,
+            used: AtomicU64::new(0),
+            context,
+            life,
+        };
+        new_kmeta.renew(now, self.klife);
+        
+        if !self.throttle.try_acquire(uname, now) {
+            self.security.emit(SecurityEvent::Lockout { uname: uname.to_string() });
+            return Err(DataError::IssuanceThrottled);
+        }
+        /* This measures the file (locking every shard) when it's nearly
+           full, so must come before any shards are locked here. */
+        if !self.kbytes.admit(size::row_size(&new_kmeta.to_rw(&new_key)), || self.estimated_file_size()) {
+            self.throttle.release(uname, now);
+            return Err(DataError::CapacityExceeded);
+        }
+        
+        let mut evicted: Vec<(String, KeyMeta)> = Vec::new();
+        if let Some(max) = self.kmax {
+            /* Enforcing the limit means counting (and maybe culling or
+               evicting from) every shard, so they must all be locked. */
+            let mut shards = self.keys.write_all();
+            if total_len(&shards) >= max {
+                let n_keys = total_len(&shards);
+                let not_before = self.not_before();
+                for keys in shards.iter_mut() {
+                    keys.retain(|_, kmeta| !kmeta.is_expired(now, &not_before));
+                }
+                if total_len(&shards) < n_keys { self.mark_dirty(); }
+            }
+            if total_len(&shards) >= max {
+                if max == 0 || self.kpolicy == CapacityPolicy::Reject {
+                    self.throttle.release(uname, now);
+                    return Err(DataError::CapacityExceeded);
                 }
+                let n_evict = total_len(&shards) + 1 - max;
+                evicted = evict_soonest_expiring(&mut shards, n_evict);
             }
+            
+            let i = self.keys.index(&new_key);
+            let _ = shards[i].insert(new_key.clone(), new_kmeta);
+            self.mark_dirty();
+        } else {
+            let mut keys = self.keys.shard(&new_key).write();
+            let _ = keys.insert(new_key.clone(), new_kmeta);
+            self.mark_dirty();
         }
         
-        if let Err(e) = w.flush() {
-            let estr = format!("{}: {}", self.kfile.to_string_lossy(), &e);
-            return Err(FileError::Write(estr));
+        for (_, kmeta) in evicted.into_iter() {
+            self.events.emit(AuthEvent::KeyEvicted {
+                uname: kmeta.uname,
+                expiry: kmeta.expiry,
+            });
+        }
+        
+        self.save_if_write_through()?;
+        return Ok(new_key);
+    }
+    
+    /**
+    Replaces a valid key with a freshly-issued one for the same user, for
+    example to guard against session fixation after a user logs in or
+    their privileges change.
+    
+    Checking the old key, issuing the new one, and removing the old one
+    all happen while holding the write locks on both keys' shards, so no
+    other thread will ever see both keys (or neither) as valid.
+    
+    Returns the same errors as `.check_key()` if the old key isn't valid
+    for the given user, in which case nothing is changed.
+    */
+    pub fn rotate_key(&self, old_key: &str, uname: &str) -> Result<String, DataError> {
+        let new_key = self.generate_key()?;
+        let now = SystemTime::now();
+        
+        {
+            let (mut keys, mut new_shard) = self.keys.write_pair(old_key, &new_key);
+            match keys.get(old_key) {
+                None => { return Err(DataError::NoSuchKey); },
+                Some(kmeta) => {
+                    if kmeta.uname != uname {
+                        return Err(DataError::BadUsername);
+                    } else if kmeta.revoked {
+                        return Err(DataError::KeyRevoked);
+                    } else if let Some(expired_for) = kmeta.expired_for(now, &self.not_before()) {
+                        return Err(DataError::KeyExpired { expired_for });
+                    }
+                },
+            }
+            
+            let mut kmeta = keys.remove(old_key).unwrap();
+            kmeta.renew(now, self.klife);
+            kmeta.issued = now;
+            let new_keys = match new_shard.as_mut() {
+                Some(new_keys) => new_keys,
+                None => &mut keys,
+            };
+            let _ = new_keys.insert(new_key.clone(), kmeta);
+            self.mark_dirty();
+        }
+        
+        self.save_if_write_through()?;
+        return Ok(new_key);
+    }
+    
+    /**
+    Revokes the given key (when its user logs out, say), so it is no longer
+    valid. Unlike a removed key, a revoked key is kept (and saved) until it
+    would have expired, and checking it returns `DataError::KeyRevoked`
+    rather than `DataError::NoSuchKey`.
+    
+    Returns `DataError::KeyRevoked` or `DataError::KeyExpired` if the key
+    isn't currently valid.
+    */
+    pub fn invalidate_key(&self, key: &str) -> Result<(), DataError> {
+        let now = SystemTime::now();
+        {
+            let mut keys = self.keys.shard(key).write();
+            match keys.get_mut(key) {
+                None => { return Err(DataError::NoSuchKey); },
+                Some(kmeta) => {
+                    if kmeta.revoked {
+                        return Err(DataError::KeyRevoked);
+                    } else if let Some(expired_for) = kmeta.expired_for(now, &self.not_before()) {
+                        return Err(DataError::KeyExpired { expired_for });
+                    }
+                    kmeta.revoked = true;
+                    kmeta.revoked_gen = self.mark_dirty();
+                },
+            }
+        }
+        
+        return self.save_if_write_through();
+    }
+    
+    /**
+    Remove the given key from the database and mark it dirty, if present.
+    
+    Returns an error if the supplied key isn't present.
+    */
+    pub fn remove_key(&self, key: &str) -> Result<(), DataError> {
+        {
+            let mut keys = self.keys.shard(key).write();
+            if keys.remove(key).is_none() {
+                return Err(DataError::NoSuchKey);
+            }
+            self.mark_dirty();
+        }
+        
+        return self.save_if_write_through();
+    }
+    
+    /**
+    Removes every key issued to the given user (for example, when their
+    password changes), returning how many there were. Marks the database
+    as dirty if there were any.
+    */
+    pub fn remove_user_keys(&self, uname: &str) -> Result<usize, DataError> {
+        let removed = self.keys.retain(|_, kmeta| kmeta.uname != uname);
+        if removed > 0 { self.mark_dirty(); }
+        
+        self.save_if_write_through()?;
+        return Ok(removed);
+    }
+    
+    /**
+    Removes every key issued to the given user, in this database and in
+    its namespaces, and forgets everything else kept about them: their
+    not-valid-before time (see `.invalidate_user_before()`), which is
+    rewritten right away, and their recent issues. Returns how many keys
+    there were. Marks the database as dirty, but doesn't save it.
+    */
+    pub(crate) fn purge_user(&self, uname: &str) -> Result<usize, FileError> {
+        let mut removed = self.keys.retain(|_, kmeta| kmeta.uname != uname);
+        if removed > 0 { self.mark_dirty(); }
+        self.throttle.forget(uname);
+        {
+            let mut not_before = self.not_before.write();
+            if not_before.users.contains_key(uname) {
+                let mut new = not_before.clone();
+                let _ = Arc::make_mut(&mut new.users).remove(uname);
+                write_not_before(&self.kfile, &new)?;
+                *not_before = new;
+            }
+        }
+        for space in self.spaces.values() { removed += space.purge_user(uname)?; }
+        return Ok(removed);
+    }
+    
+    /**
+    Sets the life of every valid key issued to the given user as if it
+    were newly issued, returning how many there were. Marks the database
+    as dirty if there were any.
+    */
+    pub fn refresh_user_keys(&self, uname: &str) -> Result<usize, DataError> {
+        let now = SystemTime::now();
+        let not_before = self.not_before();
+        let mut refreshed: usize = 0;
+        for mut keys in self.keys.write_all().into_iter() {
+            for kmeta in keys.values_mut() {
+                if kmeta.uname == uname && !kmeta.revoked && !kmeta.is_expired(now, &not_before) {
+                    kmeta.renew(now, self.klife);
+                    refreshed += 1;
+                }
+            }
+        }
+        if refreshed > 0 { self.mark_dirty(); }
+        
+        self.save_if_write_through()?;
+        return Ok(refreshed);
+    }
+    
+    /**
+    Returns an `AuthOk` naming the user if the given key is still valid
+    and was issued to the supplied user.
+    
+    Otherwise returns one of `DataError::{NoSuchKey, BadUsername, KeyRevoked,
+    KeyExpired}`.
+    */
+    pub fn check_key(&self, key: &str, uname: &str) -> Result<AuthOk, DataError> {
+        let keys = self.keys.shard(key).read();
+        self.check_key_now(&keys, key, uname)
+    }
+    
+    /**
+    Like `.check_key()`, but checks whether the key is (or was, or will be)
+    valid at the time `at` instead of now, for example when replaying logs.
+    
+    This only considers the key's _current_ expiry time; it can't account
+    for refreshes that haven't happened yet.
+    */
+    pub fn check_key_at(
+        &self,
+        key: &str,
+        uname: &str,
+        at: SystemTime
+    ) -> Result<AuthOk, DataError> {
+        let keys = self.keys.shard(key).read();
+        self.check_key_against(&keys, key, uname, at)
+    }
+    
+    /**
+    Like `.check_key()`, but gives up and returns `DataError::LockTimeout`
+    if the database can't be read within `timeout` (because another thread
+    is writing to it), rather than blocking indefinitely.
+    */
+    pub fn try_check_key(
+        &self,
+        key: &str,
+        uname: &str,
+        timeout: Duration
+    ) -> Result<AuthOk, DataError> {
+        match self.keys.shard(key).try_read_for(timeout) {
+            None => Err(DataError::LockTimeout),
+            Some(keys) => self.check_key_now(&keys, key, uname),
+        }
+    }
+    
+    /**
+    Checks that the given key is currently valid and returns the name of
+    the user it was issued to, for flows where only the key is sent (a
+    bearer token from a script, say) and the user name isn't known.
+    
+    This is as quick as `.check_key()`, since keys are looked up by the
+    key itself either way; but the caller must take the returned name on
+    trust, so a key that leaks lets its holder act as that user without
+    knowing their name.
+    */
+    pub fn authenticate(&self, key: &str) -> Result<String, DataError> {
+        let keys = self.keys.shard(key).read();
+        let uname = match keys.get(key) {
+            None => { return Err(DataError::NoSuchKey); },
+            Some(kmeta) => kmeta.uname.as_str(),
+        };
+        self.check_key_now(&keys, key, uname)?;
+        return Ok(uname.to_string());
+    }
+    
+    /** Checks the given key as of now, noting its use if it's valid. */
+    fn check_key_now(
+        &self,
+        keys: &HashMap<String, KeyMeta>,
+        key: &str,
+        uname: &str
+    ) -> Result<AuthOk, DataError> {
+        let now = SystemTime::now();
+        let ok = self.check_key_against(keys, key, uname, now)?;
+        if let Some(kmeta) = keys.get(key) { kmeta.note_use(now); }
+        return Ok(ok);
+    }
+    
+    /** Checks the given key against the (locked) map of keys `keys`. */
+    fn check_key_against(
+        &self,
+        keys: &HashMap<String, KeyMeta>,
+        key: &str,
+        uname: &str,
+        at: SystemTime
+    ) -> Result<AuthOk, DataError> {
+        match keys.get(key) {
+            None => Err(DataError::NoSuchKey),
+            Some(kmeta) => {
+                if kmeta.uname != uname {
+                    Err(DataError::BadUsername)
+                } else if kmeta.revoked {
+                    Err(DataError::KeyRevoked)
+                } else if let Some(expired_for) = kmeta.expired_for(at, &self.not_before()) {
+                    Err(DataError::KeyExpired { expired_for })
+                } else {
+                    Ok(AuthOk::new(kmeta.uname.as_str()))
+                }
+            }
+        }
+    }
+    
+    /**
+    Returns how much longer the given key will remain valid (if it isn't
+    refreshed), for example to set a cookie's `Max-Age`.
+    
+    Returns `DataError::NoSuchKey`, `DataError::KeyRevoked`, or
+    `DataError::KeyExpired` if the key isn't currently valid.
+    */
+    pub fn time_remaining(&self, key: &str) -> Result<Duration, DataError> {
+        let now = SystemTime::now();
+        let keys = self.keys.shard(key).read();
+        match keys.get(key) {
+            None => Err(DataError::NoSuchKey),
+            Some(kmeta) => {
+                if kmeta.revoked {
+                    Err(DataError::KeyRevoked)
+                } else if let Some(expired_for) = kmeta.expired_for(now, &self.not_before()) {
+                    Err(DataError::KeyExpired { expired_for })
+                } else {
+                    Ok(kmeta.remaining(now))
+                }
+            },
+        }
+    }
+    
+    /**
+    Returns what's known about the given key (see `KeyInfo`), whether or
+    not it's still valid, or `DataError::NoSuchKey` if it isn't in the
+    database.
+    */
+    pub fn key_info(&self, key: &str) -> Result<KeyInfo, DataError> {
+        let keys = self.keys.shard(key).read();
+        match keys.get(key) {
+            None => Err(DataError::NoSuchKey),
+            Some(kmeta) => Ok(kmeta.info(key)),
+        }
+    }
+    
+    /**
+    Returns what's known about each of the given user's currently valid
+    keys (their "active sessions"), oldest first.
+    */
+    pub fn user_keys(&self, uname: &str) -> Vec<KeyInfo> {
+        let now = SystemTime::now();
+        let not_before = self.not_before();
+        let shards = self.keys.read_all();
+        let mut infos: Vec<KeyInfo> = shards.iter()
+            .flat_map(|keys| keys.iter())
+            .filter(|(_, kmeta)| kmeta.uname == uname && !kmeta.revoked)
+            .filter(|(_, kmeta)| !kmeta.is_expired(now, &not_before))
+            .map(|(key, kmeta)| kmeta.info(key))
+            .collect();
+        infos.sort_by_key(|info| info.issued);
+        return infos;
+    }
+    
+    /**
+    Sets the life of the provided key as if it were newly issued.
+    
+    Marks the database as dirty.
+    
+    Returns an error if the key is not found, or has been revoked.
+    */
+    pub fn refresh_key(&self, key: &str) -> Result<(), DataError> {
+        let now = SystemTime::now();
+        {
+            let mut keys = self.keys.shard(key).write();
+            match keys.get_mut(key) {
+                None => { return Err(DataError::NoSuchKey); },
+                Some(kmeta) if kmeta.revoked => { return Err(DataError::KeyRevoked); },
+                Some(kmeta) => { kmeta.renew(now, self.klife); },
+            }
+            self.mark_dirty();
+        }
+        
+        return self.save_if_write_through();
+    }
+    
+    /**
+    If the supplied key is found and valid, resets its life as if it were
+    newly issued, otherwise returns an error.
+    
+    Marks the database as dirty.
+    */
+    pub fn check_and_refresh_key(
+        &self,
+        key: &str,
+        uname: &str
+    ) -> Result<(), DataError> {
+        let _ = self.check_and_refresh_if(key, uname, |_, _| true)?;
+        return Ok(());
+    }
+    
+    /**
+    Set the fraction of a key's life that must have passed since it was
+    issued or last refreshed before `.touch()` will refresh it. The default
+    is `0.5`; a value of `0.0` makes `.touch()` behave exactly like
+    `.check_and_refresh_key()`.
+    */
+    pub fn touch_threshold(&mut self, fraction: f64) {
+        self.touch_frac = fraction.clamp(0.0, 1.0);
+    }
+    
+    /**
+    Set how long a key may go unused (that is, without being issued,
+    refreshed, or found valid by `.check_key()` or `.touch()`) before it's
+    culled, even if it hasn't expired. With this set, `.cull_keys()` and
+    `.maintain()` remove idle keys along with expired ones. The default is
+    `None`: keys are only removed once they expire.
+    
+    Keys' last-used times are saved with them, but aren't on their own
+    enough to mark the database dirty, so after a restart a key may seem
+    idle for a little longer than it really was.
+    */
+    pub fn idle_timeout(&mut self, max_idle: Option<Duration>) { self.kidle = max_idle; }
+    
+    /**
+    Like `.check_and_refresh_key()`, but only refreshes the key if more
+    than the fraction of its life set by `.touch_threshold()` has elapsed.
+    
+    This is meant for "heartbeat" requests and other high-frequency
+    polling: active sessions stay alive, but the database isn't dirtied
+    (and, in write-through mode, rewritten) on every request.
+    
+    Returns `Ok(true)` if the key was refreshed, and `Ok(false)` if it is
+    valid but didn't need refreshing yet.
+    */
+    pub fn touch(&self, key: &str, uname: &str) -> Result<bool, DataError> {
+        let frac = self.touch_frac;
+        self.check_and_refresh_if(key, uname, |remaining, life| {
+            let elapsed = life.checked_sub(remaining).unwrap_or_default();
+            elapsed.as_secs_f64() >= life.as_secs_f64() * frac
+        })
+    }
+    
+    /**
+    Checks that the key is valid for the given user and, if so, refreshes
+    it if `should_refresh` (passed the time the key has remaining and its
+    full life) returns `true`. Returns whether the key was refreshed.
+    */
+    fn check_and_refresh_if<F>(
+        &self,
+        key: &str,
+        uname: &str,
+        should_refresh: F
+    ) -> Result<bool, DataError>
+    where F: Fn(Duration, Duration) -> bool
+    {
+        let now = SystemTime::now();
+        
+        {
+            let mut keys = self.keys.shard(key).write();
+            match keys.get_mut(key) {
+                None => { return Err(DataError::NoSuchKey); },
+                Some(kmeta) => {
+                    if kmeta.uname != uname {
+                        return Err(DataError::BadUsername);
+                    } else if kmeta.revoked {
+                        return Err(DataError::KeyRevoked);
+                    } else if let Some(expired_for) = kmeta.expired_for(now, &self.not_before()) {
+                        return Err(DataError::KeyExpired { expired_for });
+                    }
+                    if !should_refresh(kmeta.remaining(now), kmeta.life.unwrap_or(self.klife)) {
+                        kmeta.note_use(now);
+                        return Ok(false);
+                    }
+                    kmeta.renew(now, self.klife);
+                },
+            }
+            self.mark_dirty();
         }
         
-        let mut dirty = self.kdirty.write().unwrap();
-        *dirty = false;
+        self.save_if_write_through()?;
+        return Ok(true);
+    }
+    
+    /**
+    Immediately invalidates every key issued up until now, wherever it may
+    be stored.
+    
+    This works by recording a "not-valid-before" time, which is written to
+    disk right away (in a file alongside the key file, with `.nvb` appended
+    to its name) rather than waiting for `.save()`. Keys issued before this
+    time will fail `.check_key()` with `DataError::KeyExpired`, and will
+    be discarded when any copy of the key file (even an old or restored
+    one) is opened with the same path.
+    
+    Marks the database as dirty.
+    */
+    pub fn invalidate_all(&self) -> Result<(), FileError> {
+        let now = SystemTime::now();
+        {
+            let mut not_before = self.not_before.write();
+            let mut new = not_before.clone();
+            new.all = Some(now);
+            write_not_before(&self.kfile, &new)?;
+            *not_before = new;
+        }
+        self.mark_dirty();
         
+        if self.write_through { self.save()?; }
         return Ok(());
     }
-}
\ No newline at end of file
+    
+    /**
+    Like `.invalidate_all()`, but only invalidates the keys issued to the
+    given user before the time `before` (for "log out my other devices",
+    say, just before issuing them a new key). Like the time set by
+    `.invalidate_all()`, this is written to disk right away, so it also
+    applies to old copies of the key file, and to other processes sharing
+    the key file when they next open it.
+    
+    A user's time is never moved earlier; if the user already has a later
+    one, it's kept.
+    
+    Marks the database as dirty.
+    */
+    pub fn invalidate_user_before(&self, uname: &str, before: SystemTime)
+    -> Result<(), FileError> {
+        {
+            let mut not_before = self.not_before.write();
+            if matches!(not_before.users.get(uname), Some(t) if *t >= before) {
+                return Ok(());
+            }
+            let mut new = not_before.clone();
+            let _ = Arc::make_mut(&mut new.users).insert(uname.to_string(), before);
+            write_not_before(&self.kfile, &new)?;
+            *not_before = new;
+        }
+        self.mark_dirty();
+        
+        if self.write_through { self.save()?; }
+        return Ok(());
+    }
+    
+    /**
+    Removes expired keys from the database if there are any.
+    
+    Marks the database as dirty if any keys are removed. In write-through
+    mode, this saves the database, and so can fail.
+    */
+    pub fn cull_keys(&self) -> Result<(), FileError> {
+        self.cull_keys_at(SystemTime::now())
+    }
+    
+    /**
+    Like `.cull_keys()`, but removes keys that are expired as of the time
+    `at` instead of now. (Recent issue times kept for `.issue_rate_limit()`
+    are still only forgotten once they no longer count now.)
+    */
+    pub fn cull_keys_at(&self, at: SystemTime) -> Result<(), FileError> {
+        let not_before = self.not_before();
+        let removed = self.keys.retain(|_, kmeta| !self.is_stale(kmeta, at, &not_before));
+        if removed > 0 { self.mark_dirty(); }
+        /* Pruning as of `at` could forget issues that still count. */
+        self.throttle.prune(SystemTime::now());
+        for space in self.spaces.values() { space.cull_keys_at(at)?; }
+        
+        if self.write_through && self.is_dirty() { self.save()?; }
+        return Ok(());
+    }
+    
+    /**
+    Removes the keys that haven't been used (see `.idle_timeout()`) for
+    longer than `max_idle`, whether or not they've expired, returning how
+    many there were.
+    
+    Marks the database as dirty if any keys are removed. In write-through
+    mode, this saves the database, and so can fail.
+    */
+    pub fn cull_idle(&self, max_idle: Duration) -> Result<usize, FileError> {
+        let now = SystemTime::now();
+        let removed = self.keys.retain(|_, kmeta| !kmeta.is_idle(now, max_idle));
+        if removed > 0 { self.mark_dirty(); }
+        
+        if self.write_through && self.is_dirty() { self.save()?; }
+        return Ok(removed);
+    }
+    
+    /**
+    Whether the key should be culled at time `at`: because it's expired,
+    or because it's been idle for longer than the `.idle_timeout()`.
+    */
+    fn is_stale(&self, kmeta: &KeyMeta, at: SystemTime, not_before: &NotBefore) -> bool {
+        kmeta.is_expired(at, not_before) || self.kidle.is_some_and(|max| kmeta.is_idle(at, max))
+    }
+    
+    /**
+    Does as much of the work of `.cull_keys()` and `.save()` as fits in
+    `budget`, culling one shard (see `.shards()`) at a time and then
+    saving if the database is dirty and there's time left, so request
+    handlers can take turns at maintenance without any one of them
+    stalling for long. Each call picks up where the last one left off;
+    call it until the report `.is_done()`.
+    
+    The budget is checked before each step, so a step that's begun
+    (especially the save) may run past it. If another thread is already
+    maintaining the database, this returns at once without doing anything.
+    Key namespaces aren't maintained; cull and save them separately.
+    */
+    pub fn maintain(&self, budget: Duration) -> Result<MaintenanceReport, FileError> {
+        let start = Instant::now();
+        let n_shards = self.keys.n_shards();
+        let mut report = MaintenanceReport::default();
+        let mut next = match self.maint_next.try_lock() {
+            Some(next) => next,
+            None => {
+                report.shards_remaining = n_shards;
+                report.save_pending = self.kdirty.load(Ordering::Acquire);
+                return Ok(report);
+            },
+        };
+        
+        let now = SystemTime::now();
+        let not_before = self.not_before();
+        while *next < n_shards && start.elapsed() < budget {
+            let keep = |_: &String, kmeta: &mut KeyMeta| !self.is_stale(kmeta, now, &not_before);
+            report.culled += self.keys.retain_in(*next, keep);
+            *next += 1;
+        }
+        if report.culled > 0 { self.mark_dirty(); }
+        report.shards_remaining = n_shards - *next;
+        if *next == n_shards {
+            self.throttle.prune(now);
+            *next = 0;
+        }
+        
+        if self.kdirty.load(Ordering::Acquire) && start.elapsed() < budget {
+            let shards = self.keys.write_all();
+            self.write_keys(&self.kfile, &shards)?;
+            self.kdirty.store(false, Ordering::Release);
+            report.saved = true;
+        }
+        report.save_pending = self.kdirty.load(Ordering::Acquire);
+        return Ok(report);
+    }
+
+    /** Returns every key in the database along with its user name. */
+    pub(crate) fn key_users(&self) -> Vec<(String, String)> {
+        let shards = self.keys.read_all();
+        return shards.iter()
+            .flat_map(|keys| keys.iter())
+            .map(|(key, kmeta)| (key.clone(), kmeta.uname.clone()))
+            .collect();
+    }
+    
+    /**
+    Removes all the given keys that are present, marking the database dirty
+    if any were. Like `.cull_keys()`, this saves in write-through mode.
+    */
+    pub(crate) fn remove_keys(&self, to_remove: &[String]) -> Result<(), FileError> {
+        for key in to_remove.iter() {
+            let mut keys = self.keys.shard(key).write();
+            if keys.remove(key).is_some() { self.mark_dirty(); }
+        }
+        
+        if self.write_through && self.is_dirty() { self.save()?; }
+        return Ok(());
+    }
+
+    /**
+    Adds a separate, named store of keys (a "namespace"), kept in its own
+    file with its own key life, so that different kinds of sessions ("web",
+    "api", "admin", say) don't share one table and one policy. Keys are
+    issued and checked in a namespace with `.issue_key_in()` and
+    `.check_key_in()`, and the namespace itself (a `KeyAuth`) is available
+    from `.namespace()` for anything else.
+    
+    The file is opened, or created if it doesn't exist. The namespace gets
+    this database's key length, characters, and write-through setting (as
+    they are now); it's saved along with this database by `.save()`, and
+    culled by `.cull_keys()`. A namespace with the same name is replaced.
+    */
+    pub fn add_namespace(
+        &mut self,
+        name: impl Into<String>,
+        key_file: impl AsRef<Path>,
+        life: Duration
+    ) -> Result<(), FileError> {
+        let mut space = KeyAuth::open_or_new(key_file)?;
+        space.length(self.klen);
+        space.kchars = self.kchars.clone();
+        space.life(life);
+        space.write_through(self.write_through);
+        let _ = self.spaces.insert(name.into(), space);
+        return Ok(());
+    }
+    
+    /**
+    Returns the namespace with the given name (see `.add_namespace()`), or
+    `DataError::NoSuchNamespace` if there isn't one.
+    */
+    pub fn namespace(&self, name: &str) -> Result<&KeyAuth, DataError> {
+        self.spaces.get(name).ok_or(DataError::NoSuchNamespace)
+    }
+    
+    /** Issues a key to the given user in the named namespace. */
+    pub fn issue_key_in(&self, namespace: &str, uname: &str) -> Result<String, DataError> {
+        self.namespace(namespace)?.issue_key(uname)
+    }
+    
+    /**
+    Checks a key in the named namespace, as `.check_key()` does. Keys from
+    other namespaces (or this database's own) aren't valid here.
+    */
+    pub fn check_key_in(&self, namespace: &str, key: &str, uname: &str)
+    -> Result<AuthOk, DataError> {
+        self.namespace(namespace)?.check_key(key, uname)
+    }
+    
+    /** Removes a key from the named namespace. */
+    pub fn remove_key_in(&self, namespace: &str, key: &str) -> Result<(), DataError> {
+        self.namespace(namespace)?.remove_key(key)
+    }
+    
+    /** Returns the path of the file this database saves to. */
+    pub fn path(&self) -> &Path { &self.kfile }
+
+    /**
+    Returns whether the in-memory database is "dirty", that is, whether it's
+    out of sync with the persistent data on disk.
+    
+    If this function returns `true`, you must call `.save()` before the
+    `PwdAuth` drops in order to ensure the data persists.
+    */
+    pub fn is_dirty(&self) -> bool {
+        self.kdirty.load(Ordering::Acquire) || self.spaces.values().any(KeyAuth::is_dirty)
+    }
+    
+    /**
+    Returns a number that goes up every time the keys change (including
+    refreshes and culls), so a cache of anything derived from them can
+    tell whether it's stale just by comparing it with the number it was
+    built at. It counts from zero when the database is opened, and isn't
+    affected by saving.
+    */
+    pub fn generation(&self) -> u64 { self.kgen.load(Ordering::Acquire) }
+    
+    /**
+    Returns the hashes of the keys revoked (by `.invalidate_key()`) since
+    the given `.generation()`, so a verifier that caches keys (or trusts
+    tokens derived from them) can pull just the new revocations instead of
+    the whole key table. Pass 0 to get every revocation.
+    
+    Read `.generation()` _before_ calling this, and pass that next time;
+    a revocation may then be returned twice, but none will be missed.
+    Since generations count from zero when the database is opened, every
+    revocation is returned if `since` is ahead of the current generation.
+    
+    Revoked keys are only kept until they would have expired. Keys that
+    are removed outright, or invalidated by `.invalidate_all()`, aren't
+    included.
+    */
+    pub fn revocations_since(&self, since: u64) -> Vec<KeyHash> {
+        let since = if since > self.generation() { 0 } else { since };
+        let shards = self.keys.read_all();
+        return shards.iter()
+            .flat_map(|keys| keys.iter())
+            .filter(|(_, kmeta)| kmeta.revoked && (since == 0 || kmeta.revoked_gen > since))
+            .map(|(key, _)| KeyHash::of(key))
+            .collect();
+    }
+
+    /**
+    Writes data about all unexpired keys in the database to disk.
+    
+    The state of the database written will be like that of the current
+    database after having called `.cull_keys()`, except it isn't marked
+    as dirty.
+    
+    The data is written to a temporary file which then replaces the
+    original, so a failed save never leaves a partially-written file.
+    
+    Any namespaces (see `.add_namespace()`) that are dirty are saved to
+    their own files too.
+    */
+    pub fn save(&self) -> Result<(), FileError> {
+        {
+            /* Holding every shard's write lock keeps other threads from
+               changing (and dirtying) the database until it's marked clean. */
+            let shards = self.keys.write_all();
+            self.write_keys(&self.kfile, &shards)?;
+            self.kdirty.store(false, Ordering::Release);
+        }
+        for space in self.spaces.values() {
+            if space.is_dirty() { space.save()?; }
+        }
+        
+        return Ok(());
+    }
+    
+    /**
+    Writes data about all unexpired keys to the given path _instead_ of
+    the file the database was opened from. This does not affect whether
+    the database is dirty, or where `.save()` will write it.
+    */
+    pub fn save_to(&self, path: impl AsRef<Path>) -> Result<(), FileError> {
+        let shards = self.keys.read_all();
+        self.write_keys(path.as_ref(), &shards)
+    }
+    
+    /**
+    Writes data about all unexpired keys to `w` as uncompressed .csv data,
+    returning it when done. Like `.save_to()`, this does not affect
+    whether the database is dirty.
+    */
+    pub fn save_to_writer<W: Write>(&self, w: W) -> Result<W, FileError> {
+        let shards = self.keys.read_all();
+        self.serialize_keys(w, &self.kfile, &shards)
+    }
+    
+    /**
+    Returns the contents `.save()` would write, as uncompressed .csv data.
+    */
+    #[cfg(feature = "bundle")]
+    pub(crate) fn to_csv_bytes(&self) -> Result<Vec<u8>, FileError> {
+        let shards = self.keys.read_all();
+        self.serialize_keys(Vec::new(), &self.kfile, &shards)
+    }
+    
+    /**
+    Flags the database as out of sync with the file on disk, and counts a
+    new generation (see `.generation()`). The flag is read first so that
+    threads which find it already set don't contend for its cache line.
+    */
+    fn mark_dirty(&self) -> u64 {
+        let generation = self.kgen.fetch_add(1, Ordering::AcqRel) + 1;
+        if !self.kdirty.load(Ordering::Relaxed) {
+            self.kdirty.store(true, Ordering::Release);
+        }
+        return generation;
+    }
+    
+    /** Returns the times before which keys are invalid. */
+    fn not_before(&self) -> NotBefore { self.not_before.read().clone() }
+    
+    /** In write-through mode, saves the database if it's dirty. */
+    fn save_if_write_through(&self) -> Result<(), DataError> {
+        if self.write_through && self.is_dirty() {
+            self.save().map_err(DataError::SaveFailed)?;
+        }
+        return Ok(());
+    }
+    
+    /** Writes all unexpired keys in the shards `keys` to the file at `path`. */
+    fn write_keys<G>(&self, path: &Path, keys: &[G]) -> Result<(), FileError>
+    where G: Deref<Target = HashMap<String, KeyMeta>>
+    {
+        let (f, tmp) = open_for_atomic_write(path)?;
+        let enc = match Encoder::new(f, self.kcompress, path) {
+            Ok(enc) => enc,
+            Err(e) => { return Err(abort_atomic_write(&tmp, e)); },
+        };
+        let f = match self.serialize_keys(Faulty::writer(enc, path), path, keys) {
+            Ok(enc) => match enc.into_inner().finish() {
+                Ok(f) => f,
+                Err(e) => { return Err(abort_atomic_write(&tmp, FileError::from_io(path, Op::Write, &e))); },
+            },
+            Err(e) => { return Err(abort_atomic_write(&tmp, e)); },
+        };
+        
+        return commit_atomic_write(f, &tmp, path);
+    }
+    
+    /**
+    Writes all unexpired keys in the shards `keys` as .csv to `w`, in the
+    configured order, returning it when done. `path` is only used to
+    describe errors.
+    */
+    fn serialize_keys<W, G>(&self, w: W, path: &Path, keys: &[G]) -> Result<W, FileError>
+    where W: Write,
+          G: Deref<Target = HashMap<String, KeyMeta>>
+    {
+        let now = SystemTime::now();
+        let not_before = self.not_before();
+        
+        let mut rows: Vec<(&String, &KeyMeta)> = keys.iter()
+            .flat_map(|shard| shard.iter())
+            .filter(|(_, kmeta)| !kmeta.is_expired(now, &not_before))
+            .collect();
+        match self.korder {
+            SaveOrder::Unsorted => {},
+            SaveOrder::ById => rows.sort_unstable_by(|a, b| a.0.cmp(b.0)),
+            SaveOrder::ByIssueTime => rows.sort_unstable_by(|a, b| {
+                a.1.issued.cmp(&b.1.issued).then_with(|| a.0.cmp(b.0))
+            }),
+        }
+        
+        let mut w = csv::Writer::from_writer(w);
+        for (key, kmeta) in rows.into_iter() {
+            let krw = kmeta.to_rw(key);
+            if let Err(e) = w.serialize(krw) {
+                return Err(FileError::from_csv(path, Op::Write, &e));
+            }
+        }
+        
+        match w.into_inner() {
+            Ok(w) => Ok(w),
+            Err(e) => Err(FileError::from_io(path, Op::Write, e.error())),
+        }
+    }
+    
+    /**
+    Generates a random key string according to the current settings.
+    
+    Each character is drawn independently from `self.kchars` by
+    `distributions::Slice`, which picks an index with `Uniform`'s
+    rejection sampling; this is exactly uniform for any number of
+    characters, not just powers of two.
+    
+    Returns `DataError::Unsupported` if the settings can't make a key: the
+    length is 0, or there are no characters to draw from.
+    */
+    fn generate_key(&self) -> Result<String, DataError> {
+        if self.klen == 0 { return Err(DataError::Unsupported); }
+        let dist = match distributions::Slice::new(&self.kchars) {
+            Ok(dist) => dist,
+            Err(_) => { return Err(DataError::Unsupported); },
+        };
+        let rng = rand::thread_rng();
+        return Ok(rng.sample_iter(&dist).take(self.klen).collect());
+    }
+}
+
+/**
+Removes the `n` keys with the earliest expiry times from the (locked)
+`shards`, returning the removed entries.
+*/
+fn evict_soonest_expiring(
+    shards: &mut [WriteGuard<'_, KeyMeta>],
+    n: usize
+) -> Vec<(String, KeyMeta)> {
+    let mut by_expiry: Vec<(SystemTime, usize, String)> = shards.iter()
+        .enumerate()
+        .flat_map(|(i, keys)| keys.iter().map(move |(key, kmeta)| (kmeta.expiry, i, key.clone())))
+        .collect();
+    by_expiry.sort();
+    
+    by_expiry.into_iter().take(n)
+        .filter_map(|(_, i, key)| shards[i].remove_entry(&key))
+        .collect()
+}
+
+/** Returns the total number of keys in all the (locked) shards. */
+fn total_len(shards: &[WriteGuard<'_, KeyMeta>]) -> usize {
+    shards.iter().map(|keys| keys.len()).sum()
+}
@@ -0,0 +1,314 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fs::File;
+use std::io::{self, BufReader, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+
+use parking_lot::Mutex;
+
+use crate::{AuthOk, FileError, DataError, InputLimits, Op, open_for_read};
+use crate::hasher::{HashEncoding, HashFn, PasswordHasher};
+use crate::pwd::UserRW;
+use crate::secret::{Secret, SecretProvider};
+
+const DEFAULT_CACHE_SIZE: usize = 1024;
+
+/** The parts of a user's row kept in the cache. */
+#[derive(Debug, Clone)]
+struct CachedUser {
+    hash: String,
+    admin: bool,
+    uid: Option<u64>,
+}
+
+/**
+A least-recently-used cache of user rows. Each entry is stamped with the
+"time" (a counter) it was last used, and `order` maps those stamps back
+to user names so the oldest can be found without a scan.
+*/
+#[derive(Debug)]
+struct Lru {
+    capacity: usize,
+    clock: u64,
+    entries: HashMap<String, (u64, CachedUser)>,
+    order: BTreeMap<u64, String>,
+}
+
+impl Lru {
+    fn new(capacity: usize) -> Self {
+        Lru { capacity, clock: 0, entries: HashMap::new(), order: BTreeMap::new() }
+    }
+    
+    fn get(&mut self, uname: &str) -> Option<CachedUser> {
+        self.clock += 1;
+        let (stamp, user) = self.entries.get_mut(uname)?;
+        let _ = self.order.remove(stamp);
+        *stamp = self.clock;
+        let _ = self.order.insert(self.clock, uname.to_string());
+        return Some(user.clone());
+    }
+    
+    fn insert(&mut self, uname: &str, user: CachedUser) {
+        if self.capacity == 0 { return; }
+        while self.entries.len() >= self.capacity {
+            let oldest = match self.order.keys().next() {
+                Some(stamp) => *stamp,
+                None => { break; },
+            };
+            if let Some(evicted) = self.order.remove(&oldest) {
+                let _ = self.entries.remove(&evicted);
+            }
+        }
+        self.clock += 1;
+        let _ = self.order.insert(self.clock, uname.to_string());
+        let _ = self.entries.insert(uname.to_string(), (self.clock, user));
+    }
+}
+
+/** A read-only view of a (very large) password file, for checking
+    passwords without loading the whole file into memory.
+    
+    Opening a `LazyPwdAuth` reads only the user names, building an index
+    of where each user's row starts in the file; each user's row is read
+    (and parsed) the first time it's needed, and kept in a cache of
+    limited size (see `.cache_size()`), so startup is quick and memory use
+    stays bounded no matter how many users there are.
+    
+    The file must be one written by `PwdAuth`, with the same pepper and
+    `PasswordHasher` set here as there. Changes must be made through a
+    `PwdAuth` (and saved); since the index records positions in the file,
+    a `LazyPwdAuth` must be reopened after the file is rewritten.
+*/
+#[derive(Debug)]
+pub struct LazyPwdAuth {
+    ufile:   PathBuf,
+    headers: csv::ByteRecord,
+    index:   HashMap<String, u64>,
+    file:    Mutex<File>,
+    cache:   Mutex<Lru>,
+    pepper:  Secret,
+    hasher:  HashFn,
+    uniform_errors: bool,
+    limits: InputLimits,
+}
+
+impl LazyPwdAuth {
+    /**
+    Open the password file at the given path, indexing its rows. Rows that
+    can't be parsed are skipped with a warning, as with `PwdAuth::open()`;
+    if a user has more than one row, the last is used.
+    */
+    pub fn open(pwd_file: impl AsRef<Path>) -> Result<Self, FileError> {
+        let pwd_file = pwd_file.as_ref();
+        
+        let f = open_for_read(pwd_file)?;
+        let mut r = csv::Reader::from_reader(BufReader::new(f));
+        let headers = match r.byte_headers() {
+            Ok(h) => h.clone(),
+            Err(e) => { return Err(FileError::from_csv(pwd_file, Op::Read, &e)); },
+        };
+        let col = match headers.iter().position(|h| h == b"uname") {
+            Some(col) => col,
+            None => {
+                return Err(FileError::new(pwd_file, Op::Read, io::ErrorKind::InvalidData,
+                                          "no uname column".to_string()));
+            },
+        };
+        
+        let mut index: HashMap<String, u64> = HashMap::new();
+        let mut record = csv::ByteRecord::new();
+        loop {
+            match r.read_byte_record(&mut record) {
+                Ok(false) => { break; },
+                Ok(true) => {},
+                Err(e) if e.is_io_error() => {
+                    return Err(FileError::from_csv(pwd_file, Op::Read, &e));
+                },
+                Err(e) => {
+                    eprintln!("WARNING: reading {}: {}", pwd_file.to_string_lossy(), &e);
+                    continue;
+                },
+            }
+            let offset = match record.position() {
+                Some(pos) => pos.byte(),
+                None => { continue; },
+            };
+            match record.get(col).map(std::str::from_utf8) {
+                Some(Ok(uname)) => { let _ = index.insert(uname.to_string(), offset); },
+                _ => {
+                    eprintln!("WARNING: reading {}, byte {}: bad user name",
+                        pwd_file.to_string_lossy(), offset);
+                },
+            }
+        }
+        
+        let file = open_for_read(pwd_file)?;
+        return Ok(LazyPwdAuth {
+            ufile:   PathBuf::from(pwd_file),
+            headers,
+            index,
+            file:    Mutex::new(file),
+            cache:   Mutex::new(Lru::new(DEFAULT_CACHE_SIZE)),
+            pepper:  Secret::default(),
+            hasher:  HashFn::default(),
+            uniform_errors: false,
+            limits: InputLimits::default(),
+        });
+    }
+    
+    /**
+    Set the maximum number of users whose rows are kept in memory (the
+    default is 1024), emptying the cache.
+    */
+    pub fn cache_size(&mut self, size: usize) {
+        self.cache = Mutex::new(Lru::new(size));
+    }
+    
+    /** Set the pepper; see `PwdAuth::pepper()`. */
+    pub fn pepper(&mut self, provider: &dyn SecretProvider) -> io::Result<()> {
+        self.pepper = Secret::from_provider(provider)?;
+        return Ok(());
+    }
+    
+    /** Replace the password hash function; see `PwdAuth::password_hasher()`. */
+    pub fn password_hasher<H>(&mut self, hasher: H)
+    where H: PasswordHasher + 'static
+    {
+        self.hasher = HashFn::new(hasher)
+            .normalized(self.hasher.normalizes())
+            .encoded(self.hasher.encoding());
+    }
+    
+    /** Turn Unicode normalization of passwords on or off; see `PwdAuth::normalize_passwords()`. */
+    pub fn normalize_passwords(&mut self, on: bool) {
+        self.hasher = self.hasher.clone().normalized(on);
+    }
+    
+    /** Choose the encoding of hashes that aren't flagged `needs_rehash`; see `PwdAuth::hash_encoding()`. */
+    pub fn hash_encoding(&mut self, encoding: HashEncoding) {
+        self.hasher = self.hasher.clone().encoded(encoding);
+    }
+    
+    /** Turn "uniform errors" mode on or off; see `PwdAuth::uniform_errors()`. */
+    pub fn uniform_errors(&mut self, on: bool) { self.uniform_errors = on; }
+    
+    /** Set the caps on user name and password lengths; see `PwdAuth::input_limits()`. */
+    pub fn input_limits(&mut self, limits: InputLimits) { self.limits = limits; }
+    
+    /** Returns the number of users in the index. */
+    pub fn len(&self) -> usize { self.index.len() }
+    
+    /** Returns whether there are no users at all. */
+    pub fn is_empty(&self) -> bool { self.index.is_empty() }
+    
+    /** Returns the path of the file this database reads from. */
+    pub fn path(&self) -> &Path { &self.ufile }
+    
+    /**
+    Checks whether the given password/salt combination is correct for the
+    given user, exactly as `PwdAuth::check_password()` does (including
+    taking about as long when the user doesn't exist).
+    */
+    pub fn check_password(
+        &self,
+        uname: &str,
+        password: &str,
+        salt: &[u8]
+    ) -> Result<AuthOk, DataError> {
+        self.limits.check(uname, password)?;
+        let mut salted = salt.to_vec();
+        salted.extend_from_slice(self.pepper.as_bytes());
+        
+        let user = match self.user(uname) {
+            Some(user) => user,
+            None => {
+                self.hasher.verify_dummy(password, &salted);
+                return Err(self.uniform_or(DataError::NoSuchUser));
+            },
+        };
+        if self.hasher.verify(password, &salted, &user.hash) {
+            let mut ok = AuthOk::new(uname);
+            ok.needs_rehash = self.hasher.needs_rehash(&user.hash);
+            Ok(ok)
+        } else {
+            Err(self.uniform_or(DataError::BadPassword))
+        }
+    }
+    
+    /** Returns `Ok(())` if the user exists, and `DataError::NoSuchUser` otherwise. */
+    pub fn user_exists(&self, uname: &str) -> Result<(), DataError> {
+        match self.index.contains_key(uname) {
+            true => Ok(()),
+            false => Err(DataError::NoSuchUser),
+        }
+    }
+    
+    /** Returns whether the given user has administrator privileges. */
+    pub fn is_admin(&self, uname: &str) -> Result<bool, DataError> {
+        match self.user(uname) {
+            Some(user) => Ok(user.admin),
+            None => Err(DataError::NoSuchUser),
+        }
+    }
+    
+    /**
+    Returns the user's ID (see `PwdAuth::user_id()`), or
+    `DataError::NoSuchUser` if they have none because the file was never
+    saved by a version of `PwdAuth` that assigns them.
+    */
+    pub fn user_id(&self, uname: &str) -> Result<u64, DataError> {
+        match self.user(uname).and_then(|user| user.uid) {
+            Some(uid) => Ok(uid),
+            None => Err(DataError::NoSuchUser),
+        }
+    }
+    
+    /** Returns `e`, or `DataError::BadCredentials` in uniform errors mode. */
+    fn uniform_or(&self, e: DataError) -> DataError {
+        if self.uniform_errors { DataError::BadCredentials } else { e }
+    }
+    
+    /** Returns the user's row, from the cache if it's there. */
+    fn user(&self, uname: &str) -> Option<CachedUser> {
+        let offset = *self.index.get(uname)?;
+        if let Some(user) = self.cache.lock().get(uname) { return Some(user); }
+        
+        let user = match self.read_row(offset) {
+            Ok(urw) if urw.uname == uname => CachedUser {
+                hash: urw.hash, admin: urw.admin, uid: urw.uid,
+            },
+            Ok(_) => {
+                eprintln!("WARNING: reading {}: row for \"{}\" has moved; reopen the file",
+                    self.ufile.to_string_lossy(), uname);
+                return None;
+            },
+            Err(e) => {
+                eprintln!("WARNING: reading {}: {}", self.ufile.to_string_lossy(), &e);
+                return None;
+            },
+        };
+        self.cache.lock().insert(uname, user.clone());
+        return Some(user);
+    }
+    
+    /** Reads and parses the row starting at the given byte offset. */
+    fn read_row(&self, offset: u64) -> Result<UserRW, FileError> {
+        let mut file = self.file.lock();
+        if let Err(e) = file.seek(SeekFrom::Start(offset)) {
+            return Err(FileError::from_io(&self.ufile, Op::Read, &e));
+        }
+        let mut r = csv::ReaderBuilder::new().has_headers(false).from_reader(&mut *file);
+        let mut record = csv::ByteRecord::new();
+        match r.read_byte_record(&mut record) {
+            Ok(true) => {},
+            Ok(false) => {
+                return Err(FileError::new(&self.ufile, Op::Read, io::ErrorKind::UnexpectedEof,
+                                          "row is past the end of the file".to_string()));
+            },
+            Err(e) => { return Err(FileError::from_csv(&self.ufile, Op::Read, &e)); },
+        }
+        match record.deserialize(Some(&self.headers)) {
+            Ok(urw) => Ok(urw),
+            Err(e) => Err(FileError::from_csv(&self.ufile, Op::Read, &e)),
+        }
+    }
+}
@@ -0,0 +1,99 @@
+/*!
+Checking passwords against an LDAP directory (Active Directory, OpenLDAP,
+and so on) with a simple bind.
+
+```no_run
+use authlite::{BothAuth, LdapChecker};
+
+let mut auth = BothAuth::open("users.csv", "keys.csv").unwrap();
+auth.credential_checker(LdapChecker::new(
+    "ldaps://ldap.example.com",
+    "uid={},ou=people,dc=example,dc=com",
+));
+let key = auth.check_password_and_issue_key("ted", "frogs", b"").unwrap();
+```
+
+Only available with the `ldap` feature.
+*/
+use std::time::Duration;
+
+use ldap3::{LdapConn, LdapConnSettings, LdapError};
+
+use crate::{CredentialChecker, DataError};
+
+/* The LDAP result codes for a bad password and an unknown DN. */
+const INVALID_CREDENTIALS: u32 = 49;
+const NO_SUCH_OBJECT: u32 = 32;
+
+/** A `CredentialChecker` that checks a password by binding to an LDAP
+    server as the user; see the module documentation.
+    
+    Each check opens a new connection, binds, and unbinds.
+*/
+#[derive(Debug, Clone)]
+pub struct LdapChecker {
+    url: String,
+    dn_template: String,
+    timeout: Duration,
+    starttls: bool,
+}
+
+impl LdapChecker {
+    /**
+    Create a checker that binds to the server at `url` (`ldap://` or
+    `ldaps://`) as the DN made by replacing `{}` in `dn_template` with the
+    (escaped) user name, like `uid={},ou=people,dc=example,dc=com`. (For
+    Active Directory, a template like `{}@example.com` works too.)
+    */
+    pub fn new(url: &str, dn_template: &str) -> Self {
+        return LdapChecker {
+            url: url.to_string(),
+            dn_template: dn_template.to_string(),
+            timeout: Duration::from_secs(5),
+            starttls: false,
+        };
+    }
+    
+    /**
+    Sets how long to wait for the server, to connect and for each
+    operation (the default is 5 seconds).
+    */
+    pub fn timeout(&mut self, timeout: Duration) { self.timeout = timeout; }
+    
+    /** Turn StartTLS on `ldap://` connections on or off (it is off by default). */
+    pub fn starttls(&mut self, on: bool) { self.starttls = on; }
+    
+    /** Returns the DN the user binds as. */
+    pub fn dn(&self, uname: &str) -> String {
+        return self.dn_template.replace("{}", &ldap3::dn_escape(uname));
+    }
+}
+
+impl CredentialChecker for LdapChecker {
+    fn check_credentials(&self, uname: &str, password: &str) -> Result<(), DataError> {
+        /* A simple bind with an empty password is an "unauthenticated"
+           bind, which many servers allow for any DN. */
+        if password.is_empty() { return Err(DataError::BadPassword); }
+        
+        let settings = LdapConnSettings::new()
+            .set_conn_timeout(self.timeout)
+            .set_starttls(self.starttls);
+        let mut conn = LdapConn::with_settings(settings, &self.url)
+            .map_err(|_| DataError::Unavailable)?;
+        let result = conn.with_timeout(self.timeout).simple_bind(&self.dn(uname), password);
+        let _ = conn.unbind();
+        
+        match result {
+            Ok(res) => match res.rc {
+                0 => Ok(()),
+                INVALID_CREDENTIALS => Err(DataError::BadPassword),
+                NO_SUCH_OBJECT => Err(DataError::NoSuchUser),
+                _ => Err(DataError::Unavailable),
+            },
+            Err(LdapError::LdapResult { result }) if result.rc == INVALID_CREDENTIALS => {
+                Err(DataError::BadPassword)
+            },
+            Err(_) => Err(DataError::Unavailable),
+        }
+    }
+}
@@ -17,38 +17,389 @@ Features:
     algorithm, because why not?
   * Supports salted passwords plus the ability to issue temporary,
     time-limited "keys" for session management.
+
+## Thread safety
+
+Every database type here (`PwdAuth`, `KeyAuth`, `BothAuth`, and the rest)
+is `Send` and `Sync`. Everything that checks or changes users and keys
+takes `&self` and does its own locking, so one database can be shared
+between threads in an `Arc`, with no `Mutex` around it; in particular,
+any number of threads can log users in and issue keys at once.
+
+Methods that change _settings_ (`.life()`, `.write_through()`, and so on)
+take `&mut self`, so they must be called before the database is shared.
+
+Saving holds the database's write lock(s) for as long as it takes to
+write the file, so saves never interleave with each other or with
+changes; checks in other threads wait for the save to finish.
 */
+#![allow(clippy::needless_return)]
+use std::fmt;
 use std::fs::File;
-use std::io::ErrorKind;
-use std::path::Path;
+use std::io::{self, ErrorKind};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
 
 mod pwd;
 mod key;
 mod both;
-pub use pwd::PwdAuth;
-pub use key::KeyAuth;
-pub use both::BothAuth;
+mod event;
+mod compress;
+mod shard;
+mod throttle;
+mod secret;
+mod hasher;
+mod checker;
+mod remember;
+mod groups;
+mod invite;
+mod cert;
+mod external;
+mod lazy;
+mod validate;
+mod audit;
+mod verifier;
+mod multi;
+mod fault;
+mod size;
+mod health;
+pub mod v2;
+#[cfg(feature = "bundle")]
+mod bundle;
+#[cfg(feature = "http-hooks")]
+mod webhook;
+#[cfg(all(unix, feature = "admin-socket"))]
+mod admin;
+#[cfg(feature = "grpc")]
+pub mod grpc;
+#[cfg(feature = "ffi")]
+pub mod ffi;
+#[cfg(feature = "python")]
+mod python;
+#[cfg(any(test, feature = "testing"))]
+pub mod testing;
+#[cfg(feature = "pubkey")]
+mod pubkey;
+#[cfg(feature = "ldap")]
+pub mod ldap;
+pub use pwd::{PwdAuth, UsernamePolicy, InputLimits, SaltPolicy, SaltEncoding, ImportReport, UserBundle,
+              Identifier};
+pub use key::{KeyAuth, KeyCharset, KeyHash, KeyInfo, CapacityPolicy, MaintenanceReport};
+pub use both::{BothAuth, BothAuthBuilder, ConsistencyReport, DebouncedSaver, ExistingKeys,
+               LoginResult, PurgeReport, RotationPolicy, SaveReport, StatusReport};
+pub use event::{AuthEvent, SecurityEvent};
+pub use compress::Compression;
+pub use secret::{SecretProvider, EnvSecret, FileSecret};
+pub use hasher::{PasswordHasher, Blake3Hasher, HashEncoding, Calibration, calibrate_hash_cost};
+pub use checker::CredentialChecker;
+pub use remember::{RememberAuth, RememberToken};
+pub use groups::GroupAuth;
+pub use invite::InviteAuth;
+pub use cert::CertAuth;
+pub use external::ExternalAuth;
+pub use lazy::LazyPwdAuth;
+pub use verifier::Verifier;
+pub use multi::{MultiAuth, Sweeper};
+pub use validate::{ValidationReport, ValidationIssue, RecoveryReport};
+pub use audit::Finding;
+pub use health::HealthIssue;
+#[cfg(feature = "http-hooks")]
+pub use webhook::{Webhook, SIGNATURE_HEADER};
+#[cfg(all(unix, feature = "admin-socket"))]
+pub use admin::AdminServer;
+#[cfg(feature = "pubkey")]
+pub use pubkey::{PubkeyAuth, CHALLENGE_LENGTH, SSH_NAMESPACE};
+#[cfg(feature = "ldap")]
+pub use ldap::LdapChecker;
 
-/** Conditions encountered when loading or saving a database is unsuccessful. */
-#[derive(Debug, PartialEq)]
-pub enum FileError {
-    Exists(String),
-    DoesNotExist(String),
-    Write(String),
-    Read(String),
+/** The order in which a database writes its records when it saves.
+    
+    By default records are written in whatever order they're stored in
+    memory, which changes from save to save even when the data doesn't;
+    either of the sorted orders makes saving the same data produce the
+    same file, so files kept in version control or deduplicating backups
+    only change where the data does. Sorting costs O(n log n) per save.
+*/
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum SaveOrder {
+    /** Whatever order is quickest (the default). */
+    #[default]
+    Unsorted,
+    /** Sorted by user name (or, for keys, by the key itself). */
+    ById,
+    /** Sorted by when each key was issued, or for users, by their uid
+        (ties are broken as for `ById`). */
+    ByIssueTime,
 }
 
+/** The file operation that was being attempted when a `FileError`
+    occurred. */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Op {
+    /** Creating a new database file. */
+    Create,
+    /** Opening or reading a database file. */
+    Read,
+    /** Writing (saving) a database file. */
+    Write,
+}
+
+impl fmt::Display for Op {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Op::Create => write!(f, "creating"),
+            Op::Read => write!(f, "reading"),
+            Op::Write => write!(f, "writing"),
+        }
+    }
+}
+
+/** Conditions encountered when loading or saving a database is unsuccessful.
+
+    `kind` allows programmatic reaction to the underlying problem (for
+    example, `ErrorKind::NotFound` when opening a database that doesn't
+    exist, or `ErrorKind::AlreadyExists` when creating one that does);
+    malformed data is reported as `ErrorKind::InvalidData`.
+    
+    With the `serde` feature, this is `Serialize` and `Deserialize`; `kind`
+    is represented by the name of the `ErrorKind` variant (like
+    `"NotFound"`), and unrecognized names deserialize as `ErrorKind::Other`.
+*/
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct FileError {
+    pub path: PathBuf,
+    pub op: Op,
+    #[cfg_attr(feature = "serde", serde(with = "serde_kind"))]
+    pub kind: ErrorKind,
+    pub msg: String,
+}
+
+impl FileError {
+    pub(crate) fn new(p: &Path, op: Op, kind: ErrorKind, msg: String) -> Self {
+        FileError { path: PathBuf::from(p), op, kind, msg }
+    }
+    
+    pub(crate) fn from_io(p: &Path, op: Op, e: &io::Error) -> Self {
+        FileError::new(p, op, e.kind(), e.to_string())
+    }
+    
+    pub(crate) fn from_csv(p: &Path, op: Op, e: &csv::Error) -> Self {
+        let kind = match e.kind() {
+            csv::ErrorKind::Io(ioe) => ioe.kind(),
+            _ => ErrorKind::InvalidData,
+        };
+        FileError::new(p, op, kind, e.to_string())
+    }
+}
+
+impl fmt::Display for FileError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "error {} {}: {}", &self.op, self.path.to_string_lossy(), &self.msg)
+    }
+}
+
+impl std::error::Error for FileError {}
+
 /** Non-`Ok()` conditions that can be encountered when checking
     passwords/keys or updating a database.
+    
+    With the `serde` feature, this is `Serialize` and `Deserialize`, with
+    variants represented by their names (like `"BadPassword"`), or for
+    those with fields, an object with the name as its only key (like
+    `{"UserExists":{"uname":"ted"}}`); these names are stable.
 */
 #[derive(Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum DataError {
-    UserExists,
+    /** There's already a user named `uname` (the name that was asked
+        for, which may differ in case from the existing user's name). */
+    UserExists { uname: String },
     NoSuchUser,
     BadPassword,
-    KeyExpired,
+    /** The key (or token) expired, or was invalidated, `expired_for` ago. */
+    KeyExpired { expired_for: Duration },
+    /** The key was revoked (see `KeyAuth::invalidate_key()`) before it
+        expired. */
+    KeyRevoked,
     NoSuchKey,
     BadUsername,
+    /** The database is full: it holds as many users or keys as
+        `PwdAuth::max_users()` or `KeyAuth::max_keys()` allows, or adding
+        another would take its file over the size set with
+        `.max_file_size()`. */
+    CapacityExceeded,
+    NotAdmin,
+    InvalidUsername,
+    /** The change was made in memory, but persisting it to disk (in
+        write-through mode) failed; the database remains dirty. */
+    SaveFailed(FileError),
+    /** A `try_` method couldn't acquire the database's lock before its
+        timeout elapsed. */
+    LockTimeout,
+    /** The user has been issued too many keys recently (see
+        `KeyAuth::issue_rate_limit()`). */
+    IssuanceThrottled,
+    /** A token (such as a CSRF token) didn't match. */
+    BadToken,
+    /** A remember-me token was redeemed with an old validator, suggesting
+        it was copied; its series has been revoked (see `RememberAuth`). */
+    StolenToken,
+    /** The user name or password was wrong; which one isn't revealed (see
+        `PwdAuth::uniform_errors()`). */
+    BadCredentials,
+    GroupExists,
+    NoSuchGroup,
+    NotInGroup,
+    /** There's no key namespace with the given name (see
+        `KeyAuth::add_namespace()`). */
+    NoSuchNamespace,
+    /** There's no tenant with the given ID, or it couldn't be loaded (see
+        `MultiAuth`). */
+    NoSuchTenant,
+    /** A signature of a challenge didn't verify against any of the user's
+        public keys, or there was no unexpired challenge to sign (see
+        `PubkeyAuth`). */
+    BadSignature,
+    /** The password couldn't be checked, because the `CredentialChecker`
+        couldn't reach its server (or the like). */
+    Unavailable,
+    /** A user name or password was longer than allowed (see
+        `InputLimits`). */
+    InputTooLong,
+    /** The database's configuration doesn't allow this (see
+        `PwdAuth::password_challenge()`, or `KeyAuth::length()` and
+        `KeyAuth::chars()`). */
+    Unsupported,
+    /** The database can't be changed (see `PwdAuth::from_static_csv()`). */
+    ReadOnly,
+    /** The `BothAuth` has no database of the kind needed attached (see
+        `BothAuth::attach_groups()` and the like); this is a mistake in
+        setting it up, not in the request. */
+    NotAttached,
+    /** The external identity is already linked to the user `uname` (see
+        `ExternalAuth::link()`). */
+    IdentityLinked { uname: String },
+}
+
+impl DataError {
+    /**
+    Returns the HTTP status code a web service would typically respond
+    with for this error:
+    
+      * 401 Unauthorized for bad credentials: `BadPassword`,
+        `BadCredentials`, `KeyExpired`, `KeyRevoked`, `NoSuchKey`,
+        `BadUsername` (a key issued to someone else), `StolenToken`, and
+        `BadSignature`;
+      * 403 Forbidden for `NotAdmin`, `BadToken`, `NotInGroup`, and
+        `ReadOnly`;
+      * 404 Not Found for `NoSuchUser`, `NoSuchGroup`, `NoSuchNamespace`, and
+        `NoSuchTenant`;
+      * 409 Conflict for `UserExists`, `GroupExists`, and `IdentityLinked`;
+      * 400 Bad Request for `InvalidUsername`;
+      * 413 Payload Too Large for `InputTooLong`;
+      * 429 Too Many Requests for `IssuanceThrottled`;
+      * 503 Service Unavailable for `CapacityExceeded`, `LockTimeout`, and
+        `Unavailable`;
+      * 501 Not Implemented for `Unsupported`;
+      * 500 Internal Server Error for `SaveFailed` and `NotAttached`.
+    
+    Note that a login form may prefer to answer 401 for `NoSuchUser` too,
+    so as not to reveal which user names exist.
+    */
+    pub fn suggested_status(&self) -> u16 {
+        match self {
+            DataError::StolenToken
+            | DataError::BadSignature
+            | DataError::BadPassword
+            | DataError::BadCredentials
+            | DataError::KeyExpired { .. }
+            | DataError::KeyRevoked
+            | DataError::NoSuchKey
+            | DataError::BadUsername => 401,
+            DataError::NotAdmin
+            | DataError::BadToken
+            | DataError::NotInGroup
+            | DataError::ReadOnly => 403,
+            DataError::NoSuchUser
+            | DataError::NoSuchGroup
+            | DataError::NoSuchNamespace
+            | DataError::NoSuchTenant => 404,
+            DataError::UserExists { .. }
+            | DataError::GroupExists
+            | DataError::IdentityLinked { .. } => 409,
+            DataError::InvalidUsername => 400,
+            DataError::InputTooLong => 413,
+            DataError::IssuanceThrottled => 429,
+            DataError::CapacityExceeded
+            | DataError::LockTimeout
+            | DataError::Unavailable => 503,
+            DataError::Unsupported => 501,
+            DataError::SaveFailed(_)
+            | DataError::NotAttached => 500,
+        }
+    }
+}
+
+/** What a successful password or key check returns, so callers needn't
+    look up anything else about the user afterward. */
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AuthOk {
+    /** The user's name, as it is stored in the database. */
+    pub uname: String,
+    /** Whether the user's password hash should be replaced (by calling
+        `.change_password()` with the password just checked), because
+        the `PasswordHasher` considers it outdated. Always `false` for a
+        key check. */
+    pub needs_rehash: bool,
+    /** How much longer the user's password remains valid, if it expires.
+        Passwords don't currently expire, so this is always `None`. */
+    pub password_expires_in: Option<std::time::Duration>,
+}
+
+impl AuthOk {
+    pub(crate) fn new(uname: impl Into<String>) -> Self {
+        AuthOk { uname: uname.into(), needs_rehash: false, password_expires_in: None }
+    }
+}
+
+/** (De)serializes an `ErrorKind` as the name of its variant. */
+#[cfg(feature = "serde")]
+mod serde_kind {
+    use std::io::ErrorKind;
+    
+    use serde::{Deserialize, Deserializer, Serializer};
+    
+    const KINDS: &[ErrorKind] = &[
+        ErrorKind::NotFound,
+        ErrorKind::PermissionDenied,
+        ErrorKind::AlreadyExists,
+        ErrorKind::InvalidInput,
+        ErrorKind::InvalidData,
+        ErrorKind::TimedOut,
+        ErrorKind::WriteZero,
+        ErrorKind::Interrupted,
+        ErrorKind::Unsupported,
+        ErrorKind::UnexpectedEof,
+        ErrorKind::OutOfMemory,
+        ErrorKind::WouldBlock,
+        ErrorKind::Other,
+    ];
+    
+    pub fn serialize<S: Serializer>(kind: &ErrorKind, s: S) -> Result<S::Ok, S::Error> {
+        s.serialize_str(&format!("{:?}", kind))
+    }
+    
+    pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<ErrorKind, D::Error> {
+        let name = String::deserialize(d)?;
+        let kind = KINDS.iter()
+            .find(|k| format!("{:?}", k) == name)
+            .copied()
+            .unwrap_or(ErrorKind::Other);
+        return Ok(kind);
+    }
 }
 
 /**
@@ -56,20 +407,10 @@ Truncates and opens the given file for writing, translating
 `std::io::Error`s into `FileError`s.
 */
 fn open_for_write(p: &Path) -> Result<File, FileError> {
-    let f = match File::create(p) {
-        Ok(f) => f,
-        Err(e) => match e.kind() {
-            ErrorKind::PermissionDenied => {
-                let estr = format!("permission denied: {}", p.to_string_lossy());
-                return Err(FileError::Read(estr));
-            },
-            e @ _ => {
-                let estr = format!("{}: {:?}", p.to_string_lossy(), &e);
-                return Err(FileError::Read(estr));
-            },
-        },
-    };
-    return Ok(f);
+    match File::create(p) {
+        Ok(f) => Ok(f),
+        Err(e) => Err(FileError::from_io(p, Op::Write, &e)),
+    }
 }
 
 /**
@@ -77,23 +418,80 @@ Opens the given file for reading, translating
 `std::io::Error`s into `FileError`s.
 */
 fn open_for_read(p: &Path) -> Result<File, FileError> {
-    let f = match File::open(p) {
-        Ok(f) => f,
-        Err(e) => match e.kind() {
-            ErrorKind::NotFound => {
-                return Err(FileError::DoesNotExist(p.to_string_lossy().to_string()));
-            },
-            ErrorKind::PermissionDenied => {
-                let estr = format!("permission denied: {}", p.to_string_lossy());
-                return Err(FileError::Read(estr));
-            },
-            e @ _ => {
-                let estr = format!("{}: {:?}", p.to_string_lossy(), &e);
-                return Err(FileError::Read(estr));
-            },
-        },
+    match File::open(p) {
+        Ok(f) => Ok(f),
+        Err(e) => Err(FileError::from_io(p, Op::Read, &e)),
+    }
+}
+
+/**
+Creates any missing parent directories of the given path. On Unix, newly
+created directories are only accessible by their owner.
+*/
+fn create_parent_dirs(p: &Path) -> Result<(), FileError> {
+    let parent = match p.parent() {
+        Some(d) if !d.as_os_str().is_empty() => d,
+        _ => { return Ok(()); },
     };
-    return Ok(f);
+    
+    let mut builder = std::fs::DirBuilder::new();
+    builder.recursive(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::DirBuilderExt;
+        builder.mode(0o700);
+    }
+    if let Err(e) = builder.create(parent) {
+        return Err(FileError::from_io(parent, Op::Create, &e));
+    }
+    return Ok(());
+}
+
+/**
+Opens a temporary file alongside the given path for writing. Once it has
+been completely written, it should be moved into place over the original
+with `commit_atomic_write()`, so that a crash or full disk partway through
+a save never leaves a truncated file at `p`.
+
+Each call gets its own temporary file, so threads writing the same path
+at once (with `.save_to()`, say) don't clobber each other's.
+*/
+fn open_for_atomic_write(p: &Path) -> Result<(File, PathBuf), FileError> {
+    if p.as_os_str().is_empty() {
+        /* A database read with `from_reader()` has no file of its own. */
+        return Err(FileError::new(p, Op::Write, ErrorKind::InvalidInput,
+            "no file to write to (the database was read from a reader; use .save_to() or .save_to_writer())".to_owned()));
+    }
+    static NEXT_TMP: AtomicUsize = AtomicUsize::new(0);
+    let n = NEXT_TMP.fetch_add(1, Ordering::Relaxed);
+    let mut tmp = p.as_os_str().to_owned();
+    tmp.push(format!(".{}.{}.tmp", std::process::id(), n));
+    let tmp = PathBuf::from(tmp);
+    let f = open_for_write(&tmp)?;
+    return Ok((f, tmp));
+}
+
+/**
+Removes the temporary file `tmp` after a failed attempt to write it,
+passing through the `FileError` describing what went wrong.
+*/
+fn abort_atomic_write(tmp: &Path, err: FileError) -> FileError {
+    let _ = std::fs::remove_file(tmp);
+    return err;
+}
+
+/**
+Syncs the temporary file `f` (opened by `open_for_atomic_write()` at the
+path `tmp`) to disk and renames it over `p`.
+
+The temporary file is removed if anything goes wrong.
+*/
+fn commit_atomic_write(f: File, tmp: &Path, p: &Path) -> Result<(), FileError> {
+    let result = f.sync_all().and_then(|_| std::fs::rename(tmp, p));
+    if let Err(e) = result {
+        return Err(abort_atomic_write(tmp, FileError::from_io(p, Op::Write, &e)));
+    }
+    return Ok(());
 }
 
 mod tests;
\ No newline at end of file
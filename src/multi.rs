@@ -0,0 +1,283 @@
+use std::collections::HashMap;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use parking_lot::RwLock;
+
+use crate::{AuthOk, BothAuth, FileError, DataError, Op};
+
+const USERS_FILE: &str = "users.csv";
+const KEYS_FILE: &str = "keys.csv";
+
+/** A function that configures each tenant's `BothAuth` as it's loaded. */
+type Configure = Box<dyn Fn(&str, &mut BothAuth) + Send + Sync>;
+
+/** Many independent `BothAuth`s (one per "tenant") under one handle, for
+    hosting several small sites from one process.
+    
+    Each tenant has its own directory under the root directory, named for
+    its ID and holding its `users.csv` and `keys.csv`:
+    
+    ```text
+    tenants/
+        acme/
+            users.csv
+            keys.csv
+        initech/
+            users.csv
+            keys.csv
+    ```
+    
+    Tenants are loaded the first time they're used, and kept loaded. A
+    tenant ID must be a single, non-empty path component (no `/`, and not
+    `.` or `..`).
+    
+    Changes are saved as with a lone `BothAuth` (each tenant can be put in
+    write-through mode by `.configure()`), or all at once with
+    `.save_if_dirty()`. Rather than each tenant needing a timer thread of
+    its own, `.start_sweeper()` culls and saves every loaded tenant from a
+    single background thread.
+*/
+pub struct MultiAuth {
+    root: PathBuf,
+    tenants: RwLock<HashMap<String, Arc<BothAuth>>>,
+    configure: Option<Configure>,
+}
+
+impl std::fmt::Debug for MultiAuth {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("MultiAuth")
+            .field("root", &self.root)
+            .field("tenants", &self.tenants)
+            .finish()
+    }
+}
+
+impl MultiAuth {
+    /**
+    Serve the tenants in the given directory, which must exist. Nothing is
+    loaded until it's needed.
+    */
+    pub fn open(root: impl AsRef<Path>) -> Result<Self, FileError> {
+        let root = root.as_ref();
+        if !root.is_dir() {
+            return Err(FileError::new(root, Op::Read, ErrorKind::NotFound,
+                                      "no such directory".to_string()));
+        }
+        return Ok(MultiAuth {
+            root: PathBuf::from(root),
+            tenants: RwLock::new(HashMap::new()),
+            configure: None,
+        });
+    }
+    
+    /**
+    Set a function to be called (with the tenant's ID) on each tenant's
+    `BothAuth` as it's loaded or created, to set its key life, pepper,
+    write-through mode, and so on.
+    */
+    pub fn configure<F>(&mut self, f: F)
+    where F: Fn(&str, &mut BothAuth) + Send + Sync + 'static
+    {
+        self.configure = Some(Box::new(f));
+    }
+    
+    /** Returns the directory the tenants are in. */
+    pub fn root(&self) -> &Path { &self.root }
+    
+    /**
+    Returns the given tenant's database, loading it if it isn't already.
+    Returns a `FileError` with kind `ErrorKind::NotFound` if the tenant
+    doesn't exist, or `ErrorKind::InvalidInput` if the ID isn't valid.
+    */
+    pub fn tenant(&self, id: &str) -> Result<Arc<BothAuth>, FileError> {
+        if let Some(ba) = self.tenants.read().get(id) { return Ok(ba.clone()); }
+        
+        let dir = self.tenant_dir(id)?;
+        let mut tenants = self.tenants.write();
+        /* Another thread may have loaded it while we waited for the lock. */
+        if let Some(ba) = tenants.get(id) { return Ok(ba.clone()); }
+        let mut ba = BothAuth::open(dir.join(USERS_FILE), dir.join(KEYS_FILE))?;
+        if let Some(f) = &self.configure { f(id, &mut ba); }
+        let ba = Arc::new(ba);
+        let _ = tenants.insert(id.to_string(), ba.clone());
+        return Ok(ba);
+    }
+    
+    /**
+    Creates a new tenant, with a directory and empty files of its own, and
+    returns its database. Returns a `FileError` with kind
+    `ErrorKind::AlreadyExists` if it already exists.
+    */
+    pub fn create_tenant(&self, id: &str) -> Result<Arc<BothAuth>, FileError> {
+        let dir = self.tenant_dir(id)?;
+        let mut tenants = self.tenants.write();
+        if tenants.contains_key(id) || dir.exists() {
+            return Err(FileError::new(&dir, Op::Create, ErrorKind::AlreadyExists,
+                                      "tenant already exists".to_string()));
+        }
+        let mut ba = BothAuth::new_with_dirs(dir.join(USERS_FILE), dir.join(KEYS_FILE))?;
+        if let Some(f) = &self.configure { f(id, &mut ba); }
+        let ba = Arc::new(ba);
+        let _ = tenants.insert(id.to_string(), ba.clone());
+        return Ok(ba);
+    }
+    
+    /** Returns the IDs of all the tenants (loaded or not), sorted. */
+    pub fn tenant_ids(&self) -> Result<Vec<String>, FileError> {
+        let entries = match std::fs::read_dir(&self.root) {
+            Ok(entries) => entries,
+            Err(e) => { return Err(FileError::from_io(&self.root, Op::Read, &e)); },
+        };
+        let mut ids: Vec<String> = entries
+            .filter_map(|entry| entry.ok())
+            .filter(|entry| entry.path().join(USERS_FILE).is_file())
+            .filter_map(|entry| entry.file_name().into_string().ok())
+            .collect();
+        ids.sort_unstable();
+        return Ok(ids);
+    }
+    
+    /**
+    Checks a user's password in the given tenant (see
+    `BothAuth::check_password()`). Returns `DataError::NoSuchTenant` if the
+    tenant doesn't exist, or can't be loaded.
+    */
+    pub fn check_password(&self, tenant: &str, uname: &str, password: &str, salt: &[u8])
+    -> Result<AuthOk, DataError> {
+        self.loaded(tenant)?.check_password(uname, password, salt)
+    }
+    
+    /** As `.check_password()`, but for `BothAuth::check_password_and_issue_key()`. */
+    pub fn check_password_and_issue_key(
+        &self,
+        tenant: &str,
+        uname: &str,
+        password: &str,
+        salt: &[u8]
+    ) -> Result<String, DataError> {
+        self.loaded(tenant)?.check_password_and_issue_key(uname, password, salt)
+    }
+    
+    /** As `.check_password()`, but for `BothAuth::check_key()`. */
+    pub fn check_key(&self, tenant: &str, key: &str, uname: &str)
+    -> Result<AuthOk, DataError> {
+        self.loaded(tenant)?.check_key(key, uname)
+    }
+    
+    /**
+    Saves whichever databases of the loaded tenants are dirty. Every tenant
+    is attempted even if one fails; the first error is returned.
+    */
+    pub fn save_if_dirty(&self) -> Result<(), FileError> {
+        let tenants = self.tenants.read();
+        let mut result = Ok(());
+        for ba in tenants.values() {
+            if let Err(e) = ba.save_if_dirty() {
+                if result.is_ok() { result = Err(e); }
+            }
+        }
+        return result;
+    }
+    
+    /**
+    Culls expired keys from every loaded tenant, then saves whichever of
+    their databases are dirty, returning how many tenants had files
+    written. Tenants that haven't changed aren't touched. Every tenant is
+    attempted even if one fails; the first error is returned.
+    
+    Tenants loaded while this is running are left for the next sweep.
+    */
+    pub fn sweep(&self) -> Result<usize, FileError> {
+        /* Don't hold the lock while writing files, so tenants can still
+           be loaded in the meantime. */
+        let loaded: Vec<Arc<BothAuth>> = self.tenants.read().values().cloned().collect();
+        let mut written = 0;
+        let mut result = Ok(());
+        for ba in loaded.iter() {
+            let swept = ba.cull_keys().and_then(|_| ba.save_if_dirty());
+            match swept {
+                Ok(report) => {
+                    if report.any() { written += 1; }
+                },
+                Err(e) => { if result.is_ok() { result = Err(e); } },
+            }
+        }
+        return result.map(|_| written);
+    }
+    
+    /**
+    Starts a background thread that calls `.sweep()` every `interval`
+    until the returned `Sweeper` is stopped or dropped, which sweeps once
+    more so nothing is left unsaved. Errors are printed as warnings.
+    */
+    pub fn start_sweeper(self: &Arc<Self>, interval: Duration) -> Sweeper {
+        let (stop, stopped) = mpsc::channel::<()>();
+        let multi = self.clone();
+        let handle = thread::spawn(move || {
+            loop {
+                let last = stopped.recv_timeout(interval) != Err(RecvTimeoutError::Timeout);
+                if let Err(e) = multi.sweep() {
+                    eprintln!("WARNING: sweeping tenants: {}", &e);
+                }
+                if last { break; }
+            }
+        });
+        return Sweeper { stop: Some(stop), handle: Some(handle) };
+    }
+    
+    /** Returns the tenant, or `DataError::NoSuchTenant`. */
+    fn loaded(&self, id: &str) -> Result<Arc<BothAuth>, DataError> {
+        match self.tenant(id) {
+            Ok(ba) => Ok(ba),
+            Err(e) => {
+                if e.kind != ErrorKind::NotFound && e.kind != ErrorKind::InvalidInput {
+                    eprintln!("WARNING: loading tenant \"{}\": {}", id, &e);
+                }
+                Err(DataError::NoSuchTenant)
+            },
+        }
+    }
+    
+    /** Returns the directory of the tenant with the given ID, if it's valid. */
+    fn tenant_dir(&self, id: &str) -> Result<PathBuf, FileError> {
+        let valid = !id.is_empty() && id != "." && id != ".."
+            && !id.contains(['/', '\\', '\0']);
+        if !valid {
+            return Err(FileError::new(&self.root, Op::Read, ErrorKind::InvalidInput,
+                                      format!("invalid tenant ID \"{}\"", id)));
+        }
+        return Ok(self.root.join(id));
+    }
+}
+
+/**
+The background thread started by `MultiAuth::start_sweeper()`. Stops (after
+a final sweep) when `.stop()` is called or it's dropped.
+*/
+#[derive(Debug)]
+pub struct Sweeper {
+    stop: Option<Sender<()>>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl Sweeper {
+    /** Stops the thread, waiting for its final sweep to finish. */
+    pub fn stop(mut self) { self.shutdown(); }
+    
+    fn shutdown(&mut self) {
+        /* Dropping the sender wakes the thread up. */
+        drop(self.stop.take());
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+impl Drop for Sweeper {
+    fn drop(&mut self) { self.shutdown(); }
+}
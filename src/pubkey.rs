@@ -0,0 +1,454 @@
+/*!
+Password-less logins with Ed25519 keys, for command-line tools and the
+like, which persist as a .csv file on disk.
+
+A user registers one or more public keys. To log in, the client asks for
+a challenge (`PubkeyAuth::begin_challenge()`), signs it with the private
+key, and sends the signature back (`PubkeyAuth::verify_challenge()`, or
+`BothAuth::login_with_signature()` to get a session key in one go).
+
+Developers' existing SSH keys can be used too: `.import_authorized_keys()`
+reads the Ed25519 keys from an `authorized_keys` file, and
+`.verify_ssh_challenge()` checks a signature made by `ssh-keygen`:
+
+```text
+printf %s "$CHALLENGE_HEX" | ssh-keygen -Y sign -n authlite -f ~/.ssh/id_ed25519
+```
+
+Only available with the `pubkey` feature.
+*/
+use std::collections::HashMap;
+use std::convert::TryInto;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use base64::{Engine, engine::general_purpose::STANDARD as BASE64};
+use ed25519_dalek::{Signature, VerifyingKey, PUBLIC_KEY_LENGTH};
+use parking_lot::{Mutex, RwLock};
+use rand::RngCore;
+use serde::{Serialize, Deserialize};
+use sha2::{Digest, Sha256, Sha512};
+
+use crate::{AuthOk, FileError, DataError, Op, open_for_read, open_for_write,
+            open_for_atomic_write, abort_atomic_write, commit_atomic_write};
+
+/** The length of a challenge, in bytes. */
+pub const CHALLENGE_LENGTH: usize = 32;
+
+/** The namespace (`ssh-keygen -Y sign -n`) SSH signatures must be made in. */
+pub const SSH_NAMESPACE: &str = "authlite";
+
+const DEFAULT_CHALLENGE_LIFE: Duration = Duration::from_secs(120);
+const SSH_ED25519: &[u8] = b"ssh-ed25519";
+const SSHSIG_MAGIC: &[u8] = b"SSHSIG";
+const SSHSIG_ARMOR: (&str, &str) = ("-----BEGIN SSH SIGNATURE-----", "-----END SSH SIGNATURE-----");
+
+#[derive(Debug, Serialize, Deserialize)]
+struct PubkeyRW {
+    uname: String,
+    /* Hex. */
+    key: String,
+}
+
+#[derive(Debug)]
+struct Challenge {
+    nonce: [u8; CHALLENGE_LENGTH],
+    expiry: Instant,
+}
+
+/** Represents a database of users' Ed25519 public keys; see the module
+    documentation.
+    
+    Outstanding challenges are only kept in memory, so they don't survive
+    a restart (the client just asks for another).
+    
+    There's no write-through mode: after a change the database is flagged
+    as "dirty" until `.save()` or `BothAuth::save_if_dirty()` writes it.
+*/
+#[derive(Debug)]
+pub struct PubkeyAuth {
+    keys: RwLock<HashMap<String, Vec<VerifyingKey>>>,
+    challenges: Mutex<HashMap<String, Challenge>>,
+    pfile: PathBuf,
+    pdirty: RwLock<bool>,
+    life: Duration,
+}
+
+impl PubkeyAuth {
+    /**
+    Create a new public key database that will save its data to a .csv
+    file at the supplied path.
+    */
+    pub fn new(pubkey_file: impl AsRef<Path>) -> Result<Self, FileError> {
+        let pubkey_file = pubkey_file.as_ref();
+        
+        if Path::exists(pubkey_file) {
+            return Err(FileError::new(pubkey_file, Op::Create, ErrorKind::AlreadyExists,
+                                      "file already exists".to_string()));
+        }
+        
+        let f = open_for_write(pubkey_file)?;
+        let mut w = csv::Writer::from_writer(f);
+        if let Err(e) = w.write_record(["uname", "key"]) {
+            return Err(FileError::from_csv(pubkey_file, Op::Create, &e));
+        }
+        if let Err(e) = w.flush() {
+            return Err(FileError::from_io(pubkey_file, Op::Create, &e));
+        }
+        
+        return Ok(PubkeyAuth::from_keys(pubkey_file, HashMap::new()));
+    }
+    
+    /** Open a public key database with data from the .csv file at the given path. */
+    pub fn open(pubkey_file: impl AsRef<Path>) -> Result<Self, FileError> {
+        let pubkey_file = pubkey_file.as_ref();
+        
+        let f = open_for_read(pubkey_file)?;
+        let mut keys: HashMap<String, Vec<VerifyingKey>> = HashMap::new();
+        let mut r = csv::Reader::from_reader(f);
+        for (n, result) in r.deserialize::<PubkeyRW>().enumerate() {
+            match result {
+                Err(e) => {
+                    eprintln!("WARNING: reading {}, record {}: {}",
+                        pubkey_file.to_string_lossy(), n, &e);
+                },
+                Ok(prw) => match parse_hex_key(&prw.key) {
+                    None => {
+                        eprintln!("WARNING: reading {}, record {}: \"{}\" isn't an Ed25519 public key",
+                            pubkey_file.to_string_lossy(), n, &prw.key);
+                    },
+                    Some(key) => { keys.entry(prw.uname).or_default().push(key); },
+                },
+            }
+        }
+        
+        return Ok(PubkeyAuth::from_keys(pubkey_file, keys));
+    }
+    
+    /**
+    Open the public key database at the given path if the file exists, or
+    create a new one there if it doesn't.
+    */
+    pub fn open_or_new(pubkey_file: impl AsRef<Path>) -> Result<Self, FileError> {
+        let pubkey_file = pubkey_file.as_ref();
+        match PubkeyAuth::open(pubkey_file) {
+            Err(e) if e.kind == ErrorKind::NotFound => PubkeyAuth::new(pubkey_file),
+            x => x,
+        }
+    }
+    
+    fn from_keys(pubkey_file: &Path, keys: HashMap<String, Vec<VerifyingKey>>) -> Self {
+        return PubkeyAuth {
+            keys: RwLock::new(keys),
+            challenges: Mutex::new(HashMap::new()),
+            pfile: PathBuf::from(pubkey_file),
+            pdirty: RwLock::new(false),
+            life: DEFAULT_CHALLENGE_LIFE,
+        };
+    }
+    
+    /** Set how long a challenge may be answered for (the default is two minutes). */
+    pub fn challenge_life(&mut self, life: Duration) { self.life = life; }
+    
+    /**
+    Registers an Ed25519 public key for the user (who may have several).
+    Marks the database as "dirty" (if the user didn't already have it).
+    
+    Returns `DataError::BadSignature` if the bytes aren't a valid Ed25519
+    public key.
+    */
+    pub fn add_key(&self, uname: &str, key: &[u8; PUBLIC_KEY_LENGTH]) -> Result<(), DataError> {
+        let key = VerifyingKey::from_bytes(key).map_err(|_| DataError::BadSignature)?;
+        let mut keys = self.keys.write();
+        let user_keys = keys.entry(uname.to_string()).or_default();
+        if !user_keys.contains(&key) {
+            user_keys.push(key);
+            let mut dirty = self.pdirty.write();
+            *dirty = true;
+        }
+        return Ok(());
+    }
+    
+    /**
+    Removes one of the user's public keys. Marks the database as "dirty".
+    
+    Returns `DataError::NoSuchKey` if the user doesn't have that key.
+    */
+    pub fn remove_key(&self, uname: &str, key: &[u8; PUBLIC_KEY_LENGTH]) -> Result<(), DataError> {
+        let mut keys = self.keys.write();
+        let user_keys = keys.get_mut(uname).ok_or(DataError::NoSuchKey)?;
+        let n = user_keys.len();
+        user_keys.retain(|k| k.as_bytes() != key);
+        if user_keys.len() == n { return Err(DataError::NoSuchKey); }
+        if user_keys.is_empty() { let _ = keys.remove(uname); }
+        
+        let mut dirty = self.pdirty.write();
+        *dirty = true;
+        return Ok(());
+    }
+    
+    /**
+    Removes all of the user's public keys (when the user is deleted, say),
+    returning how many there were. Marks the database as "dirty" if there
+    were any.
+    */
+    pub fn remove_user(&self, uname: &str) -> usize {
+        let mut keys = self.keys.write();
+        match keys.remove(uname) {
+            None => 0,
+            Some(user_keys) => {
+                let mut dirty = self.pdirty.write();
+                *dirty = true;
+                user_keys.len()
+            },
+        }
+    }
+    
+    /**
+    Registers the Ed25519 keys (`ssh-ed25519` lines) in `authorized_keys`,
+    the contents of an OpenSSH `authorized_keys` file (or a `.pub` file),
+    for the user, returning how many weren't registered already. Lines
+    with other types of key, blank lines, and comments are skipped; so are
+    any options at the start of a line.
+    
+    Returns `DataError::BadSignature` (having registered none of them) if
+    an `ssh-ed25519` line's key can't be read.
+    */
+    pub fn import_authorized_keys(&self, uname: &str, authorized_keys: &str)
+    -> Result<usize, DataError> {
+        let mut found: Vec<[u8; PUBLIC_KEY_LENGTH]> = Vec::new();
+        for line in authorized_keys.lines().map(str::trim) {
+            if line.is_empty() || line.starts_with('#') { continue; }
+            let mut fields = line.split_whitespace()
+                .skip_while(|f| f.as_bytes() != SSH_ED25519);
+            if fields.next().is_none() { continue; }
+            let blob = fields.next().and_then(|b| BASE64.decode(b).ok());
+            match blob.as_deref().and_then(parse_ssh_key) {
+                Some(key) => { found.push(key); },
+                None => { return Err(DataError::BadSignature); },
+            }
+        }
+        
+        let before = self.keys_of(uname).len();
+        for key in found.iter() { self.add_key(uname, key)?; }
+        return Ok(self.keys_of(uname).len() - before);
+    }
+    
+    /** Returns the user's public keys, in the order they were added. */
+    pub fn keys_of(&self, uname: &str) -> Vec<[u8; PUBLIC_KEY_LENGTH]> {
+        let keys = self.keys.read();
+        match keys.get(uname) {
+            None => Vec::new(),
+            Some(user_keys) => user_keys.iter().map(|k| k.to_bytes()).collect(),
+        }
+    }
+    
+    /**
+    Returns a fresh random challenge for the user to sign with one of
+    their private keys, replacing any they had outstanding. It must be
+    answered (with `.verify_challenge()`) within the challenge life.
+    
+    Returns `DataError::NoSuchUser` if the user has no public keys.
+    */
+    pub fn begin_challenge(&self, uname: &str) -> Result<[u8; CHALLENGE_LENGTH], DataError> {
+        if !self.keys.read().contains_key(uname) {
+            return Err(DataError::NoSuchUser);
+        }
+        let mut nonce = [0u8; CHALLENGE_LENGTH];
+        rand::thread_rng().fill_bytes(&mut nonce);
+        
+        let now = Instant::now();
+        let mut challenges = self.challenges.lock();
+        /* Forget abandoned challenges, so they can't pile up. */
+        challenges.retain(|_, c| c.expiry > now);
+        let _ = challenges.insert(uname.to_string(), Challenge { nonce, expiry: now + self.life });
+        return Ok(nonce);
+    }
+    
+    /**
+    Checks the user's signature of their outstanding challenge against
+    each of their public keys. The challenge is used up either way.
+    
+    Returns `DataError::BadSignature` if the signature doesn't verify
+    against any of their keys, or they have no unexpired challenge.
+    */
+    pub fn verify_challenge(&self, uname: &str, signature: &[u8]) -> Result<AuthOk, DataError> {
+        let nonce = self.take_challenge(uname)?;
+        let signature = Signature::from_slice(signature).map_err(|_| DataError::BadSignature)?;
+        
+        let keys = self.keys.read();
+        let user_keys = keys.get(uname).ok_or(DataError::BadSignature)?;
+        match user_keys.iter().any(|k| k.verify_strict(&nonce, &signature).is_ok()) {
+            true => Ok(AuthOk::new(uname)),
+            false => Err(DataError::BadSignature),
+        }
+    }
+    
+    /**
+    Like `.verify_challenge()`, but for an armored SSH signature (as
+    written by `ssh-keygen -Y sign`) of the challenge _as lowercase hex_,
+    made in the namespace `SSH_NAMESPACE`; see the module documentation.
+    
+    Returns `DataError::BadSignature` if the signature can't be read, is
+    in another namespace, wasn't made with one of the user's keys, or
+    doesn't verify, or they have no unexpired challenge.
+    */
+    pub fn verify_ssh_challenge(&self, uname: &str, armored: &str) -> Result<AuthOk, DataError> {
+        let nonce = self.take_challenge(uname)?;
+        let sig = parse_sshsig(armored).ok_or(DataError::BadSignature)?;
+        if sig.namespace != SSH_NAMESPACE.as_bytes() { return Err(DataError::BadSignature); }
+        
+        let message = to_hex(&nonce);
+        let digest = match sig.hash_alg.as_slice() {
+            b"sha512" => Sha512::digest(message.as_bytes()).to_vec(),
+            b"sha256" => Sha256::digest(message.as_bytes()).to_vec(),
+            _ => { return Err(DataError::BadSignature); },
+        };
+        let mut signed = SSHSIG_MAGIC.to_vec();
+        for field in [&sig.namespace, &sig.reserved, &sig.hash_alg, &digest].iter() {
+            put_ssh_string(&mut signed, field);
+        }
+        
+        let keys = self.keys.read();
+        let key = keys.get(uname)
+            .and_then(|user_keys| user_keys.iter().find(|k| k.as_bytes() == &sig.key))
+            .ok_or(DataError::BadSignature)?;
+        match key.verify_strict(&signed, &sig.signature) {
+            Ok(()) => Ok(AuthOk::new(uname)),
+            Err(_) => Err(DataError::BadSignature),
+        }
+    }
+    
+    /**
+    Removes and returns the user's outstanding challenge, or returns
+    `DataError::BadSignature` if they have none (or it has expired).
+    */
+    fn take_challenge(&self, uname: &str) -> Result<[u8; CHALLENGE_LENGTH], DataError> {
+        let challenge = self.challenges.lock().remove(uname).ok_or(DataError::BadSignature)?;
+        if challenge.expiry <= Instant::now() { return Err(DataError::BadSignature); }
+        return Ok(challenge.nonce);
+    }
+    
+    /** Returns the path of the file this database saves to. */
+    pub fn path(&self) -> &Path { &self.pfile }
+    
+    /**
+    Returns whether the in-memory database is "dirty", that is, whether it's
+    out of sync with the persistent data on disk.
+    */
+    pub fn is_dirty(&self) -> bool {
+        let dirty = self.pdirty.read();
+        return *dirty;
+    }
+    
+    /**
+    Writes the current state of the database to disk (sorted by user name),
+    marking it as no longer dirty. The file is replaced atomically.
+    */
+    pub fn save(&self) -> Result<(), FileError> {
+        let path = &self.pfile;
+        let keys = self.keys.write();
+        let mut unames: Vec<&String> = keys.keys().collect();
+        unames.sort_unstable();
+        
+        let (f, tmp) = open_for_atomic_write(path)?;
+        let mut w = csv::Writer::from_writer(f);
+        for uname in unames.into_iter() {
+            for key in keys[uname].iter() {
+                let prw = PubkeyRW { uname: uname.clone(), key: to_hex(key.as_bytes()) };
+                if let Err(e) = w.serialize(prw) {
+                    return Err(abort_atomic_write(&tmp, FileError::from_csv(path, Op::Write, &e)));
+                }
+            }
+        }
+        let f = match w.into_inner() {
+            Ok(f) => f,
+            Err(e) => { return Err(abort_atomic_write(&tmp, FileError::from_io(path, Op::Write, e.error()))); },
+        };
+        commit_atomic_write(f, &tmp, path)?;
+        
+        let mut dirty = self.pdirty.write();
+        *dirty = false;
+        
+        return Ok(());
+    }
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/** The parts of an SSH signature (the `SSHSIG` format) needed to check it. */
+struct SshSig {
+    key: [u8; PUBLIC_KEY_LENGTH],
+    namespace: Vec<u8>,
+    reserved: Vec<u8>,
+    hash_alg: Vec<u8>,
+    signature: Signature,
+}
+
+/** Reads the length-prefixed strings of the SSH wire format. */
+struct SshReader<'a>(&'a [u8]);
+
+impl<'a> SshReader<'a> {
+    fn bytes(&mut self, n: usize) -> Option<&'a [u8]> {
+        if self.0.len() < n { return None; }
+        let (head, tail) = self.0.split_at(n);
+        self.0 = tail;
+        return Some(head);
+    }
+    
+    fn u32(&mut self) -> Option<u32> {
+        let b = self.bytes(4)?;
+        return Some(u32::from_be_bytes([b[0], b[1], b[2], b[3]]));
+    }
+    
+    fn string(&mut self) -> Option<&'a [u8]> {
+        let n = self.u32()? as usize;
+        self.bytes(n)
+    }
+}
+
+fn put_ssh_string(buf: &mut Vec<u8>, s: &[u8]) {
+    buf.extend_from_slice(&(s.len() as u32).to_be_bytes());
+    buf.extend_from_slice(s);
+}
+
+/** Reads an Ed25519 public key in the SSH wire format, if it is one. */
+fn parse_ssh_key(blob: &[u8]) -> Option<[u8; PUBLIC_KEY_LENGTH]> {
+    let mut r = SshReader(blob);
+    if r.string()? != SSH_ED25519 { return None; }
+    return r.string()?.try_into().ok();
+}
+
+/** Decodes an armored SSH signature made with an Ed25519 key. */
+fn parse_sshsig(armored: &str) -> Option<SshSig> {
+    let body = armored.trim()
+        .strip_prefix(SSHSIG_ARMOR.0)?
+        .strip_suffix(SSHSIG_ARMOR.1)?;
+    let body: String = body.split_whitespace().collect();
+    let blob = BASE64.decode(body).ok()?;
+    
+    let mut r = SshReader(&blob);
+    if r.bytes(SSHSIG_MAGIC.len())? != SSHSIG_MAGIC || r.u32()? != 1 { return None; }
+    let key = parse_ssh_key(r.string()?)?;
+    let namespace = r.string()?.to_vec();
+    let reserved = r.string()?.to_vec();
+    let hash_alg = r.string()?.to_vec();
+    let mut sig = SshReader(r.string()?);
+    if sig.string()? != SSH_ED25519 { return None; }
+    let signature = Signature::from_slice(sig.string()?).ok()?;
+    
+    return Some(SshSig { key, namespace, reserved, hash_alg, signature });
+}
+
+/** Parses a public key stored as hex, if it is one. */
+fn parse_hex_key(hex: &str) -> Option<VerifyingKey> {
+    if hex.len() != 2 * PUBLIC_KEY_LENGTH || !hex.is_ascii() { return None; }
+    let mut bytes = [0u8; PUBLIC_KEY_LENGTH];
+    for (i, b) in bytes.iter_mut().enumerate() {
+        *b = u8::from_str_radix(&hex[2 * i..2 * i + 2], 16).ok()?;
+    }
+    return VerifyingKey::from_bytes(&bytes).ok();
+}
@@ -1,13 +1,360 @@
 
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::collections::hash_map::Entry;
+use std::convert::TryInto;
+use std::io::{self, ErrorKind, Read, Write};
 use std::path::{Path, PathBuf};
-use std::sync::RwLock;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::time::{Duration, Instant};
 
-use blake3::{Hash, Hasher};
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD_NO_PAD;
+use parking_lot::{Mutex, RwLock};
+use rand::{Rng, distributions::Alphanumeric};
+use serde::{Serialize, Deserialize};
 
-use crate::{FileError, DataError, open_for_read, open_for_write};
+use crate::{AuthOk, FileError, DataError, Op, SaveOrder, open_for_read, open_for_write, create_parent_dirs,
+            open_for_atomic_write, abort_atomic_write, commit_atomic_write};
+use crate::hasher::{self, HashEncoding, HashFn, PasswordHasher};
+use crate::checker::{CheckerFn, CredentialChecker};
+use crate::secret::{Secret, SecretProvider};
+use crate::audit::Finding;
+use crate::both::StatusReport;
+use crate::event::{AuthEvent, EventHook, SecurityEvent};
+use crate::fault::Faulty;
+use crate::validate::{self, RecoveryReport, ValidationIssue, ValidationReport};
+use crate::verifier;
+use crate::shard::UserLocks;
+use crate::size::{self, ByteCounter, SizeLimit};
 
-const PWD_FILE_HEADERS: [&str; 2] = ["uname", "hash"];
+const PWD_FILE_HEADERS: [&str; 7] = ["uname", "hash", "admin", "uid", "class", "email", "salt"];
+const REPORT_HEADERS: [&str; 5] = ["uname", "uid", "admin", "class", "email"];
+const BOOTSTRAP_PASSWORD_LENGTH: usize = 20;
+const IMPORT_SALT_LENGTH: usize = 16;
+const CHALLENGE_NONCE_LENGTH: usize = 32;
+const DEFAULT_CHALLENGE_LIFE: Duration = Duration::from_secs(120);
+
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct UserRW {
+    pub(crate) uname: String,
+    pub(crate) hash: String,
+    /* Older files have no admin column. */
+    #[serde(default)]
+    pub(crate) admin: bool,
+    /* Nor a uid column. */
+    #[serde(default)]
+    pub(crate) uid: Option<u64>,
+    /* Nor a class column; empty for users with no class. */
+    #[serde(default)]
+    pub(crate) class: Option<String>,
+    /* Nor an email column; empty for users with no email address. */
+    #[serde(default)]
+    pub(crate) email: Option<String>,
+    /* Nor a salt column; empty for users with no stored salt. */
+    #[serde(default)]
+    pub(crate) salt: Option<String>,
+}
+
+impl UserRW {
+    fn id(&self) -> &str { &self.uname }
+}
+
+#[derive(Debug)]
+struct UserMeta {
+    hash: String,
+    admin: bool,
+    uid: u64,
+    class: Option<String>,
+    email: Option<String>,
+    /* As written in the file; see `SaltEncoding`. */
+    salt: Option<String>,
+}
+
+/* An outstanding `.password_challenge()`. */
+#[derive(Debug)]
+struct PasswordChallenge {
+    nonce: [u8; CHALLENGE_NONCE_LENGTH],
+    expiry: Instant,
+}
+
+impl UserMeta {
+    fn to_rw(&self, uname: &str) -> UserRW {
+        return UserRW {
+            uname: uname.to_string(),
+            hash: self.hash.clone(),
+            admin: self.admin,
+            uid: Some(self.uid),
+            class: self.class.clone(),
+            email: self.email.clone(),
+            salt: self.salt.clone(),
+        };
+    }
+}
+
+/** Restrictions on the user names that may be added to a `PwdAuth`.
+    
+    The default policy imposes no restrictions at all.
+*/
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct UsernamePolicy {
+    /** Minimum length of a user name, in characters. */
+    pub min_length: usize,
+    /** Maximum length of a user name, in characters, if any. */
+    pub max_length: Option<usize>,
+    /** If set, user names may contain only these characters. */
+    pub allowed_chars: Option<String>,
+}
+
+impl UsernamePolicy {
+    /**
+    Returns `Ok(())` if the given user name is acceptable under this
+    policy, and `DataError::InvalidUsername` otherwise.
+    */
+    pub fn check(&self, uname: &str) -> Result<(), DataError> {
+        let n_chars = uname.chars().count();
+        if n_chars < self.min_length {
+            return Err(DataError::InvalidUsername);
+        }
+        if let Some(max) = self.max_length {
+            if n_chars > max { return Err(DataError::InvalidUsername); }
+        }
+        if let Some(allowed) = &self.allowed_chars {
+            if !uname.chars().all(|c| allowed.contains(c)) {
+                return Err(DataError::InvalidUsername);
+            }
+        }
+        return Ok(());
+    }
+}
+
+/** Caps on the length of the user names and passwords a `PwdAuth` will
+    even look at, checked before any hashing, so that a client can't tie
+    up the server by sending an enormous "password" to a slow hash
+    function.
+    
+    The default caps are 1024 bytes for user names and 4096 bytes for
+    passwords, more than any real one needs.
+*/
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InputLimits {
+    /** Maximum length of a user name (or email address), in bytes, if any. */
+    pub max_uname_bytes: Option<usize>,
+    /** Maximum length of a password, in bytes, if any. */
+    pub max_password_bytes: Option<usize>,
+}
+
+impl InputLimits {
+    /** No caps at all. */
+    pub fn unlimited() -> Self {
+        InputLimits { max_uname_bytes: None, max_password_bytes: None }
+    }
+    
+    /**
+    Returns `Ok(())` if the user name and password are both within the
+    caps, and `DataError::InputTooLong` otherwise.
+    */
+    pub fn check(&self, uname: &str, password: &str) -> Result<(), DataError> {
+        if let Some(max) = self.max_uname_bytes {
+            if uname.len() > max { return Err(DataError::InputTooLong); }
+        }
+        if let Some(max) = self.max_password_bytes {
+            if password.len() > max { return Err(DataError::InputTooLong); }
+        }
+        return Ok(());
+    }
+}
+
+impl Default for InputLimits {
+    fn default() -> Self {
+        InputLimits { max_uname_bytes: Some(1024), max_password_bytes: Some(4096) }
+    }
+}
+
+/** How `PwdAuth::set_salt()` writes salts in the password file.
+    
+    Salts are written as [multibase](https://github.com/multiformats/multibase)
+    strings, which start with a character saying how the rest is encoded,
+    so salts in either encoding are read correctly whatever this is set to.
+*/
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum SaltEncoding {
+    /** `f` followed by lowercase hex digits. */
+    Hex,
+    /** `m` followed by standard base64, without padding (the default,
+        which is shorter). */
+    #[default]
+    Base64,
+}
+
+impl SaltEncoding {
+    /** Writes a salt in this encoding. */
+    fn encode(self, salt: &[u8]) -> String {
+        match self {
+            SaltEncoding::Hex => format!("f{}", hasher::encode_hex(salt)),
+            SaltEncoding::Base64 => format!("m{}", STANDARD_NO_PAD.encode(salt)),
+        }
+    }
+    
+    /** Reads a salt written in either encoding, or returns `None` if it isn't one. */
+    fn decode(stored: &str) -> Option<Vec<u8>> {
+        if let Some(hex) = stored.strip_prefix('f') {
+            hasher::decode_hex(hex)
+        } else if let Some(b64) = stored.strip_prefix('m') {
+            STANDARD_NO_PAD.decode(b64).ok()
+        } else {
+            None
+        }
+    }
+}
+
+/** Where the salts for users imported by `PwdAuth::import_plaintext_csv()`
+    come from. */
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SaltPolicy {
+    /** Every user gets the same salt (which may be empty). */
+    Fixed(Vec<u8>),
+    /** Each user's salt is in the file's `salt` column. */
+    Column,
+    /** Each user gets a new random salt, returned in the `ImportReport`. */
+    Random,
+}
+
+/** What `PwdAuth::import_plaintext_csv()` did. */
+#[derive(Debug, Default, PartialEq)]
+pub struct ImportReport {
+    /** The users added, in the order they appeared in the file. */
+    pub added: Vec<String>,
+    /** The users that couldn't be added (because they already exist, say),
+        and why. */
+    pub skipped: Vec<(String, DataError)>,
+    /** With `SaltPolicy::Random`, the salt generated for each added user,
+        which must be stored somewhere and passed with their password
+        from now on. Empty otherwise. */
+    pub salts: Vec<(String, Vec<u8>)>,
+}
+
+/**
+Everything a `PwdAuth` stores about one user, from `PwdAuth::export_user()`,
+for moving the account to another database with `PwdAuth::import_user()`.
+
+It holds the user's password _hash_, not their password, but should still
+be handled as carefully as the password file itself. It includes their
+salt only if it's stored in the database (see `PwdAuth::set_salt()`); it
+doesn't include group memberships (see `GroupAuth`). With the `serde` feature it's `Serialize` and
+`Deserialize`.
+*/
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct UserBundle {
+    pub uname: String,
+    pub hash: String,
+    pub admin: bool,
+    pub uid: u64,
+    pub class: Option<String>,
+    /* Bundles exported before email addresses were stored have none. */
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub email: Option<String>,
+    /* Likewise salts. */
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub salt: Option<Vec<u8>>,
+}
+
+/** A way of identifying a user, for `PwdAuth::check_password_by()` and
+    `PwdAuth::find_user()`. */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Identifier<'a> {
+    /** The user's name. */
+    Username(&'a str),
+    /** The user's email address (see `PwdAuth::set_email()`), which is
+        matched ignoring case. */
+    Email(&'a str),
+}
+
+/* Record format of the file saved alongside a password file, describing
+   it as it was written, so `PwdAuth::recover()` can tell if it's since
+   been cut short. */
+#[derive(Debug, Serialize, Deserialize)]
+struct PwdSumRW {
+    rows: usize,
+    bytes: u64,
+    blake3: String,
+}
+
+/** Returns the path of the file describing the given password file. */
+pub(crate) fn sum_path(pwd_file: &Path) -> PathBuf {
+    let mut p = pwd_file.as_os_str().to_owned();
+    p.push(".sum");
+    PathBuf::from(p)
+}
+
+/** Reads the description of the given password file, if there is one. */
+fn read_sum(pwd_file: &Path) -> Result<Option<PwdSumRW>, FileError> {
+    let sum_file = sum_path(pwd_file);
+    if !Path::exists(&sum_file) { return Ok(None); }
+    
+    let f = open_for_read(&sum_file)?;
+    let mut sum: Option<PwdSumRW> = None;
+    for result in csv::Reader::from_reader(f).deserialize::<PwdSumRW>() {
+        match result {
+            Err(e) => { return Err(FileError::from_csv(&sum_file, Op::Read, &e)); },
+            Ok(sumrw) => { sum = Some(sumrw); },
+        }
+    }
+    return Ok(sum);
+}
+
+/** Writes the description of the given password file. */
+fn write_sum(pwd_file: &Path, sum: PwdSumRW) -> Result<(), FileError> {
+    let sum_file = sum_path(pwd_file);
+    let (f, tmp) = open_for_atomic_write(&sum_file)?;
+    let mut w = csv::Writer::from_writer(f);
+    if let Err(e) = w.serialize(sum) {
+        return Err(abort_atomic_write(&tmp, FileError::from_csv(&sum_file, Op::Write, &e)));
+    }
+    let f = match w.into_inner() {
+        Ok(f) => f,
+        Err(e) => { return Err(abort_atomic_write(&tmp, FileError::from_io(&sum_file, Op::Write, e.error()))); },
+    };
+    return commit_atomic_write(f, &tmp, &sum_file);
+}
+
+/** A writer that passes what's written to it on to `inner`, counting and
+    hashing it. */
+struct Summing<W> {
+    inner: W,
+    hasher: blake3::Hasher,
+    bytes: u64,
+}
+
+impl<W: Write> Summing<W> {
+    fn new(inner: W) -> Self { Summing { inner, hasher: blake3::Hasher::new(), bytes: 0 } }
+    
+    /** Returns the inner writer, and a description of `rows` rows of what was written. */
+    fn finish(self, rows: usize) -> (W, PwdSumRW) {
+        let sum = PwdSumRW { rows, bytes: self.bytes, blake3: self.hasher.finalize().to_hex().to_string() };
+        return (self.inner, sum);
+    }
+}
+
+impl<W: Write> Write for Summing<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        let _ = self.hasher.update(&buf[..n]);
+        self.bytes += n as u64;
+        return Ok(n);
+    }
+    
+    fn flush(&mut self) -> io::Result<()> { self.inner.flush() }
+}
+
+/** A row of a plaintext file imported by `.import_plaintext_csv()`. */
+#[derive(Deserialize)]
+struct PlaintextRow {
+    uname: String,
+    password: String,
+    #[serde(default)]
+    salt: Option<String>,
+}
 
 /** Represents a password authorization database, which persists as
     a .csv file on disk.
@@ -16,12 +363,41 @@ const PWD_FILE_HEADERS: [&str; 2] = ["uname", "hash"];
     except checking a password) are _not_ automatically written to disk;
     instead, the database will be internally flagged as "dirty" (that is,
     out of sync with the data on disk) until it is explicitly written.
+    
+    Everything that changes the users (as opposed to the settings) takes
+    `&self`, so a single `PwdAuth` can be shared between threads in an
+    `Arc` without any extra locking.
 */
 #[derive(Debug)]
 pub struct PwdAuth {
-    hashes: RwLock<HashMap<String, Hash>>,
+    users:  RwLock<HashMap<String, UserMeta>>,
     ufile:  PathBuf,
-    udirty: RwLock<bool>,
+    udirty: AtomicBool,
+    ugen:   AtomicU64,
+    umax:   Option<usize>,
+    write_through: bool,
+    uorder: SaveOrder,
+    upolicy: UsernamePolicy,
+    limits: InputLimits,
+    pepper: Secret,
+    hasher: HashFn,
+    checker: Option<CheckerFn>,
+    load_report: ValidationReport,
+    uniform_errors: bool,
+    security: EventHook<SecurityEvent>,
+    /* Only kept in case-insensitive mode; see `.case_insensitive()`. */
+    folded: Option<RwLock<HashMap<String, usize>>>,
+    /* Lowercased email address -> user name; see `.set_email()`. */
+    emails: RwLock<HashMap<String, String>>,
+    /* Serializes `.change_password()` calls for the same user. */
+    ulocks: UserLocks,
+    salt_encoding: SaltEncoding,
+    /* Only kept in memory; see `.password_challenge()`. */
+    challenges: Mutex<HashMap<String, PasswordChallenge>>,
+    challenge_life: Duration,
+    ubytes: SizeLimit,
+    /* Set by `from_static_csv()`. */
+    read_only: bool,
 }
 
 impl PwdAuth {
@@ -30,242 +406,1747 @@ impl PwdAuth {
     Create a new password authorization database that will save its data
     to a .csv file at the supplied path.
     */
-    pub fn new(pwd_file: &dyn AsRef<Path>) -> Result<Self, FileError> {
+    pub fn new(pwd_file: impl AsRef<Path>) -> Result<Self, FileError> {
         let pwd_file = pwd_file.as_ref();
 
         if Path::exists(pwd_file) {
-            let estr = pwd_file.to_string_lossy().to_string();
-            return Err(FileError::Exists(estr));
+            return Err(FileError::new(pwd_file, Op::Create, ErrorKind::AlreadyExists,
+                                      "file already exists".to_string()));
         }
         
         let f = open_for_write(pwd_file)?;
         let mut w = csv::Writer::from_writer(f);
         
-        if let Err(e) = w.write_record(&PWD_FILE_HEADERS) {
-            let estr = format!("{}: {}", pwd_file.to_string_lossy(), &e);
-            return Err(FileError::Write(estr));
+        if let Err(e) = w.write_record(PWD_FILE_HEADERS) {
+            return Err(FileError::from_csv(pwd_file, Op::Create, &e));
         }
         if let Err(e) = w.flush() {
-            let estr = format!("{}: {}", pwd_file.to_string_lossy(), &e);
-            return Err(FileError::Write(estr));
+            return Err(FileError::from_io(pwd_file, Op::Create, &e));
         }
+        /* One left by an earlier file at this path would describe it instead. */
+        let _ = std::fs::remove_file(sum_path(pwd_file));
         
         let pwd_a = PwdAuth {
-            hashes: RwLock::new(HashMap::new()),
+            users:  RwLock::new(HashMap::new()),
             ufile:  PathBuf::from(pwd_file),
-            udirty: RwLock::new(false),
+            udirty: AtomicBool::new(false),
+            ugen:   AtomicU64::new(0),
+            umax:   None,
+            write_through: false,
+            uorder: SaveOrder::default(),
+            upolicy: UsernamePolicy::default(),
+            limits: InputLimits::default(),
+            pepper: Secret::default(),
+            hasher: HashFn::default(),
+            checker: None,
+            load_report: ValidationReport::empty(pwd_file),
+            uniform_errors: false,
+            security: EventHook::default(),
+            folded: None,
+            emails: RwLock::new(HashMap::new()),
+            ulocks: UserLocks::new(),
+            salt_encoding: SaltEncoding::default(),
+            challenges: Mutex::new(HashMap::new()),
+            challenge_life: DEFAULT_CHALLENGE_LIFE,
+            ubytes: SizeLimit::default(),
+            read_only: false,
         };
         
         return Ok(pwd_a);
     }
     
+    /**
+    Like `.new()`, but first creates any missing directories in the path
+    to the file (readable only by their owner, on Unix).
+    */
+    pub fn new_with_dirs(pwd_file: impl AsRef<Path>) -> Result<Self, FileError> {
+        let pwd_file = pwd_file.as_ref();
+        create_parent_dirs(pwd_file)?;
+        PwdAuth::new(pwd_file)
+    }
+    
     /**
     Open password authorization database with data from the .csv
     file in the given path.
         
     If the database is updated and saved, this is also where changes
     will be written to disk.
+    
+    Rows that can't be read are skipped, and if a user has more than one
+    row, the last one wins; either way, a warning is printed, and the
+    problem is recorded in the `.load_report()`.
+    
+    Users without a user ID (in files written by older versions), or with
+    the same ID as an earlier user, are given a new one, and the database
+    is marked "dirty" so the new IDs will be saved.
     */
-    pub fn open(pwd_file: &dyn AsRef<Path>) -> Result<Self, FileError> {
+    pub fn open(pwd_file: impl AsRef<Path>) -> Result<Self, FileError> {
         let pwd_file = pwd_file.as_ref();
-        
-        let f = open_for_read(pwd_file)?;
-        let mut new_users: HashMap<String, Hash> = HashMap::new();
-        let mut r = csv::Reader::from_reader(f);
-        for (n, result) in r.records().enumerate() {
-            match result {
-                Err(e) => {
-                    eprintln!("WARNING: reading {}, record {}: {}",
-                        pwd_file.to_string_lossy(), n, &e);
-                },
-                Ok(record) => {
-                    if record.len() != 2 {
-                        eprintln!("WARNING: reading {}, record {}: record wrong length ({})",
-                            pwd_file.to_string_lossy(), n, record.len());
-                        continue;
-                    }
-                    let uname = String::from(record.get(0).unwrap());
-                    let keystr = record.get(1).unwrap();
-                    let key = match Hash::from_hex(keystr) {
-                        Ok(x) => x,
-                        Err(e) => {
-                            eprintln!("WARNING: reading {}, record {}: can't parse \"{}\" as Hash: {}",
-                                pwd_file.to_string_lossy(), n, keystr, &e);
-                            continue;
-                        },
-                    };
-                    
-                    if let Some(_) = new_users.insert(uname.clone(), key) {
-                        eprintln!("WARNING: reading {}: user \"{}\" has multiple entries.",
-                            pwd_file.to_string_lossy(), &uname);
-                    }
+        let f = Faulty::reader(open_for_read(pwd_file)?, pwd_file);
+        return PwdAuth::read_from(f, pwd_file);
+    }
+    
+    /**
+    Read a password authorization database from uncompressed .csv data
+    (in the format `.save()` writes), such as an embedded asset, stdin or
+    a decrypting wrapper. Rows are handled as by `.open()`.
+    
+    The database has no file of its own, so `.save()` fails with
+    `ErrorKind::InvalidInput`; write it out with `.save_to()` or
+    `.save_to_writer()` instead.
+    */
+    pub fn from_reader<R: Read>(r: R) -> Result<Self, FileError> {
+        return PwdAuth::read_from(r, Path::new(""));
+    }
+    
+    /**
+    Read a read-only password authorization database from .csv data baked
+    into the program (with `include_bytes!()`, say), for images that ship a
+    fixed list of accounts. Rows are handled as by `.open()`.
+    
+    Passwords can be checked as usual, but anything that would change the
+    users (adding or deleting them, changing passwords, and so on) returns
+    `DataError::ReadOnly`, and there's no file to save to.
+    */
+    pub fn from_static_csv(data: &'static [u8]) -> Result<Self, FileError> {
+        let mut pwd_a = PwdAuth::from_reader(data)?;
+        pwd_a.read_only = true;
+        return Ok(pwd_a);
+    }
+    
+    /** Whether the database was made with `from_static_csv()`. */
+    pub fn is_read_only(&self) -> bool { self.read_only }
+    
+    /** Reads a database from `r`, which will be saved to `pwd_file`. */
+    fn read_from<R: Read>(f: R, pwd_file: &Path) -> Result<Self, FileError> {
+        let mut new_users: HashMap<String, UserMeta> = HashMap::new();
+        let mut emails: HashMap<String, String> = HashMap::new();
+        let mut uids: HashSet<u64> = HashSet::new();
+        let mut new_uids = false;
+        let report = validate::validate_rows(f, pwd_file, UserRW::id, |line, urw, issues| {
+            if let Some(issue) = check_user_row(line, &urw) {
+                issues.push(issue);
+                return;
+            }
+            let uid = match urw.uid {
+                Some(uid) if uids.insert(uid) => uid,
+                _ => {
+                    new_uids = true;
+                    let mut uid = new_uid();
+                    while !uids.insert(uid) { uid = new_uid(); }
+                    uid
                 },
+            };
+            let class = urw.class.filter(|c| !c.is_empty());
+            let mut email = urw.email.filter(|e| !e.is_empty());
+            if let Some(address) = &email {
+                match emails.entry(fold_email(address)) {
+                    Entry::Occupied(owner) => {
+                        eprintln!("WARNING: reading {}: user {} has the same email address as {}; ignoring it",
+                            pwd_file.to_string_lossy(), &urw.uname, owner.get());
+                        email = None;
+                    },
+                    Entry::Vacant(entry) => { let _ = entry.insert(urw.uname.clone()); },
+                }
             }
-        }
+            let mut salt = urw.salt.filter(|s| !s.is_empty());
+            if salt.as_deref().is_some_and(|s| SaltEncoding::decode(s).is_none()) {
+                eprintln!("WARNING: reading {}: user {} has an unreadable salt; ignoring it",
+                    pwd_file.to_string_lossy(), &urw.uname);
+                salt = None;
+            }
+            let umeta = UserMeta { hash: urw.hash, admin: urw.admin, uid, class, email, salt };
+            let _ = new_users.insert(urw.uname, umeta);
+        })?;
+        report.warn();
         
         let pwd_a = PwdAuth {
-            hashes: RwLock::new(new_users),
+            users:  RwLock::new(new_users),
             ufile:  PathBuf::from(pwd_file),
-            udirty: RwLock::new(false),
+            udirty: AtomicBool::new(new_uids),
+            ugen:   AtomicU64::new(0),
+            umax:   None,
+            write_through: false,
+            uorder: SaveOrder::default(),
+            upolicy: UsernamePolicy::default(),
+            limits: InputLimits::default(),
+            pepper: Secret::default(),
+            hasher: HashFn::default(),
+            checker: None,
+            load_report: report,
+            uniform_errors: false,
+            security: EventHook::default(),
+            folded: None,
+            emails: RwLock::new(emails),
+            ulocks: UserLocks::new(),
+            salt_encoding: SaltEncoding::default(),
+            challenges: Mutex::new(HashMap::new()),
+            challenge_life: DEFAULT_CHALLENGE_LIFE,
+            ubytes: SizeLimit::default(),
+            read_only: false,
         };
         
         return Ok(pwd_a);
     }
     
     /**
-    Add a user with the given name and password, with the password hash
-    salted by the supplied salt data.
-        
-    Marks the database as "dirty".
-        
-    Returns `Err()` when a user with the given name already exists.
+    Open the password authorization database at the given path if the file
+    exists, or create a new one there if it doesn't.
     */
-    pub fn add_user(
-        &mut self,
-        uname: &str,
-        password: &str,
-        salt: &[u8]
-    ) -> Result<(), DataError> {
-        
-        let hash = hash_with_salt(password, salt);
-        
-        let mut hashes = self.hashes.write().unwrap();
-        if hashes.contains_key(uname) { return Err(DataError::UserExists); }
-        let _ = hashes.insert(uname.to_string(), hash);
-        
-        let mut dirty = self.udirty.write().unwrap();
-        *dirty = true;
-        
-        return Ok(());
+    pub fn open_or_new(pwd_file: impl AsRef<Path>) -> Result<Self, FileError> {
+        let pwd_file = pwd_file.as_ref();
+        match PwdAuth::open(pwd_file) {
+            Err(e) if e.kind == ErrorKind::NotFound => PwdAuth::new(pwd_file),
+            x => x,
+        }
     }
     
     /**
-    Delete the user with the given name.
+    Checks the password file at the given path without opening it as a
+    database, reporting rows that `.open()` would skip or overwrite (and
+    whether the file is readable by other users), for example as a check
+    before deploying a new file.
     
-    Marks the database as "dirty".
-        
-    Returns `Err()` if the user doesn't exist.
+    Returns `Err()` only if the file can't be read at all.
     */
-    pub fn delete_user(&mut self, uname: &str) -> Result<(), DataError> {
-        let mut hashes = self.hashes.write().unwrap();
-        match hashes.remove(uname) {
-            None => Err(DataError::NoSuchUser),
-            Some(_) => {
-                let mut dirty = self.udirty.write().unwrap();
-                *dirty = true;
-                Ok(())
-            },
-        }
+    pub fn validate_file(pwd_file: impl AsRef<Path>) -> Result<ValidationReport, FileError> {
+        let pwd_file = pwd_file.as_ref();
+        let f = open_for_read(pwd_file)?;
+        let mut report = validate::validate_rows(f, pwd_file, UserRW::id, |line, urw, issues| {
+            issues.extend(check_user_row(line, &urw));
+        })?;
+        report.check_permissions();
+        return Ok(report);
     }
     
     /**
-    Changes the password of the given user.
+    Salvages what it can from a damaged password file (one cut short by a
+    full disk or a crash while something other than `.save()`, which never
+    leaves a partial file, was writing it, say), writing every complete,
+    readable record to a new file at `recovered`, which can then be opened
+    as usual. The damaged file isn't changed.
     
-    Marks the database as "dirty".
-        
-    Returns `Err()` if the user doesn't exist.
+    Every record ends with a newline, so a file that doesn't is reported as
+    `truncated`, and its last record is lost even if what's left of it can
+    be read, since its password hash may have been cut short. Records that
+    can't be read are lost too.
+    
+    Saving a password file also writes a small `.sum` file alongside it,
+    with its number of records, its length, and a checksum. If the damaged
+    file has one, a file cut off at the end of a record is reported as
+    `truncated` too (being shorter than it was), the report gives the
+    number of records it `expected`, and whether the data still matches
+    the checksum. The recovered file gets a `.sum` file of its own.
+    
+    Returns `Err()` if the damaged file can't be read, if `recovered`
+    already exists, or if it can't be written.
     */
-    pub fn change_password(
-        &mut self,
-        uname: &str,
-        password: &str,
-        salt: &[u8]
-    ) -> Result<(), DataError> {
+    pub fn recover(
+        damaged: impl AsRef<Path>,
+        recovered: impl AsRef<Path>
+    ) -> Result<RecoveryReport, FileError> {
+        let (damaged, recovered) = (damaged.as_ref(), recovered.as_ref());
+        if Path::exists(recovered) {
+            return Err(FileError::new(recovered, Op::Create, ErrorKind::AlreadyExists,
+                                      "file already exists".to_string()));
+        }
         
-        let hash = hash_with_salt(password, salt);
+        let data = match std::fs::read(damaged) {
+            Ok(data) => data,
+            Err(e) => { return Err(FileError::from_io(damaged, Op::Read, &e)); },
+        };
+        /* A description that can't be read is no help; go by the data alone. */
+        let sum = read_sum(damaged).unwrap_or(None);
+        let ends_mid_record = !data.is_empty() && !data.ends_with(b"\n");
+        let truncated = ends_mid_record || sum.as_ref().is_some_and(|sum| (data.len() as u64) < sum.bytes);
+        let checksum_matches = sum.as_ref().map(|sum| blake3::hash(&data).to_hex().as_str() == sum.blake3);
+        /* The line the incomplete last record starts on, if there is one. */
+        let partial_line = match ends_mid_record {
+            true => Some(data.iter().filter(|&&b| b == b'\n').count() as u64 + 1),
+            false => None,
+        };
+        let mut rows: Vec<UserRW> = Vec::new();
+        let report = validate::validate_rows(&data[..], damaged, UserRW::id, |line, urw, issues| {
+            if Some(line) == partial_line {
+                issues.push(ValidationIssue::Malformed {
+                    line, msg: "incomplete record at the end of the file".to_string()
+                });
+            } else if let Some(issue) = check_user_row(line, &urw) {
+                issues.push(issue);
+            } else {
+                rows.push(urw);
+            }
+        })?;
         
-        let mut hashes = self.hashes.write().unwrap();
-        if !hashes.contains_key(uname) { return Err(DataError::NoSuchUser); }
-        let _ = hashes.insert(uname.to_string(), hash);
+        let (f, tmp) = open_for_atomic_write(recovered)?;
+        let mut w = csv::WriterBuilder::new().has_headers(false).from_writer(Summing::new(f));
+        let mut result = w.write_record(PWD_FILE_HEADERS);
+        for urw in rows.iter() {
+            if result.is_err() { break; }
+            result = w.serialize(urw);
+        }
+        if let Err(e) = result {
+            return Err(abort_atomic_write(&tmp, FileError::from_csv(recovered, Op::Write, &e)));
+        }
+        match w.into_inner() {
+            Ok(w) => {
+                let (f, recovered_sum) = w.finish(rows.len());
+                commit_atomic_write(f, &tmp, recovered)?;
+                write_sum(recovered, recovered_sum)?;
+            },
+            Err(e) => {
+                return Err(abort_atomic_write(&tmp, FileError::from_io(recovered, Op::Write, e.error())));
+            },
+        }
         
-        return Ok(());
+        let lost = report.issues.into_iter()
+            .filter(|issue| matches!(issue, ValidationIssue::Malformed { .. }))
+            .collect();
+        return Ok(RecoveryReport {
+            path: PathBuf::from(recovered),
+            recovered: rows.len(),
+            truncated,
+            lost,
+            expected: sum.map(|sum| sum.rows),
+            checksum_matches,
+        });
     }
     
     /**
-    Checks whether the given password/salt combination is correct for
-    the given user. This is the meat, here.
-        
-    Returns an error if the password is bad or the user doesn't exist.
+    Returns the problems found in the file when the database was opened
+    (rows that were skipped, and users with more than one row), so they
+    can be located and fixed. This is empty for a database created with
+    `.new()`.
     */
-    pub fn check_password(
-        &self,
-        uname: &str,
-        password: &str,
-        salt: &[u8]
-    ) -> Result<(), DataError> {
-        
-        let hash = hash_with_salt(password, salt);
-        
-        let hashes = self.hashes.read().unwrap();
-        match hashes.get(uname) {
-            None => Err(DataError::NoSuchUser),
-            Some(h) => {
-                if *h == hash {
-                    Ok(())
-                } else {
-                    Err(DataError::BadPassword)
-                }
-            },
+    pub fn load_report(&self) -> &ValidationReport { &self.load_report }
+    
+    /** Adds any weaknesses in this database's configuration to `findings`. */
+    pub(crate) fn audit(&self, findings: &mut Vec<Finding>) {
+        if self.upolicy == UsernamePolicy::default() {
+            findings.push(Finding::NoUsernamePolicy);
         }
+        if self.pepper.is_empty() {
+            findings.push(Finding::NoPepper);
+        }
+        if validate::is_world_readable(&self.ufile) {
+            findings.push(Finding::WorldReadable { path: self.ufile.clone() });
+        }
+    }
+    
+    /** Fills in the password database's part of a `StatusReport`. */
+    pub(crate) fn status(&self, report: &mut StatusReport) {
+        report.users = self.row_count();
+        report.pwd_file = self.ufile.clone();
+        report.pwd_dirty = self.is_dirty();
+        report.max_users = self.umax;
+        report.write_through = self.write_through;
+    }
+    
+    pub(crate) fn duplicate_events(&self) -> Vec<AuthEvent> {
+        self.load_report.duplicate_events()
     }
     
     /**
-    Check whether the supplied user name is in the database.
+    Limit the number of users the database will hold. The default, `None`,
+    is unlimited.
     */
-    pub fn user_exists(&self, uname: &str) -> Result<(), DataError> {
-        let hashes = self.hashes.read().unwrap();
-        match hashes.get(uname) {
-            None => Err(DataError::NoSuchUser),
-            Some(_) => Ok(()),
-        }
-    }
+    pub fn max_users(&mut self, max: Option<usize>) { self.umax = max; }
     
     /**
-    Returns whether the in-memory database is "dirty", that is, whether it's
-    out of sync with the persistent data on disk.
+    Limit how big (in bytes) the password file may grow: adding a user that
+    would take it over returns `DataError::CapacityExceeded` rather than
+    growing it further. The default, `None`, is unlimited.
     
-    If this function returns `true`, you must call `.save()` before the
-    `PwdAuth` drops in order to ensure the data persists.
+    The size is that of the .csv data (see `.estimated_file_size()`), and
+    changes to existing users (longer hashes, new email addresses) aren't
+    checked, so the file can end up a little over the limit.
     */
-    pub fn is_dirty(&self) -> bool {
-        let dirty = self.udirty.read().unwrap();
-        return *dirty;
+    pub fn max_file_size(&mut self, max: Option<u64>) {
+        let size = self.estimated_file_size();
+        self.ubytes.set(max, size);
     }
     
     /**
-    Writes the current state of the database to disk, marking the database
-    as no longer dirty.
+    Returns how many bytes `.save()` would write (the .csv data, that is),
+    for keeping an eye on growth. This serializes the whole database, so
+    it takes about as long as saving, without touching the disk.
     */
-    pub fn save(&mut self) -> Result<(), FileError> {
-        /* We secure the _write_ lock here to ensure multiple threads aren't
-           writing to the file simultaneously. */
-        let hashes = self.hashes.write().unwrap();
-        let f = open_for_write(&(self.ufile))?;
-        let mut w = csv::Writer::from_writer(f);
-        if let Err(e) = w.write_record(&PWD_FILE_HEADERS) {
-            let estr = format!("{}: {}", &(self.ufile).to_string_lossy(), &e);
-            return Err(FileError::Write(estr));
-        }
-        for (uname, hash) in hashes.iter() {
-            let hash_hex = hash.to_hex();
-            let record: [&str; 2] = [uname, &hash_hex];
-            if let Err(e) = w.write_record(&record) {
-                let estr = format!("{}: {}", &(self.ufile).to_string_lossy(), &e);
-                return Err(FileError::Write(estr));
-            }
-        }
-        
-        let mut dirty = self.udirty.write().unwrap();
-        *dirty = false;
-        
+    pub fn estimated_file_size(&self) -> u64 {
+        let users = self.users.read();
+        users_size(&self.ufile, &users)
+    }
+    
+    /** Returns how many users there are. */
+    pub fn row_count(&self) -> usize { self.users.read().len() }
+    
+    /**
+    Set the policy that user names must satisfy to be added with
+    `.add_user()`. Users already in the database are unaffected.
+    */
+    pub fn username_policy(&mut self, policy: UsernamePolicy) { self.upolicy = policy; }
+    
+    /**
+    Set the caps on user name and password lengths (see `InputLimits` for
+    the defaults). Everything that takes a password, and everything that
+    adds a user, returns `DataError::InputTooLong` if either is too long.
+    */
+    pub fn input_limits(&mut self, limits: InputLimits) { self.limits = limits; }
+    
+    /**
+    Set a "pepper": a secret mixed into every password hash along with
+    each password's salt. Unlike the salt, it's never stored in the
+    password file, so a stolen copy of the file alone is useless for
+    guessing passwords.
+    
+    The pepper is retrieved from `provider` once, right away. All the
+    passwords in a database must be hashed with the same pepper (or none),
+    so this should be set before any passwords are added or checked, and
+    never changed afterward.
+    */
+    pub fn pepper(&mut self, provider: &dyn SecretProvider) -> io::Result<()> {
+        self.pepper = Secret::from_provider(provider)?;
         return Ok(());
     }
-}
-
-/** Hashes the given password with the supplied salt data. */
-fn hash_with_salt(pwd: &str, salt: &[u8]) -> Hash {
-    let mut hasher = Hasher::new();
-    hasher.update(pwd.as_bytes());
-    hasher.update(salt);
-    hasher.finalize()
-}
\ No newline at end of file
+    
+    pub(crate) fn set_pepper(&mut self, pepper: Secret) { self.pepper = pepper; }
+    
+    /**
+    Replace the password hash function (the default is `Blake3Hasher`).
+    
+    Like the pepper, this must be set before any passwords are added or
+    checked, and never changed afterward: passwords hashed by one
+    `PasswordHasher` won't verify with another.
+    */
+    pub fn password_hasher<H>(&mut self, hasher: H)
+    where H: PasswordHasher + 'static
+    {
+        self.hasher = HashFn::new(hasher)
+            .normalized(self.hasher.normalizes())
+            .encoded(self.hasher.encoding());
+    }
+    
+    pub(crate) fn set_hasher(&mut self, hasher: HashFn) { self.hasher = hasher; }
+    
+    /**
+    Turn Unicode normalization of passwords on or off (it is off by
+    default).
+    
+    When it's on, passwords are converted to Unicode Normalization Form
+    KC before they're hashed or checked, so a password typed with a
+    composed "é" on one keyboard matches the same password typed as "e"
+    plus a combining accent on another (and full-width letters match
+    ordinary ones, and so on). ASCII passwords are unaffected.
+    
+    Like the pepper, this should be set before any passwords are added:
+    turning it on for an existing database stops any stored passwords
+    that weren't already normalized from checking out.
+    */
+    pub fn normalize_passwords(&mut self, on: bool) {
+        self.hasher = self.hasher.clone().normalized(on);
+    }
+    
+    /**
+    Choose how the default hasher's digests are written in the password
+    file (the default is `HashEncoding::Hex`), for other tools that read
+    or write it. This has no effect with any other `PasswordHasher`.
+    
+    Hashes in every encoding are checked correctly whatever this is set
+    to, so it can be changed at any time; users whose hashes are in
+    another encoding are flagged with `needs_rehash` when they log in, so
+    `.rehash_user()` moves them onto this one.
+    */
+    pub fn hash_encoding(&mut self, encoding: HashEncoding) {
+        self.hasher = self.hasher.clone().encoded(encoding);
+    }
+    
+    /**
+    Choose how salts stored with `.set_salt()` (or `.import_user()`) from
+    now on are written (the default is `SaltEncoding::Base64`). Salts
+    already stored are read correctly either way.
+    */
+    pub fn salt_encoding(&mut self, encoding: SaltEncoding) { self.salt_encoding = encoding; }
+    
+    /**
+    Set how long a `.password_challenge()` may be answered for (the default
+    is two minutes).
+    */
+    pub fn challenge_life(&mut self, life: Duration) { self.challenge_life = life; }
+    
+    /**
+    Check passwords with the given `CredentialChecker` (an LDAP server,
+    say) instead of against the stored hashes.
+    
+    Users must still be added to (and can still be deleted from) this
+    database, and the other methods work as usual; but the passwords
+    stored here are ignored when checking, as are the salt and pepper, and
+    needn't be the users' real ones.
+    
+    So that checking a password takes about as long whether or not there's
+    such a user, the checker is also asked about names that aren't in this
+    database (its answer is ignored, and the login still fails). A checker
+    that answers faster for users it doesn't know will still give them away.
+    */
+    pub fn credential_checker<C>(&mut self, checker: C)
+    where C: CredentialChecker + 'static
+    {
+        self.checker = Some(CheckerFn::new(checker));
+    }
+    
+    pub(crate) fn set_checker(&mut self, checker: CheckerFn) { self.checker = Some(checker); }
+    
+    /**
+    Turn "write-through" mode on or off (it is off by default).
+    
+    In write-through mode, every method that changes the database saves
+    it to disk before returning, so there's never any need to think about
+    whether it's dirty. If that save fails, the method returns
+    `DataError::SaveFailed` (the change is still made in memory, and the
+    database is left dirty).
+    
+    The cost is that _every_ change rewrites (and syncs) the entire file,
+    so each change takes time proportional to the number of users, plus
+    the latency of an `fsync()`; this is fine for a few hundred users
+    whose data rarely changes, but batching changes and calling `.save()`
+    explicitly scales much better.
+    */
+    pub fn write_through(&mut self, on: bool) { self.write_through = on; }
+    
+    pub(crate) fn is_write_through(&self) -> bool { self.write_through }
+    
+    /**
+    Set the order in which `.save()` (and `.save_to()`) write users; see
+    `SaveOrder`. The default is `SaveOrder::Unsorted`.
+    */
+    pub fn save_order(&mut self, order: SaveOrder) { self.uorder = order; }
+    
+    /**
+    Turn "uniform errors" mode on or off (it is off by default).
+    
+    In this mode, `.check_password()` (and the methods built on it) return
+    `DataError::BadCredentials` both when the user doesn't exist and when
+    the password is wrong, so the error doesn't reveal which user names
+    exist. (The time taken doesn't either, in any mode; see
+    `.check_password()`.)
+    */
+    pub fn uniform_errors(&mut self, on: bool) { self.uniform_errors = on; }
+    
+    /**
+    Turn case-insensitive mode on or off (it is off by default).
+    
+    In this mode user names are still stored (and must still be given)
+    exactly as they were added, but `.add_user()` returns
+    `DataError::UserExists` if there's already a user whose name differs
+    from the new one only in case, so "Alice" can't be added alongside
+    "alice". An index of lowercased names is kept so this doesn't mean
+    scanning every user.
+    */
+    pub fn case_insensitive(&mut self, on: bool) {
+        self.folded = if on {
+            let users = self.users.read();
+            let mut folded: HashMap<String, usize> = HashMap::with_capacity(users.len());
+            for uname in users.keys() { *folded.entry(uname.to_lowercase()).or_default() += 1; }
+            Some(RwLock::new(folded))
+        } else {
+            None
+        };
+    }
+    
+    /**
+    Register a function to be called whenever a `SecurityEvent` occurs
+    (a password change or the deletion of an admin), replacing any
+    previously-registered handler.
+    
+    The handler is called after the database's internal locks have been
+    released, so it's safe for it to call methods on this `PwdAuth`.
+    */
+    pub fn on_security_event<F>(&mut self, handler: F)
+    where F: Fn(&SecurityEvent) + Send + Sync + 'static
+    {
+        self.security = EventHook::new(handler);
+    }
+    
+    pub(crate) fn set_security_hook(&mut self, hook: EventHook<SecurityEvent>) {
+        self.security = hook;
+    }
+    
+    /** Passes the event to the security event handler, if there is one. */
+    pub(crate) fn emit_security(&self, event: SecurityEvent) { self.security.emit(event); }
+    
+    /**
+    Add a user with the given name and password, with the password hash
+    salted by the supplied salt data.
+        
+    Marks the database as "dirty".
+        
+    Returns `Err()` when a user with the given name (or, in case-insensitive
+    mode, a name differing only in case) already exists, when the name
+    doesn't satisfy the `UsernamePolicy`, or when adding the user would
+    exceed the limit set by `.max_users()`.
+    */
+    pub fn add_user(
+        &self,
+        uname: &str,
+        password: &str,
+        salt: &[u8]
+    ) -> Result<(), DataError> {
+        self.insert_user(uname, password, salt)?;
+        return self.save_if_write_through();
+    }
+    
+    /** Does the work of `.add_user()`, except for saving. */
+    fn insert_user(&self, uname: &str, password: &str, salt: &[u8]) -> Result<(), DataError> {
+        self.writable()?;
+        self.limits.check(uname, password)?;
+        self.upolicy.check(uname)?;
+        let hash = self.hasher.hash(password, &self.peppered(salt));
+        
+        let mut users = self.users.write();
+        if users.contains_key(uname) {
+            return Err(DataError::UserExists { uname: uname.to_string() });
+        }
+        let umeta = UserMeta {
+            hash, admin: false, uid: unused_uid(&users), class: None, email: None, salt: None
+        };
+        if let Some(max) = self.umax {
+            if users.len() >= max { return Err(DataError::CapacityExceeded); }
+        }
+        if !self.ubytes.admit(size::row_size(&umeta.to_rw(uname)), || users_size(&self.ufile, &users)) {
+            return Err(DataError::CapacityExceeded);
+        }
+        /* Lock order is always users first, then folded. */
+        if let Some(folded) = &self.folded {
+            let mut folded = folded.write();
+            let count = folded.entry(uname.to_lowercase()).or_default();
+            if *count > 0 { return Err(DataError::UserExists { uname: uname.to_string() }); }
+            *count = 1;
+        }
+        let _ = users.insert(uname.to_string(), umeta);
+        
+        self.mark_dirty();
+        return Ok(());
+    }
+    
+    /**
+    Adds users from a .csv file of plaintext passwords, with `uname` and
+    `password` columns (and a `salt` column, with `SaltPolicy::Column`),
+    then shreds the file: it's overwritten with zeros and deleted, so the
+    passwords don't linger on disk. This is for provisioning a new system
+    from a spreadsheet, say.
+    
+    Users that can't be added (because they already exist, or their names
+    don't satisfy the `UsernamePolicy`) are skipped, and listed in the
+    returned `ImportReport`; the file is shredded anyway.
+    
+    Marks the database as "dirty" (and, in write-through mode, saves it
+    once at the end) if any users are added.
+    
+    Returns an error, without adding anyone or touching the file, if it
+    can't be read or any row is malformed; or if the file can't be
+    shredded after the users are added. Once the users are added, the file
+    is shredded even if saving the database then fails (in which case the
+    save's error is returned, and the users are only in memory).
+    */
+    pub fn import_plaintext_csv(
+        &self,
+        path: impl AsRef<Path>,
+        salt_policy: SaltPolicy
+    ) -> Result<ImportReport, FileError> {
+        let path = path.as_ref();
+        if self.read_only {
+            /* Leave the plaintext file alone, since none of it can be imported. */
+            return Err(FileError::new(path, Op::Read, ErrorKind::PermissionDenied,
+                "the password database is read-only".to_owned()));
+        }
+        let f = open_for_read(path)?;
+        let mut rows: Vec<PlaintextRow> = Vec::new();
+        for row in csv::Reader::from_reader(f).deserialize() {
+            let row: PlaintextRow = match row {
+                Ok(row) => row,
+                Err(e) => { return Err(FileError::from_csv(path, Op::Read, &e)); },
+            };
+            if salt_policy == SaltPolicy::Column && row.salt.is_none() {
+                return Err(FileError::new(path, Op::Read, ErrorKind::InvalidData,
+                    format!("no salt for user \"{}\"", &row.uname)));
+            }
+            rows.push(row);
+        }
+        
+        let mut report = ImportReport::default();
+        for row in rows.iter() {
+            let salt: Vec<u8> = match &salt_policy {
+                SaltPolicy::Fixed(salt) => salt.clone(),
+                SaltPolicy::Column => row.salt.clone().unwrap_or_default().into_bytes(),
+                SaltPolicy::Random => rand::thread_rng()
+                    .sample_iter(&Alphanumeric)
+                    .take(IMPORT_SALT_LENGTH)
+                    .collect(),
+            };
+            match self.insert_user(&row.uname, &row.password, &salt) {
+                Ok(()) => {
+                    report.added.push(row.uname.clone());
+                    if salt_policy == SaltPolicy::Random {
+                        report.salts.push((row.uname.clone(), salt));
+                    }
+                },
+                Err(e) => { report.skipped.push((row.uname.clone(), e)); },
+            }
+        }
+        drop(rows);
+        
+        /* The plaintext goes whether or not the save below succeeds. */
+        let shredded = shred(path);
+        if self.write_through && self.is_dirty() { self.save()?; }
+        if let Err(e) = shredded {
+            return Err(FileError::from_io(path, Op::Write, &e));
+        }
+        return Ok(report);
+    }
+    
+    /**
+    Creates the first user of a fresh database: an administrator with the
+    given name and a randomly-generated password (salted by the supplied
+    salt data), which is returned. This is the only time the password is
+    available, so it should be shown to whoever is setting up the system
+    (and changed by them soon after).
+    
+    Marks the database as "dirty".
+    
+    Returns `DataError::UserExists` (without changing anything) if the
+    database already has any users, or `DataError::InvalidUsername` if the
+    name doesn't satisfy the `UsernamePolicy`.
+    */
+    pub fn bootstrap_admin(&self, uname: &str, salt: &[u8]) -> Result<String, DataError> {
+        self.writable()?;
+        self.limits.check(uname, "")?;
+        self.upolicy.check(uname)?;
+        let password: String = rand::thread_rng()
+            .sample_iter(&Alphanumeric)
+            .take(BOOTSTRAP_PASSWORD_LENGTH)
+            .map(char::from)
+            .collect();
+        let hash = self.hasher.hash(&password, &self.peppered(salt));
+        
+        {
+            let mut users = self.users.write();
+            if !users.is_empty() {
+                return Err(DataError::UserExists { uname: uname.to_string() });
+            }
+            if let Some(folded) = &self.folded {
+                let _ = folded.write().insert(uname.to_lowercase(), 1);
+            }
+            let umeta = UserMeta {
+                hash, admin: true, uid: new_uid(), class: None, email: None, salt: None
+            };
+            let _ = users.insert(uname.to_string(), umeta);
+            
+            self.mark_dirty();
+        }
+        
+        self.save_if_write_through()?;
+        return Ok(password);
+    }
+    
+    /**
+    Delete the user with the given name.
+    
+    Marks the database as "dirty", and emits a `SecurityEvent::AdminDeleted`
+    if the user was an admin.
+        
+    Returns `Err()` if the user doesn't exist.
+    */
+    pub fn delete_user(&self, uname: &str) -> Result<(), DataError> {
+        self.writable()?;
+        let was_admin = {
+            let mut users = self.users.write();
+            let umeta = match users.remove(uname) {
+                None => { return Err(DataError::NoSuchUser); },
+                Some(umeta) => umeta,
+            };
+            if let Some(folded) = &self.folded {
+                let mut folded = folded.write();
+                let key = uname.to_lowercase();
+                match folded.get_mut(&key) {
+                    Some(count) if *count > 1 => { *count -= 1; },
+                    _ => { let _ = folded.remove(&key); },
+                }
+            }
+            if let Some(email) = &umeta.email {
+                let _ = self.emails.write().remove(&fold_email(email));
+            }
+            let _ = self.challenges.lock().remove(uname);
+            self.mark_dirty();
+            umeta.admin
+        };
+        if was_admin {
+            self.security.emit(SecurityEvent::AdminDeleted { uname: uname.to_string() });
+        }
+        
+        return self.save_if_write_through();
+    }
+    
+    /**
+    Changes the password of the given user.
+    
+    Marks the database as "dirty", and emits a
+    `SecurityEvent::PasswordChanged`. Concurrent changes to the same user's
+    password happen one after the other (and their events are emitted in
+    that order); changes to different users' passwords proceed in parallel.
+        
+    Returns `Err()` if the user doesn't exist.
+    */
+    pub fn change_password(
+        &self,
+        uname: &str,
+        password: &str,
+        salt: &[u8]
+    ) -> Result<(), DataError> {
+        
+        self.writable()?;
+        self.limits.check(uname, password)?;
+        /* So that concurrent changes' events come out in the same order as
+           their effects, and the last one reported is the one that stuck. */
+        let _ulock = self.ulocks.lock(uname);
+        let hash = self.hasher.hash(password, &self.peppered(salt));
+        
+        {
+            let mut users = self.users.write();
+            match users.get_mut(uname) {
+                None => { return Err(DataError::NoSuchUser); },
+                Some(umeta) => { umeta.hash = hash; },
+            }
+            self.mark_dirty();
+        }
+        self.security.emit(SecurityEvent::PasswordChanged { uname: uname.to_string() });
+        
+        return self.save_if_write_through();
+    }
+    
+    /**
+    Checks whether the given password/salt combination is correct for
+    the given user. This is the meat, here.
+        
+    Returns an `AuthOk` describing the user if so, or an error if the
+    password is bad or the user doesn't exist.
+    
+    If the user doesn't exist, the password is still hashed (and checked
+    against a dummy hash), or with a `.credential_checker()` set, still
+    checked by it, so that this takes about as long as it does for a user
+    who does.
+    */
+    pub fn check_password(
+        &self,
+        uname: &str,
+        password: &str,
+        salt: &[u8]
+    ) -> Result<AuthOk, DataError> {
+        
+        self.limits.check(uname, password)?;
+        let found = {
+            let users = self.users.read();
+            stored_hash(&users, uname)
+        };
+        self.verify(uname, found, password, salt)
+    }
+    
+    /**
+    Like `.check_password()`, but the user can be identified by their
+    email address (see `.set_email()`) instead of their name, as many
+    login forms allow. The returned `AuthOk` has the user's name.
+    
+    As with `.check_password()`, this takes about as long whether or not
+    there's such a user.
+    */
+    pub fn check_password_by(
+        &self,
+        id: Identifier<'_>,
+        password: &str,
+        salt: &[u8]
+    ) -> Result<AuthOk, DataError> {
+        let given = match id { Identifier::Username(s) | Identifier::Email(s) => s };
+        self.limits.check(given, password)?;
+        let found = {
+            let users = self.users.read();
+            match self.resolve(&users, id) {
+                Some(uname) => stored_hash(&users, &uname),
+                None => None,
+            }
+        };
+        self.verify(given, found, password, salt)
+    }
+    
+    /**
+    Returns the name of the user with the given identifier.
+    
+    Returns `DataError::NoSuchUser` if there isn't one.
+    */
+    pub fn find_user(&self, id: Identifier<'_>) -> Result<String, DataError> {
+        let users = self.users.read();
+        self.resolve(&users, id).ok_or(DataError::NoSuchUser)
+    }
+    
+    /**
+    Returns the name of the user with the given identifier, if there is
+    one, from the (locked) map of users `users`.
+    */
+    fn resolve(&self, users: &HashMap<String, UserMeta>, id: Identifier<'_>) -> Option<String> {
+        match id {
+            Identifier::Username(uname) if users.contains_key(uname) => Some(uname.to_string()),
+            Identifier::Username(_) => None,
+            /* Lock order is always users first, then emails. */
+            Identifier::Email(email) => self.emails.read().get(&fold_email(email)).cloned(),
+        }
+    }
+    
+    /**
+    Returns the names of the users whose stored hashes the hasher says
+    should be replaced (see `PasswordHasher::needs_rehash()`), sorted.
+    Since hashes can only be replaced when the password is known, these
+    are the users who haven't logged in since the hasher was changed.
+    */
+    pub fn rehash_needed(&self) -> Vec<String> {
+        let users = self.users.read();
+        let mut unames: Vec<String> = users.iter()
+            .filter(|(_, umeta)| self.hasher.needs_rehash(&umeta.hash))
+            .map(|(uname, _)| uname.clone())
+            .collect();
+        unames.sort_unstable();
+        return unames;
+    }
+    
+    /**
+    Checks the user's password and, if it's right and their stored hash
+    needs replacing (see `PasswordHasher::needs_rehash()`), replaces it
+    with a fresh one from the current hasher. Returns whether it did.
+    
+    This is meant to be called at login, when `.check_password()` returns
+    an `AuthOk` with `needs_rehash` set, so that a database is moved onto
+    a new hash scheme (from plain BLAKE3 to a slow KDF, say) user by user,
+    without anyone having to reset their password. (The hasher must be
+    able to verify the old hashes as well as the new ones for that to
+    work.) Progress can be followed with `.hash_schemes()`.
+    
+    Returns an error as `.check_password()` does if the password is wrong.
+    */
+    pub fn rehash_user(&self, uname: &str, password: &str, salt: &[u8])
+    -> Result<bool, DataError> {
+        self.writable()?;
+        self.limits.check(uname, password)?;
+        let found = {
+            let users = self.users.read();
+            stored_hash(&users, uname)
+        };
+        let old = found.as_ref().map(|(_, hash)| hash.clone());
+        if !self.verify(uname, found, password, salt)?.needs_rehash {
+            return Ok(false);
+        }
+        
+        let hash = self.hasher.hash(password, &self.peppered(salt));
+        {
+            let mut users = self.users.write();
+            match users.get_mut(uname) {
+                None => { return Err(DataError::NoSuchUser); },
+                Some(umeta) => {
+                    /* Leave it alone if the password was changed meanwhile. */
+                    if Some(&umeta.hash) != old.as_ref() { return Ok(false); }
+                    umeta.hash = hash;
+                },
+            }
+            self.mark_dirty();
+        }
+        
+        self.save_if_write_through()?;
+        return Ok(true);
+    }
+    
+    /**
+    Returns how many users' passwords are hashed with each scheme (as
+    named by `PasswordHasher::scheme()`), to follow the progress of a
+    migration from one to another (see `.rehash_user()`).
+    */
+    pub fn hash_schemes(&self) -> HashMap<String, usize> {
+        let users = self.users.read();
+        let mut counts: HashMap<String, usize> = HashMap::new();
+        for umeta in users.values() {
+            *counts.entry(self.hasher.scheme(&umeta.hash)).or_default() += 1;
+        }
+        return counts;
+    }
+    
+    /**
+    Like `.check_password()`, but gives up and returns
+    `DataError::LockTimeout` if the database can't be read within `timeout`
+    (because another thread is writing to it), rather than blocking
+    indefinitely.
+    */
+    pub fn try_check_password(
+        &self,
+        uname: &str,
+        password: &str,
+        salt: &[u8],
+        timeout: Duration
+    ) -> Result<AuthOk, DataError> {
+        self.limits.check(uname, password)?;
+        let found = match self.users.try_read_for(timeout) {
+            None => { return Err(DataError::LockTimeout); },
+            Some(users) => stored_hash(&users, uname),
+        };
+        self.verify(uname, found, password, salt)
+    }
+    
+    /**
+    Check whether the supplied user name is in the database.
+    */
+    pub fn user_exists(&self, uname: &str) -> Result<(), DataError> {
+        let users = self.users.read();
+        match users.get(uname) {
+            None => Err(DataError::NoSuchUser),
+            Some(_) => Ok(()),
+        }
+    }
+    
+    /**
+    Returns the given user's ID: a number assigned (at random, and unique
+    within this database) when the user was added, which never changes.
+    If a user is deleted and another added later with the same name, the
+    new user gets a new ID, so applications can refer to users by ID in
+    their own data without mistaking one for the other.
+    
+    Returns `Err()` if the user doesn't exist.
+    */
+    pub fn user_id(&self, uname: &str) -> Result<u64, DataError> {
+        let users = self.users.read();
+        match users.get(uname) {
+            None => Err(DataError::NoSuchUser),
+            Some(umeta) => Ok(umeta.uid),
+        }
+    }
+    
+    /**
+    Returns the name of the user with the given ID (see `.user_id()`).
+    This has to look through every user, so it's much slower than the
+    other lookups.
+    
+    Returns `DataError::NoSuchUser` if no user has that ID.
+    */
+    pub fn user_by_id(&self, uid: u64) -> Result<String, DataError> {
+        let users = self.users.read();
+        match users.iter().find(|(_, umeta)| umeta.uid == uid) {
+            None => Err(DataError::NoSuchUser),
+            Some((uname, _)) => Ok(uname.clone()),
+        }
+    }
+    
+    /**
+    Grant or revoke administrator privileges for the given user.
+    
+    Marks the database as "dirty".
+    
+    Returns `Err()` if the user doesn't exist.
+    */
+    pub fn set_admin(&self, uname: &str, is_admin: bool) -> Result<(), DataError> {
+        self.writable()?;
+        {
+            let mut users = self.users.write();
+            match users.get_mut(uname) {
+                None => { return Err(DataError::NoSuchUser); },
+                Some(umeta) => { umeta.admin = is_admin; },
+            }
+            self.mark_dirty();
+        }
+        
+        return self.save_if_write_through();
+    }
+    
+    /** Returns the names of all the users, in order. */
+    pub fn usernames(&self) -> Vec<String> {
+        let users = self.users.read();
+        let mut unames: Vec<String> = users.keys().cloned().collect();
+        unames.sort_unstable();
+        return unames;
+    }
+    
+    /**
+    Returns whether the given user has administrator privileges.
+    
+    Returns `Err()` if the user doesn't exist.
+    */
+    pub fn is_admin(&self, uname: &str) -> Result<bool, DataError> {
+        let users = self.users.read();
+        match users.get(uname) {
+            None => Err(DataError::NoSuchUser),
+            Some(umeta) => Ok(umeta.admin),
+        }
+    }
+    
+    /**
+    Assigns the given user to a class (like `"staff"` or `"kiosk"`), or
+    removes them from any class if `class` is `None`. What a class means
+    is up to the application; `BothAuth` uses it to choose how long the
+    user's keys last (see `BothAuth::class_life()`).
+    
+    Marks the database as "dirty".
+    
+    Returns `Err()` if the user doesn't exist.
+    */
+    pub fn set_class(&self, uname: &str, class: Option<&str>) -> Result<(), DataError> {
+        self.writable()?;
+        {
+            let mut users = self.users.write();
+            match users.get_mut(uname) {
+                None => { return Err(DataError::NoSuchUser); },
+                Some(umeta) => { umeta.class = class.map(|c| c.to_string()); },
+            }
+            self.mark_dirty();
+        }
+        
+        return self.save_if_write_through();
+    }
+    
+    /**
+    Returns the class the given user belongs to, if any.
+    
+    Returns `Err()` if the user doesn't exist.
+    */
+    pub fn class_of(&self, uname: &str) -> Result<Option<String>, DataError> {
+        let users = self.users.read();
+        match users.get(uname) {
+            None => Err(DataError::NoSuchUser),
+            Some(umeta) => Ok(umeta.class.clone()),
+        }
+    }
+    
+    /**
+    Sets the given user's email address, so they can be found (and log
+    in) by it as well as by name (see `Identifier`), or removes it if
+    `email` is `None` (or empty). Addresses are kept as given, but
+    compared ignoring case; they aren't otherwise checked.
+    
+    Marks the database as "dirty".
+    
+    Returns `DataError::NoSuchUser` if the user doesn't exist, or
+    `DataError::UserExists` (naming them) if another user has the same
+    address.
+    */
+    pub fn set_email(&self, uname: &str, email: Option<&str>) -> Result<(), DataError> {
+        self.writable()?;
+        let email = email.filter(|e| !e.is_empty());
+        {
+            let mut users = self.users.write();
+            let umeta = match users.get_mut(uname) {
+                None => { return Err(DataError::NoSuchUser); },
+                Some(umeta) => umeta,
+            };
+            /* Lock order is always users first, then emails. */
+            let mut emails = self.emails.write();
+            if let Some(email) = email {
+                match emails.get(&fold_email(email)) {
+                    Some(owner) if owner != uname => {
+                        return Err(DataError::UserExists { uname: owner.clone() });
+                    },
+                    _ => {},
+                }
+            }
+            if let Some(old) = &umeta.email {
+                let _ = emails.remove(&fold_email(old));
+            }
+            if let Some(email) = email {
+                let _ = emails.insert(fold_email(email), uname.to_string());
+            }
+            umeta.email = email.map(|e| e.to_string());
+            self.mark_dirty();
+        }
+        
+        return self.save_if_write_through();
+    }
+    
+    /**
+    Returns the given user's email address, if they have one.
+    
+    Returns `Err()` if the user doesn't exist.
+    */
+    pub fn email_of(&self, uname: &str) -> Result<Option<String>, DataError> {
+        let users = self.users.read();
+        match users.get(uname) {
+            None => Err(DataError::NoSuchUser),
+            Some(umeta) => Ok(umeta.email.clone()),
+        }
+    }
+    
+    /**
+    Stores the given user's salt in the password file, or removes it if
+    `salt` is `None`, for applications that keep a salt per user and
+    would rather not store them somewhere else. (It's still up to the
+    application to pass it with the user's password.)
+    
+    Marks the database as "dirty".
+    
+    Returns `Err()` if the user doesn't exist.
+    */
+    pub fn set_salt(&self, uname: &str, salt: Option<&[u8]>) -> Result<(), DataError> {
+        self.writable()?;
+        {
+            let mut users = self.users.write();
+            match users.get_mut(uname) {
+                None => { return Err(DataError::NoSuchUser); },
+                Some(umeta) => {
+                    umeta.salt = salt.map(|salt| self.salt_encoding.encode(salt));
+                },
+            }
+            self.mark_dirty();
+        }
+        
+        return self.save_if_write_through();
+    }
+    
+    /**
+    Returns the given user's stored salt (see `.set_salt()`), if they have
+    one, for handing to something that hashes passwords before they're
+    sent, say.
+    
+    Returns `Err()` if the user doesn't exist. (An application that
+    mustn't reveal which users exist to whoever asks for salts can make up
+    a salt for unknown users, say by hashing their name with a secret.)
+    */
+    pub fn salt_of(&self, uname: &str) -> Result<Option<Vec<u8>>, DataError> {
+        let users = self.users.read();
+        match users.get(uname) {
+            None => Err(DataError::NoSuchUser),
+            Some(umeta) => Ok(umeta.salt.as_deref().and_then(SaltEncoding::decode)),
+        }
+    }
+    
+    /**
+    Begins a challenge-response login, in which the client proves it knows
+    the user's password without sending it, for small apps on a LAN that
+    can't use HTTPS. Returns the user's salt (their stored salt, see
+    `.set_salt()`, or an empty one if they have none) and a fresh random
+    nonce, replacing any challenge they had outstanding. The client
+    computes
+    
+    ```text
+    digest   = BLAKE3(password || salt)
+    response = BLAKE3_keyed(key = digest, nonce)
+    ```
+    
+    (with the password normalized first, if `.normalize_passwords()` is on)
+    and sends the 32-byte response to `.verify_challenge_response()`
+    within the challenge life.
+    
+    This keeps the password away from eavesdroppers, but not from anyone
+    who can change the traffic, and it makes each stored hash as good as
+    the password for logging in this way, so the password file must be
+    kept as secret as the passwords themselves.
+    
+    Returns `DataError::Unsupported` unless passwords are hashed by the
+    default `Blake3Hasher` with no pepper and no `CredentialChecker` (since
+    the client has to compute the stored hash), or `DataError::NoSuchUser`
+    if the user doesn't exist. (In uniform errors mode, an unknown user
+    gets an empty salt and a nonce that can't be answered instead.)
+    */
+    pub fn password_challenge(
+        &self,
+        uname: &str
+    ) -> Result<(Vec<u8>, [u8; CHALLENGE_NONCE_LENGTH]), DataError> {
+        self.limits.check(uname, "")?;
+        self.check_challengeable()?;
+        let salt = {
+            let users = self.users.read();
+            users.get(uname)
+                .map(|umeta| umeta.salt.as_deref().and_then(SaltEncoding::decode).unwrap_or_default())
+        };
+        let mut nonce = [0u8; CHALLENGE_NONCE_LENGTH];
+        rand::thread_rng().fill(&mut nonce);
+        let salt = match salt {
+            Some(salt) => salt,
+            None if self.uniform_errors => { return Ok((Vec::new(), nonce)); },
+            None => { return Err(DataError::NoSuchUser); },
+        };
+        
+        let now = Instant::now();
+        let mut challenges = self.challenges.lock();
+        /* Forget abandoned challenges, so they can't pile up. */
+        challenges.retain(|_, c| c.expiry > now);
+        let challenge = PasswordChallenge { nonce, expiry: now + self.challenge_life };
+        let _ = challenges.insert(uname.to_string(), challenge);
+        return Ok((salt, nonce));
+    }
+    
+    /**
+    Checks the client's `response` to the user's outstanding challenge,
+    whose `nonce` came from `.password_challenge()`. The challenge is used
+    up either way.
+    
+    Returns `DataError::BadPassword` (or `DataError::BadCredentials`, in
+    uniform errors mode) if the response is wrong, or the user has no
+    unexpired challenge with that nonce; or `DataError::Unsupported` as
+    `.password_challenge()` does.
+    */
+    pub fn verify_challenge_response(
+        &self,
+        uname: &str,
+        nonce: &[u8],
+        response: &[u8]
+    ) -> Result<AuthOk, DataError> {
+        self.limits.check(uname, "")?;
+        self.check_challengeable()?;
+        let wrong = || match self.uniform_errors {
+            true => DataError::BadCredentials,
+            false => DataError::BadPassword,
+        };
+        let challenge = match self.challenges.lock().remove(uname) {
+            Some(c) if c.expiry > Instant::now() && c.nonce[..] == *nonce => c,
+            _ => { return Err(wrong()); },
+        };
+        let digest = {
+            let users = self.users.read();
+            users.get(uname).and_then(|umeta| hasher::blake3_digest(&umeta.hash))
+        };
+        let digest = digest.ok_or_else(wrong)?;
+        let response: [u8; blake3::OUT_LEN] = response.try_into().map_err(|_| wrong())?;
+        
+        /* Hash comparison takes constant time. */
+        match blake3::keyed_hash(&digest, &challenge.nonce) == blake3::Hash::from(response) {
+            true => Ok(AuthOk::new(uname)),
+            false => Err(wrong()),
+        }
+    }
+    
+    /**
+    Returns `DataError::Unsupported` if clients can't compute the stored
+    hashes, so can't answer a `.password_challenge()`.
+    */
+    fn check_challengeable(&self) -> Result<(), DataError> {
+        let usable = self.hasher.is_blake3()
+            && self.pepper.as_bytes().is_empty()
+            && self.checker.is_none();
+        match usable {
+            true => Ok(()),
+            false => Err(DataError::Unsupported),
+        }
+    }
+    
+    /**
+    Like `.check_password()`, but additionally returns
+    `DataError::NotAdmin` if the user doesn't have administrator
+    privileges.
+    */
+    pub fn check_password_admin(
+        &self,
+        uname: &str,
+        password: &str,
+        salt: &[u8]
+    ) -> Result<(), DataError> {
+        self.check_password(uname, password, salt)?;
+        if self.is_admin(uname)? {
+            Ok(())
+        } else {
+            Err(DataError::NotAdmin)
+        }
+    }
+    
+    /** Returns the path of the file this database saves to. */
+    pub fn path(&self) -> &Path { &self.ufile }
+    
+    /**
+    Returns whether the in-memory database is "dirty", that is, whether it's
+    out of sync with the persistent data on disk.
+    
+    If this function returns `true`, you must call `.save()` before the
+    `PwdAuth` drops in order to ensure the data persists.
+    */
+    pub fn is_dirty(&self) -> bool { self.udirty.load(Ordering::Acquire) }
+    
+    /**
+    Returns a number that goes up every time the users change, so a cache
+    of anything derived from them can tell whether it's stale just by
+    comparing it with the number it was built at. It counts from zero when
+    the database is opened, and isn't affected by saving.
+    */
+    pub fn generation(&self) -> u64 { self.ugen.load(Ordering::Acquire) }
+    
+    /**
+    Writes the current state of the database to disk, marking the database
+    as no longer dirty.
+    
+    The data is written to a temporary file which then replaces the
+    original, so a failed save never leaves a partially-written file.
+    */
+    pub fn save(&self) -> Result<(), FileError> {
+        /* We secure the _write_ lock here to ensure multiple threads aren't
+           writing to the file simultaneously. */
+        let users = self.users.write();
+        write_users(&self.ufile, &users, self.uorder)?;
+        
+        self.udirty.store(false, Ordering::Release);
+        
+        return Ok(());
+    }
+    
+    /**
+    Writes the current state of the database to the given path _instead_
+    of the file it was opened from. This does not affect whether the
+    database is dirty, or where `.save()` will write it.
+    */
+    pub fn save_to(&self, path: impl AsRef<Path>) -> Result<(), FileError> {
+        let users = self.users.read();
+        write_users(path.as_ref(), &users, self.uorder)
+    }
+    
+    /**
+    Writes the current state of the database to `w` as uncompressed .csv
+    data, returning it when done. Like `.save_to()`, this does not affect
+    whether the database is dirty.
+    */
+    pub fn save_to_writer<W: Write>(&self, w: W) -> Result<W, FileError> {
+        let users = self.users.read();
+        serialize_users(w, &self.ufile, &users, self.uorder)
+    }
+    
+    /**
+    Writes a read-only snapshot of the users' names, password hashes and
+    admin flags to the file at `path`, for use by a `Verifier`. This
+    doesn't touch the primary file or the dirty flag.
+    */
+    pub fn export_verifier(&self, path: impl AsRef<Path>) -> Result<(), FileError> {
+        let users = self.users.read();
+        let mut rows: Vec<(&str, &str, bool)> = users.iter()
+            .map(|(uname, umeta)| (uname.as_str(), umeta.hash.as_str(), umeta.admin))
+            .collect();
+        rows.sort_unstable_by(|a, b| a.0.cmp(b.0));
+        verifier::write_verifier(path.as_ref(), &rows)
+    }
+    
+    /**
+    Writes a .csv report of the users to the file at `path`, with columns
+    `uname`, `uid`, `admin`, `class`, and `email` (the last two empty for
+    users without them), sorted by name. It holds everything about the
+    users _except_ their password hashes and salts, so it can be handed to
+    support staff who need a list of users without handing them any
+    credentials. This doesn't touch the primary file or the dirty flag.
+    */
+    pub fn export_report(&self, path: impl AsRef<Path>) -> Result<(), FileError> {
+        let path = path.as_ref();
+        let users = self.users.read();
+        let mut unames: Vec<&String> = users.keys().collect();
+        unames.sort_unstable();
+        
+        let (f, tmp) = open_for_atomic_write(path)?;
+        let mut w = csv::Writer::from_writer(f);
+        let mut result = w.write_record(REPORT_HEADERS);
+        for uname in unames.into_iter() {
+            if result.is_err() { break; }
+            let umeta = &users[uname];
+            let uid = umeta.uid.to_string();
+            let admin = if umeta.admin { "true" } else { "false" };
+            let class = umeta.class.as_deref().unwrap_or("");
+            let email = umeta.email.as_deref().unwrap_or("");
+            result = w.write_record([uname.as_str(), &uid, admin, class, email]);
+        }
+        if let Err(e) = result {
+            return Err(abort_atomic_write(&tmp, FileError::from_csv(path, Op::Write, &e)));
+        }
+        match w.into_inner() {
+            Ok(f) => commit_atomic_write(f, &tmp, path),
+            Err(e) => Err(abort_atomic_write(&tmp, FileError::from_io(path, Op::Write, e.error()))),
+        }
+    }
+    
+    /**
+    Returns everything stored about the given user, to be imported into
+    another database with `.import_user()`. This doesn't remove the user.
+    */
+    pub fn export_user(&self, uname: &str) -> Result<UserBundle, DataError> {
+        let users = self.users.read();
+        match users.get(uname) {
+            None => Err(DataError::NoSuchUser),
+            Some(umeta) => Ok(UserBundle {
+                uname: uname.to_string(),
+                hash: umeta.hash.clone(),
+                admin: umeta.admin,
+                uid: umeta.uid,
+                class: umeta.class.clone(),
+                email: umeta.email.clone(),
+                salt: umeta.salt.as_deref().and_then(SaltEncoding::decode),
+            }),
+        }
+    }
+    
+    /**
+    Adds a user exported from another database by `.export_user()`, with
+    the same password hash, admin flag, class, email address, and stored
+    salt. Their password will only check out here if both databases use
+    the same hasher and pepper.
+    
+    The user keeps their uid unless another user here already has it, in
+    which case they get a new one; likewise they keep their email address
+    unless another user here has it, in which case they have none. Fails
+    as `.add_user()` does if the user already exists, the name doesn't
+    satisfy the `UsernamePolicy`, or the database is full.
+    */
+    pub fn import_user(&self, bundle: UserBundle) -> Result<(), DataError> {
+        self.writable()?;
+        self.limits.check(&bundle.uname, "")?;
+        self.upolicy.check(&bundle.uname)?;
+        {
+            let mut users = self.users.write();
+            if users.contains_key(&bundle.uname) {
+                return Err(DataError::UserExists { uname: bundle.uname });
+            }
+            if let Some(max) = self.umax {
+                if users.len() >= max { return Err(DataError::CapacityExceeded); }
+            }
+            /* Lock order is always users first, then folded (or emails).
+               Nothing is added to either index until every check has passed,
+               so a refused import leaves no trace. */
+            let lower = bundle.uname.to_lowercase();
+            if let Some(folded) = &self.folded {
+                if folded.read().get(&lower).is_some_and(|count| *count > 0) {
+                    return Err(DataError::UserExists { uname: bundle.uname });
+                }
+            }
+            let uid = match users.values().any(|umeta| umeta.uid == bundle.uid) {
+                true => unused_uid(&users),
+                false => bundle.uid,
+            };
+            let email = bundle.email
+                .filter(|e| !e.is_empty())
+                .filter(|e| !self.emails.read().contains_key(&fold_email(e)));
+            let salt = bundle.salt.map(|salt| self.salt_encoding.encode(&salt));
+            let umeta = UserMeta {
+                hash: bundle.hash, admin: bundle.admin, uid, class: bundle.class, email, salt
+            };
+            let row = size::row_size(&umeta.to_rw(&bundle.uname));
+            if !self.ubytes.admit(row, || users_size(&self.ufile, &users)) {
+                return Err(DataError::CapacityExceeded);
+            }
+            
+            if let Some(folded) = &self.folded {
+                let _ = folded.write().insert(lower, 1);
+            }
+            if let Some(email) = &umeta.email {
+                let _ = self.emails.write().insert(fold_email(email), bundle.uname.clone());
+            }
+            let _ = users.insert(bundle.uname, umeta);
+            self.mark_dirty();
+        }
+        
+        return self.save_if_write_through();
+    }
+    
+    /** Returns the contents `.save()` would write, as .csv data. */
+    #[cfg(feature = "bundle")]
+    pub(crate) fn to_csv_bytes(&self) -> Result<Vec<u8>, FileError> {
+        let users = self.users.read();
+        serialize_users(Vec::new(), &self.ufile, &users, self.uorder)
+    }
+    
+    /** Returns the salt with the pepper appended, as passed to the hasher. */
+    fn peppered(&self, salt: &[u8]) -> Vec<u8> {
+        let mut salted = salt.to_vec();
+        salted.extend_from_slice(self.pepper.as_bytes());
+        return salted;
+    }
+    
+    /**
+    Checks a password against a user's stored name and hash (as found by
+    `stored_hash()`), returning the appropriate error (depending on
+    whether uniform errors mode is on) if there's no such user. `given`
+    is the name (or email address) the user was looked up by.
+    */
+    fn verify(
+        &self,
+        given: &str,
+        found: Option<(String, String)>,
+        password: &str,
+        salt: &[u8]
+    ) -> Result<AuthOk, DataError> {
+        if let Some(checker) = &self.checker {
+            let result = match found {
+                Some((uname, _)) => checker.check(&uname, password).map(|_| AuthOk::new(uname)),
+                None => {
+                    /* The user couldn't log in anyway, but the checker is
+                       asked regardless (and its answer ignored), so the
+                       time taken doesn't reveal that there's no such user. */
+                    let _ = checker.check(given, password);
+                    Err(DataError::NoSuchUser)
+                },
+            };
+            return match result {
+                Err(DataError::NoSuchUser) | Err(DataError::BadPassword) if self.uniform_errors => {
+                    Err(DataError::BadCredentials)
+                },
+                x => x,
+            };
+        }
+        
+        let salt = self.peppered(salt);
+        let (uname, stored) = match found {
+            Some(found) => found,
+            None => {
+                /* Spend as long as checking a real password would, so the
+                   time taken doesn't reveal that there's no such user. */
+                self.hasher.verify_dummy(password, &salt);
+                if self.uniform_errors {
+                    return Err(DataError::BadCredentials);
+                } else {
+                    return Err(DataError::NoSuchUser);
+                }
+            },
+        };
+        
+        if self.hasher.verify(password, &salt, &stored) {
+            let mut ok = AuthOk::new(uname);
+            ok.needs_rehash = self.hasher.needs_rehash(&stored);
+            Ok(ok)
+        } else if self.uniform_errors {
+            Err(DataError::BadCredentials)
+        } else {
+            Err(DataError::BadPassword)
+        }
+    }
+    
+    /** Returns `DataError::ReadOnly` if the database can't be changed. */
+    fn writable(&self) -> Result<(), DataError> {
+        match self.read_only {
+            true => Err(DataError::ReadOnly),
+            false => Ok(()),
+        }
+    }
+    
+    /**
+    Flags the database as out of sync with the file on disk, and counts a
+    new generation (see `.generation()`).
+    */
+    fn mark_dirty(&self) {
+        let _ = self.ugen.fetch_add(1, Ordering::AcqRel);
+        if !self.udirty.load(Ordering::Relaxed) {
+            self.udirty.store(true, Ordering::Release);
+        }
+    }
+    
+    /** In write-through mode, saves the database if it's dirty. */
+    fn save_if_write_through(&self) -> Result<(), DataError> {
+        if self.write_through && self.is_dirty() {
+            self.save().map_err(DataError::SaveFailed)?;
+        }
+        return Ok(());
+    }
+}
+
+/**
+Generates a new user ID. These are random, so they don't reveal how many
+users there are (or have been), and (with 64 bits) a collision between
+two added users is vanishingly unlikely.
+*/
+fn new_uid() -> u64 { rand::random() }
+
+/** Returns a new user ID that none of `users` has. */
+fn unused_uid(users: &HashMap<String, UserMeta>) -> u64 {
+    loop {
+        let uid = new_uid();
+        if !users.values().any(|umeta| umeta.uid == uid) { return uid; }
+    }
+}
+
+/** Email addresses are indexed (and compared) lowercased. */
+fn fold_email(email: &str) -> String { email.to_lowercase() }
+
+/** Returns the problem with a (parseable) row of a password file, if any. */
+fn check_user_row(line: u64, urw: &UserRW) -> Option<ValidationIssue> {
+    if urw.hash.is_empty() {
+        return Some(ValidationIssue::Malformed { line, msg: "empty password hash".to_string() });
+    }
+    return None;
+}
+
+/**
+Overwrites the file at `path` with zeros, syncs it to disk, and deletes it.
+(On journaling or copy-on-write filesystems, or SSDs, the old data may
+survive anyway, but this is the best that can be done portably.)
+*/
+fn shred(path: &Path) -> io::Result<()> {
+    let mut f = std::fs::OpenOptions::new().write(true).open(path)?;
+    let mut remaining = f.metadata()?.len();
+    let zeros = [0u8; 4096];
+    while remaining > 0 {
+        let n = remaining.min(zeros.len() as u64) as usize;
+        f.write_all(&zeros[..n])?;
+        remaining -= n as u64;
+    }
+    f.sync_all()?;
+    drop(f);
+    return std::fs::remove_file(path);
+}
+
+/**
+Writes a password database's user data to the file at `path`, then a
+description of it (see `PwdAuth::recover()`) alongside.
+*/
+fn write_users(
+    path: &Path,
+    users: &HashMap<String, UserMeta>,
+    order: SaveOrder
+) -> Result<(), FileError> {
+    let (f, tmp) = open_for_atomic_write(path)?;
+    let (f, sum) = match serialize_users(Summing::new(Faulty::writer(f, path)), path, users, order) {
+        Ok(w) => w.finish(users.len()),
+        Err(e) => { return Err(abort_atomic_write(&tmp, e)); },
+    };
+    
+    commit_atomic_write(f.into_inner(), &tmp, path)?;
+    return write_sum(path, sum);
+}
+
+/**
+Writes a password database's user data as .csv to `w` in the given order,
+returning it when done. `path` is only used to describe errors.
+*/
+fn serialize_users<W: Write>(
+    w: W,
+    path: &Path,
+    users: &HashMap<String, UserMeta>,
+    order: SaveOrder
+) -> Result<W, FileError> {
+    let mut w = csv::WriterBuilder::new().has_headers(false).from_writer(w);
+    if let Err(e) = w.write_record(PWD_FILE_HEADERS) {
+        return Err(FileError::from_csv(path, Op::Write, &e));
+    }
+    let mut rows: Vec<(&String, &UserMeta)> = users.iter().collect();
+    match order {
+        SaveOrder::Unsorted => {},
+        SaveOrder::ById => rows.sort_unstable_by(|a, b| a.0.cmp(b.0)),
+        SaveOrder::ByIssueTime => rows.sort_unstable_by(|a, b| {
+            a.1.uid.cmp(&b.1.uid).then_with(|| a.0.cmp(b.0))
+        }),
+    }
+    for (uname, umeta) in rows.into_iter() {
+        if let Err(e) = w.serialize(umeta.to_rw(uname)) {
+            return Err(FileError::from_csv(path, Op::Write, &e));
+        }
+    }
+    match w.into_inner() {
+        Ok(w) => Ok(w),
+        Err(e) => Err(FileError::from_io(path, Op::Write, e.error())),
+    }
+}
+
+/** Returns how many bytes of .csv data the (locked) map of users `users` makes. */
+fn users_size(path: &Path, users: &HashMap<String, UserMeta>) -> u64 {
+    match serialize_users(ByteCounter::default(), path, users, SaveOrder::Unsorted) {
+        Ok(counter) => counter.0,
+        Err(_) => 0,
+    }
+}
+
+/**
+Returns (copies of) the stored name and password hash for the given user
+from the (locked) map of users `users`, if there is one, so the password
+can be checked without holding the lock.
+*/
+fn stored_hash(
+    users: &HashMap<String, UserMeta>,
+    uname: &str
+) -> Option<(String, String)> {
+    users.get_key_value(uname)
+        .map(|(uname, umeta)| (uname.clone(), umeta.hash.clone()))
+}
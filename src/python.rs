@@ -0,0 +1,277 @@
+/*!
+Python bindings, so a Flask (or other Python) app can share the same
+credential files as Rust services.
+
+The `authlite` Python module has `PwdAuth`, `KeyAuth`, and `BothAuth`
+classes wrapping the Rust types of the same names. Their constructors
+open the given files, creating them if they don't exist. Salts are
+`bytes`, and may be left out (they're empty). Methods that fail raise
+`authlite.AuthError`, whose arguments are the name of the `DataError`
+and its `suggested_status()`, or `OSError` for file errors:
+
+```python
+import authlite
+
+auth = authlite.BothAuth("data/users.csv", "data/keys.csv", write_through=True)
+auth.add_user("ted", "frogs")
+key = auth.check_password_and_issue_key("ted", "frogs")
+try:
+    auth.check_key(key, "ted")
+except authlite.AuthError as e:
+    name, status = e.args
+```
+
+Build the module with [maturin](https://www.maturin.rs/) (`maturin build`
+or `maturin develop`), which picks the features up from `pyproject.toml`.
+Only available with the `python` feature.
+*/
+use pyo3::create_exception;
+use pyo3::exceptions::{PyException, PyOSError, PyValueError};
+use pyo3::prelude::*;
+
+use crate::{BothAuth, DataError, FileError, KeyAuth, PwdAuth};
+
+create_exception!(authlite, AuthError, PyException,
+    "Raised when a check or change fails; args are (error name, HTTP status).");
+
+fn data_err(e: DataError) -> PyErr {
+    let name = format!("{:?}", &e);
+    let name = match name.find(|c: char| !c.is_alphanumeric()) {
+        Some(n) => name[..n].to_string(),
+        None => name,
+    };
+    AuthError::new_err((name, e.suggested_status()))
+}
+
+fn file_err(e: FileError) -> PyErr { PyOSError::new_err(e.to_string()) }
+
+/** Wraps `PwdAuth`; see there for what each method does. */
+#[pyclass(name = "PwdAuth", module = "authlite", frozen)]
+pub struct PyPwdAuth {
+    inner: PwdAuth,
+}
+
+#[pymethods]
+impl PyPwdAuth {
+    #[new]
+    #[pyo3(signature = (pwd_file, write_through = false))]
+    fn new(pwd_file: &str, write_through: bool) -> PyResult<Self> {
+        let mut inner = PwdAuth::open_or_new(pwd_file).map_err(file_err)?;
+        inner.write_through(write_through);
+        Ok(PyPwdAuth { inner })
+    }
+    
+    #[pyo3(signature = (uname, password, salt = None))]
+    fn add_user(&self, py: Python<'_>, uname: &str, password: &str, salt: Option<&[u8]>)
+    -> PyResult<()> {
+        let salt = salt.unwrap_or_default();
+        py.allow_threads(|| self.inner.add_user(uname, password, salt)).map_err(data_err)
+    }
+    
+    fn delete_user(&self, uname: &str) -> PyResult<()> {
+        self.inner.delete_user(uname).map_err(data_err)
+    }
+    
+    #[pyo3(signature = (uname, password, salt = None))]
+    fn change_password(&self, py: Python<'_>, uname: &str, password: &str, salt: Option<&[u8]>)
+    -> PyResult<()> {
+        let salt = salt.unwrap_or_default();
+        py.allow_threads(|| self.inner.change_password(uname, password, salt)).map_err(data_err)
+    }
+    
+    /** Raises `AuthError` unless the password is correct. */
+    #[pyo3(signature = (uname, password, salt = None))]
+    fn check_password(&self, py: Python<'_>, uname: &str, password: &str, salt: Option<&[u8]>)
+    -> PyResult<()> {
+        let salt = salt.unwrap_or_default();
+        py.allow_threads(|| self.inner.check_password(uname, password, salt))
+            .map(|_| ()).map_err(data_err)
+    }
+    
+    fn user_exists(&self, uname: &str) -> bool { self.inner.user_exists(uname).is_ok() }
+    
+    fn set_admin(&self, uname: &str, is_admin: bool) -> PyResult<()> {
+        self.inner.set_admin(uname, is_admin).map_err(data_err)
+    }
+    
+    fn is_admin(&self, uname: &str) -> PyResult<bool> {
+        self.inner.is_admin(uname).map_err(data_err)
+    }
+    
+    fn usernames(&self) -> Vec<String> { self.inner.usernames() }
+    
+    fn is_dirty(&self) -> bool { self.inner.is_dirty() }
+    
+    fn save(&self) -> PyResult<()> { self.inner.save().map_err(file_err) }
+}
+
+/** Wraps `KeyAuth`; see there for what each method does. */
+#[pyclass(name = "KeyAuth", module = "authlite", frozen)]
+pub struct PyKeyAuth {
+    inner: KeyAuth,
+}
+
+#[pymethods]
+impl PyKeyAuth {
+    #[new]
+    #[pyo3(signature = (key_file, write_through = false))]
+    fn new(key_file: &str, write_through: bool) -> PyResult<Self> {
+        let mut inner = KeyAuth::open_or_new(key_file).map_err(file_err)?;
+        inner.write_through(write_through);
+        Ok(PyKeyAuth { inner })
+    }
+    
+    fn issue_key(&self, uname: &str) -> PyResult<String> {
+        self.inner.issue_key(uname).map_err(data_err)
+    }
+    
+    /** Raises `AuthError` unless the key is valid for the user. */
+    fn check_key(&self, key: &str, uname: &str) -> PyResult<()> {
+        self.inner.check_key(key, uname).map(|_| ()).map_err(data_err)
+    }
+    
+    fn check_and_refresh_key(&self, key: &str, uname: &str) -> PyResult<()> {
+        self.inner.check_and_refresh_key(key, uname).map_err(data_err)
+    }
+    
+    fn remove_key(&self, key: &str) -> PyResult<()> {
+        self.inner.remove_key(key).map_err(data_err)
+    }
+    
+    fn remove_user_keys(&self, uname: &str) -> PyResult<usize> {
+        self.inner.remove_user_keys(uname).map_err(data_err)
+    }
+    
+    fn cull_keys(&self) -> PyResult<()> { self.inner.cull_keys().map_err(file_err) }
+    
+    fn is_dirty(&self) -> bool { self.inner.is_dirty() }
+    
+    fn save(&self) -> PyResult<()> { self.inner.save().map_err(file_err) }
+}
+
+/** Wraps `BothAuth`; see there for what each method does. */
+#[pyclass(name = "BothAuth", module = "authlite", frozen)]
+pub struct PyBothAuth {
+    inner: BothAuth,
+}
+
+#[pymethods]
+impl PyBothAuth {
+    /**
+    Opens (or creates) the given files. `key_life`, if given, is a string
+    like "2h 30m" (see `KeyAuth::life_str()`).
+    */
+    #[new]
+    #[pyo3(signature = (pwd_file, key_file, write_through = false, key_life = None))]
+    fn new(pwd_file: &str, key_file: &str, write_through: bool, key_life: Option<&str>)
+    -> PyResult<Self> {
+        let mut builder = BothAuth::builder()
+            .pwd_file(pwd_file)
+            .key_file(key_file)
+            .write_through(write_through);
+        if let Some(life) = key_life {
+            builder = builder.key_life_str(life).map_err(|e| {
+                PyValueError::new_err(format!("bad key life \"{}\": {}", life, e))
+            })?;
+        }
+        match builder.build() {
+            Ok(inner) => Ok(PyBothAuth { inner }),
+            Err(e) => Err(file_err(e)),
+        }
+    }
+    
+    #[pyo3(signature = (uname, password, salt = None))]
+    fn add_user(&self, py: Python<'_>, uname: &str, password: &str, salt: Option<&[u8]>)
+    -> PyResult<()> {
+        let salt = salt.unwrap_or_default();
+        py.allow_threads(|| self.inner.add_user(uname, password, salt)).map_err(data_err)
+    }
+    
+    fn delete_user(&self, uname: &str) -> PyResult<()> {
+        self.inner.delete_user(uname).map_err(data_err)
+    }
+    
+    #[pyo3(signature = (uname, password, salt = None))]
+    fn change_password(&self, py: Python<'_>, uname: &str, password: &str, salt: Option<&[u8]>)
+    -> PyResult<()> {
+        let salt = salt.unwrap_or_default();
+        py.allow_threads(|| self.inner.change_password(uname, password, salt)).map_err(data_err)
+    }
+    
+    /** Raises `AuthError` unless the password is correct. */
+    #[pyo3(signature = (uname, password, salt = None))]
+    fn check_password(&self, py: Python<'_>, uname: &str, password: &str, salt: Option<&[u8]>)
+    -> PyResult<()> {
+        let salt = salt.unwrap_or_default();
+        py.allow_threads(|| self.inner.check_password(uname, password, salt))
+            .map(|_| ()).map_err(data_err)
+    }
+    
+    #[pyo3(signature = (uname, password, salt = None))]
+    fn check_password_and_issue_key(
+        &self,
+        py: Python<'_>,
+        uname: &str,
+        password: &str,
+        salt: Option<&[u8]>
+    ) -> PyResult<String> {
+        let salt = salt.unwrap_or_default();
+        py.allow_threads(|| self.inner.check_password_and_issue_key(uname, password, salt))
+            .map_err(data_err)
+    }
+    
+    fn user_exists(&self, uname: &str) -> bool { self.inner.user_exists(uname).is_ok() }
+    
+    fn set_admin(&self, uname: &str, is_admin: bool) -> PyResult<()> {
+        self.inner.set_admin(uname, is_admin).map_err(data_err)
+    }
+    
+    fn is_admin(&self, uname: &str) -> PyResult<bool> {
+        self.inner.is_admin(uname).map_err(data_err)
+    }
+    
+    fn usernames(&self) -> Vec<String> { self.inner.usernames() }
+    
+    fn issue_key(&self, uname: &str) -> PyResult<String> {
+        self.inner.issue_key(uname).map_err(data_err)
+    }
+    
+    /** Raises `AuthError` unless the key is valid for the user. */
+    fn check_key(&self, key: &str, uname: &str) -> PyResult<()> {
+        self.inner.check_key(key, uname).map(|_| ()).map_err(data_err)
+    }
+    
+    /** Raises `AuthError` unless the key is valid for an administrator. */
+    fn check_key_admin(&self, key: &str, uname: &str) -> PyResult<()> {
+        self.inner.check_key_admin(key, uname).map_err(data_err)
+    }
+    
+    fn check_and_refresh_key(&self, key: &str, uname: &str) -> PyResult<()> {
+        self.inner.check_and_refresh_key(key, uname).map_err(data_err)
+    }
+    
+    fn remove_key(&self, key: &str) -> PyResult<()> {
+        self.inner.remove_key(key).map_err(data_err)
+    }
+    
+    fn remove_user_keys(&self, uname: &str) -> PyResult<usize> {
+        self.inner.remove_user_keys(uname).map_err(data_err)
+    }
+    
+    fn cull_keys(&self) -> PyResult<()> { self.inner.cull_keys().map_err(file_err) }
+    
+    /** Saves whichever files have changed. */
+    fn save_if_dirty(&self) -> PyResult<()> {
+        self.inner.save_if_dirty().map(|_| ()).map_err(file_err)
+    }
+}
+
+/** The `authlite` Python module. */
+#[pymodule]
+pub(crate) fn authlite(m: &Bound<'_, PyModule>) -> PyResult<()> {
+    m.add_class::<PyPwdAuth>()?;
+    m.add_class::<PyKeyAuth>()?;
+    m.add_class::<PyBothAuth>()?;
+    m.add("AuthError", m.py().get_type::<AuthError>())?;
+    return Ok(());
+}
@@ -0,0 +1,344 @@
+use std::collections::HashMap;
+use std::io::ErrorKind;
+use std::ops::Add;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+use blake3::Hash;
+use parking_lot::RwLock;
+use rand::{Rng, distributions::Alphanumeric};
+use serde::{Serialize, Deserialize};
+
+use crate::{FileError, DataError, Op, open_for_read, open_for_write, open_for_atomic_write,
+            abort_atomic_write, commit_atomic_write};
+
+const SELECTOR_LENGTH: usize = 16;
+const VALIDATOR_LENGTH: usize = 32;
+const DEFAULT_REMEMBER_LIFE: Duration = Duration::from_secs(30 * 24 * 3600);
+
+#[derive(Debug, Serialize, Deserialize)]
+struct SeriesRW {
+    selector: String,
+    uname: String,
+    hash: String,
+    #[serde(with ="humantime_serde")]
+    expiry: SystemTime,
+}
+
+#[derive(Debug)]
+struct SeriesMeta {
+    uname: String,
+    hash: Hash,
+    expiry: SystemTime,
+}
+
+impl SeriesMeta {
+    fn to_rw(&self, selector: &str) -> SeriesRW {
+        return SeriesRW {
+            selector: selector.to_string(),
+            uname: self.uname.clone(),
+            hash: self.hash.to_hex().to_string(),
+            expiry: self.expiry,
+        };
+    }
+}
+
+/** A "remember me" token, as issued by `RememberAuth`.
+
+    Both parts need to be stored by the client (typically together in one
+    long-lived cookie) and presented to `.redeem_remember_token()`.
+*/
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RememberToken {
+    /** Identifies the token's series; this stays the same when the token
+        is redeemed. */
+    pub selector: String,
+    /** The secret part of the token, which changes every time it is
+        redeemed. Only a hash of it is stored. */
+    pub validator: String,
+}
+
+/** A database of long-lived "remember me" tokens, which persists as a .csv
+    file on disk.
+    
+    This implements the selector/validator scheme for persistent logins:
+    each token belongs to a _series_, identified by its selector, which
+    starts when a user logs in and asks to be remembered. Every time the
+    token is redeemed (to log the user in again without a password), the
+    series gets a new validator, and the old one stops working.
+    
+    If a series' selector is presented with an _old_ validator, someone
+    has redeemed a copy of the token (the user or the thief, whichever
+    was second); the whole series is then revoked, and
+    `DataError::StolenToken` returned, so the application can warn the
+    user (and, say, invalidate their sessions).
+    
+    Attach a `RememberAuth` to a `BothAuth` (with
+    `BothAuth::attach_remember()`) so purging a user ends their series.
+    
+    Changes are _not_ automatically written to disk (there's no
+    write-through mode); the database is flagged as "dirty" until it's
+    saved, by `.save()` or `BothAuth::save_if_dirty()`.
+*/
+#[derive(Debug)]
+pub struct RememberAuth {
+    series: RwLock<HashMap<String, SeriesMeta>>,
+    rfile:  PathBuf,
+    rdirty: RwLock<bool>,
+    rlife:  Duration,
+}
+
+impl RememberAuth {
+    /**
+    Create a new remember-me token database that will save its data to a
+    .csv file at the supplied path.
+    */
+    pub fn new(token_file: impl AsRef<Path>) -> Result<Self, FileError> {
+        let token_file = token_file.as_ref();
+        
+        if Path::exists(token_file) {
+            return Err(FileError::new(token_file, Op::Create, ErrorKind::AlreadyExists,
+                                      "file already exists".to_string()));
+        }
+        
+        let f = open_for_write(token_file)?;
+        let mut w = csv::Writer::from_writer(f);
+        if let Err(e) = w.write_record(["selector", "uname", "hash", "expiry"]) {
+            return Err(FileError::from_csv(token_file, Op::Create, &e));
+        }
+        if let Err(e) = w.flush() {
+            return Err(FileError::from_io(token_file, Op::Create, &e));
+        }
+        
+        return Ok(RememberAuth::from_series(token_file, HashMap::new()));
+    }
+    
+    /**
+    Open a remember-me token database with data from the .csv file in the
+    given path. Expired series are not added to the in-memory database.
+    */
+    pub fn open(token_file: impl AsRef<Path>) -> Result<Self, FileError> {
+        let token_file = token_file.as_ref();
+        
+        let now = SystemTime::now();
+        let f = open_for_read(token_file)?;
+        let mut new_series: HashMap<String, SeriesMeta> = HashMap::new();
+        let mut r = csv::Reader::from_reader(f);
+        for (n, result) in r.deserialize::<SeriesRW>().enumerate() {
+            match result {
+                Err(e) => {
+                    eprintln!("WARNING: reading {}, record {}: {}",
+                        token_file.to_string_lossy(), n, &e);
+                },
+                Ok(srw) => {
+                    let hash = match Hash::from_hex(&srw.hash) {
+                        Ok(x) => x,
+                        Err(e) => {
+                            eprintln!("WARNING: reading {}, record {}: can't parse \"{}\" as Hash: {}",
+                                token_file.to_string_lossy(), n, &srw.hash, &e);
+                            continue;
+                        },
+                    };
+                    if srw.expiry < now { continue; }
+                    
+                    let smeta = SeriesMeta { uname: srw.uname, hash, expiry: srw.expiry };
+                    if new_series.insert(srw.selector.clone(), smeta).is_some() {
+                        eprintln!("WARNING: duplicate remember-me series \"{}\"", &srw.selector);
+                    }
+                },
+            }
+        }
+        
+        return Ok(RememberAuth::from_series(token_file, new_series));
+    }
+    
+    /**
+    Open the remember-me token database at the given path if the file
+    exists, or create a new one there if it doesn't.
+    */
+    pub fn open_or_new(token_file: impl AsRef<Path>) -> Result<Self, FileError> {
+        let token_file = token_file.as_ref();
+        match RememberAuth::open(token_file) {
+            Err(e) if e.kind == ErrorKind::NotFound => RememberAuth::new(token_file),
+            x => x,
+        }
+    }
+    
+    fn from_series(token_file: &Path, series: HashMap<String, SeriesMeta>) -> Self {
+        return RememberAuth {
+            series: RwLock::new(series),
+            rfile:  PathBuf::from(token_file),
+            rdirty: RwLock::new(false),
+            rlife:  DEFAULT_REMEMBER_LIFE,
+        };
+    }
+    
+    /**
+    Change how long a series lasts after its token was last redeemed (or
+    first issued). The default is 30 days.
+    */
+    pub fn life(&mut self, life: Duration) { self.rlife = life; }
+    
+    /** Starts a new series for the given user, returning its first token. */
+    pub fn issue_remember_token(&self, uname: &str) -> RememberToken {
+        let token = RememberToken {
+            selector:  random_string(SELECTOR_LENGTH),
+            validator: random_string(VALIDATOR_LENGTH),
+        };
+        let smeta = SeriesMeta {
+            uname:  uname.to_string(),
+            hash:   blake3::hash(token.validator.as_bytes()),
+            expiry: SystemTime::now().add(self.rlife),
+        };
+        
+        {
+            let mut series = self.series.write();
+            let _ = series.insert(token.selector.clone(), smeta);
+            let mut dirty = self.rdirty.write();
+            *dirty = true;
+        }
+        
+        return token;
+    }
+    
+    /**
+    Redeems a remember-me token, returning the name of the user it was
+    issued to and the series' next token (which replaces the one
+    redeemed, and should be sent back to the client). Marks the database
+    as dirty.
+    
+    Returns `DataError::NoSuchKey` if there's no such series,
+    `DataError::KeyExpired` if it has expired, or `DataError::StolenToken`
+    (revoking the series) if the validator is wrong.
+    */
+    pub fn redeem_remember_token(
+        &self,
+        selector: &str,
+        validator: &str
+    ) -> Result<(String, RememberToken), DataError> {
+        let now = SystemTime::now();
+        let hash = blake3::hash(validator.as_bytes());
+        let new_validator = random_string(VALIDATOR_LENGTH);
+        
+        let mut series = self.series.write();
+        let uname = match series.get_mut(selector) {
+            None => { return Err(DataError::NoSuchKey); },
+            Some(smeta) if smeta.expiry < now => {
+                let expired_for = now.duration_since(smeta.expiry).unwrap_or_default();
+                return Err(DataError::KeyExpired { expired_for });
+            },
+            /* Hash comparison takes constant time. */
+            Some(smeta) if smeta.hash == hash => {
+                smeta.hash = blake3::hash(new_validator.as_bytes());
+                smeta.expiry = now.add(self.rlife);
+                smeta.uname.clone()
+            },
+            Some(_) => {
+                let _ = series.remove(selector);
+                let mut dirty = self.rdirty.write();
+                *dirty = true;
+                return Err(DataError::StolenToken);
+            },
+        };
+        let mut dirty = self.rdirty.write();
+        *dirty = true;
+        
+        let token = RememberToken {
+            selector:  selector.to_string(),
+            validator: new_validator,
+        };
+        return Ok((uname, token));
+    }
+    
+    /**
+    Ends the series with the given selector (for example, when the user
+    logs out), returning `DataError::NoSuchKey` if there isn't one.
+    */
+    pub fn revoke_series(&self, selector: &str) -> Result<(), DataError> {
+        let mut series = self.series.write();
+        if series.remove(selector).is_none() {
+            return Err(DataError::NoSuchKey);
+        }
+        let mut dirty = self.rdirty.write();
+        *dirty = true;
+        return Ok(());
+    }
+    
+    /**
+    Ends every series belonging to the given user (for example, when they
+    change their password), returning how many there were.
+    */
+    pub fn revoke_user(&self, uname: &str) -> usize {
+        let mut series = self.series.write();
+        let n = series.len();
+        series.retain(|_, smeta| smeta.uname != uname);
+        let removed = n - series.len();
+        if removed > 0 {
+            let mut dirty = self.rdirty.write();
+            *dirty = true;
+        }
+        return removed;
+    }
+    
+    /** Removes expired series, marking the database dirty if there were any. */
+    pub fn cull(&self) {
+        let now = SystemTime::now();
+        let mut series = self.series.write();
+        let n = series.len();
+        series.retain(|_, smeta| smeta.expiry >= now);
+        if series.len() < n {
+            let mut dirty = self.rdirty.write();
+            *dirty = true;
+        }
+    }
+    
+    /** Returns the path of the file this database saves to. */
+    pub fn path(&self) -> &Path { &self.rfile }
+    
+    /**
+    Returns whether the in-memory database is "dirty", that is, whether it's
+    out of sync with the persistent data on disk.
+    */
+    pub fn is_dirty(&self) -> bool {
+        let dirty = self.rdirty.read();
+        return *dirty;
+    }
+    
+    /**
+    Writes all unexpired series to disk, marking the database as no longer
+    dirty. As with the other databases, the file is replaced atomically.
+    */
+    pub fn save(&self) -> Result<(), FileError> {
+        let now = SystemTime::now();
+        let path = &self.rfile;
+        let series = self.series.write();
+        
+        let (f, tmp) = open_for_atomic_write(path)?;
+        let mut w = csv::Writer::from_writer(f);
+        for (selector, smeta) in series.iter() {
+            if smeta.expiry < now { continue; }
+            if let Err(e) = w.serialize(smeta.to_rw(selector)) {
+                return Err(abort_atomic_write(&tmp, FileError::from_csv(path, Op::Write, &e)));
+            }
+        }
+        let f = match w.into_inner() {
+            Ok(f) => f,
+            Err(e) => { return Err(abort_atomic_write(&tmp, FileError::from_io(path, Op::Write, e.error()))); },
+        };
+        commit_atomic_write(f, &tmp, path)?;
+        
+        let mut dirty = self.rdirty.write();
+        *dirty = false;
+        
+        return Ok(());
+    }
+}
+
+/** Generates a random alphanumeric string of the given length. */
+fn random_string(len: usize) -> String {
+    rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(len)
+        .map(char::from)
+        .collect()
+}
@@ -0,0 +1,96 @@
+use std::fmt;
+use std::io::{self, ErrorKind};
+use std::path::PathBuf;
+
+/** A source for secret material (such as a password "pepper") that
+    shouldn't be hard-coded in an application's source.
+    
+    Implementations are provided for reading the secret from an environment
+    variable (`EnvSecret`) or a file (`FileSecret`), and for any closure
+    returning `io::Result<Vec<u8>>`, which can fetch it from anywhere else
+    (a secrets manager, say).
+*/
+pub trait SecretProvider {
+    /** Retrieves the secret. */
+    fn secret(&self) -> io::Result<Vec<u8>>;
+}
+
+/** Reads a secret from the environment variable with the given name.
+    
+    It is an error (of kind `ErrorKind::NotFound`) for the variable to be
+    unset or empty.
+*/
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EnvSecret(pub String);
+
+impl SecretProvider for EnvSecret {
+    fn secret(&self) -> io::Result<Vec<u8>> {
+        match std::env::var_os(&self.0) {
+            Some(v) if !v.is_empty() => Ok(v.to_string_lossy().into_owned().into_bytes()),
+            _ => Err(io::Error::new(
+                ErrorKind::NotFound,
+                format!("environment variable {} is not set", &self.0)
+            )),
+        }
+    }
+}
+
+/** Reads a secret from the file at the given path, ignoring any trailing
+    newline.
+    
+    It is an error (of kind `ErrorKind::InvalidData`) for the file to be
+    empty.
+*/
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileSecret(pub PathBuf);
+
+impl SecretProvider for FileSecret {
+    fn secret(&self) -> io::Result<Vec<u8>> {
+        let mut data = std::fs::read(&self.0)?;
+        if data.ends_with(b"\n") { data.pop(); }
+        if data.ends_with(b"\r") { data.pop(); }
+        if data.is_empty() {
+            return Err(io::Error::new(
+                ErrorKind::InvalidData,
+                format!("{} is empty", self.0.to_string_lossy())
+            ));
+        }
+        return Ok(data);
+    }
+}
+
+impl<F> SecretProvider for F
+where F: Fn() -> io::Result<Vec<u8>>
+{
+    fn secret(&self) -> io::Result<Vec<u8>> { self() }
+}
+
+/** Secret bytes, which are never shown by `Debug`. */
+#[derive(Clone, Default, PartialEq, Eq)]
+pub(crate) struct Secret(Vec<u8>);
+
+impl Secret {
+    /** Retrieves a secret from the given provider. */
+    pub(crate) fn from_provider(provider: &dyn SecretProvider) -> io::Result<Self> {
+        provider.secret().map(Secret)
+    }
+    
+    /** Wraps secret bytes supplied directly by the caller. */
+    #[cfg(feature = "http-hooks")]
+    pub(crate) fn from_bytes(bytes: &[u8]) -> Self { Secret(bytes.to_vec()) }
+    
+    /** Generates a random 32-byte secret. */
+    pub(crate) fn random() -> Self {
+        Secret(rand::random::<[u8; 32]>().to_vec())
+    }
+    
+    pub(crate) fn as_bytes(&self) -> &[u8] { &self.0 }
+    
+    pub(crate) fn is_empty(&self) -> bool { self.0.is_empty() }
+}
+
+impl fmt::Debug for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Secret([{} bytes redacted])", self.0.len())
+    }
+}
@@ -0,0 +1,162 @@
+use std::collections::HashMap;
+use std::collections::hash_map::RandomState;
+use std::hash::BuildHasher;
+
+use parking_lot::{Mutex, MutexGuard, RwLock, RwLockReadGuard, RwLockWriteGuard};
+
+/** The number of shards a `KeyAuth` uses unless told otherwise. */
+pub(crate) const DEFAULT_SHARDS: usize = 16;
+
+type Shard<V> = RwLock<HashMap<String, V>>;
+pub(crate) type ReadGuard<'a, V> = RwLockReadGuard<'a, HashMap<String, V>>;
+pub(crate) type WriteGuard<'a, V> = RwLockWriteGuard<'a, HashMap<String, V>>;
+
+/**
+A map with `String` keys split across several independently-locked
+shards, so that threads working on entries in different shards don't
+contend for the same lock.
+
+Whenever more than one shard is locked at a time, they are locked in
+order of their index, so that operations on several shards can't
+deadlock.
+*/
+#[derive(Debug)]
+pub(crate) struct ShardedMap<V> {
+    shards: Vec<Shard<V>>,
+    hasher: RandomState,
+}
+
+impl<V> ShardedMap<V> {
+    /** Distributes the entries of `map` across `n` shards (at least one). */
+    pub(crate) fn new(n: usize, map: HashMap<String, V>) -> Self {
+        let mut smap = ShardedMap {
+            shards: (0..n.max(1)).map(|_| RwLock::new(HashMap::new())).collect(),
+            hasher: RandomState::new(),
+        };
+        for (k, v) in map.into_iter() {
+            let i = smap.index(&k);
+            let _ = smap.shards[i].get_mut().insert(k, v);
+        }
+        
+        return smap;
+    }
+    
+    /** Gathers all the entries back into a single map. */
+    pub(crate) fn into_map(self) -> HashMap<String, V> {
+        let mut map = HashMap::new();
+        for shard in self.shards.into_iter() {
+            map.extend(shard.into_inner());
+        }
+        return map;
+    }
+    
+    /** Returns the index of the shard holding (or that would hold) `key`. */
+    pub(crate) fn index(&self, key: &str) -> usize {
+        (self.hasher.hash_one(key) % self.shards.len() as u64) as usize
+    }
+    
+    /** Returns the shard holding (or that would hold) `key`. */
+    pub(crate) fn shard(&self, key: &str) -> &Shard<V> {
+        &self.shards[self.index(key)]
+    }
+    
+    /**
+    Keeps only the entries for which `keep` returns `true`, locking one
+    shard at a time, and returns how many entries were removed.
+    */
+    pub(crate) fn retain<F>(&self, mut keep: F) -> usize
+    where F: FnMut(&String, &mut V) -> bool
+    {
+        let mut removed = 0;
+        for shard in self.shards.iter() {
+            let mut map = shard.write();
+            let n = map.len();
+            map.retain(|k, v| keep(k, v));
+            removed += n - map.len();
+        }
+        return removed;
+    }
+    
+    /** Returns the number of shards. */
+    pub(crate) fn n_shards(&self) -> usize { self.shards.len() }
+    
+    /**
+    Like `.retain()`, but only for the entries in shard `i`, which must be
+    less than `.n_shards()`.
+    */
+    pub(crate) fn retain_in<F>(&self, i: usize, mut keep: F) -> usize
+    where F: FnMut(&String, &mut V) -> bool
+    {
+        let mut map = self.shards[i].write();
+        let n = map.len();
+        map.retain(|k, v| keep(k, v));
+        return n - map.len();
+    }
+    
+    /** Read-locks every shard. */
+    pub(crate) fn read_all(&self) -> Vec<ReadGuard<'_, V>> {
+        self.shards.iter().map(|s| s.read()).collect()
+    }
+    
+    /** Write-locks every shard. */
+    pub(crate) fn write_all(&self) -> Vec<WriteGuard<'_, V>> {
+        self.shards.iter().map(|s| s.write()).collect()
+    }
+    
+    /**
+    Write-locks the shards holding keys `a` and `b`. The second guard is
+    `None` if they're in the same shard, in which case the first guard
+    covers both.
+    */
+    pub(crate) fn write_pair(&self, a: &str, b: &str)
+    -> (WriteGuard<'_, V>, Option<WriteGuard<'_, V>>) {
+        let (ia, ib) = (self.index(a), self.index(b));
+        if ia == ib {
+            (self.shards[ia].write(), None)
+        } else if ia < ib {
+            let ga = self.shards[ia].write();
+            (ga, Some(self.shards[ib].write()))
+        } else {
+            let gb = self.shards[ib].write();
+            (self.shards[ia].write(), Some(gb))
+        }
+    }
+}
+
+/** The number of locks in a `UserLocks`. */
+const USER_LOCKS: usize = 64;
+
+/**
+A fixed set of mutexes, one of which each user name hashes to, for
+serializing multi-step changes to one user's credentials (so that, say,
+two password changes for the same user can't interleave) while changes
+to other users almost always proceed in parallel. Two users only wait
+for each other if their names hash to the same lock.
+
+The locks aren't reentrant: code holding a user's lock mustn't try to
+take it again from the same `UserLocks`.
+*/
+#[derive(Debug)]
+pub(crate) struct UserLocks {
+    locks: Vec<Mutex<()>>,
+    hasher: RandomState,
+}
+
+impl UserLocks {
+    pub(crate) fn new() -> Self {
+        UserLocks {
+            locks: (0..USER_LOCKS).map(|_| Mutex::new(())).collect(),
+            hasher: RandomState::new(),
+        }
+    }
+    
+    /** Blocks until it holds the lock for `uname`, which is released when the guard drops. */
+    pub(crate) fn lock(&self, uname: &str) -> MutexGuard<'_, ()> {
+        let i = (self.hasher.hash_one(uname) % self.locks.len() as u64) as usize;
+        self.locks[i].lock()
+    }
+}
+
+impl Default for UserLocks {
+    fn default() -> Self { UserLocks::new() }
+}
@@ -0,0 +1,77 @@
+/*!
+Estimating how big a database's file is, to keep it under the limit set
+with `PwdAuth::max_file_size()` or `KeyAuth::max_file_size()`.
+*/
+use std::io::{self, Write};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use serde::Serialize;
+
+/** A writer that throws away what's written to it, counting the bytes. */
+#[derive(Debug, Default)]
+pub(crate) struct ByteCounter(pub(crate) u64);
+
+impl Write for ByteCounter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0 += buf.len() as u64;
+        return Ok(buf.len());
+    }
+    
+    fn flush(&mut self) -> io::Result<()> { Ok(()) }
+}
+
+/** Returns how many bytes `row` takes up as a line of a .csv file. */
+pub(crate) fn row_size<T: Serialize>(row: &T) -> u64 {
+    let mut w = csv::WriterBuilder::new().has_headers(false).from_writer(ByteCounter::default());
+    if w.serialize(row).is_err() { return 0; }
+    match w.into_inner() {
+        Ok(counter) => counter.0,
+        Err(_) => 0,
+    }
+}
+
+/**
+Keeps a database's file under a maximum size, by refusing to add rows
+that would take it over.
+
+Measuring the file means serializing the whole database, so rather than
+doing that for every row added, this keeps an estimate: the size last
+measured plus the rows added since. Rows removed aren't taken off it, so
+it only ever runs high, and the file is measured again whenever the
+estimate says it's full.
+*/
+#[derive(Debug, Default)]
+pub(crate) struct SizeLimit {
+    max: Option<u64>,
+    estimate: AtomicU64,
+}
+
+impl SizeLimit {
+    /** Sets (or removes) the limit; `size` is the file's size now. */
+    pub(crate) fn set(&mut self, max: Option<u64>, size: u64) {
+        self.max = max;
+        self.estimate = AtomicU64::new(size);
+    }
+    
+    /**
+    Returns whether a row of `row` bytes can be added without taking the
+    file over the limit, and if so, counts it as added. `measure` returns
+    the file's size as it is now (without the row).
+    */
+    pub(crate) fn admit<F>(&self, row: u64, measure: F) -> bool
+    where F: FnOnce() -> u64
+    {
+        let max = match self.max {
+            Some(max) => max,
+            None => { return true; },
+        };
+        let mut estimate = self.estimate.load(Ordering::Relaxed);
+        if estimate + row > max {
+            estimate = measure();
+            self.estimate.store(estimate, Ordering::Relaxed);
+        }
+        if estimate + row > max { return false; }
+        let _ = self.estimate.fetch_add(row, Ordering::Relaxed);
+        return true;
+    }
+}
@@ -0,0 +1,193 @@
+/*!
+Helpers for testing code that uses this crate.
+
+A `TestAuth` is a `BothAuth` whose files live in a new temporary
+directory, which is deleted when it's dropped; so every test can have its
+own database, tests can run in parallel, and nothing is left behind. For
+tests that need to create the files themselves (or many databases at
+once), a `TestDir` is just the directory.
+
+```
+use authlite::testing::TestAuth;
+
+let auth = TestAuth::with_users(&[("ted", "frogs"), ("bob", "toads")]);
+let key = auth.check_password_and_issue_key("ted", "frogs", b"").unwrap();
+auth.check_key(&key, "ted").unwrap();
+```
+
+Users are added with an empty salt. Everything here panics rather than
+returning errors, as a test should.
+
+A `FaultInjector` makes saving or opening a particular file fail partway
+through, for testing how an application copes:
+
+```
+use authlite::PwdAuth;
+use authlite::testing::{Fault, FaultInjector, TestAuth};
+
+let auth = TestAuth::with_users(&[("ted", "frogs")]);
+auth.add_user("bob", "toads", b"").unwrap();
+{
+    let _fault = FaultInjector::on_save(auth.pwd_file(), Fault::MidRecord(1));
+    assert!(auth.save_passwords().is_err());
+}
+/* The failed save left the file as it was. */
+let saved = PwdAuth::open(auth.pwd_file()).unwrap();
+assert!(saved.check_password("bob", "toads", b"").is_err());
+```
+
+Only available with the `testing` feature.
+*/
+use std::ops::Deref;
+use std::path::{Path, PathBuf};
+
+use parking_lot::{const_mutex, Mutex};
+use tempfile::TempDir;
+
+use crate::{BothAuth, Op};
+pub use crate::fault::Fault;
+
+const PWD_FILE: &str = "users.csv";
+const KEY_FILE: &str = "keys.csv";
+
+/** A new, empty temporary directory, deleted (with everything in it) when dropped. */
+#[derive(Debug)]
+pub struct TestDir {
+    dir: TempDir,
+}
+
+impl TestDir {
+    pub fn new() -> Self {
+        TestDir { dir: TempDir::new().expect("creating temporary directory") }
+    }
+    
+    /** The directory's path. */
+    pub fn path(&self) -> &Path { self.dir.path() }
+    
+    /**
+    The path of the file (or subdirectory) `name` in the directory, which
+    needn't exist yet.
+    */
+    pub fn file(&self, name: impl AsRef<Path>) -> PathBuf { self.dir.path().join(name) }
+}
+
+impl Default for TestDir {
+    fn default() -> Self { TestDir::new() }
+}
+
+/** A `BothAuth` in a temporary directory; see the module documentation. */
+#[derive(Debug)]
+pub struct TestAuth {
+    /* Declared first so it's dropped before the directory is deleted. */
+    auth: BothAuth,
+    dir: TestDir,
+}
+
+impl TestAuth {
+    /** Creates an empty database in a new temporary directory. */
+    pub fn new() -> Self { TestAuth::with_users(&[]) }
+    
+    /**
+    Creates a database in a new temporary directory, with the given
+    (user name, password) pairs as users, and saves it.
+    */
+    pub fn with_users(users: &[(&str, &str)]) -> Self {
+        let dir = TestDir::new();
+        let auth = BothAuth::new(dir.file(PWD_FILE), dir.file(KEY_FILE))
+            .expect("creating test database");
+        for (uname, password) in users.iter() {
+            auth.add_user(uname, password, b"").expect("adding test user");
+        }
+        auth.save_passwords().expect("saving test database");
+        
+        return TestAuth { auth, dir };
+    }
+    
+    /** The temporary directory holding the files. */
+    pub fn dir(&self) -> &Path { self.dir.path() }
+    
+    /** The path of the password file. */
+    pub fn pwd_file(&self) -> PathBuf { self.dir.file(PWD_FILE) }
+    
+    /** The path of the key file. */
+    pub fn key_file(&self) -> PathBuf { self.dir.file(KEY_FILE) }
+    
+    /**
+    Saves the database and opens the files again as a separate `BothAuth`,
+    for testing that changes survive a restart.
+    */
+    pub fn reopen(&self) -> BothAuth {
+        self.auth.save_passwords().expect("saving test database");
+        self.auth.save_keys().expect("saving test database");
+        BothAuth::open(self.pwd_file(), self.key_file()).expect("reopening test database")
+    }
+}
+
+impl Default for TestAuth {
+    fn default() -> Self { TestAuth::new() }
+}
+
+impl Deref for TestAuth {
+    type Target = BothAuth;
+    
+    fn deref(&self) -> &BothAuth { &self.auth }
+}
+
+/* The armed faults: the file, whether saving (`Op::Write`) or opening
+   (`Op::Read`) it fails, and where. */
+static FAULTS: Mutex<Vec<(PathBuf, Op, Fault)>> = const_mutex(Vec::new());
+
+/** Returns the fault armed for the given operation on the file at `path`, if any. */
+pub(crate) fn armed(path: &Path, op: Op) -> Option<Fault> {
+    let faults = FAULTS.lock();
+    faults.iter().rev()
+        .find(|(p, o, _)| p == path && *o == op)
+        .map(|(_, _, fault)| *fault)
+}
+
+/** Makes every save (or every open) of one `PwdAuth` or `KeyAuth` file
+    fail at a given point, until it's dropped.
+    
+    A failing save writes the data before the fault (to the temporary file
+    it would have renamed into place) and then returns a `FileError`,
+    leaving the file itself as it was; a failing open reads the data before
+    the fault and then returns one. Either way, the error's kind is
+    `ErrorKind::Other`. If there's too little data to reach the fault, the
+    save fails when it's flushed, or the open when it reaches the end of
+    the file, so an armed fault always fails the operation.
+    
+    The file must be named by the same path the database was created or
+    opened with. Faults for different files don't affect each other, so
+    tests using them can run in parallel.
+*/
+#[derive(Debug)]
+pub struct FaultInjector {
+    path: PathBuf,
+    op: Op,
+}
+
+impl FaultInjector {
+    /** Makes saving the file at `path` fail at `fault`. */
+    pub fn on_save(path: impl AsRef<Path>, fault: Fault) -> Self {
+        FaultInjector::arm(path.as_ref(), Op::Write, fault)
+    }
+    
+    /** Makes opening the file at `path` fail at `fault`. */
+    pub fn on_open(path: impl AsRef<Path>, fault: Fault) -> Self {
+        FaultInjector::arm(path.as_ref(), Op::Read, fault)
+    }
+    
+    fn arm(path: &Path, op: Op, fault: Fault) -> Self {
+        FAULTS.lock().push((PathBuf::from(path), op, fault));
+        return FaultInjector { path: PathBuf::from(path), op };
+    }
+}
+
+impl Drop for FaultInjector {
+    fn drop(&mut self) {
+        let mut faults = FAULTS.lock();
+        if let Some(n) = faults.iter().rposition(|(p, o, _)| *p == self.path && *o == self.op) {
+            let _ = faults.remove(n);
+        }
+    }
+}
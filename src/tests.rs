@@ -1,13 +1,10 @@
 #![cfg(test)]
+#![allow(clippy::bool_assert_comparison, clippy::needless_borrow)]
 use std::collections::HashMap;
 use std::path::Path;
 
-use serial_test::serial;
-
 use super::*;
-
-static NEW_USERS_FILE: &str = "test/new_users.csv";
-static NEW_KEYS_FILE:  &str = "test/new_keys.csv";
+use super::testing::TestDir;
 
 static UNAMES_AND_PWDS: &[[&str; 2]] = &[
     ["ted", "frogs"],
@@ -15,7 +12,34 @@ static UNAMES_AND_PWDS: &[[&str; 2]] = &[
     ["qwert", "asdfjkl;"],
 ];
 
-fn ensure_delete(p: &dyn AsRef<Path>) {
+/**
+One test's files, in a temporary directory of its own (so tests can run
+in parallel), which is deleted when it's dropped.
+*/
+struct Fixture {
+    dir: TestDir,
+    users: String,
+    keys: String,
+}
+
+impl Fixture {
+    fn new() -> Self {
+        let dir = TestDir::new();
+        let users = path_string(dir.file("new_users.csv"));
+        let keys = path_string(dir.file("new_keys.csv"));
+        return Fixture { dir, users, keys };
+    }
+    
+    /** The path of the file (or directory) `name` in the test's directory. */
+    fn file(&self, name: &str) -> String { path_string(self.dir.file(name)) }
+    
+    /** The path of the key file's not-valid-before file. */
+    fn nvb(&self) -> String { format!("{}.nvb", self.keys) }
+}
+
+fn path_string(path: std::path::PathBuf) -> String { path.to_str().unwrap().to_string() }
+
+fn ensure_delete(p: impl AsRef<Path>) {
     let p = p.as_ref();
     if Path::exists(p) {
         std::fs::remove_file(p).unwrap();
@@ -23,25 +47,24 @@ fn ensure_delete(p: &dyn AsRef<Path>) {
 }
 
 #[test]
-#[serial]
 fn pwd_auth() {
+    let fx = Fixture::new();
     let salt = "xslt";
-    ensure_delete(&NEW_USERS_FILE);
     
-    let mut a = PwdAuth::new(&NEW_USERS_FILE).unwrap();
+    let a = PwdAuth::new(&fx.users).unwrap();
     for unp in UNAMES_AND_PWDS.iter() {
         a.add_user(unp[0], unp[1], salt.as_bytes()).unwrap();
     }
     
     let uname = UNAMES_AND_PWDS[0][0];
     assert_eq!(a.add_user(uname, "doesn't matter", "same".as_bytes()),
-               Err(DataError::UserExists));
+               Err(DataError::UserExists { uname: uname.to_string() }));
     
     assert_eq!(a.is_dirty(), true);
     a.save().unwrap();
     assert_eq!(a.is_dirty(), false);
     
-    let mut a = PwdAuth::open(&NEW_USERS_FILE).unwrap();
+    let a = PwdAuth::open(&fx.users).unwrap();
     for unp in UNAMES_AND_PWDS.iter() {
         a.check_password(unp[0], unp[1], salt.as_bytes()).unwrap();
     }
@@ -53,13 +76,18 @@ fn pwd_auth() {
     a.save().unwrap();
     assert_eq!(a.is_dirty(), false);
     
-    let a = PwdAuth::open(&NEW_USERS_FILE).unwrap();
+    let a = PwdAuth::open(&fx.users).unwrap();
     assert_eq!(a.is_dirty(), false);
     assert_eq!(a.check_password(uname, UNAMES_AND_PWDS[0][1], salt.as_bytes()),
                Err(DataError::NoSuchUser));
     
     let (uname, pass) = (UNAMES_AND_PWDS[1][0], UNAMES_AND_PWDS[1][1]);
-    a.check_password(uname, pass, salt.as_bytes()).unwrap();
+    let ok = a.check_password(uname, pass, salt.as_bytes()).unwrap();
+    assert_eq!(ok, AuthOk {
+        uname: uname.to_string(),
+        needs_rehash: false,
+        password_expires_in: None,
+    });
     assert_eq!(a.check_password(uname, "wrong password", salt.as_bytes()),
                Err(DataError::BadPassword));
     assert_eq!(a.check_password(uname, pass, "wrong salt".as_bytes()),
@@ -68,32 +96,33 @@ fn pwd_auth() {
 }
 
 #[test]
-#[serial]
 fn key_auth() {
-    ensure_delete(&NEW_KEYS_FILE);
-    
+    let fx = Fixture::new();
     let mut keyz: HashMap<String, String> = HashMap::new();
     
-    let mut a = KeyAuth::new(&NEW_KEYS_FILE).unwrap();
+    let a = KeyAuth::new(&fx.keys).unwrap();
     assert_eq!(a.is_dirty(), false);
     for unp in UNAMES_AND_PWDS.iter() {
         let u = unp[0];
-        let k = a.issue_key(u);
+        let k = a.issue_key(u).unwrap();
         keyz.insert(u.to_string(), k);
     }
     
     let uname = UNAMES_AND_PWDS[0][0];
     let key   = keyz.get(uname).unwrap().clone();
-    a.check_key(keyz.get(uname).unwrap(), uname).unwrap();
+    assert_eq!(a.check_key(keyz.get(uname).unwrap(), uname).unwrap().uname, uname);
     a.invalidate_key(&key).unwrap();
-    assert_eq!(a.check_key(&key, &uname), Err(DataError::KeyExpired));
+    assert_eq!(a.check_key(&key, &uname), Err(DataError::KeyRevoked));
+    assert_eq!(a.invalidate_key(&key), Err(DataError::KeyRevoked));
+    assert_eq!(a.refresh_key(&key), Err(DataError::KeyRevoked));
     
     assert_eq!(a.is_dirty(), true);
     a.save().unwrap();
     assert_eq!(a.is_dirty(), false);
     
-    let mut a = KeyAuth::open(&NEW_KEYS_FILE).unwrap();
-    assert_eq!(a.check_key(&key, &uname), Err(DataError::NoSuchKey));
+    /* Revoked keys are saved until they expire. */
+    let a = KeyAuth::open(&fx.keys).unwrap();
+    assert_eq!(a.check_key(&key, &uname), Err(DataError::KeyRevoked));
     
     let uname = UNAMES_AND_PWDS[1][0];
     let key   = keyz.get(uname).unwrap().clone();
@@ -101,10 +130,10 @@ fn key_auth() {
     assert_eq!(a.is_dirty(), false);
     a.invalidate_key(&key).unwrap();
     assert_eq!(a.is_dirty(), true);
-    assert_eq!(a.check_key(&key, &uname), Err(DataError::KeyExpired));
+    assert_eq!(a.check_key(&key, &uname), Err(DataError::KeyRevoked));
 
-    a.cull_keys();
-    assert_eq!(a.check_key(&key, &uname), Err(DataError::NoSuchKey));
+    a.cull_keys().unwrap();
+    assert_eq!(a.check_key(&key, &uname), Err(DataError::KeyRevoked));
     
     let uname = UNAMES_AND_PWDS[2][0];
     let key   = keyz.get(uname).unwrap().clone();
@@ -114,15 +143,73 @@ fn key_auth() {
 }
 
 #[test]
-#[serial]
+fn time_remaining() {
+    let fx = Fixture::new();
+    let a = KeyAuth::new(&fx.keys).unwrap();
+    let key = a.issue_key("ted").unwrap();
+    let remaining = a.time_remaining(&key).unwrap();
+    assert!(remaining <= std::time::Duration::from_secs(20 * 60));
+    assert!(remaining > std::time::Duration::from_secs(19 * 60));
+    assert_eq!(a.time_remaining("not a key"), Err(DataError::NoSuchKey));
+}
+
+#[test]
+fn try_locking() {
+    let fx = Fixture::new();
+    let timeout = std::time::Duration::from_millis(10);
+    let p = PwdAuth::new(&fx.users).unwrap();
+    p.add_user("ted", "frogs", b"salt").unwrap();
+    assert_eq!(p.try_check_password("ted", "frogs", b"salt", timeout).unwrap().uname, "ted");
+    assert_eq!(p.try_check_password("ted", "toads", b"salt", timeout), Err(DataError::BadPassword));
+    
+    let k = KeyAuth::new(&fx.keys).unwrap();
+    let key = k.issue_key("ted").unwrap();
+    assert_eq!(k.try_check_key(&key, "ted", timeout).unwrap().uname, "ted");
+    assert_eq!(k.try_check_key(&key, "bob", timeout), Err(DataError::BadUsername));
+    assert_eq!(k.try_check_key("not a key", "ted", timeout), Err(DataError::NoSuchKey));
+}
+
+#[test]
+fn check_and_cull_keys_at() {
+    use std::time::{Duration, SystemTime};
+    let fx = Fixture::new();
+    let a = KeyAuth::new(&fx.keys).unwrap();
+    let key = a.issue_key("ted").unwrap();
+    let revoked = a.issue_key("bob").unwrap();
+    a.invalidate_key(&revoked).unwrap();
+    
+    let later = SystemTime::now() + Duration::from_secs(3600);
+    a.check_key_at(&key, "ted", SystemTime::now()).unwrap();
+    assert!(matches!(a.check_key_at(&key, "ted", later), Err(DataError::KeyExpired { .. })));
+    a.check_key(&key, "ted").unwrap();
+    
+    /* Culling "later" removes revoked keys along with expired ones. */
+    a.cull_keys_at(later).unwrap();
+    assert_eq!(a.check_key(&key, "ted"), Err(DataError::NoSuchKey));
+    assert_eq!(a.check_key(&revoked, "bob"), Err(DataError::NoSuchKey));
+}
+
+#[test]
+fn rotate_key() {
+    let fx = Fixture::new();
+    let a = KeyAuth::new(&fx.keys).unwrap();
+    let key = a.issue_key("ted").unwrap();
+    assert_eq!(a.rotate_key(&key, "wrong user"), Err(DataError::BadUsername));
+    a.check_key(&key, "ted").unwrap();
+    
+    let new_key = a.rotate_key(&key, "ted").unwrap();
+    assert_eq!(a.check_key(&key, "ted"), Err(DataError::NoSuchKey));
+    a.check_key(&new_key, "ted").unwrap();
+    assert_eq!(a.rotate_key(&key, "ted"), Err(DataError::NoSuchKey));
+}
+
+#[test]
 fn both_auth() {
+    let fx = Fixture::new();
     let salt = "node";
     
-    for p in [NEW_USERS_FILE, NEW_KEYS_FILE].iter() {
-        ensure_delete(p);
-    }
     
-    let mut a = BothAuth::new(&NEW_USERS_FILE, &NEW_KEYS_FILE).unwrap();
+    let a = BothAuth::new(&fx.users, &fx.keys).unwrap();
     assert_eq!(a.pwd_dirty(), false);
     assert_eq!(a.key_dirty(), false);
     for unp in UNAMES_AND_PWDS.iter() {
@@ -131,7 +218,7 @@ fn both_auth() {
     assert_eq!(a.pwd_dirty(), true);
     assert_eq!(a.key_dirty(), false);
     
-    a.save_if_dirty().unwrap();
+    assert_eq!(a.save_if_dirty().unwrap(), SaveReport { passwords: true, ..Default::default() });
     assert_eq!(a.pwd_dirty(), false);
     assert_eq!(a.key_dirty(), false);
     
@@ -145,7 +232,7 @@ fn both_auth() {
     assert_eq!(a.pwd_dirty(), true);
     assert_eq!(a.key_dirty(), false);
     
-    let mut a = BothAuth::open(&NEW_USERS_FILE, &NEW_KEYS_FILE).unwrap();
+    let a = BothAuth::open(&fx.users, &fx.keys).unwrap();
     assert_eq!(a.pwd_dirty(), false);
     assert_eq!(a.key_dirty(), false);
     
@@ -156,21 +243,23 @@ fn both_auth() {
     }
     assert_eq!(a.pwd_dirty(), false);
     assert_eq!(a.key_dirty(), true);
-    a.save_if_dirty().unwrap();
+    assert_eq!(a.save_if_dirty().unwrap(), SaveReport { keys: true, ..Default::default() });
+    assert_eq!(a.save_if_dirty().unwrap(), SaveReport::default());
+    a.save_passwords().unwrap();
     assert_eq!(a.pwd_dirty(), false);
     assert_eq!(a.key_dirty(), false);
     
     let (uname, _pass) = (UNAMES_AND_PWDS[0][0], UNAMES_AND_PWDS[0][1]);
     a.invalidate_key(keyz.get(uname).unwrap()).unwrap();
     assert_eq!(a.check_key(keyz.get(uname).unwrap(), uname),
-               Err(DataError::KeyExpired));
+               Err(DataError::KeyRevoked));
     a.remove_key(keyz.get(uname).unwrap()).unwrap();
     assert_eq!(a.check_key(keyz.get(uname).unwrap(), uname),
                Err(DataError::NoSuchKey));
     assert_eq!(a.pwd_dirty(), false);
     assert_eq!(a.key_dirty(), true);
     
-    let mut a = BothAuth::open(&NEW_USERS_FILE, &NEW_KEYS_FILE).unwrap();
+    let a = BothAuth::open(&fx.users, &fx.keys).unwrap();
     assert_eq!(a.pwd_dirty(), false);
     assert_eq!(a.key_dirty(), false);
     for unp in UNAMES_AND_PWDS.iter() {
@@ -178,8 +267,2885 @@ fn both_auth() {
     }
     
     assert_eq!(a.add_user(uname, "doesn't matter", salt.as_bytes()),
-                Err(DataError::UserExists));
+                Err(DataError::UserExists { uname: uname.to_string() }));
     assert_eq!(a.check_key("This will not be a key.", uname),
                Err(DataError::NoSuchKey)); 
+}
+
+#[test]
+fn capacity() {
+    let fx = Fixture::new();
+    let salt = "cap";
+    
+    let mut a = BothAuth::new(&fx.users, &fx.keys).unwrap();
+    a.max_users(Some(2));
+    a.add_user(UNAMES_AND_PWDS[0][0], UNAMES_AND_PWDS[0][1], salt.as_bytes()).unwrap();
+    a.add_user(UNAMES_AND_PWDS[1][0], UNAMES_AND_PWDS[1][1], salt.as_bytes()).unwrap();
+    assert_eq!(a.add_user(UNAMES_AND_PWDS[2][0], UNAMES_AND_PWDS[2][1], salt.as_bytes()),
+               Err(DataError::CapacityExceeded));
+    
+    let uname = UNAMES_AND_PWDS[0][0];
+    a.max_keys(Some(2));
+    let first = a.issue_key(uname).unwrap();
+    let second = a.issue_key(uname).unwrap();
+    assert_eq!(a.issue_key(uname), Err(DataError::CapacityExceeded));
+    
+    let evictions = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+    let ev = evictions.clone();
+    a.on_event(move |e| ev.lock().unwrap().push(e.clone()));
+    a.capacity_policy(CapacityPolicy::EvictSoonestExpiring);
+    let third = a.issue_key(uname).unwrap();
+    match evictions.lock().unwrap().as_slice() {
+        [AuthEvent::KeyEvicted { uname: u, .. }] => assert_eq!(u, uname),
+        x => panic!("expected one eviction, got {:?}", x),
+    }
+    assert_eq!(a.check_key(&first, uname), Err(DataError::NoSuchKey));
+    a.check_key(&second, uname).unwrap();
+    a.check_key(&third, uname).unwrap();
+}
+
+#[test]
+fn admin() {
+    let fx = Fixture::new();
+    let salt = "adm";
+    
+    let (boss, boss_pwd) = (UNAMES_AND_PWDS[0][0], UNAMES_AND_PWDS[0][1]);
+    let (peon, peon_pwd) = (UNAMES_AND_PWDS[1][0], UNAMES_AND_PWDS[1][1]);
+    
+    let a = BothAuth::new(&fx.users, &fx.keys).unwrap();
+    a.add_user(boss, boss_pwd, salt.as_bytes()).unwrap();
+    a.add_user(peon, peon_pwd, salt.as_bytes()).unwrap();
+    a.set_admin(boss, true).unwrap();
+    assert_eq!(a.set_admin("nobody", true), Err(DataError::NoSuchUser));
+    a.save_if_dirty().unwrap();
+    
+    let a = BothAuth::open(&fx.users, &fx.keys).unwrap();
+    assert_eq!(a.is_admin(boss), Ok(true));
+    assert_eq!(a.is_admin(peon), Ok(false));
+    a.check_password_admin(boss, boss_pwd, salt.as_bytes()).unwrap();
+    assert_eq!(a.check_password_admin(peon, peon_pwd, salt.as_bytes()),
+               Err(DataError::NotAdmin));
+    
+    let boss_key = a.issue_key(boss).unwrap();
+    let peon_key = a.issue_key(peon).unwrap();
+    a.check_key_admin(&boss_key, boss).unwrap();
+    a.require_admin(&boss_key, boss).unwrap();
+    assert_eq!(a.check_key_admin(&peon_key, peon), Err(DataError::NotAdmin));
+    assert_eq!(a.require_admin(&peon_key, boss), Err(DataError::BadUsername));
+}
+
+#[test]
+fn invalidate_all() {
+    let fx = Fixture::new();
+    let uname = UNAMES_AND_PWDS[0][0];
+    let a = KeyAuth::new(&fx.keys).unwrap();
+    let old_key = a.issue_key(uname).unwrap();
+    a.save().unwrap();
+    let stale_copy = std::fs::read(&fx.keys).unwrap();
+    
+    a.invalidate_all().unwrap();
+    assert_eq!(a.is_dirty(), true);
+    assert!(matches!(a.check_key(&old_key, uname), Err(DataError::KeyExpired { .. })));
+    let new_key = a.issue_key(uname).unwrap();
+    a.check_key(&new_key, uname).unwrap();
+    a.save().unwrap();
+    
+    let a = KeyAuth::open(&fx.keys).unwrap();
+    a.check_key(&new_key, uname).unwrap();
+    assert_eq!(a.check_key(&old_key, uname), Err(DataError::NoSuchKey));
+    
+    /* Restoring a stale key file must not resurrect old sessions. */
+    std::fs::write(&fx.keys, stale_copy).unwrap();
+    let a = KeyAuth::open(&fx.keys).unwrap();
+    assert_eq!(a.check_key(&old_key, uname), Err(DataError::NoSuchKey));
+}
+
+#[test]
+fn dump_to() {
+    let fx = Fixture::new();
+    let salt = "dmp";
+    let dump_dir = &fx.file("dump");
+    std::fs::create_dir(dump_dir).unwrap();
+    
+    let (uname, pwd) = (UNAMES_AND_PWDS[0][0], UNAMES_AND_PWDS[0][1]);
+    let a = BothAuth::new(&fx.users, &fx.keys).unwrap();
+    assert_eq!(a.issue_user_key(uname), Err(DataError::NoSuchUser));
+    a.add_user(uname, pwd, salt.as_bytes()).unwrap();
+    let key = a.issue_user_key(uname).unwrap();
+    a.dump_to(dump_dir).unwrap();
+    assert_eq!(a.pwd_dirty(), true);
+    assert_eq!(a.key_dirty(), true);
+    
+    let a = BothAuth::open(fx.file("dump/new_users.csv"), fx.file("dump/new_keys.csv")).unwrap();
+    a.check_password(uname, pwd, salt.as_bytes()).unwrap();
+    a.check_key(&key, uname).unwrap();
+}
+
+#[test]
+fn write_through() {
+    let fx = Fixture::new();
+    let salt = "wt";
+    
+    let (uname, pwd) = (UNAMES_AND_PWDS[0][0], UNAMES_AND_PWDS[0][1]);
+    let mut a = BothAuth::new(&fx.users, &fx.keys).unwrap();
+    a.write_through(true);
+    a.add_user(uname, pwd, salt.as_bytes()).unwrap();
+    let key = a.issue_user_key(uname).unwrap();
+    assert_eq!(a.pwd_dirty(), false);
+    assert_eq!(a.key_dirty(), false);
+    
+    let b = BothAuth::open(&fx.users, &fx.keys).unwrap();
+    b.check_password(uname, pwd, salt.as_bytes()).unwrap();
+    b.check_key(&key, uname).unwrap();
+    
+    a.change_password(uname, "new password", salt.as_bytes()).unwrap();
+    a.remove_key(&key).unwrap();
+    let b = BothAuth::open(&fx.users, &fx.keys).unwrap();
+    b.check_password(uname, "new password", salt.as_bytes()).unwrap();
+    assert_eq!(b.check_key(&key, uname), Err(DataError::NoSuchKey));
+}
+
+#[test]
+fn touch() {
+    let fx = Fixture::new();
+    let uname = UNAMES_AND_PWDS[0][0];
+    let mut a = KeyAuth::new(&fx.keys).unwrap();
+    let key = a.issue_key(uname).unwrap();
+    a.save().unwrap();
+    
+    assert_eq!(a.touch(&key, uname), Ok(false));
+    assert_eq!(a.is_dirty(), false);
+    assert_eq!(a.touch(&key, "someone else"), Err(DataError::BadUsername));
+    
+    a.touch_threshold(0.0);
+    assert_eq!(a.touch(&key, uname), Ok(true));
+    assert_eq!(a.is_dirty(), true);
+}
+
+#[test]
+fn file_errors() {
+    let fx = Fixture::new();
+    let e = PwdAuth::open(&fx.users).unwrap_err();
+    assert_eq!(e.kind, std::io::ErrorKind::NotFound);
+    assert_eq!(e.op, Op::Read);
+    assert_eq!(e.path, Path::new(&fx.users));
+    
+    let _ = PwdAuth::new(&fx.users).unwrap();
+    let e = PwdAuth::new(&fx.users).unwrap_err();
+    assert_eq!(e.kind, std::io::ErrorKind::AlreadyExists);
+    assert_eq!(e.op, Op::Create);
+    assert!(e.to_string().contains(&fx.users));
+}
+
+#[test]
+fn new_with_dirs() {
+    let fx = Fixture::new();
+    let pwd_file = &fx.file("nested/auth/users.csv");
+    let key_file = &fx.file("nested/auth/keys/keys.csv");
+    assert_eq!(BothAuth::new(pwd_file, key_file).unwrap_err().kind,
+               std::io::ErrorKind::NotFound);
+    
+    let _ = BothAuth::new_with_dirs(pwd_file, key_file).unwrap();
+    let _ = BothAuth::open(pwd_file, key_file).unwrap();
+    
+    std::fs::remove_file(key_file).unwrap();
+    let a = BothAuth::open_or_new(pwd_file, key_file).unwrap();
+    a.add_user("u", "p", b"s").unwrap();
+    a.save_if_dirty().unwrap();
+    let a = BothAuth::open_or_new(pwd_file, key_file).unwrap();
+    a.check_password("u", "p", b"s").unwrap();
+}
+
+#[test]
+fn builder() {
+    let fx = Fixture::new();
+    let e = BothAuth::builder().pwd_file(&fx.users).build().unwrap_err();
+    assert_eq!(e.kind, std::io::ErrorKind::InvalidInput);
+    
+    let policy = UsernamePolicy {
+        min_length: 3,
+        max_length: Some(8),
+        allowed_chars: Some("abcdefghijklmnopqrstuvwxyz0123456789".to_string()),
+    };
+    let a = BothAuth::builder()
+        .pwd_file(&fx.users)
+        .key_file(&fx.keys)
+        .key_length(12)
+        .key_charset(KeyCharset::Hex)
+        .key_life(std::time::Duration::from_secs(60))
+        .username_policy(policy)
+        .build()
+        .unwrap();
+    
+    for bad in ["ab", "waytoolongname", "Upper", "spa ce"].iter() {
+        assert_eq!(a.add_user(bad, "pwd", b"salt"), Err(DataError::InvalidUsername));
+    }
+    a.add_user("ted", "pwd", b"salt").unwrap();
+    let key = a.issue_user_key("ted").unwrap();
+    assert_eq!(key.len(), 12);
+    assert!(key.chars().all(|c| c.is_ascii_hexdigit()));
+    assert!(a.time_remaining(&key).unwrap() <= std::time::Duration::from_secs(60));
+}
+
+#[test]
+fn key_uniformity() {
+    let fx = Fixture::new();
+    let mut a = KeyAuth::new(&fx.keys).unwrap();
+    assert!((a.key_entropy_bits() - 32.0 * 75f64.log2()).abs() < 1e-9);
+    
+    /* Three characters (not a power of two), one of them repeated, which
+       must not make it more likely. */
+    a.chars("abca");
+    a.length(1000);
+    assert!((a.key_entropy_bits() - 1000.0 * 3f64.log2()).abs() < 1e-9);
+    
+    let mut counts: HashMap<char, usize> = HashMap::new();
+    for _ in 0..30 {
+        for c in a.issue_key("u").unwrap().chars() {
+            *counts.entry(c).or_insert(0) += 1;
+        }
+    }
+    assert_eq!(counts.len(), 3);
+    
+    /* Chi-squared test with 2 degrees of freedom; 13.82 is the critical
+       value at p = 0.001. */
+    let expected = 30_000.0 / 3.0;
+    let chi_sq: f64 = counts.values()
+        .map(|&n| (n as f64 - expected).powi(2) / expected)
+        .sum();
+    assert!(chi_sq < 13.82, "chi-squared = {}, counts = {:?}", chi_sq, counts);
+}
+
+#[test]
+fn compressed_keys() {
+    let fx = Fixture::new();
+    let uname = UNAMES_AND_PWDS[0][0];
+    let mut a = KeyAuth::new(&fx.keys).unwrap();
+    let key = a.issue_key(uname).unwrap();
+    
+    for c in [Compression::Gzip, Compression::Zstd].iter() {
+        a.compression(*c);
+        let enabled = match c {
+            Compression::Gzip => cfg!(feature = "gzip"),
+            Compression::Zstd => cfg!(feature = "zstd"),
+            Compression::None => true,
+        };
+        if !enabled {
+            assert_eq!(a.save().unwrap_err().kind, std::io::ErrorKind::Unsupported);
+            continue;
+        }
+        a.save().unwrap();
+        assert!(!std::fs::read(&fx.keys).unwrap().starts_with(b"key,"));
+        let b = KeyAuth::open(&fx.keys).unwrap();
+        b.check_key(&key, uname).unwrap();
+    }
+}
+
+#[test]
+fn verify_consistency() {
+    let fx = Fixture::new();
+    let salt = b"salt";
+    let a = BothAuth::new(&fx.users, &fx.keys).unwrap();
+    let [uname, pwd] = UNAMES_AND_PWDS[0];
+    a.add_user(uname, pwd, salt).unwrap();
+    let good = a.issue_user_key(uname).unwrap();
+    let orphan = a.issue_key("nobody").unwrap();
+    
+    let report = a.verify_consistency(false).unwrap();
+    assert_eq!(report.is_consistent(), false);
+    assert_eq!(report.orphaned_keys, vec![(orphan.clone(), String::from("nobody"))]);
+    a.check_key(&orphan, "nobody").unwrap();
+    
+    let report = a.verify_consistency(true).unwrap();
+    assert_eq!(report.repaired, true);
+    assert_eq!(a.check_key(&orphan, "nobody"), Err(DataError::NoSuchKey));
+    a.check_key(&good, uname).unwrap();
+    assert!(a.verify_consistency(false).unwrap().is_consistent());
+}
+
+#[cfg(feature = "bundle")]
+#[test]
+fn bundle() {
+    let fx = Fixture::new();
+    let bundle_file = &fx.file("bundle.tar");
+    let (pwd_copy, key_copy) = (&fx.file("bundle/users.csv"), &fx.file("bundle/keys.csv"));
+    let _ = std::fs::create_dir_all(fx.file("bundle"));
+    
+    let salt = b"salt";
+    let a = BothAuth::new(&fx.users, &fx.keys).unwrap();
+    let [uname, pwd] = UNAMES_AND_PWDS[0];
+    a.add_user(uname, pwd, salt).unwrap();
+    let key = a.issue_user_key(uname).unwrap();
+    a.export_bundle(bundle_file).unwrap();
+    assert_eq!(a.pwd_dirty(), true);
+    
+    let b = BothAuth::import_bundle(bundle_file, pwd_copy, key_copy).unwrap();
+    b.check_password(uname, pwd, salt).unwrap();
+    b.check_key(&key, uname).unwrap();
+    
+    /* Corrupt a byte of the password file's contents. */
+    let mut data = std::fs::read(bundle_file).unwrap();
+    let header = b"uname,hash,admin,uid,class,email,salt\n";
+    let i = data.windows(header.len()).position(|w| w == header).unwrap();
+    data[i + header.len()] ^= 1;
+    std::fs::write(bundle_file, &data).unwrap();
+    let e = BothAuth::import_bundle(bundle_file, pwd_copy, key_copy).unwrap_err();
+    assert_eq!(e.kind, std::io::ErrorKind::InvalidData);
+}
+
+#[test]
+fn sharding() {
+    let fx = Fixture::new();
+    let mut a = KeyAuth::new(&fx.keys).unwrap();
+    let keys: Vec<(&str, String)> = UNAMES_AND_PWDS.iter()
+        .map(|unp| (unp[0], a.issue_key(unp[0]).unwrap()))
+        .collect();
+    
+    /* Resharding keeps every key. */
+    for n in [1, 7, 64].iter() {
+        a.shards(*n);
+        for (uname, key) in keys.iter() {
+            a.check_key(key, uname).unwrap();
+        }
+    }
+}
+
+#[test]
+fn concurrent_stress() {
+    use std::sync::Arc;
+    let fx = Fixture::new();
+    const THREADS: usize = 8;
+    const ROUNDS: usize = 200;
+    let salt = b"salt";
+    let a = Arc::new(BothAuth::new(&fx.users, &fx.keys).unwrap());
+    
+    let handles: Vec<_> = (0..THREADS).map(|t| {
+        let a = Arc::clone(&a);
+        std::thread::spawn(move || {
+            let uname = format!("user{}", t);
+            a.add_user(&uname, "pwd", salt).unwrap();
+            let mut keys: Vec<String> = Vec::new();
+            for n in 0..ROUNDS {
+                let key = a.check_password_and_issue_key(&uname, "pwd", salt).unwrap();
+                a.check_key(&key, &uname).unwrap();
+                if n % 2 == 0 {
+                    a.invalidate_key(&key).unwrap();
+                } else {
+                    keys.push(key);
+                }
+                if n % 50 == 0 { a.save_if_dirty().unwrap(); }
+            }
+            (uname, keys)
+        })
+    }).collect();
+    
+    let results: Vec<(String, Vec<String>)> = handles.into_iter()
+        .map(|h| h.join().unwrap())
+        .collect();
+    a.save_if_dirty().unwrap();
+    
+    let b = BothAuth::open(&fx.users, &fx.keys).unwrap();
+    for (uname, keys) in results.iter() {
+        b.check_password(uname, "pwd", salt).unwrap();
+        assert_eq!(keys.len(), ROUNDS / 2);
+        for key in keys.iter() {
+            b.check_key(key, uname).unwrap();
+        }
+    }
+    assert_eq!(b.verify_consistency(false).unwrap().is_consistent(), true);
+}
+
+#[test]
+fn thread_safety() {
+    fn send_sync<T: Send + Sync>() {}
+    send_sync::<PwdAuth>();
+    send_sync::<KeyAuth>();
+    send_sync::<BothAuth>();
+    send_sync::<GroupAuth>();
+    send_sync::<InviteAuth>();
+    send_sync::<RememberAuth>();
+    send_sync::<LazyPwdAuth>();
+    send_sync::<Verifier>();
+    send_sync::<MultiAuth>();
+    send_sync::<DataError>();
+    send_sync::<FileError>();
+}
+
+#[test]
+fn concurrent_write_through() {
+    use std::sync::Arc;
+    let fx = Fixture::new();
+    const THREADS: usize = 8;
+    const ROUNDS: usize = 20;
+    let mut a = BothAuth::new(&fx.users, &fx.keys).unwrap();
+    a.write_through(true);
+    let a = Arc::new(a);
+    
+    let handles: Vec<_> = (0..THREADS).map(|t| {
+        let a = Arc::clone(&a);
+        std::thread::spawn(move || {
+            let uname = format!("user{}", t);
+            a.add_user(&uname, "pwd0", b"").unwrap();
+            let mut key = String::new();
+            for n in 0..ROUNDS {
+                let old = format!("pwd{}", n);
+                let new = format!("pwd{}", n + 1);
+                a.check_password(&uname, &old, b"").unwrap();
+                a.change_password(&uname, &new, b"").unwrap();
+                if !key.is_empty() { a.remove_key(&key).unwrap(); }
+                key = a.check_password_and_issue_key(&uname, &new, b"").unwrap();
+                /* Another thread's user comes and goes. */
+                let other = format!("temp{}", t);
+                a.add_user(&other, "x", b"").unwrap();
+                a.delete_user(&other).unwrap();
+            }
+            (uname, key)
+        })
+    }).collect();
+    let results: Vec<(String, String)> = handles.into_iter()
+        .map(|h| h.join().unwrap())
+        .collect();
+    
+    assert_eq!(a.pwd_dirty(), false);
+    assert_eq!(a.key_dirty(), false);
+    let b = BothAuth::open(&fx.users, &fx.keys).unwrap();
+    assert_eq!(b.usernames().len(), THREADS);
+    for (uname, key) in results.iter() {
+        b.check_password(uname, &format!("pwd{}", ROUNDS), b"").unwrap();
+        b.check_key(key, uname).unwrap();
+    }
+}
+
+#[test]
+fn concurrent_readers_and_savers() {
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    let fx = Fixture::new();
+    const THREADS: usize = 4;
+    let snapshot = fx.file("snapshot");
+    let _ = std::fs::create_dir(&snapshot);
+    let a = Arc::new(BothAuth::new(&fx.users, &fx.keys).unwrap());
+    a.add_user("ted", "frogs", b"").unwrap();
+    let keys: Vec<String> = (0..100).map(|_| a.issue_key("ted").unwrap()).collect();
+    let keys = Arc::new(keys);
+    let done = Arc::new(AtomicBool::new(false));
+    
+    /* Readers check keys while savers write the databases (and snapshots
+       of them) out from under them. */
+    let readers: Vec<_> = (0..THREADS).map(|_| {
+        let (a, keys, done) = (Arc::clone(&a), Arc::clone(&keys), Arc::clone(&done));
+        std::thread::spawn(move || {
+            let mut checks: usize = 0;
+            while !done.load(Ordering::SeqCst) {
+                for key in keys.iter() { a.check_key(key, "ted").unwrap(); }
+                checks += keys.len();
+            }
+            checks
+        })
+    }).collect();
+    let savers: Vec<_> = (0..THREADS).map(|_| {
+        let (a, snapshot) = (Arc::clone(&a), snapshot.clone());
+        std::thread::spawn(move || {
+            for _ in 0..20 {
+                a.issue_key("ted").unwrap();
+                a.save_if_dirty().unwrap();
+                a.dump_to(&snapshot).unwrap();
+            }
+        })
+    }).collect();
+    
+    for h in savers.into_iter() { h.join().unwrap(); }
+    done.store(true, Ordering::SeqCst);
+    for h in readers.into_iter() { assert!(h.join().unwrap() > 0); }
+    
+    let snap = BothAuth::open(Path::new(&snapshot).join("new_users.csv"),
+                              Path::new(&snapshot).join("new_keys.csv")).unwrap();
+    for key in keys.iter() { snap.check_key(key, "ted").unwrap(); }
+}
+
+#[test]
+fn issue_rate_limit() {
+    use std::time::{Duration, SystemTime};
+    let fx = Fixture::new();
+    let minute = Duration::from_secs(60);
+    let [ted, eyes] = [UNAMES_AND_PWDS[0][0], UNAMES_AND_PWDS[1][0]];
+    let mut a = KeyAuth::new(&fx.keys).unwrap();
+    a.issue_rate_limit(Some(2), minute);
+    
+    let key = a.issue_key(ted).unwrap();
+    a.issue_key(ted).unwrap();
+    assert_eq!(a.issue_key(ted), Err(DataError::IssuanceThrottled));
+    assert_eq!(DataError::IssuanceThrottled.suggested_status(), 429);
+    a.issue_key(eyes).unwrap();
+    a.rotate_key(&key, ted).unwrap();
+    
+    /* Culling as of a later time doesn't forget issues that still count,
+       but once the window has passed they no longer do. */
+    a.cull_keys_at(SystemTime::now() + minute).unwrap();
+    assert_eq!(a.issue_key(ted), Err(DataError::IssuanceThrottled));
+    a.issue_rate_limit(Some(2), Duration::from_millis(1));
+    std::thread::sleep(Duration::from_millis(5));
+    a.issue_key(ted).unwrap();
+    
+    a.issue_rate_limit(None, minute);
+    for _ in 0..5 { a.issue_key(ted).unwrap(); }
+}
+
+#[test]
+fn pepper() {
+    let fx = Fixture::new();
+    let pepper_file = &fx.file("pepper.txt");
+    std::fs::write(pepper_file, "pepper\n").unwrap();
+    
+    let [uname, pwd] = UNAMES_AND_PWDS[0];
+    let salt = b"salt";
+    let mut a = PwdAuth::new(&fx.users).unwrap();
+    a.pepper(&FileSecret(pepper_file.into())).unwrap();
+    a.add_user(uname, pwd, salt).unwrap();
+    a.save().unwrap();
+    
+    let mut b = PwdAuth::open(&fx.users).unwrap();
+    assert_eq!(b.check_password(uname, pwd, salt), Err(DataError::BadPassword));
+    b.pepper(&|| Ok(b"pepper".to_vec())).unwrap();
+    b.check_password(uname, pwd, salt).unwrap();
+    assert!(!format!("{:?}", b).contains("112, 101, 112"));
+    
+    let e = b.pepper(&EnvSecret("AUTHLITE_TEST_NO_SUCH_VAR".into())).unwrap_err();
+    assert_eq!(e.kind(), std::io::ErrorKind::NotFound);
+    
+    let e = BothAuth::builder()
+        .pwd_file(&fx.users)
+        .key_file(&fx.keys)
+        .pepper(&EnvSecret("AUTHLITE_TEST_NO_SUCH_VAR".into()))
+        .build()
+        .unwrap_err();
+    assert_eq!(e.kind, std::io::ErrorKind::NotFound);
+}
+
+/* A (hopelessly insecure) hasher that just stores what it's given. */
+struct PlainHasher;
+
+impl PasswordHasher for PlainHasher {
+    fn hash(&self, password: &str, salt: &[u8]) -> String {
+        format!("plain${}${}", password, String::from_utf8_lossy(salt))
+    }
+    
+    fn verify(&self, password: &str, salt: &[u8], stored: &str) -> bool {
+        self.hash(password, salt) == stored
+    }
+    
+    fn needs_rehash(&self, stored: &str) -> bool { stored.len() < 24 }
+}
+
+#[test]
+fn password_hasher() {
+    let fx = Fixture::new();
+    let [uname, pwd] = UNAMES_AND_PWDS[0];
+    let salt = b"salt";
+    let mut a = PwdAuth::new(&fx.users).unwrap();
+    a.password_hasher(PlainHasher);
+    a.pepper(&|| Ok(b"pepper".to_vec())).unwrap();
+    a.add_user(uname, pwd, salt).unwrap();
+    a.save().unwrap();
+    let contents = std::fs::read_to_string(&fx.users).unwrap();
+    assert!(contents.contains("plain$frogs$saltpepper"));
+    
+    let mut b = PwdAuth::open(&fx.users).unwrap();
+    b.pepper(&|| Ok(b"pepper".to_vec())).unwrap();
+    assert_eq!(b.check_password(uname, pwd, salt), Err(DataError::BadPassword));
+    b.password_hasher(PlainHasher);
+    assert_eq!(b.check_password(uname, pwd, salt).unwrap().needs_rehash, true);
+    assert_eq!(b.check_password(uname, "toads", salt), Err(DataError::BadPassword));
+    
+    let c = BothAuth::builder()
+        .pwd_file(&fx.users)
+        .key_file(&fx.keys)
+        .password_hasher(PlainHasher)
+        .pepper(&|| Ok(b"pepper".to_vec()))
+        .build()
+        .unwrap();
+    c.check_password(uname, pwd, salt).unwrap();
+}
+
+#[test]
+fn login() {
+    let fx = Fixture::new();
+    let [uname, pwd] = UNAMES_AND_PWDS[0];
+    let salt = b"salt";
+    let mut a = BothAuth::new(&fx.users, &fx.keys).unwrap();
+    a.add_user(uname, pwd, salt).unwrap();
+    assert_eq!(a.login(uname, "wrong", salt), Err(DataError::BadPassword));
+    
+    let LoginResult { session_key, csrf_token } = a.login(uname, pwd, salt).unwrap();
+    a.check_key(&session_key, uname).unwrap();
+    a.check_csrf(&session_key, &csrf_token).unwrap();
+    assert_eq!(a.csrf_token(&session_key), csrf_token);
+    assert_eq!(a.check_csrf(&session_key, "nonsense"), Err(DataError::BadToken));
+    let other = a.login(uname, pwd, salt).unwrap();
+    assert_eq!(a.check_csrf(&session_key, &other.csrf_token), Err(DataError::BadToken));
+    
+    /* A configured secret makes tokens stable across instances. */
+    let secret = || Ok(b"signing secret".to_vec());
+    a.signing_secret(&secret).unwrap();
+    let token = a.csrf_token(&session_key);
+    assert_ne!(token, csrf_token);
+    a.save_if_dirty().unwrap();
+    let mut b = BothAuth::open(&fx.users, &fx.keys).unwrap();
+    b.signing_secret(&secret).unwrap();
+    b.check_csrf(&session_key, &token).unwrap();
+}
+
+#[test]
+fn remember_tokens() {
+    let fx = Fixture::new();
+    let token_file = &fx.file("remember.csv");
+    let uname = UNAMES_AND_PWDS[0][0];
+    
+    let r = RememberAuth::new(token_file).unwrap();
+    let first = r.issue_remember_token(uname);
+    assert_eq!(r.is_dirty(), true);
+    r.save().unwrap();
+    
+    let r = RememberAuth::open(token_file).unwrap();
+    let (who, second) = r.redeem_remember_token(&first.selector, &first.validator).unwrap();
+    assert_eq!(who, uname);
+    assert_eq!(second.selector, first.selector);
+    assert_ne!(second.validator, first.validator);
+    let (_, third) = r.redeem_remember_token(&second.selector, &second.validator).unwrap();
+    
+    /* Replaying an old validator revokes the whole series. */
+    assert_eq!(r.redeem_remember_token(&first.selector, &first.validator),
+               Err(DataError::StolenToken));
+    assert_eq!(r.redeem_remember_token(&third.selector, &third.validator),
+               Err(DataError::NoSuchKey));
+    
+    let other = r.issue_remember_token(uname);
+    r.revoke_series(&other.selector).unwrap();
+    assert_eq!(r.revoke_series(&other.selector), Err(DataError::NoSuchKey));
+    let _ = r.issue_remember_token(uname);
+    let _ = r.issue_remember_token(uname);
+    assert_eq!(r.revoke_user(uname), 2);
+}
+
+#[test]
+fn groups() {
+    let fx = Fixture::new();
+    let group_file = &fx.file("groups.csv");
+    
+    let [ted, eyes] = [UNAMES_AND_PWDS[0][0], UNAMES_AND_PWDS[1][0]];
+    let g = GroupAuth::new(group_file).unwrap();
+    g.create_group("red").unwrap();
+    g.create_group("empty").unwrap();
+    assert_eq!(g.create_group("red"), Err(DataError::GroupExists));
+    g.add_member("red", ted).unwrap();
+    g.add_member("red", eyes).unwrap();
+    assert_eq!(g.add_member("blue", ted), Err(DataError::NoSuchGroup));
+    g.remove_member("red", eyes).unwrap();
+    assert_eq!(g.remove_member("red", eyes), Err(DataError::NotInGroup));
+    
+    let mut a = BothAuth::new(&fx.users, &fx.keys).unwrap();
+    let key = a.issue_key(ted).unwrap();
+    assert_eq!(a.check_key_and_group(&key, ted, "red"), Err(DataError::NotAttached));
+    a.attach_groups(g);
+    a.check_key_and_group(&key, ted, "red").unwrap();
+    assert_eq!(a.check_key_and_group(&key, ted, "empty"), Err(DataError::NotInGroup));
+    assert_eq!(a.save_if_dirty().unwrap().groups, true);
+    
+    let g = GroupAuth::open(group_file).unwrap();
+    g.user_in_group("red", ted).unwrap();
+    assert_eq!(g.user_in_group("red", eyes), Err(DataError::NotInGroup));
+    assert_eq!(g.groups_of(ted), vec![String::from("red")]);
+    g.delete_group("empty").unwrap();
+    assert_eq!(g.user_in_group("empty", ted), Err(DataError::NoSuchGroup));
+}
+
+#[test]
+fn bootstrap_admin() {
+    let fx = Fixture::new();
+    let salt = b"salt";
+    let a = BothAuth::new(&fx.users, &fx.keys).unwrap();
+    let pwd = a.bootstrap_admin("root", salt).unwrap();
+    assert_eq!(a.pwd_dirty(), true);
+    a.check_password_admin("root", &pwd, salt).unwrap();
+    assert_eq!(a.bootstrap_admin("root2", salt), Err(DataError::UserExists { uname: "root2".into() }));
+    assert_eq!(a.user_exists("root2"), Err(DataError::NoSuchUser));
+}
+
+#[test]
+fn validate_file() {
+    let fx = Fixture::new();
+    std::fs::write(&fx.users, "uname,hash,admin\n\
+        ted,0123abcd,false\n\
+        eyes2,,false\n\
+        ted,4567abcd,true\n\
+        qwert,89ab,maybe\n").unwrap();
+    let report = PwdAuth::validate_file(&fx.users).unwrap();
+    assert_eq!(report.rows, 4);
+    assert!(report.has_errors());
+    let issues: Vec<_> = report.issues.iter()
+        .filter(|i| **i != ValidationIssue::WorldReadable)
+        .collect();
+    assert_eq!(issues.len(), 3);
+    assert!(matches!(issues[0], ValidationIssue::Malformed { line: 3, .. }));
+    assert_eq!(issues[1], &ValidationIssue::Duplicate {
+        line: 4, first_line: 2, id: "ted".to_string()
+    });
+    assert!(matches!(issues[2], ValidationIssue::Malformed { line: 5, .. }));
+    
+    std::fs::write(&fx.keys, "key,expiry,uname,issued\n\
+        short,2999-01-01T00:00:00Z,ted,\n\
+        abcdefghijklmnopqrstuvwxyz,2001-01-01T00:00:00Z,ted,\n").unwrap();
+    let report = KeyAuth::validate_file(&fx.keys).unwrap();
+    assert_eq!(report.rows, 2);
+    assert!(!report.has_errors());
+    assert!(report.issues.contains(&ValidationIssue::ShortKey { line: 2, length: 5 }));
+    assert!(report.issues.contains(&ValidationIssue::Expired { line: 3 }));
+    
+    let e = KeyAuth::validate_file("test/no_such_file.csv").unwrap_err();
+    assert_eq!(e.kind, std::io::ErrorKind::NotFound);
+    
+    let a = PwdAuth::open(&fx.users).unwrap();
+    assert_eq!(a.load_report().issues.iter().collect::<Vec<_>>(), issues);
+    a.user_exists("ted").unwrap();
+    assert_eq!(a.user_exists("eyes2"), Err(DataError::NoSuchUser));
+    
+    std::fs::write(&fx.keys, "key,expiry,uname,issued\n\
+        abcdefghijklmnopqrstuvwxyz,2999-01-01T00:00:00Z,ted,\n\
+        abcdefghijklmnopqrstuvwxyz,2999-01-01T00:00:00Z,eyes2,\n").unwrap();
+    let mut a = BothAuth::open(&fx.users, &fx.keys).unwrap();
+    let events = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+    let sink = events.clone();
+    a.on_event(move |e| sink.lock().unwrap().push(e.clone()));
+    let events = events.lock().unwrap();
+    let lines: Vec<(u64, u64)> = events.iter().map(|e| match e {
+        AuthEvent::DuplicateEntry { line, first_line, .. } => (*line, *first_line),
+        _ => panic!("unexpected event {:?}", e),
+    }).collect();
+    assert_eq!(lines, vec![(4, 2), (3, 2)]);
+    a.check_key("abcdefghijklmnopqrstuvwxyz", "eyes2").unwrap();
+}
+
+#[test]
+fn user_ids() {
+    let fx = Fixture::new();
+    let salt = b"salt";
+    let a = PwdAuth::new(&fx.users).unwrap();
+    for [uname, pwd] in UNAMES_AND_PWDS.iter() {
+        a.add_user(uname, pwd, salt).unwrap();
+    }
+    let [ted, eyes] = [UNAMES_AND_PWDS[0][0], UNAMES_AND_PWDS[1][0]];
+    let ted_id = a.user_id(ted).unwrap();
+    assert_ne!(ted_id, a.user_id(eyes).unwrap());
+    assert_eq!(a.user_by_id(ted_id).unwrap(), ted);
+    a.change_password(ted, "toads", salt).unwrap();
+    a.save().unwrap();
+    
+    let a = PwdAuth::open(&fx.users).unwrap();
+    assert_eq!(a.is_dirty(), false);
+    assert_eq!(a.user_id(ted).unwrap(), ted_id);
+    a.delete_user(ted).unwrap();
+    assert_eq!(a.user_by_id(ted_id), Err(DataError::NoSuchUser));
+    assert_eq!(a.user_id(ted), Err(DataError::NoSuchUser));
+    
+    /* Files from before user IDs get them assigned. */
+    std::fs::write(&fx.users, "uname,hash\nted,0123abcd\neyes2,4567abcd\n").unwrap();
+    let a = PwdAuth::open(&fx.users).unwrap();
+    assert_eq!(a.is_dirty(), true);
+    let ted_id = a.user_id(ted).unwrap();
+    a.save().unwrap();
+    let a = PwdAuth::open(&fx.users).unwrap();
+    assert_eq!(a.user_id(ted).unwrap(), ted_id);
+}
 
-}
\ No newline at end of file
+#[test]
+fn rotate_credentials() {
+    let fx = Fixture::new();
+    let [uname, pwd] = UNAMES_AND_PWDS[0];
+    let salt = b"salt";
+    let mut a = BothAuth::new(&fx.users, &fx.keys).unwrap();
+    a.add_user(uname, pwd, salt).unwrap();
+    let old_keys = [a.issue_key(uname).unwrap(), a.issue_key(uname).unwrap()];
+    let other = a.issue_key(UNAMES_AND_PWDS[1][0]).unwrap();
+    
+    let key = a.rotate_credentials(uname, "toads", salt).unwrap().unwrap();
+    a.check_password(uname, "toads", salt).unwrap();
+    a.check_key(&key, uname).unwrap();
+    for k in old_keys.iter() {
+        assert_eq!(a.check_key(k, uname), Err(DataError::NoSuchKey));
+    }
+    a.check_key(&other, UNAMES_AND_PWDS[1][0]).unwrap();
+    
+    a.rotation_policy(RotationPolicy { existing_keys: ExistingKeys::Refresh, issue_key: false });
+    assert_eq!(a.rotate_credentials(uname, pwd, salt), Ok(None));
+    a.check_key(&key, uname).unwrap();
+    assert_eq!(a.rotate_credentials("nobody", pwd, salt), Err(DataError::NoSuchUser));
+    
+    assert_eq!(a.remove_user_keys(uname), Ok(1));
+    assert_eq!(a.refresh_user_keys(uname), Ok(0));
+}
+
+#[test]
+fn uniform_errors() {
+    let fx = Fixture::new();
+    let [uname, pwd] = UNAMES_AND_PWDS[0];
+    let salt = b"salt";
+    let mut a = PwdAuth::new(&fx.users).unwrap();
+    a.add_user(uname, pwd, salt).unwrap();
+    assert_eq!(a.check_password("nobody", pwd, salt), Err(DataError::NoSuchUser));
+    
+    a.uniform_errors(true);
+    assert_eq!(a.check_password("nobody", pwd, salt), Err(DataError::BadCredentials));
+    assert_eq!(a.check_password(uname, "toads", salt), Err(DataError::BadCredentials));
+    let timeout = std::time::Duration::from_millis(10);
+    assert_eq!(a.try_check_password("nobody", pwd, salt, timeout),
+               Err(DataError::BadCredentials));
+    a.check_password(uname, pwd, salt).unwrap();
+    
+    /* Unknown users still cost a hash check. */
+    struct CountingHasher(std::sync::Arc<std::sync::atomic::AtomicUsize>);
+    impl PasswordHasher for CountingHasher {
+        fn hash(&self, password: &str, salt: &[u8]) -> String {
+            Blake3Hasher.hash(password, salt)
+        }
+        fn verify(&self, password: &str, salt: &[u8], stored: &str) -> bool {
+            let _ = self.0.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            Blake3Hasher.verify(password, salt, stored)
+        }
+    }
+    let count = std::sync::Arc::new(std::sync::atomic::AtomicUsize::new(0));
+    a.password_hasher(CountingHasher(count.clone()));
+    a.uniform_errors(false);
+    assert_eq!(a.check_password("nobody", pwd, salt), Err(DataError::NoSuchUser));
+    assert_eq!(count.load(std::sync::atomic::Ordering::SeqCst), 1);
+}
+
+#[test]
+fn security_report() {
+    let fx = Fixture::new();
+    let mut a = BothAuth::new(&fx.users, &fx.keys).unwrap();
+    let findings = a.security_report();
+    assert!(findings.contains(&Finding::NoUsernamePolicy));
+    assert!(findings.contains(&Finding::NoPepper));
+    assert!(findings.contains(&Finding::PlaintextKeys { path: fx.keys.clone().into() }));
+    assert!(!findings.iter().any(|f| matches!(f, Finding::ShortKeys { .. })));
+    
+    a.charset(KeyCharset::Custom("01".to_string()));
+    a.length(8);
+    a.username_policy(UsernamePolicy { min_length: 1, ..Default::default() });
+    a.pepper(&|| Ok(b"pepper".to_vec())).unwrap();
+    let findings = a.security_report();
+    assert!(findings.contains(&Finding::ShortKeys { length: 8 }));
+    assert!(findings.contains(&Finding::SmallAlphabet { size: 2 }));
+    assert!(findings.contains(&Finding::LowKeyEntropy { bits: 8.0 }));
+    assert!(!findings.contains(&Finding::NoUsernamePolicy));
+    assert!(!findings.contains(&Finding::NoPepper));
+    assert!(Finding::ShortKeys { length: 8 }.to_string().contains("8 characters"));
+}
+
+#[test]
+fn invites() {
+    let fx = Fixture::new();
+    let invite_file = &fx.file("invites.csv");
+    
+    let salt = b"salt";
+    let mut a = BothAuth::new(&fx.users, &fx.keys).unwrap();
+    let life = std::time::Duration::from_secs(3600);
+    assert_eq!(a.create_invite(None, life), Err(DataError::NotAttached));
+    a.attach_invites(InviteAuth::new(invite_file).unwrap());
+    
+    let open = a.create_invite(None, life).unwrap();
+    let named = a.create_invite(Some("ted"), life).unwrap();
+    let stale = a.create_invite(None, std::time::Duration::from_secs(0)).unwrap();
+    assert_eq!(a.redeem_invite(&named, "bill", "pwd", salt), Err(DataError::BadUsername));
+    assert!(matches!(a.redeem_invite(&stale, "bill", "pwd", salt), Err(DataError::KeyExpired { .. })));
+    a.redeem_invite(&named, "ted", "pwd", salt).unwrap();
+    assert_eq!(a.redeem_invite(&named, "ted", "pwd", salt), Err(DataError::NoSuchKey));
+    assert_eq!(a.redeem_invite(&open, "ted", "pwd", salt), Err(DataError::UserExists { uname: "ted".into() }));
+    assert!(a.check_password("ted", "pwd", salt).is_ok());
+    assert_eq!(a.save_if_dirty().unwrap().invites, true);
+    
+    let i = InviteAuth::open(invite_file).unwrap();
+    i.revoke_invite(&open).unwrap();
+    assert_eq!(i.revoke_invite(&open), Err(DataError::NoSuchKey));
+}
+
+#[test]
+fn write_through_invites() {
+    let fx = Fixture::new();
+    let invite_file = &fx.file("invites.csv");
+    let life = std::time::Duration::from_secs(3600);
+    let mut a = BothAuth::new(&fx.users, &fx.keys).unwrap();
+    a.attach_invites(InviteAuth::new(invite_file).unwrap());
+    let token = a.create_invite(None, life).unwrap();
+    a.save_if_dirty().unwrap();
+    
+    a.write_through(true);
+    a.redeem_invite(&token, "ted", "pwd", b"").unwrap();
+    assert!(!a.invites().unwrap().is_dirty());
+    
+    /* As if after a crash: the invite is already used up on disk. */
+    let mut b = BothAuth::open(&fx.users, &fx.keys).unwrap();
+    b.attach_invites(InviteAuth::open(invite_file).unwrap());
+    assert_eq!(b.redeem_invite(&token, "bob", "pwd", b""), Err(DataError::NoSuchKey));
+}
+
+#[test]
+fn security_events() {
+    let fx = Fixture::new();
+    let salt = b"salt";
+    let mut a = BothAuth::new(&fx.users, &fx.keys).unwrap();
+    let events = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+    let sink = events.clone();
+    a.on_security_event(move |e| sink.lock().unwrap().push(e.clone()));
+    a.issue_rate_limit(Some(1), std::time::Duration::from_secs(60));
+    
+    a.add_user("ted", "frogs", salt).unwrap();
+    a.add_user("root", "toor", salt).unwrap();
+    a.set_admin("root", true).unwrap();
+    a.change_password("ted", "toads", salt).unwrap();
+    a.issue_key("ted").unwrap();
+    assert_eq!(a.issue_key("ted"), Err(DataError::IssuanceThrottled));
+    a.delete_user("ted").unwrap();
+    a.delete_user("root").unwrap();
+    
+    assert_eq!(events.lock().unwrap().as_slice(), &[
+        SecurityEvent::PasswordChanged { uname: "ted".to_string() },
+        SecurityEvent::Lockout { uname: "ted".to_string() },
+        SecurityEvent::AdminDeleted { uname: "root".to_string() },
+    ]);
+}
+
+#[cfg(feature = "http-hooks")]
+#[test]
+fn webhook() {
+    use std::io::{BufRead, BufReader, Read, Write};
+    use hmac::{Hmac, Mac};
+    
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("http://{}/hook", listener.local_addr().unwrap());
+    let server = std::thread::spawn(move || {
+        let (stream, _) = listener.accept().unwrap();
+        let mut r = BufReader::new(stream);
+        let mut headers: Vec<String> = Vec::new();
+        loop {
+            let mut line = String::new();
+            r.read_line(&mut line).unwrap();
+            if line.trim().is_empty() { break; }
+            headers.push(line.trim().to_lowercase());
+        }
+        let len: usize = headers.iter()
+            .find_map(|h| h.strip_prefix("content-length: ").map(|n| n.parse().unwrap()))
+            .unwrap();
+        let mut body = vec![0u8; len];
+        r.read_exact(&mut body).unwrap();
+        r.get_mut().write_all(b"HTTP/1.1 204 No Content\r\n\r\n").unwrap();
+        (headers, body)
+    });
+    
+    let hook = Webhook::new(url, b"secret").events(&["key_evicted"]);
+    let event = AuthEvent::KeyEvicted {
+        uname: "ted".to_string(),
+        expiry: std::time::SystemTime::UNIX_EPOCH,
+    };
+    let dup = AuthEvent::DuplicateEntry {
+        path: "x.csv".into(), line: 3, first_line: 2, id: "ted".to_string(),
+    };
+    assert!(hook.wants(&event));
+    assert!(!hook.wants(&dup));
+    hook.send(&event).unwrap();
+    
+    let (headers, body) = server.join().unwrap();
+    let json: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(json["event"], "key_evicted");
+    assert_eq!(json["uname"], "ted");
+    assert_eq!(json["expiry"], "1970-01-01T00:00:00Z");
+    
+    let mut mac = Hmac::<sha2::Sha256>::new_from_slice(b"secret").unwrap();
+    mac.update(&body);
+    let expected: String = mac.finalize().into_bytes().iter()
+        .map(|b| format!("{:02x}", b)).collect();
+    let header = format!("{}: sha256={}", SIGNATURE_HEADER.to_lowercase(), expected);
+    assert!(headers.contains(&header));
+}
+
+#[test]
+fn class_lives() {
+    let fx = Fixture::new();
+    let salt = b"salt";
+    let hour = std::time::Duration::from_secs(3600);
+    let minute = std::time::Duration::from_secs(60);
+    let mut a = BothAuth::new(&fx.users, &fx.keys).unwrap();
+    a.life(hour);
+    a.class_life("kiosk", minute);
+    a.add_user("ted", "frogs", salt).unwrap();
+    a.add_user("eyes2", "google", salt).unwrap();
+    a.set_class("ted", Some("kiosk")).unwrap();
+    a.set_class("eyes2", Some("staff")).unwrap();
+    assert_eq!(a.set_class("nobody", None), Err(DataError::NoSuchUser));
+    
+    let key = a.check_password_and_issue_key("ted", "frogs", salt).unwrap();
+    assert!(a.time_remaining(&key).unwrap() <= minute);
+    a.check_and_refresh_key(&key, "ted").unwrap();
+    assert!(a.time_remaining(&key).unwrap() <= minute);
+    a.refresh_key(&key).unwrap();
+    assert_eq!(a.refresh_user_keys("ted").unwrap(), 1);
+    assert!(a.time_remaining(&key).unwrap() <= minute);
+    let kiosk_key = a.rotate_key(&key, "ted").unwrap();
+    assert!(a.time_remaining(&kiosk_key).unwrap() <= minute);
+    let key = a.issue_user_key("eyes2").unwrap();
+    assert!(a.time_remaining(&key).unwrap() > minute);
+    a.check_and_refresh_key(&key, "eyes2").unwrap();
+    assert!(a.time_remaining(&key).unwrap() > minute);
+    a.save_passwords().unwrap();
+    a.save_keys().unwrap();
+    
+    let mut b = BothAuth::open(&fx.users, &fx.keys).unwrap();
+    b.life(hour);
+    b.check_and_refresh_key(&kiosk_key, "ted").unwrap();
+    assert!(b.time_remaining(&kiosk_key).unwrap() <= minute);
+    assert_eq!(b.class_of("ted").unwrap(), Some(String::from("kiosk")));
+    b.set_class("ted", None).unwrap();
+    assert_eq!(b.class_of("ted").unwrap(), None);
+}
+
+#[test]
+fn case_insensitive() {
+    let fx = Fixture::new();
+    let salt = b"salt";
+    let mut a = BothAuth::new(&fx.users, &fx.keys).unwrap();
+    a.add_user("alice", "pwd", salt).unwrap();
+    a.add_user("Bob", "pwd", salt).unwrap();
+    a.case_insensitive(true);
+    assert_eq!(a.add_user("Alice", "pwd", salt), Err(DataError::UserExists { uname: "Alice".into() }));
+    assert_eq!(a.add_user("BOB", "pwd", salt), Err(DataError::UserExists { uname: "BOB".into() }));
+    a.add_user("Carol", "pwd", salt).unwrap();
+    assert_eq!(a.add_user("carol", "pwd", salt), Err(DataError::UserExists { uname: "carol".into() }));
+    assert!(a.check_password("Carol", "pwd", salt).is_ok());
+    
+    a.delete_user("alice").unwrap();
+    a.add_user("Alice", "pwd", salt).unwrap();
+    a.case_insensitive(false);
+    a.add_user("alice", "pwd", salt).unwrap();
+}
+
+#[test]
+fn lazy_pwd_auth() {
+    let fx = Fixture::new();
+    let salt = b"salt";
+    let a = PwdAuth::new(&fx.users).unwrap();
+    for unp in UNAMES_AND_PWDS.iter() {
+        a.add_user(unp[0], unp[1], salt).unwrap();
+    }
+    let admin = UNAMES_AND_PWDS[1][0];
+    a.set_admin(admin, true).unwrap();
+    a.save().unwrap();
+    
+    let mut lazy = LazyPwdAuth::open(&fx.users).unwrap();
+    assert_eq!(lazy.len(), UNAMES_AND_PWDS.len());
+    lazy.cache_size(1);
+    for _ in 0..2 {
+        for unp in UNAMES_AND_PWDS.iter() {
+            assert_eq!(lazy.check_password(unp[0], unp[1], salt).unwrap().uname, unp[0]);
+            assert_eq!(lazy.check_password(unp[0], "wrong", salt), Err(DataError::BadPassword));
+            assert_eq!(lazy.user_id(unp[0]), a.user_id(unp[0]));
+        }
+    }
+    assert_eq!(lazy.is_admin(admin), Ok(true));
+    assert_eq!(lazy.check_password("nobody", "pwd", salt), Err(DataError::NoSuchUser));
+    lazy.uniform_errors(true);
+    assert_eq!(lazy.check_password("nobody", "pwd", salt), Err(DataError::BadCredentials));
+}
+
+#[test]
+fn verifier() {
+    let fx = Fixture::new();
+    let verifier_file = &fx.file("verifier.bin");
+    
+    let salt = b"salt";
+    let a = PwdAuth::new(&fx.users).unwrap();
+    for unp in UNAMES_AND_PWDS.iter() {
+        a.add_user(unp[0], unp[1], salt).unwrap();
+    }
+    let admin = UNAMES_AND_PWDS[1][0];
+    a.set_admin(admin, true).unwrap();
+    a.export_verifier(verifier_file).unwrap();
+    assert_eq!(a.is_dirty(), true);
+    
+    let mut v = Verifier::open(verifier_file).unwrap();
+    assert_eq!(v.len(), UNAMES_AND_PWDS.len());
+    for unp in UNAMES_AND_PWDS.iter() {
+        assert_eq!(v.check_password(unp[0], unp[1], salt).unwrap().uname, unp[0]);
+        assert_eq!(v.check_password(unp[0], "wrong", salt), Err(DataError::BadPassword));
+    }
+    assert_eq!(v.is_admin(admin), Ok(true));
+    assert_eq!(v.is_admin(UNAMES_AND_PWDS[0][0]), Ok(false));
+    assert_eq!(v.check_password("nobody", "pwd", salt), Err(DataError::NoSuchUser));
+    v.uniform_errors(true);
+    assert_eq!(v.check_password("nobody", "pwd", salt), Err(DataError::BadCredentials));
+    
+    let data = std::fs::read(verifier_file).unwrap();
+    std::fs::write(verifier_file, &data[..data.len() - 1]).unwrap();
+    let e = Verifier::open(verifier_file).unwrap_err();
+    assert_eq!(e.kind, std::io::ErrorKind::InvalidData);
+}
+
+#[test]
+fn key_config() {
+    let fx = Fixture::new();
+    
+    let life = std::time::Duration::from_secs(5 * 60);
+    let mut a = KeyAuth::new(&fx.keys).unwrap();
+    a.length(12);
+    a.charset(KeyCharset::Hex);
+    a.life(life);
+    a.save_config().unwrap();
+    
+    let b = KeyAuth::open(&fx.keys).unwrap();
+    let key = b.issue_key("ted").unwrap();
+    assert_eq!(key.len(), 12);
+    assert!(key.chars().all(|c| c.is_ascii_hexdigit()));
+    assert!(b.time_remaining(&key).unwrap() <= life);
+}
+
+#[test]
+fn unusable_key_config() {
+    let fx = Fixture::new();
+    let mut a = KeyAuth::new(&fx.keys).unwrap();
+    a.chars("");
+    assert_eq!(a.issue_key("ted"), Err(DataError::Unsupported));
+    a.save_config().unwrap();
+    let e = KeyAuth::open(&fx.keys).unwrap_err();
+    assert_eq!(e.kind, std::io::ErrorKind::InvalidData);
+    
+    a.charset(KeyCharset::Hex);
+    a.length(0);
+    assert_eq!(a.issue_key("ted"), Err(DataError::Unsupported));
+    a.save_config().unwrap();
+    assert_eq!(KeyAuth::open(&fx.keys).unwrap_err().kind, std::io::ErrorKind::InvalidData);
+}
+
+#[test]
+fn life_str() {
+    let fx = Fixture::new();
+    let mut a = KeyAuth::new(&fx.keys).unwrap();
+    a.life_str("5m").unwrap();
+    assert!(a.life_str("five minutes").is_err());
+    let key = a.issue_key("ted").unwrap();
+    assert!(a.time_remaining(&key).unwrap() <= std::time::Duration::from_secs(5 * 60));
+    ensure_delete(&fx.keys);
+    
+    assert!(BothAuth::builder().key_life_str("soon").is_err());
+    let a = BothAuth::builder()
+        .pwd_file(&fx.users)
+        .key_file(&fx.keys)
+        .key_life_str("1h 30m")
+        .unwrap()
+        .build()
+        .unwrap();
+    let key = a.issue_key("ted").unwrap();
+    assert!(a.time_remaining(&key).unwrap() > std::time::Duration::from_secs(3600));
+}
+
+#[cfg(all(unix, feature = "admin-socket"))]
+#[test]
+fn admin_socket() {
+    use std::io::{BufRead, BufReader, Write};
+    use std::os::unix::net::UnixStream;
+    let fx = Fixture::new();
+    let socket = &fx.file("admin.sock");
+    
+    let a = std::sync::Arc::new(BothAuth::new(&fx.users, &fx.keys).unwrap());
+    a.add_user("ted", "frogs", b"").unwrap();
+    a.issue_key("ted").unwrap();
+    let server = AdminServer::start(a.clone(), socket).unwrap();
+    {
+        use std::os::unix::fs::PermissionsExt;
+        let mode = std::fs::metadata(socket).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+        /* The directory it was bound in is gone. */
+        assert!(std::fs::read_dir(fx.dir.path()).unwrap().all(|e| !e.unwrap().path().is_dir()));
+    }
+    
+    let stream = UnixStream::connect(socket).unwrap();
+    let mut w = stream.try_clone().unwrap();
+    let mut r = BufReader::new(stream);
+    let mut send = |cmd: &str, n_lines: usize| -> Vec<String> {
+        writeln!(w, "{}", cmd).unwrap();
+        (0..n_lines).map(|_| {
+            let mut line = String::new();
+            r.read_line(&mut line).unwrap();
+            line.trim_end().to_string()
+        }).collect()
+    };
+    assert_eq!(send("adduser eyes2 google salt", 1), vec!["OK"]);
+    assert_eq!(send("adduser eyes2 google", 1), vec![r#"ERR UserExists { uname: "eyes2" }"#]);
+    assert_eq!(send("list", 3), vec!["eyes2", "ted", "OK 2"]);
+    assert_eq!(send("deluser ted", 1), vec!["OK"]);
+    assert_eq!(send("cull", 1), vec!["OK"]);
+    assert!(send("save", 1)[0].starts_with("OK"));
+    assert!(send("frobnicate", 1)[0].starts_with("ERR"));
+    assert_eq!(send("quit", 0), Vec::<String>::new());
+    
+    server.stop();
+    assert!(!Path::new(socket).exists());
+    assert_eq!(a.usernames(), vec![String::from("eyes2")]);
+    assert_eq!(a.key_dirty(), false);
+    a.check_password("eyes2", "google", b"salt").unwrap();
+}
+
+#[cfg(feature = "grpc")]
+#[test]
+fn grpc_service() {
+    use tonic::{Code, Request};
+    use crate::grpc::{AuthService, KeyRequest, PasswordRequest, RevokeRequest};
+    use crate::grpc::auth_server::Auth;
+    let fx = Fixture::new();
+    let a = std::sync::Arc::new(BothAuth::new(&fx.users, &fx.keys).unwrap());
+    a.add_user("ted", "frogs", b"salt").unwrap();
+    let svc = AuthService::new(a.clone());
+    let rt = tokio::runtime::Builder::new_current_thread().build().unwrap();
+    rt.block_on(async {
+        let pwd = |password: &str| Request::new(PasswordRequest {
+            uname: "ted".to_string(), password: password.to_string(), salt: b"salt".to_vec(),
+        });
+        let reply = svc.check_password(pwd("frogs")).await.unwrap().into_inner();
+        assert_eq!(reply.uname, "ted");
+        let e = svc.check_password(pwd("toads")).await.unwrap_err();
+        assert_eq!(e.code(), Code::Unauthenticated);
+        assert_eq!(svc.issue_key(pwd("toads")).await.unwrap_err().code(), Code::Unauthenticated);
+        
+        let key = svc.issue_key(pwd("frogs")).await.unwrap().into_inner().key;
+        let check = |uname: &str| Request::new(KeyRequest {
+            uname: uname.to_string(), key: key.clone(),
+        });
+        assert_eq!(svc.check_key(check("ted")).await.unwrap().into_inner().uname, "ted");
+        assert_eq!(svc.check_key(check("tom")).await.unwrap_err().code(), Code::Unauthenticated);
+        
+        svc.revoke_key(Request::new(RevokeRequest { key: key.clone() })).await.unwrap();
+        assert_eq!(svc.check_key(check("ted")).await.unwrap_err().code(), Code::Unauthenticated);
+        let e = svc.revoke_key(Request::new(RevokeRequest { key: key.clone() })).await.unwrap_err();
+        assert_eq!(e.code(), Code::Unauthenticated);
+    });
+}
+
+#[cfg(feature = "ffi")]
+#[test]
+fn ffi() {
+    use std::ffi::CString;
+    use crate::ffi::*;
+    let fx = Fixture::new();
+    let key = {
+        let a = BothAuth::new(&fx.users, &fx.keys).unwrap();
+        a.add_user("ted", "frogs", b"salt").unwrap();
+        let key = a.issue_key("ted").unwrap();
+        a.save_if_dirty().unwrap();
+        key
+    };
+    let c = |s: &str| CString::new(s).unwrap();
+    let (ufile, kfile) = (c(&fx.users), c(&fx.keys));
+    let (ted, frogs, toads, key) = (c("ted"), c("frogs"), c("toads"), c(&key));
+    let salt = b"salt";
+    unsafe {
+        assert!(authlite_open(std::ptr::null(), kfile.as_ptr()).is_null());
+        let a = authlite_open(ufile.as_ptr(), kfile.as_ptr());
+        assert!(!a.is_null());
+        
+        assert_eq!(authlite_check_password(a, ted.as_ptr(), frogs.as_ptr(), salt.as_ptr(), 4),
+                   AUTHLITE_OK);
+        assert_eq!(authlite_check_password(a, ted.as_ptr(), toads.as_ptr(), salt.as_ptr(), 4),
+                   401);
+        assert_eq!(authlite_check_password(a, toads.as_ptr(), frogs.as_ptr(), salt.as_ptr(), 4),
+                   404);
+        assert_eq!(authlite_check_password(a, ted.as_ptr(), frogs.as_ptr(), std::ptr::null(), 0),
+                   401);
+        assert_eq!(authlite_check_password(a, std::ptr::null(), frogs.as_ptr(), salt.as_ptr(), 4),
+                   AUTHLITE_BAD_ARGUMENT);
+        
+        assert_eq!(authlite_check_key(a, key.as_ptr(), ted.as_ptr()), AUTHLITE_OK);
+        assert_eq!(authlite_check_key(a, frogs.as_ptr(), ted.as_ptr()), 401);
+        assert_eq!(authlite_check_key(std::ptr::null(), key.as_ptr(), ted.as_ptr()),
+                   AUTHLITE_BAD_ARGUMENT);
+        
+        authlite_free(a);
+        authlite_free(std::ptr::null_mut());
+    }
+}
+
+#[cfg(feature = "python")]
+#[test]
+fn python_bindings() {
+    use pyo3::prelude::*;
+    use pyo3::types::PyDict;
+    let fx = Fixture::new();
+    pyo3::prepare_freethreaded_python();
+    Python::with_gil(|py| {
+        let m = PyModule::new(py, "authlite").unwrap();
+        crate::python::authlite(&m).unwrap();
+        let globals = PyDict::new(py);
+        globals.set_item("authlite", m).unwrap();
+        globals.set_item("USERS", &fx.users).unwrap();
+        globals.set_item("KEYS", &fx.keys).unwrap();
+        let code = std::ffi::CString::new(r#"
+auth = authlite.BothAuth(USERS, KEYS, write_through=True, key_life="1h")
+auth.add_user("ted", "frogs", b"salt")
+auth.add_user("eyes2", "google")
+assert auth.usernames() == ["eyes2", "ted"]
+auth.check_password("eyes2", "google")
+key = auth.check_password_and_issue_key("ted", "frogs", b"salt")
+auth.check_key(key, "ted")
+try:
+    auth.check_key(key, "eyes2")
+    assert False
+except authlite.AuthError as e:
+    assert e.args == ("BadUsername", 401)
+try:
+    auth.add_user("ted", "toads")
+    assert False
+except authlite.AuthError as e:
+    assert e.args == ("UserExists", 409)
+auth.remove_key(key)
+try:
+    authlite.BothAuth(USERS, KEYS, key_life="forever")
+    assert False
+except ValueError:
+    pass
+
+pwds = authlite.PwdAuth(USERS)
+assert pwds.user_exists("ted") and not pwds.user_exists("tom")
+pwds.check_password("ted", "frogs", b"salt")
+keys = authlite.KeyAuth(KEYS)
+assert keys.remove_user_keys("ted") == 0
+"#).unwrap();
+        if let Err(e) = py.run(&code, Some(&globals), None) {
+            e.print(py);
+            panic!("Python test failed");
+        }
+    });
+}
+
+#[test]
+fn generation() {
+    let fx = Fixture::new();
+    let a = BothAuth::new(&fx.users, &fx.keys).unwrap();
+    assert_eq!(a.generation(), 0);
+    a.add_user("ted", "frogs", b"").unwrap();
+    let g = a.generation();
+    assert!(g > 0);
+    
+    /* Checks and saves don't count; failed changes don't either. */
+    a.check_password("ted", "frogs", b"").unwrap();
+    a.save_if_dirty().unwrap();
+    assert_eq!(a.add_user("ted", "toads", b""), Err(DataError::UserExists { uname: "ted".into() }));
+    assert_eq!(a.generation(), g);
+    
+    let key = a.issue_key("ted").unwrap();
+    let g2 = a.generation();
+    assert!(g2 > g);
+    a.check_key(&key, "ted").unwrap();
+    assert_eq!(a.generation(), g2);
+    a.set_admin("ted", true).unwrap();
+    assert!(a.generation() > g2);
+}
+
+#[test]
+fn import_plaintext() {
+    use crate::testing::{Fault, FaultInjector};
+    let fx = Fixture::new();
+    let plain = &fx.file("plaintext.csv");
+    
+    let a = PwdAuth::new(&fx.users).unwrap();
+    a.add_user("ted", "frogs", b"").unwrap();
+    
+    std::fs::write(plain, "uname,password\nted,toads\n").unwrap();
+    let e = a.import_plaintext_csv(plain, SaltPolicy::Column).unwrap_err();
+    assert_eq!(e.kind, std::io::ErrorKind::InvalidData);
+    assert!(Path::new(plain).exists());
+    
+    std::fs::write(plain, "uname,password\nted,toads\neyes2,google\nsam,ham\n").unwrap();
+    let report = a.import_plaintext_csv(plain, SaltPolicy::Random).unwrap();
+    assert!(!Path::new(plain).exists());
+    assert_eq!(report.added, vec!["eyes2", "sam"]);
+    let exists = DataError::UserExists { uname: String::from("ted") };
+    assert_eq!(report.skipped, vec![(String::from("ted"), exists)]);
+    assert_eq!(report.salts.len(), 2);
+    for (uname, salt) in report.salts.iter() {
+        let pwd = if uname == "sam" { "ham" } else { "google" };
+        a.check_password(uname, pwd, salt).unwrap();
+    }
+    a.check_password("ted", "frogs", b"").unwrap();
+    
+    std::fs::write(plain, "uname,password,salt\njo,pass,pepper\n").unwrap();
+    let report = a.import_plaintext_csv(plain, SaltPolicy::Column).unwrap();
+    assert!(report.salts.is_empty());
+    a.check_password("jo", "pass", b"pepper").unwrap();
+    
+    std::fs::write(plain, "password,uname\nx,kim\n").unwrap();
+    a.import_plaintext_csv(plain, SaltPolicy::Fixed(b"s".to_vec())).unwrap();
+    a.check_password("kim", "x", b"s").unwrap();
+    assert_eq!(a.is_dirty(), true);
+    
+    /* A failed write-through save still shreds the file. */
+    let users = fx.file("wt_users.csv");
+    let mut a = PwdAuth::new(&users).unwrap();
+    a.write_through(true);
+    std::fs::write(plain, "uname,password\nliz,lizards\n").unwrap();
+    {
+        let _fault = FaultInjector::on_save(&users, Fault::OnFlush);
+        assert!(a.import_plaintext_csv(plain, SaltPolicy::Fixed(Vec::new())).is_err());
+    }
+    assert!(!Path::new(plain).exists());
+    a.check_password("liz", "lizards", b"").unwrap();
+}
+
+#[test]
+fn export_report() {
+    let fx = Fixture::new();
+    let report = &fx.file("report.csv");
+    
+    let a = PwdAuth::new(&fx.users).unwrap();
+    a.add_user("ted", "frogs", b"").unwrap();
+    a.add_user("eyes2", "google", b"").unwrap();
+    a.set_admin("eyes2", true).unwrap();
+    a.set_class("ted", Some("staff")).unwrap();
+    a.set_email("ted", Some("ted@example.com")).unwrap();
+    a.export_report(report).unwrap();
+    
+    let text = std::fs::read_to_string(report).unwrap();
+    let expected = format!("uname,uid,admin,class,email\neyes2,{},true,,\nted,{},false,staff,ted@example.com\n",
+                           a.user_id("eyes2").unwrap(), a.user_id("ted").unwrap());
+    assert_eq!(text, expected);
+    a.save().unwrap();
+    let users = std::fs::read_to_string(&fx.users).unwrap();
+    for line in users.lines().skip(1) {
+        let hash = line.split(',').nth(1).unwrap();
+        assert!(!text.contains(hash));
+    }
+}
+
+#[test]
+fn key_namespaces() {
+    let fx = Fixture::new();
+    let admin_keys = &fx.file("admin_keys.csv");
+    
+    let mut a = BothAuth::new(&fx.users, &fx.keys).unwrap();
+    a.add_key_namespace("admin", admin_keys, std::time::Duration::from_secs(60)).unwrap();
+    a.add_user("ted", "frogs", b"").unwrap();
+    
+    let web = a.issue_key("ted").unwrap();
+    let admin = a.issue_key_in("admin", "ted").unwrap();
+    a.check_key_in("admin", &admin, "ted").unwrap();
+    assert_eq!(a.check_key_in("admin", &web, "ted"), Err(DataError::NoSuchKey));
+    assert_eq!(a.check_key(&admin, "ted"), Err(DataError::NoSuchKey));
+    assert_eq!(a.issue_key_in("api", "ted"), Err(DataError::NoSuchNamespace));
+    let left = a.key_namespace("admin").unwrap().time_remaining(&admin).unwrap();
+    assert!(left <= std::time::Duration::from_secs(60));
+    
+    assert_eq!(a.key_dirty(), true);
+    a.save_if_dirty().unwrap();
+    assert_eq!(a.key_dirty(), false);
+    
+    let mut b = BothAuth::open(&fx.users, &fx.keys).unwrap();
+    b.add_key_namespace("admin", admin_keys, std::time::Duration::from_secs(60)).unwrap();
+    b.check_key(&web, "ted").unwrap();
+    b.check_key_in("admin", &admin, "ted").unwrap();
+    b.remove_key_in("admin", &admin).unwrap();
+    assert_eq!(b.key_dirty(), true);
+    assert_eq!(b.check_key_in("admin", &admin, "ted"), Err(DataError::NoSuchKey));
+}
+
+#[test]
+fn multi_tenant() {
+    let fx = Fixture::new();
+    let root = &fx.file("tenants");
+    std::fs::create_dir(root).unwrap();
+    
+    let mut m = MultiAuth::open(root).unwrap();
+    m.configure(|_, ba| ba.write_through(true));
+    let acme = m.create_tenant("acme").unwrap();
+    acme.add_user("ted", "frogs", b"").unwrap();
+    m.create_tenant("initech").unwrap().add_user("ted", "toads", b"").unwrap();
+    assert_eq!(m.create_tenant("acme").unwrap_err().kind, std::io::ErrorKind::AlreadyExists);
+    assert_eq!(m.tenant("../test").unwrap_err().kind, std::io::ErrorKind::InvalidInput);
+    
+    m.check_password("acme", "ted", "frogs", b"").unwrap();
+    assert_eq!(m.check_password("initech", "ted", "frogs", b""), Err(DataError::BadPassword));
+    assert_eq!(m.check_password("globex", "ted", "frogs", b""), Err(DataError::NoSuchTenant));
+    let key = m.check_password_and_issue_key("initech", "ted", "toads", b"").unwrap();
+    m.check_key("initech", &key, "ted").unwrap();
+    assert_eq!(m.check_key("acme", &key, "ted"), Err(DataError::NoSuchKey));
+    assert_eq!(m.tenant_ids().unwrap(), vec!["acme", "initech"]);
+    
+    /* A fresh handle loads tenants from disk as they're needed. */
+    let m2 = MultiAuth::open(root).unwrap();
+    m2.check_password("acme", "ted", "frogs", b"").unwrap();
+    m2.check_key("initech", &key, "ted").unwrap();
+    assert!(std::sync::Arc::ptr_eq(&m2.tenant("acme").unwrap(), &m2.tenant("acme").unwrap()));
+    m2.save_if_dirty().unwrap();
+}
+
+#[test]
+fn multi_tenant_sweep() {
+    let fx = Fixture::new();
+    let root = &fx.file("tenants_sweep");
+    std::fs::create_dir(root).unwrap();
+    
+    let mut m = MultiAuth::open(root).unwrap();
+    m.configure(|_, ba| ba.life(std::time::Duration::from_secs(1)));
+    let m = std::sync::Arc::new(m);
+    m.create_tenant("acme").unwrap().add_user("ted", "frogs", b"").unwrap();
+    m.create_tenant("initech").unwrap();
+    assert_eq!(m.sweep().unwrap(), 1);
+    assert_eq!(m.sweep().unwrap(), 0);
+    
+    let key = m.check_password_and_issue_key("acme", "ted", "frogs", b"").unwrap();
+    let sweeper = m.start_sweeper(std::time::Duration::from_millis(100));
+    std::thread::sleep(std::time::Duration::from_millis(300));
+    assert!(!m.tenant("acme").unwrap().key_dirty());
+    assert_eq!(MultiAuth::open(root).unwrap().check_key("acme", &key, "ted").unwrap().uname, "ted");
+    
+    /* The key expires, and is culled by the sweeper. */
+    std::thread::sleep(std::time::Duration::from_millis(1000));
+    m.create_tenant("globex").unwrap().add_user("bob", "toads", b"").unwrap();
+    sweeper.stop();
+    let m2 = MultiAuth::open(root).unwrap();
+    assert_eq!(m2.check_key("acme", &key, "ted"), Err(DataError::NoSuchKey));
+    m2.check_password("globex", "bob", "toads", b"").unwrap();
+}
+
+#[test]
+fn multi_tenant_sweep_counts_attached_stores() {
+    let fx = Fixture::new();
+    let root = fx.file("tenants_attached");
+    std::fs::create_dir(&root).unwrap();
+    
+    let mut m = MultiAuth::open(&root).unwrap();
+    let dir = fx.dir.path().to_path_buf();
+    m.configure(move |id, ba| {
+        let certs = CertAuth::open_or_new(dir.join(format!("{}-certs.csv", id))).unwrap();
+        ba.attach_certs(certs);
+    });
+    let _ = m.create_tenant("acme").unwrap();
+    assert_eq!(m.sweep().unwrap(), 0);
+    
+    /* Only the certificate file changes. */
+    m.tenant("acme").unwrap().certs().unwrap().add_fingerprint("AB:CD", "ted").unwrap();
+    assert_eq!(m.sweep().unwrap(), 1);
+    assert_eq!(m.sweep().unwrap(), 0);
+    assert!(SaveReport { remember: true, ..Default::default() }.any());
+    assert!(!SaveReport::default().any());
+}
+
+#[test]
+fn key_life_with_monotonic_time() {
+    let fx = Fixture::new();
+    let hour = std::time::Duration::from_secs(3600);
+    let mut a = KeyAuth::new(&fx.keys).unwrap();
+    a.life(hour);
+    let key = a.issue_key("ted").unwrap();
+    
+    /* Times other than now are still measured relative to now. */
+    let now = std::time::SystemTime::now();
+    a.check_key_at(&key, "ted", now + hour / 2).unwrap();
+    match a.check_key_at(&key, "ted", now + hour * 2) {
+        Err(DataError::KeyExpired { expired_for }) => {
+            let minute = std::time::Duration::from_secs(60);
+            assert!(expired_for > hour - minute && expired_for < hour + minute);
+        },
+        x => panic!("expected KeyExpired, got {:?}", x),
+    }
+    a.check_key_at(&key, "ted", now - hour * 24 * 365 * 100).unwrap();
+    let left = a.time_remaining(&key).unwrap();
+    assert!(left <= hour && left > hour - std::time::Duration::from_secs(60));
+    
+    /* Keys read back from the file are timed by the system clock. */
+    a.save().unwrap();
+    let b = KeyAuth::open(&fx.keys).unwrap();
+    b.check_key(&key, "ted").unwrap();
+    assert!(matches!(b.check_key_at(&key, "ted", now + hour * 2), Err(DataError::KeyExpired { .. })));
+    b.refresh_key(&key).unwrap();
+    a.cull_keys_at(now + hour * 2).unwrap();
+    assert_eq!(a.check_key(&key, "ted"), Err(DataError::NoSuchKey));
+}
+
+#[test]
+fn revocations_since() {
+    let fx = Fixture::new();
+    let a = KeyAuth::new(&fx.keys).unwrap();
+    let keys: Vec<String> = (0..3).map(|_| a.issue_key("ted").unwrap()).collect();
+    assert!(a.revocations_since(0).is_empty());
+    
+    a.invalidate_key(&keys[0]).unwrap();
+    let gen = a.generation();
+    assert_eq!(a.revocations_since(0), vec![KeyHash::of(&keys[0])]);
+    assert!(a.revocations_since(gen).is_empty());
+    
+    a.invalidate_key(&keys[1]).unwrap();
+    a.remove_key(&keys[2]).unwrap();
+    assert_eq!(a.revocations_since(gen), vec![KeyHash::of(&keys[1])]);
+    assert_eq!(KeyHash::of(&keys[1]).to_hex().len(), 64);
+    
+    /* A reopened database starts counting again, so a verifier that's
+       ahead of it gets everything. */
+    a.save().unwrap();
+    let a = KeyAuth::open(&fx.keys).unwrap();
+    assert_eq!(a.revocations_since(gen).len(), 2);
+}
+
+#[test]
+fn invalidate_sessions_before() {
+    let fx = Fixture::new();
+    let _ = std::fs::remove_file(fx.nvb());
+    let a = BothAuth::new(&fx.users, &fx.keys).unwrap();
+    let old = a.issue_key("ted").unwrap();
+    let other = a.issue_key("bob").unwrap();
+    a.save_if_dirty().unwrap();
+    
+    std::thread::sleep(std::time::Duration::from_millis(10));
+    a.invalidate_sessions_before("ted", std::time::SystemTime::now()).unwrap();
+    let new = a.issue_key("ted").unwrap();
+    assert!(matches!(a.check_key(&old, "ted"), Err(DataError::KeyExpired { .. })));
+    a.check_key(&new, "ted").unwrap();
+    a.check_key(&other, "bob").unwrap();
+    
+    /* It applies to stale copies of the key file, too. */
+    let b = BothAuth::open(&fx.users, &fx.keys).unwrap();
+    assert_eq!(b.check_key(&old, "ted"), Err(DataError::NoSuchKey));
+    b.check_key(&other, "bob").unwrap();
+    
+    /* Along with the time for everyone. */
+    a.invalidate_all_sessions().unwrap();
+    let b = BothAuth::open(&fx.users, &fx.keys).unwrap();
+    assert_eq!(b.check_key(&other, "bob"), Err(DataError::NoSuchKey));
+    let _ = std::fs::remove_file(fx.nvb());
+}
+
+#[test]
+fn v2_api() {
+    let fx = Fixture::new();
+    let auth = v2::Auth::create(&fx.users, &fx.keys).unwrap();
+    assert!(v2::Auth::create(&fx.users, &fx.keys).is_err());
+    auth.create_user("ted", "frogs", b"").unwrap();
+    assert!(auth.user_exists("ted"));
+    assert_eq!(auth.verify_password("ted", "toads", b""), Err(DataError::BadPassword));
+    
+    let session = auth.create_session("ted", "frogs", b"").unwrap();
+    assert_eq!(auth.verify_session(&session, "ted").unwrap().uname, "ted");
+    auth.renew_session(&session, "ted").unwrap();
+    auth.save().unwrap();
+    assert!(!auth.is_dirty());
+    
+    /* The old API sees the same files. */
+    let both = BothAuth::open(&fx.users, &fx.keys).unwrap();
+    both.check_key(&session, "ted").unwrap();
+    let auth = v2::Auth::from(both);
+    auth.end_session(&session).unwrap();
+    assert_eq!(auth.verify_session(&session, "ted"), Err(DataError::KeyRevoked));
+    auth.create_session("ted", "frogs", b"").unwrap();
+    assert_eq!(auth.end_user_sessions("ted"), Ok(2));
+}
+
+#[test]
+fn signed_values() {
+    let fx = Fixture::new();
+    let mut a = BothAuth::new(&fx.users, &fx.keys).unwrap();
+    let signed = a.sign_value("flash=Saved.");
+    assert!(signed.starts_with("flash=Saved.."));
+    assert_eq!(a.verify_value(&signed).unwrap(), "flash=Saved.");
+    assert_eq!(a.verify_value(&signed.replace("Saved", "Sived")), Err(DataError::BadToken));
+    assert_eq!(a.verify_value("no signature"), Err(DataError::BadToken));
+    assert_eq!(a.verify_value(&format!("x.{}", a.csrf_token("x"))), Err(DataError::BadToken));
+    
+    /* A different secret makes different signatures. */
+    let secret = || -> std::io::Result<Vec<u8>> { Ok(b"correct horse battery staple".to_vec()) };
+    a.signing_secret(&secret).unwrap();
+    assert_eq!(a.verify_value(&signed), Err(DataError::BadToken));
+    let signed = a.sign_value("");
+    assert_eq!(a.verify_value(&signed).unwrap(), "");
+}
+
+#[test]
+fn maintain_within_budget() {
+    let fx = Fixture::new();
+    let mut a = KeyAuth::new(&fx.keys).unwrap();
+    a.shards(4);
+    let live = a.issue_key("ted").unwrap();
+    for _ in 0..20 {
+        a.issue_key_with_life("bob", std::time::Duration::from_secs(0)).unwrap();
+    }
+    std::thread::sleep(std::time::Duration::from_millis(5));
+    
+    let report = a.maintain(std::time::Duration::from_secs(0)).unwrap();
+    assert_eq!(report, MaintenanceReport {
+        culled: 0, shards_remaining: 4, saved: false, save_pending: true,
+    });
+    assert!(!report.is_done());
+    
+    let report = a.maintain(std::time::Duration::from_secs(60)).unwrap();
+    assert_eq!(report.culled, 20);
+    assert!(report.saved && report.is_done());
+    assert!(!a.is_dirty());
+    KeyAuth::open(&fx.keys).unwrap().check_key(&live, "ted").unwrap();
+}
+
+#[test]
+fn cull_idle_keys() {
+    use std::time::Duration;
+    let fx = Fixture::new();
+    let a = KeyAuth::new(&fx.keys).unwrap();
+    let busy = a.issue_key("ted").unwrap();
+    let idle = a.issue_key("bob").unwrap();
+    std::thread::sleep(Duration::from_millis(300));
+    a.check_key(&busy, "ted").unwrap();
+    
+    assert_eq!(a.cull_idle(Duration::from_secs(60)).unwrap(), 0);
+    assert_eq!(a.cull_idle(Duration::from_millis(200)).unwrap(), 1);
+    assert!(a.is_dirty());
+    a.check_key(&busy, "ted").unwrap();
+    assert_eq!(a.check_key(&idle, "bob"), Err(DataError::NoSuchKey));
+    
+    /* Last-used times survive a restart. */
+    let idle = a.issue_key("bob").unwrap();
+    std::thread::sleep(Duration::from_millis(300));
+    a.touch(&busy, "ted").unwrap();
+    a.save().unwrap();
+    let mut b = KeyAuth::open(&fx.keys).unwrap();
+    
+    /* The idle timeout makes the usual culling remove idle keys too. */
+    b.cull_keys().unwrap();
+    assert!(b.time_remaining(&idle).is_ok());
+    b.idle_timeout(Some(Duration::from_millis(200)));
+    b.cull_keys().unwrap();
+    b.check_key(&busy, "ted").unwrap();
+    assert_eq!(b.check_key(&idle, "bob"), Err(DataError::NoSuchKey));
+    
+    let idle = b.issue_key("bob").unwrap();
+    std::thread::sleep(Duration::from_millis(300));
+    b.check_key(&busy, "ted").unwrap();
+    assert_eq!(b.maintain(Duration::from_secs(60)).unwrap().culled, 1);
+    assert_eq!(b.check_key(&idle, "bob"), Err(DataError::NoSuchKey));
+}
+
+#[test]
+fn save_debounced() {
+    let fx = Fixture::new();
+    let a = std::sync::Arc::new(BothAuth::new(&fx.users, &fx.keys).unwrap());
+    a.add_user("ted", "frogs", b"").unwrap();
+    let saver = a.save_debounced(std::time::Duration::from_millis(200));
+    
+    /* Changes made in a burst are saved together, after the interval. */
+    let keys: Vec<String> = (0..10).map(|_| a.issue_key("ted").unwrap()).collect();
+    assert!(a.key_dirty());
+    std::thread::sleep(std::time::Duration::from_millis(400));
+    assert!(!a.pwd_dirty() && !a.key_dirty());
+    let b = BothAuth::open(&fx.users, &fx.keys).unwrap();
+    b.check_key(&keys[9], "ted").unwrap();
+    
+    /* Stopping saves anything left. */
+    a.add_user("bob", "toads", b"").unwrap();
+    saver.stop();
+    assert!(!a.pwd_dirty());
+    BothAuth::open(&fx.users, &fx.keys).unwrap().user_exists("bob").unwrap();
+}
+
+#[test]
+fn sorted_save_order() {
+    let fx = Fixture::new();
+    let mut a = BothAuth::builder()
+        .pwd_file(&fx.users).key_file(&fx.keys)
+        .save_order(SaveOrder::ById)
+        .build().unwrap();
+    for uname in ["mary", "ted", "alice", "zed", "bob"].iter() {
+        a.add_user(uname, "frogs", b"").unwrap();
+        let _ = a.issue_key(uname).unwrap();
+    }
+    a.save_passwords().unwrap();
+    a.save_keys().unwrap();
+    let users1 = std::fs::read(&fx.users).unwrap();
+    let keys1 = std::fs::read(&fx.keys).unwrap();
+    
+    /* Saving the same data again writes the same files. */
+    let b = BothAuth::builder()
+        .pwd_file(&fx.users).key_file(&fx.keys)
+        .save_order(SaveOrder::ById)
+        .build().unwrap();
+    b.save_passwords().unwrap();
+    b.save_keys().unwrap();
+    assert_eq!(users1, std::fs::read(&fx.users).unwrap());
+    assert_eq!(keys1, std::fs::read(&fx.keys).unwrap());
+    let text = String::from_utf8(users1).unwrap();
+    let unames: Vec<&str> = text.lines().skip(1)
+        .map(|l| l.split(',').next().unwrap()).collect();
+    assert_eq!(unames, vec!["alice", "bob", "mary", "ted", "zed"]);
+    
+    /* By issue time, keys come out in the order they were issued. */
+    a.save_order(SaveOrder::ByIssueTime);
+    a.save_keys().unwrap();
+    let text = std::fs::read_to_string(&fx.keys).unwrap();
+    let mut issued: Vec<String> = text.lines().skip(1)
+        .map(|l| l.split(',').nth(3).unwrap().to_string()).collect();
+    let in_file = issued.clone();
+    issued.sort();
+    assert_eq!(in_file, issued);
+}
+
+#[test]
+fn authenticate_by_key_alone() {
+    let fx = Fixture::new();
+    let a = BothAuth::new(&fx.users, &fx.keys).unwrap();
+    a.add_user("ted", "frogs", b"").unwrap();
+    let key = a.issue_key("ted").unwrap();
+    assert_eq!(a.authenticate(&key).unwrap(), "ted");
+    assert!(matches!(a.authenticate("nonsense"), Err(DataError::NoSuchKey)));
+    a.invalidate_key(&key).unwrap();
+    assert!(matches!(a.authenticate(&key), Err(DataError::KeyRevoked)));
+}
+
+#[test]
+fn export_and_import_user() {
+    let fx = Fixture::new();
+    let src = PwdAuth::new(&fx.users).unwrap();
+    src.add_user("ted", "frogs", b"salt").unwrap();
+    src.set_admin("ted", true).unwrap();
+    src.set_class("ted", Some("staff")).unwrap();
+    let bundle = src.export_user("ted").unwrap();
+    assert!(matches!(src.export_user("bob"), Err(DataError::NoSuchUser)));
+    
+    #[cfg(feature = "serde")]
+    let bundle: UserBundle = serde_json::from_str(
+        &serde_json::to_string(&bundle).unwrap()
+    ).unwrap();
+    
+    let dst = PwdAuth::new(&fx.keys).unwrap();
+    dst.import_user(bundle.clone()).unwrap();
+    dst.check_password("ted", "frogs", b"salt").unwrap();
+    assert!(dst.is_admin("ted").unwrap());
+    assert_eq!(dst.user_id("ted").unwrap(), src.user_id("ted").unwrap());
+    assert_eq!(dst.export_user("ted").unwrap(), bundle);
+    assert!(matches!(dst.import_user(bundle), Err(DataError::UserExists { .. })));
+}
+
+#[test]
+fn testing_fixture() {
+    let auth = testing::TestAuth::with_users(&[("ted", "frogs"), ("bob", "toads")]);
+    let dir = auth.dir().to_path_buf();
+    let key = auth.check_password_and_issue_key("ted", "frogs", b"").unwrap();
+    let again = auth.reopen();
+    again.check_password("bob", "toads", b"").unwrap();
+    again.check_key(&key, "ted").unwrap();
+    drop(again);
+    drop(auth);
+    assert!(!dir.exists());
+}
+
+/** A PBKDF-style hasher: BLAKE3, iterated `self.0` times. */
+struct IteratedHasher(u32);
+
+impl PasswordHasher for IteratedHasher {
+    fn hash(&self, password: &str, salt: &[u8]) -> String {
+        let mut hash = blake3::hash(&[password.as_bytes(), salt].concat());
+        for _ in 1..self.0 { hash = blake3::hash(hash.as_bytes()); }
+        format!("{}${}", self.0, hash.to_hex())
+    }
+    
+    fn verify(&self, password: &str, salt: &[u8], stored: &str) -> bool {
+        self.hash(password, salt) == stored
+    }
+}
+
+#[test]
+fn calibrate_hash_cost() {
+    use std::time::Duration;
+    let target = Duration::from_millis(2);
+    let candidates = (0..24).map(|n| 1u32 << n);
+    let cal = super::calibrate_hash_cost(target, candidates, |&n| IteratedHasher(n)).unwrap();
+    assert!(cal.met_target);
+    assert!(cal.took >= target);
+    assert!(cal.params > 1);
+    
+    let cal = super::calibrate_hash_cost(target, vec![1, 2], |&n| IteratedHasher(n)).unwrap();
+    assert!(!cal.met_target);
+    assert_eq!(cal.params, 2);
+    let none = super::calibrate_hash_cost(target, Vec::new(), |&n: &u32| IteratedHasher(n));
+    assert!(none.is_none());
+}
+
+/** Hashes as `v2$<hex>`, but still verifies the default hasher's hashes. */
+struct MigratingHasher;
+
+impl PasswordHasher for MigratingHasher {
+    fn hash(&self, password: &str, salt: &[u8]) -> String {
+        IteratedHasher(2).hash(password, salt).replacen("2$", "v2$", 1)
+    }
+    
+    fn verify(&self, password: &str, salt: &[u8], stored: &str) -> bool {
+        match stored.starts_with("v2$") {
+            true => self.hash(password, salt) == stored,
+            false => Blake3Hasher.verify(password, salt, stored),
+        }
+    }
+    
+    fn needs_rehash(&self, stored: &str) -> bool { !stored.starts_with("v2$") }
+}
+
+#[test]
+fn rehash_users() {
+    let fx = Fixture::new();
+    let a = PwdAuth::new(&fx.users).unwrap();
+    for [uname, pwd] in UNAMES_AND_PWDS.iter() { a.add_user(uname, pwd, b"salt").unwrap(); }
+    assert!(a.rehash_needed().is_empty());
+    a.save().unwrap();
+    
+    let mut a = PwdAuth::open(&fx.users).unwrap();
+    a.password_hasher(MigratingHasher);
+    assert_eq!(a.rehash_needed(), vec!["eyes2", "qwert", "ted"]);
+    assert_eq!(a.hash_schemes().get("blake3"), Some(&3));
+    
+    assert!(a.check_password("ted", "frogs", b"salt").unwrap().needs_rehash);
+    assert_eq!(a.rehash_user("ted", "toads", b"salt"), Err(DataError::BadPassword));
+    assert_eq!(a.rehash_user("ted", "frogs", b"salt"), Ok(true));
+    assert_eq!(a.rehash_user("ted", "frogs", b"salt"), Ok(false));
+    assert!(!a.check_password("ted", "frogs", b"salt").unwrap().needs_rehash);
+    assert_eq!(a.rehash_needed(), vec!["eyes2", "qwert"]);
+    let schemes = a.hash_schemes();
+    assert_eq!((schemes["blake3"], schemes["v2"]), (2, 1));
+}
+
+#[cfg(feature = "pubkey")]
+#[test]
+fn pubkey_login() {
+    use ed25519_dalek::{Signer, SigningKey};
+    let fx = Fixture::new();
+    let signer = SigningKey::from_bytes(&[7u8; 32]);
+    let public = signer.verifying_key().to_bytes();
+    let mut a = BothAuth::new(&fx.users, &fx.keys).unwrap();
+    a.attach_pubkeys(PubkeyAuth::new(fx.file("pubkeys.csv")).unwrap());
+    a.add_user("ted", "frogs", b"").unwrap();
+    let p = a.pubkeys().unwrap();
+    assert_eq!(p.begin_challenge("ted"), Err(DataError::NoSuchUser));
+    p.add_key("ted", &public).unwrap();
+    
+    let nonce = p.begin_challenge("ted").unwrap();
+    let key = a.login_with_signature("ted", &signer.sign(&nonce).to_bytes()).unwrap();
+    a.check_key(&key, "ted").unwrap();
+    /* Each challenge can only be answered once. */
+    assert_eq!(a.login_with_signature("ted", &signer.sign(&nonce).to_bytes()),
+               Err(DataError::BadSignature));
+    
+    let nonce = p.begin_challenge("ted").unwrap();
+    let other = SigningKey::from_bytes(&[8u8; 32]);
+    assert_eq!(p.verify_challenge("ted", &other.sign(&nonce).to_bytes()),
+               Err(DataError::BadSignature));
+    
+    assert!(a.save_if_dirty().unwrap().pubkeys);
+    let p = PubkeyAuth::open(fx.file("pubkeys.csv")).unwrap();
+    assert_eq!(p.keys_of("ted"), vec![public]);
+    let nonce = p.begin_challenge("ted").unwrap();
+    assert_eq!(p.verify_challenge("ted", &signer.sign(&nonce).to_bytes()).unwrap().uname, "ted");
+}
+
+#[cfg(feature = "pubkey")]
+#[test]
+fn pubkey_login_with_ssh_signature() {
+    use base64::{engine::general_purpose::STANDARD, Engine};
+    use ed25519_dalek::{Signer, SigningKey};
+    use sha2::{Digest, Sha512};
+    
+    fn put(buf: &mut Vec<u8>, s: &[u8]) {
+        buf.extend_from_slice(&(s.len() as u32).to_be_bytes());
+        buf.extend_from_slice(s);
+    }
+    fn key_blob(signer: &SigningKey) -> Vec<u8> {
+        let mut blob = Vec::new();
+        put(&mut blob, b"ssh-ed25519");
+        put(&mut blob, signer.verifying_key().as_bytes());
+        blob
+    }
+    /* What `ssh-keygen -Y sign -n <namespace>` writes for `message`. */
+    fn sshsig(signer: &SigningKey, namespace: &str, message: &[u8]) -> String {
+        let mut signed = b"SSHSIG".to_vec();
+        put(&mut signed, namespace.as_bytes());
+        put(&mut signed, b"");
+        put(&mut signed, b"sha512");
+        put(&mut signed, &Sha512::digest(message));
+        let mut sig = Vec::new();
+        put(&mut sig, b"ssh-ed25519");
+        put(&mut sig, &signer.sign(&signed).to_bytes());
+        
+        let mut blob = b"SSHSIG".to_vec();
+        blob.extend_from_slice(&1u32.to_be_bytes());
+        put(&mut blob, &key_blob(signer));
+        put(&mut blob, namespace.as_bytes());
+        put(&mut blob, b"");
+        put(&mut blob, b"sha512");
+        put(&mut blob, &sig);
+        let b64 = STANDARD.encode(&blob);
+        let lines: Vec<&str> = b64.as_bytes().chunks(70)
+            .map(|c| std::str::from_utf8(c).unwrap()).collect();
+        format!("-----BEGIN SSH SIGNATURE-----\n{}\n-----END SSH SIGNATURE-----\n", lines.join("\n"))
+    }
+    fn hex(bytes: &[u8]) -> String { bytes.iter().map(|b| format!("{:02x}", b)).collect() }
+    
+    let fx = Fixture::new();
+    let signer = SigningKey::from_bytes(&[7u8; 32]);
+    let other = SigningKey::from_bytes(&[8u8; 32]);
+    let authorized_keys = format!(
+        "# ted's keys\n\nssh-rsa AAAAB3NzaC1yc2E= ted@old\nno-pty,command=\"x\" ssh-ed25519 {} ted@laptop\n",
+        STANDARD.encode(key_blob(&signer)),
+    );
+    let mut a = BothAuth::new(&fx.users, &fx.keys).unwrap();
+    a.attach_pubkeys(PubkeyAuth::new(fx.file("pubkeys.csv")).unwrap());
+    a.add_user("ted", "frogs", b"").unwrap();
+    let p = a.pubkeys().unwrap();
+    assert_eq!(p.import_authorized_keys("ted", &authorized_keys), Ok(1));
+    assert_eq!(p.import_authorized_keys("ted", &authorized_keys), Ok(0));
+    assert_eq!(p.keys_of("ted"), vec![signer.verifying_key().to_bytes()]);
+    assert_eq!(p.import_authorized_keys("ted", "ssh-ed25519 !!!"), Err(DataError::BadSignature));
+    
+    let nonce = p.begin_challenge("ted").unwrap();
+    let signature = sshsig(&signer, SSH_NAMESPACE, hex(&nonce).as_bytes());
+    let key = a.login_with_ssh_signature("ted", &signature).unwrap();
+    a.check_key(&key, "ted").unwrap();
+    
+    /* Wrong namespace, wrong key, signing the raw nonce instead of its hex. */
+    let nonce = p.begin_challenge("ted").unwrap();
+    assert_eq!(p.verify_ssh_challenge("ted", &sshsig(&signer, "git", hex(&nonce).as_bytes())),
+               Err(DataError::BadSignature));
+    let nonce = p.begin_challenge("ted").unwrap();
+    assert_eq!(p.verify_ssh_challenge("ted", &sshsig(&other, SSH_NAMESPACE, hex(&nonce).as_bytes())),
+               Err(DataError::BadSignature));
+    let nonce = p.begin_challenge("ted").unwrap();
+    assert_eq!(p.verify_ssh_challenge("ted", &sshsig(&signer, SSH_NAMESPACE, &nonce)),
+               Err(DataError::BadSignature));
+    let _ = p.begin_challenge("ted").unwrap();
+    assert_eq!(p.verify_ssh_challenge("ted", "not a signature"), Err(DataError::BadSignature));
+}
+
+#[test]
+fn client_certs() {
+    let fx = Fixture::new();
+    let cert_file = fx.file("certs.csv");
+    let mut a = BothAuth::new(&fx.users, &fx.keys).unwrap();
+    a.attach_certs(CertAuth::new(&cert_file).unwrap());
+    assert_eq!(a.check_cert_fingerprint("ab:cd"), Err(DataError::NoSuchKey));
+    a.add_user("ted", "frogs", b"").unwrap();
+    a.add_user("bob", "toads", b"").unwrap();
+    
+    let c = a.certs().unwrap();
+    c.add_fingerprint("AB:CD:EF:01", "ted").unwrap();
+    c.add_fingerprint("abcdef01", "ted").unwrap();
+    c.add_fingerprint("99887766", "ted").unwrap();
+    assert_eq!(c.add_fingerprint("abcdef01", "bob"),
+               Err(DataError::UserExists { uname: "ted".to_string() }));
+    c.add_fingerprint("1234", "bob").unwrap();
+    assert_eq!(a.check_cert_fingerprint("abcdef01").unwrap(), "ted");
+    assert_eq!(a.check_cert_fingerprint("AB:CD:EF:01").unwrap(), "ted");
+    assert_eq!(c.fingerprints_of("ted"), vec!["99887766".to_string(), "abcdef01".to_string()]);
+    
+    c.remove_fingerprint("99:88:77:66").unwrap();
+    assert_eq!(c.remove_fingerprint("99887766"), Err(DataError::NoSuchKey));
+    a.delete_user("bob").unwrap();
+    assert_eq!(a.check_cert_fingerprint("1234"), Err(DataError::NoSuchUser));
+    assert_eq!(c.remove_user("bob"), 1);
+    assert_eq!(a.check_cert_fingerprint("1234"), Err(DataError::NoSuchKey));
+    
+    assert!(a.save_if_dirty().unwrap().certs);
+    assert!(!a.save_if_dirty().unwrap().certs);
+    let c = CertAuth::open(&cert_file).unwrap();
+    assert_eq!(c.check_cert_fingerprint("abcdef01").unwrap(), "ted");
+    assert_eq!(c.fingerprints_of("ted"), vec!["abcdef01".to_string()]);
+}
+
+#[test]
+fn credential_checker() {
+    #[derive(Default)]
+    struct Directory { asked: std::sync::Mutex<Vec<String>> }
+    impl CredentialChecker for std::sync::Arc<Directory> {
+        fn check_credentials(&self, uname: &str, password: &str) -> Result<(), DataError> {
+            self.asked.lock().unwrap().push(uname.to_string());
+            match (uname, password) {
+                ("ted", "corporate") => Ok(()),
+                ("ted", _) => Err(DataError::BadPassword),
+                ("bob", _) => Err(DataError::Unavailable),
+                _ => Err(DataError::NoSuchUser),
+            }
+        }
+    }
+    
+    let fx = Fixture::new();
+    let directory = std::sync::Arc::new(Directory::default());
+    let mut a = BothAuth::new(&fx.users, &fx.keys).unwrap();
+    a.add_user("ted", "frogs", b"").unwrap();
+    a.add_user("bob", "toads", b"").unwrap();
+    a.set_admin("ted", true).unwrap();
+    a.credential_checker(directory.clone());
+    
+    /* The local password no longer works; the directory's does. */
+    assert_eq!(a.check_password("ted", "frogs", b""), Err(DataError::BadPassword));
+    assert_eq!(a.check_password("ted", "corporate", b"salt is ignored").unwrap().uname, "ted");
+    a.check_password_admin("ted", "corporate", b"").unwrap();
+    let key = a.check_password_and_issue_key("ted", "corporate", b"").unwrap();
+    a.check_key(&key, "ted").unwrap();
+    assert_eq!(a.check_password("bob", "toads", b""), Err(DataError::Unavailable));
+    assert_eq!(DataError::Unavailable.suggested_status(), 503);
+    
+    /* Users who aren't local can't log in, though the directory is still
+       asked about them so the time taken is the same. */
+    assert_eq!(a.check_password("carol", "corporate", b""), Err(DataError::NoSuchUser));
+    assert_eq!(*directory.asked.lock().unwrap(), vec!["ted", "ted", "ted", "ted", "bob", "carol"]);
+    a.uniform_errors(true);
+    assert_eq!(a.check_password("carol", "corporate", b""), Err(DataError::BadCredentials));
+    assert_eq!(a.check_password("ted", "frogs", b""), Err(DataError::BadCredentials));
+    
+    let b = BothAuth::builder().pwd_file(fx.file("b_users.csv")).key_file(fx.file("b_keys.csv"))
+        .credential_checker(directory).build().unwrap();
+    b.add_user("ted", "frogs", b"").unwrap();
+    b.check_password("ted", "corporate", b"").unwrap();
+}
+
+#[cfg(feature = "ldap")]
+#[test]
+fn ldap_checker() {
+    let mut checker = LdapChecker::new("ldap://127.0.0.1:1", "uid={},ou=people,dc=example,dc=com");
+    checker.timeout(std::time::Duration::from_secs(1));
+    assert_eq!(checker.dn("ted"), "uid=ted,ou=people,dc=example,dc=com");
+    assert_eq!(checker.dn("ted,ou=admins"), "uid=ted\\2cou\\3dadmins,ou=people,dc=example,dc=com");
+    /* An empty password would be an unauthenticated bind, so is never tried. */
+    assert_eq!(checker.check_credentials("ted", ""), Err(DataError::BadPassword));
+    assert_eq!(checker.check_credentials("ted", "frogs"), Err(DataError::Unavailable));
+}
+
+#[test]
+fn external_identities() {
+    let fx = Fixture::new();
+    let ext_file = fx.file("external.csv");
+    let mut a = BothAuth::new(&fx.users, &fx.keys).unwrap();
+    assert_eq!(a.issue_key_for_external("ted", "google", "1234"), Err(DataError::NotAttached));
+    a.attach_external(ExternalAuth::new(&ext_file).unwrap());
+    a.add_user("ted", "frogs", b"").unwrap();
+    a.add_user("bob", "toads", b"").unwrap();
+    a.class_life("staff", std::time::Duration::from_secs(60));
+    a.set_class("ted", Some("staff")).unwrap();
+    
+    let x = a.external().unwrap();
+    assert_eq!(x.user_for("google", "1234"), Err(DataError::NoSuchUser));
+    assert_eq!(a.issue_key_for_external("carol", "google", "1234"), Err(DataError::NoSuchUser));
+    let key = a.issue_key_for_external("ted", "google", "1234").unwrap();
+    a.check_key(&key, "ted").unwrap();
+    assert_eq!(x.user_for("google", "1234").unwrap(), "ted");
+    /* The same identity again is fine; someone else's isn't. */
+    a.issue_key_for_external("ted", "google", "1234").unwrap();
+    assert_eq!(a.issue_key_for_external("bob", "google", "1234"),
+               Err(DataError::IdentityLinked { uname: "ted".to_string() }));
+    a.issue_key_for_external("ted", "github", "77").unwrap();
+    a.issue_key_for_external("bob", "github", "1234").unwrap();
+    assert_eq!(x.links_of("ted"), vec![("github".to_string(), "77".to_string()),
+                                       ("google".to_string(), "1234".to_string())]);
+    
+    x.unlink("github", "77").unwrap();
+    assert_eq!(x.unlink("github", "77"), Err(DataError::NoSuchUser));
+    assert_eq!(x.remove_user("bob"), 1);
+    
+    assert!(a.save_if_dirty().unwrap().external);
+    assert!(!a.save_if_dirty().unwrap().external);
+    let x = ExternalAuth::open(&ext_file).unwrap();
+    assert_eq!(x.links_of("ted"), vec![("google".to_string(), "1234".to_string())]);
+    assert_eq!(x.user_for("github", "1234"), Err(DataError::NoSuchUser));
+}
+
+#[test]
+fn stores_not_attached() {
+    let fx = Fixture::new();
+    let a = BothAuth::new(&fx.users, &fx.keys).unwrap();
+    a.add_user("ted", "frogs", b"").unwrap();
+    let key = a.issue_key("ted").unwrap();
+    let life = std::time::Duration::from_secs(60);
+    
+    assert_eq!(a.check_key_and_group(&key, "ted", "red"), Err(DataError::NotAttached));
+    assert_eq!(a.create_invite(None, life), Err(DataError::NotAttached));
+    assert_eq!(a.redeem_invite("token", "bob", "toads", b""), Err(DataError::NotAttached));
+    assert_eq!(a.check_cert_fingerprint("ab:cd"), Err(DataError::NotAttached));
+    assert_eq!(a.issue_key_for_external("ted", "google", "1234"), Err(DataError::NotAttached));
+    #[cfg(feature = "pubkey")]
+    assert_eq!(a.login_with_signature("ted", b"signature"), Err(DataError::NotAttached));
+    
+    assert_eq!(DataError::NotAttached.suggested_status(), 500);
+    assert_eq!(DataError::IdentityLinked { uname: "ted".into() }.suggested_status(), 409);
+}
+
+#[test]
+fn login_by_email() {
+    let fx = Fixture::new();
+    let a = BothAuth::new(&fx.users, &fx.keys).unwrap();
+    a.add_user("ted", "frogs", b"").unwrap();
+    a.add_user("bob", "toads", b"").unwrap();
+    assert_eq!(a.set_email("carol", Some("carol@example.com")), Err(DataError::NoSuchUser));
+    a.set_email("ted", Some("Ted@Example.com")).unwrap();
+    assert_eq!(a.set_email("bob", Some("ted@example.com")),
+               Err(DataError::UserExists { uname: "ted".to_string() }));
+    assert_eq!(a.email_of("ted").unwrap().as_deref(), Some("Ted@Example.com"));
+    assert_eq!(a.email_of("bob").unwrap(), None);
+    
+    let by_email = Identifier::Email("ted@EXAMPLE.com");
+    assert_eq!(a.check_password_by(by_email, "frogs", b"").unwrap().uname, "ted");
+    assert_eq!(a.check_password_by(Identifier::Username("ted"), "frogs", b"").unwrap().uname, "ted");
+    assert_eq!(a.check_password_by(by_email, "toads", b""), Err(DataError::BadPassword));
+    assert_eq!(a.check_password_by(Identifier::Email("bob@example.com"), "toads", b""),
+               Err(DataError::NoSuchUser));
+    assert_eq!(a.find_user(by_email).unwrap(), "ted");
+    assert_eq!(a.find_user(Identifier::Username("carol")), Err(DataError::NoSuchUser));
+    
+    /* Changing an address frees the old one. */
+    a.set_email("ted", Some("ted@example.org")).unwrap();
+    a.set_email("bob", Some("ted@example.com")).unwrap();
+    assert_eq!(a.find_user(by_email).unwrap(), "bob");
+    a.save_passwords().unwrap();
+    
+    let p = PwdAuth::open(&fx.users).unwrap();
+    assert_eq!(p.find_user(Identifier::Email("TED@example.org")).unwrap(), "ted");
+    let bundle = p.export_user("bob").unwrap();
+    assert_eq!(bundle.email.as_deref(), Some("ted@example.com"));
+    p.delete_user("bob").unwrap();
+    assert_eq!(p.find_user(by_email), Err(DataError::NoSuchUser));
+    p.import_user(bundle).unwrap();
+    assert_eq!(p.find_user(by_email).unwrap(), "bob");
+}
+
+#[test]
+fn normalized_passwords() {
+    let fx = Fixture::new();
+    let (composed, decomposed) = ("caf\u{e9}", "cafe\u{301}");
+    
+    let mut a = PwdAuth::new(&fx.users).unwrap();
+    a.add_user("ted", composed, b"").unwrap();
+    assert_eq!(a.check_password("ted", decomposed, b""), Err(DataError::BadPassword));
+    
+    a.normalize_passwords(true);
+    a.password_hasher(Blake3Hasher);
+    a.add_user("bob", decomposed, b"").unwrap();
+    a.check_password("bob", composed, b"").unwrap();
+    a.check_password("bob", decomposed, b"").unwrap();
+    /* Compatibility forms fold too: full-width letters, ligatures. */
+    a.add_user("carol", "\u{ff21}\u{fb01}", b"").unwrap();
+    a.check_password("carol", "Afi", b"").unwrap();
+    assert_eq!(a.check_password("bob", "cafe", b""), Err(DataError::BadPassword));
+    a.save().unwrap();
+    
+    let mut v = LazyPwdAuth::open(&fx.users).unwrap();
+    assert_eq!(v.check_password("bob", decomposed, b""), Err(DataError::BadPassword));
+    v.normalize_passwords(true);
+    v.check_password("bob", decomposed, b"").unwrap();
+    
+    let b = BothAuth::builder().pwd_file(fx.file("b_users.csv")).key_file(fx.file("b_keys.csv"))
+        .normalize_passwords(true).build().unwrap();
+    b.add_user("ted", composed, b"").unwrap();
+    b.check_password_and_issue_key("ted", decomposed, b"").unwrap();
+}
+
+#[test]
+fn input_limits() {
+    let fx = Fixture::new();
+    let huge = "x".repeat(10_000);
+    let mut a = BothAuth::new(&fx.users, &fx.keys).unwrap();
+    a.add_user("ted", "frogs", b"").unwrap();
+    assert_eq!(a.add_user("bob", &huge, b""), Err(DataError::InputTooLong));
+    assert_eq!(a.add_user(&huge, "toads", b""), Err(DataError::InputTooLong));
+    assert_eq!(a.check_password("ted", &huge, b""), Err(DataError::InputTooLong));
+    assert_eq!(a.check_password_by(Identifier::Email(&huge), "frogs", b""),
+               Err(DataError::InputTooLong));
+    assert_eq!(a.change_password("ted", &huge, b""), Err(DataError::InputTooLong));
+    assert_eq!(DataError::InputTooLong.suggested_status(), 413);
+    
+    a.input_limits(InputLimits { max_uname_bytes: Some(3), max_password_bytes: Some(5) });
+    a.check_password("ted", "frogs", b"").unwrap();
+    assert_eq!(a.check_password("ted", "frogs!", b""), Err(DataError::InputTooLong));
+    assert_eq!(a.add_user("carol", "toads", b""), Err(DataError::InputTooLong));
+    a.input_limits(InputLimits::unlimited());
+    a.add_user("bob", &huge, b"").unwrap();
+    a.check_password("bob", &huge, b"").unwrap();
+    a.save_passwords().unwrap();
+    
+    let v = LazyPwdAuth::open(&fx.users).unwrap();
+    assert_eq!(v.check_password("bob", &huge, b""), Err(DataError::InputTooLong));
+}
+
+#[test]
+fn concurrent_rotations() {
+    use std::sync::Arc;
+    let fx = Fixture::new();
+    let a = Arc::new(BothAuth::new(&fx.users, &fx.keys).unwrap());
+    a.add_user("ted", "frogs", b"").unwrap();
+    a.add_user("bob", "toads", b"").unwrap();
+    
+    let handles: Vec<_> = (0..8).map(|i| {
+        let a = a.clone();
+        std::thread::spawn(move || {
+            let uname = if i % 2 == 0 { "ted" } else { "bob" };
+            let password = format!("password {}", i);
+            let key = a.rotate_credentials(uname, &password, b"").unwrap().unwrap();
+            (uname, password, key)
+        })
+    }).collect();
+    let results: Vec<_> = handles.into_iter().map(|h| h.join().unwrap()).collect();
+    
+    /* For each user, exactly one session survives: the one issued along
+       with the password that stuck. */
+    for uname in ["ted", "bob"].iter() {
+        let live: Vec<_> = results.iter()
+            .filter(|(u, _, key)| u == uname && a.check_key(key, u).is_ok())
+            .collect();
+        assert_eq!(live.len(), 1);
+        a.check_password(uname, &live[0].1, b"").unwrap();
+    }
+}
+
+#[test]
+fn fault_injection() {
+    use crate::testing::{Fault, FaultInjector};
+    let fx = Fixture::new();
+    let a = BothAuth::new(&fx.users, &fx.keys).unwrap();
+    a.add_user("ted", "frogs", b"").unwrap();
+    a.add_user("bob", "toads", b"").unwrap();
+    let key = a.issue_key("ted").unwrap();
+    a.save_passwords().unwrap();
+    a.save_keys().unwrap();
+    let saved_users = std::fs::read(&fx.users).unwrap();
+    let saved_keys = std::fs::read(&fx.keys).unwrap();
+    
+    /* Failed saves leave the files (and nothing else) as they were, and
+       the database still dirty. */
+    a.add_user("jen", "newts", b"").unwrap();
+    let _ = a.issue_key("jen").unwrap();
+    let faults = [Fault::AfterHeader, Fault::MidRecord(0), Fault::MidRecord(2), Fault::OnFlush];
+    for fault in faults.iter() {
+        {
+            let _fault = FaultInjector::on_save(&fx.users, *fault);
+            let e = a.save_passwords().unwrap_err();
+            assert_eq!(e.kind, ErrorKind::Other);
+        }
+        {
+            let _fault = FaultInjector::on_save(&fx.keys, *fault);
+            assert!(a.save_keys().is_err());
+        }
+        assert_eq!(std::fs::read(&fx.users).unwrap(), saved_users);
+        assert_eq!(std::fs::read(&fx.keys).unwrap(), saved_keys);
+        /* The two files, and the password file's .sum file. */
+        assert_eq!(std::fs::read_dir(fx.dir.path()).unwrap().count(), 3);
+        assert!(a.pwd_dirty() && a.key_dirty());
+    }
+    /* A fault past the end of the data still fails the save. */
+    {
+        let _fault = FaultInjector::on_save(&fx.users, Fault::MidRecord(10));
+        assert!(a.save_passwords().is_err());
+    }
+    /* Only the armed file is affected, and only while armed. */
+    {
+        let _fault = FaultInjector::on_save(fx.file("other.csv"), Fault::OnFlush);
+        a.save_passwords().unwrap();
+    }
+    a.save_keys().unwrap();
+    
+    for fault in faults.iter() {
+        let _fault = FaultInjector::on_open(&fx.users, *fault);
+        let e = PwdAuth::open(&fx.users).unwrap_err();
+        assert_eq!(e.kind, ErrorKind::Other);
+        let _fault = FaultInjector::on_open(&fx.keys, *fault);
+        assert!(KeyAuth::open(&fx.keys).is_err());
+    }
+    let b = BothAuth::open(&fx.users, &fx.keys).unwrap();
+    b.check_password("jen", "newts", b"").unwrap();
+    b.check_key(&key, "ted").unwrap();
+}
+
+#[test]
+fn recover_truncated_file() {
+    let fx = Fixture::new();
+    let mut a = PwdAuth::new(&fx.users).unwrap();
+    a.save_order(SaveOrder::ById);
+    for (uname, password) in [("ann", "frogs"), ("bob", "toads"), ("cat", "newts")].iter() {
+        a.add_user(uname, password, b"").unwrap();
+    }
+    a.save().unwrap();
+    
+    /* A file saved whole recovers completely. */
+    let report = PwdAuth::recover(&fx.users, fx.file("whole.csv")).unwrap();
+    assert!(report.is_complete());
+    assert_eq!(report.recovered, 3);
+    
+    /* Garble bob's row and cut cat's short, as an interrupted write might. */
+    let data = std::fs::read_to_string(&fx.users).unwrap();
+    let data = data.replacen("bob,", "bob", 1);
+    let cut = data.len() - 10;
+    std::fs::write(&fx.users, &data[..cut]).unwrap();
+    
+    let recovered = fx.file("recovered.csv");
+    let report = PwdAuth::recover(&fx.users, &recovered).unwrap();
+    assert!(report.truncated);
+    assert_eq!(report.recovered, 1);
+    assert_eq!(report.lost.len(), 2);
+    assert!(report.lost.iter().all(|issue| issue.is_error()));
+    assert_eq!(report.path, recovered);
+    
+    let b = PwdAuth::open(&recovered).unwrap();
+    assert!(b.load_report().issues.is_empty());
+    b.check_password("ann", "frogs", b"").unwrap();
+    assert_eq!(b.user_exists("bob"), Err(DataError::NoSuchUser));
+    assert_eq!(b.user_exists("cat"), Err(DataError::NoSuchUser));
+    
+    /* It won't overwrite anything. */
+    let e = PwdAuth::recover(&fx.users, &recovered).unwrap_err();
+    assert_eq!(e.kind, ErrorKind::AlreadyExists);
+}
+
+#[test]
+fn recover_file_cut_between_records() {
+    let fx = Fixture::new();
+    let mut a = PwdAuth::new(&fx.users).unwrap();
+    a.save_order(SaveOrder::ById);
+    for (uname, password) in [("ann", "frogs"), ("bob", "toads"), ("cat", "newts")].iter() {
+        a.add_user(uname, password, b"").unwrap();
+    }
+    a.save().unwrap();
+    
+    let whole = PwdAuth::recover(&fx.users, fx.file("whole.csv")).unwrap();
+    assert_eq!((whole.expected, whole.checksum_matches), (Some(3), Some(true)));
+    assert!(whole.is_complete());
+    
+    /* Drop cat's row whole, so the file still ends with a newline. */
+    let data = std::fs::read_to_string(&fx.users).unwrap();
+    let cut = data.find("cat,").unwrap();
+    std::fs::write(&fx.users, &data[..cut]).unwrap();
+    
+    let recovered = fx.file("recovered.csv");
+    let report = PwdAuth::recover(&fx.users, &recovered).unwrap();
+    assert!(report.truncated);
+    assert!(!report.is_complete());
+    assert_eq!(report.recovered, 2);
+    assert_eq!(report.expected, Some(3));
+    assert_eq!(report.checksum_matches, Some(false));
+    assert!(report.lost.is_empty());
+    
+    /* The recovered file is described by its own .sum file. */
+    let again = PwdAuth::recover(&recovered, fx.file("again.csv")).unwrap();
+    assert!(again.is_complete());
+    assert_eq!(again.expected, Some(2));
+    
+    /* Without a .sum file, such a cut can't be seen. */
+    std::fs::remove_file(format!("{}.sum", &fx.users)).unwrap();
+    let blind = PwdAuth::recover(&fx.users, fx.file("blind.csv")).unwrap();
+    assert!(blind.is_complete());
+    assert_eq!((blind.expected, blind.checksum_matches), (None, None));
+}
+
+#[test]
+fn hash_encodings() {
+    let fx = Fixture::new();
+    let mut a = PwdAuth::new(&fx.users).unwrap();
+    a.hash_encoding(HashEncoding::Base64);
+    a.add_user("ted", "frogs", b"").unwrap();
+    assert_eq!(a.export_user("ted").unwrap().hash.len(), 43);
+    a.hash_encoding(HashEncoding::Multihash);
+    a.add_user("bob", "toads", b"").unwrap();
+    let hash = a.export_user("bob").unwrap().hash;
+    assert!(hash.starts_with("mHiA") && hash.len() == 47);
+    
+    /* A hash in multibase hex, as another tool might write it. */
+    let hex = Blake3Hasher.hash("newts", b"");
+    let mut bundle = a.export_user("bob").unwrap();
+    bundle.uname = "jen".to_string();
+    bundle.hash = format!("f1e20{}", hex);
+    bundle.email = None;
+    a.import_user(bundle).unwrap();
+    a.save().unwrap();
+    
+    /* Every encoding checks out, whatever the setting, and hashes in
+       other encodings are moved onto it. */
+    let b = PwdAuth::open(&fx.users).unwrap();
+    assert!(b.check_password("ted", "frogs", b"").unwrap().needs_rehash);
+    assert!(b.check_password("bob", "toads", b"").unwrap().needs_rehash);
+    assert!(b.check_password("jen", "newts", b"").unwrap().needs_rehash);
+    assert!(b.check_password("jen", "toads", b"").is_err());
+    assert!(b.rehash_user("ted", "frogs", b"").unwrap());
+    assert_eq!(b.export_user("ted").unwrap().hash.len(), 64);
+    assert_eq!(b.check_password("ted", "frogs", b"").unwrap().needs_rehash, false);
+    
+    /* Other hashers' strings are left alone. */
+    struct Plain;
+    impl PasswordHasher for Plain {
+        fn hash(&self, password: &str, salt: &[u8]) -> String {
+            format!("plain${}", Blake3Hasher.hash(password, salt))
+        }
+        fn verify(&self, password: &str, salt: &[u8], stored: &str) -> bool {
+            stored == self.hash(password, salt)
+        }
+    }
+    let mut c = PwdAuth::new(fx.file("plain.csv")).unwrap();
+    c.hash_encoding(HashEncoding::Base64);
+    c.password_hasher(Plain);
+    c.add_user("ted", "frogs", b"").unwrap();
+    assert!(c.export_user("ted").unwrap().hash.starts_with("plain$"));
+    assert_eq!(c.check_password("ted", "frogs", b"").unwrap().needs_rehash, false);
+}
+
+#[test]
+fn stored_salts() {
+    use base64::{engine::general_purpose::STANDARD_NO_PAD, Engine};
+    let fx = Fixture::new();
+    let mut a = PwdAuth::new(&fx.users).unwrap();
+    a.add_user("ted", "frogs", b"ted's salt").unwrap();
+    a.add_user("bob", "toads", b"").unwrap();
+    a.add_user("jen", "newts", b"").unwrap();
+    assert_eq!(a.salt_of("ted"), Ok(None));
+    a.set_salt("ted", Some(b"ted's salt")).unwrap();
+    a.salt_encoding(SaltEncoding::Hex);
+    a.set_salt("bob", Some(&[0, 1, 254, 255])).unwrap();
+    a.set_salt("jen", Some(b"")).unwrap();
+    assert_eq!(a.set_salt("amy", Some(b"x")), Err(DataError::NoSuchUser));
+    assert_eq!(a.salt_of("amy"), Err(DataError::NoSuchUser));
+    a.save().unwrap();
+    
+    let data = std::fs::read_to_string(&fx.users).unwrap();
+    assert!(data.contains(",f0001feff\n"));
+    assert!(data.contains(&format!(",m{}\n", STANDARD_NO_PAD.encode(b"ted's salt"))));
+    
+    /* Either encoding reads back, whatever the setting. */
+    let b = PwdAuth::open(&fx.users).unwrap();
+    let salt = b.salt_of("ted").unwrap().unwrap();
+    assert_eq!(salt, b"ted's salt");
+    b.check_password("ted", "frogs", &salt).unwrap();
+    assert_eq!(b.salt_of("bob"), Ok(Some(vec![0, 1, 254, 255])));
+    assert_eq!(b.salt_of("jen"), Ok(Some(Vec::new())));
+    
+    /* Salts move with their users, and can be removed. */
+    let c = PwdAuth::new(fx.file("other.csv")).unwrap();
+    c.import_user(b.export_user("ted").unwrap()).unwrap();
+    assert_eq!(c.salt_of("ted").unwrap().unwrap(), b"ted's salt");
+    c.set_salt("ted", None).unwrap();
+    assert_eq!(c.salt_of("ted"), Ok(None));
+}
+
+#[test]
+fn password_challenges() {
+    let fx = Fixture::new();
+    let mut a = PwdAuth::new(&fx.users).unwrap();
+    a.add_user("ted", "frogs", b"ted's salt").unwrap();
+    a.set_salt("ted", Some(b"ted's salt")).unwrap();
+    a.add_user("bob", "toads", b"").unwrap();
+    
+    /* What the client does. */
+    let respond = |password: &str, salt: &[u8], nonce: &[u8; 32]| -> Vec<u8> {
+        let mut hasher = blake3::Hasher::new();
+        hasher.update(password.as_bytes());
+        hasher.update(salt);
+        let digest = hasher.finalize();
+        blake3::keyed_hash(digest.as_bytes(), nonce).as_bytes().to_vec()
+    };
+    
+    let (salt, nonce) = a.password_challenge("ted").unwrap();
+    assert_eq!(salt, b"ted's salt");
+    let response = respond("frogs", &salt, &nonce);
+    assert_eq!(a.verify_challenge_response("ted", &nonce, &response).unwrap().uname, "ted");
+    /* Each challenge can only be answered once. */
+    assert_eq!(a.verify_challenge_response("ted", &nonce, &response), Err(DataError::BadPassword));
+    
+    let (salt, nonce) = a.password_challenge("bob").unwrap();
+    assert!(salt.is_empty());
+    let response = respond("frogs", &salt, &nonce);
+    assert_eq!(a.verify_challenge_response("bob", &nonce, &response), Err(DataError::BadPassword));
+    let (_, old) = a.password_challenge("bob").unwrap();
+    let (_, nonce) = a.password_challenge("bob").unwrap();
+    let response = respond("toads", &salt, &old);
+    assert_eq!(a.verify_challenge_response("bob", &old, &response), Err(DataError::BadPassword));
+    assert_eq!(a.verify_challenge_response("bob", &nonce, b"short"), Err(DataError::BadPassword));
+    assert_eq!(a.password_challenge("amy"), Err(DataError::NoSuchUser));
+    
+    a.challenge_life(Duration::from_secs(0));
+    let (salt, nonce) = a.password_challenge("bob").unwrap();
+    let response = respond("toads", &salt, &nonce);
+    assert_eq!(a.verify_challenge_response("bob", &nonce, &response), Err(DataError::BadPassword));
+    
+    /* The client can't compute a peppered hash. */
+    a.pepper(&|| Ok(b"pepper".to_vec())).unwrap();
+    assert_eq!(a.password_challenge("bob"), Err(DataError::Unsupported));
+    assert_eq!(DataError::Unsupported.suggested_status(), 501);
+}
+
+#[test]
+fn key_context() {
+    let fx = Fixture::new();
+    let a = KeyAuth::new(&fx.keys).unwrap();
+    let context = serde_json::json!({ "ip": "10.0.0.1", "agent": "Firefox on Linux" });
+    let phone = a.issue_key("ted").unwrap();
+    let laptop = a.issue_key_with_context("ted", context.clone()).unwrap();
+    let _other = a.issue_key("bob").unwrap();
+    
+    let info = a.key_info(&laptop).unwrap();
+    assert_eq!(info.uname, "ted");
+    assert_eq!(info.hash, KeyHash::of(&laptop));
+    assert_eq!(info.context.as_ref(), Some(&context));
+    assert!(!info.revoked);
+    assert_eq!(a.key_info(&phone).unwrap().context, None);
+    assert_eq!(a.key_info("nope"), Err(DataError::NoSuchKey));
+    
+    /* Rotation keeps the context, and it survives a restart. */
+    let laptop = a.rotate_key(&laptop, "ted").unwrap();
+    a.save().unwrap();
+    let b = KeyAuth::open(&fx.keys).unwrap();
+    let sessions = b.user_keys("ted");
+    assert_eq!(sessions.len(), 2);
+    assert_eq!(sessions[1].hash, KeyHash::of(&laptop));
+    assert_eq!(sessions[1].context, Some(context));
+    
+    b.invalidate_key(&phone).unwrap();
+    assert_eq!(b.user_keys("ted").len(), 1);
+    assert!(b.key_info(&phone).unwrap().revoked);
+}
+
+#[test]
+fn purge_user() {
+    use std::sync::{Arc, Mutex};
+    let fx = Fixture::new();
+    let mut a = BothAuth::new(&fx.users, &fx.keys).unwrap();
+    let groups = GroupAuth::new(fx.file("groups.csv")).unwrap();
+    groups.create_group("red").unwrap();
+    groups.add_member("red", "ted").unwrap();
+    groups.add_member("red", "bob").unwrap();
+    a.attach_groups(groups);
+    a.attach_invites(InviteAuth::new(fx.file("invites.csv")).unwrap());
+    a.attach_certs(CertAuth::new(fx.file("certs.csv")).unwrap());
+    a.attach_remember(RememberAuth::new(fx.file("remember.csv")).unwrap());
+    let purged = Arc::new(Mutex::new(Vec::new()));
+    let seen = purged.clone();
+    a.on_security_event(move |e| seen.lock().unwrap().push(e.clone()));
+    
+    a.add_user("ted", "frogs", b"").unwrap();
+    a.add_user("bob", "toads", b"").unwrap();
+    let _ = a.issue_key("ted").unwrap();
+    let revoked = a.issue_key("ted").unwrap();
+    a.invalidate_key(&revoked).unwrap();
+    let bobs = a.issue_key("bob").unwrap();
+    let _ = a.create_invite(Some("ted"), std::time::Duration::from_secs(60)).unwrap();
+    a.certs().unwrap().add_fingerprint("AB:CD", "ted").unwrap();
+    let remembered = a.remember().unwrap().issue_remember_token("ted");
+    let _ = a.remember().unwrap().issue_remember_token("ted");
+    let _ = a.remember().unwrap().issue_remember_token("bob");
+    a.invalidate_sessions_before("ted", std::time::SystemTime::now()).unwrap();
+    
+    let report = a.purge_user("ted").unwrap();
+    assert_eq!(report, PurgeReport {
+        user: true, keys: 2, groups: 1, invites: 1, certs: 1, remember: 2, ..Default::default()
+    });
+    assert_eq!(purged.lock().unwrap().last(),
+               Some(&SecurityEvent::UserPurged { uname: "ted".to_string() }));
+    
+    /* Everything was saved, and nothing mentions ted any more. */
+    assert!(!a.pwd_dirty() && !a.key_dirty());
+    for entry in std::fs::read_dir(fx.dir.path()).unwrap() {
+        let text = std::fs::read_to_string(entry.unwrap().path()).unwrap();
+        assert!(!text.contains("ted"), "{}", text);
+    }
+    let b = BothAuth::open(&fx.users, &fx.keys).unwrap();
+    assert_eq!(b.user_exists("ted"), Err(DataError::NoSuchUser));
+    b.check_key(&bobs, "bob").unwrap();
+    let r = RememberAuth::open(fx.file("remember.csv")).unwrap();
+    assert_eq!(r.redeem_remember_token(&remembered.selector, &remembered.validator).unwrap_err(), DataError::NoSuchKey);
+    
+    /* Purging again finds nothing left. */
+    assert_eq!(a.purge_user("ted").unwrap(), PurgeReport::default());
+}
+
+#[test]
+fn file_size_limits() {
+    let fx = Fixture::new();
+    let mut a = BothAuth::new(&fx.users, &fx.keys).unwrap();
+    a.add_user("ted", "frogs", b"").unwrap();
+    let key = a.issue_key("ted").unwrap();
+    assert_eq!((a.user_count(), a.key_count()), (1, 1));
+    
+    /* The estimates are exact for uncompressed files. */
+    a.save_passwords().unwrap();
+    a.save_keys().unwrap();
+    let pwd_size = std::fs::metadata(&fx.users).unwrap().len();
+    let key_size = std::fs::metadata(&fx.keys).unwrap().len();
+    assert_eq!(a.estimated_pwd_file_size(), pwd_size);
+    assert_eq!(a.estimated_key_file_size(), key_size);
+    
+    /* Room for one more key, but not two. */
+    let text = std::fs::read_to_string(&fx.keys).unwrap();
+    let row = text.lines().nth(1).unwrap().len() as u64 + 1;
+    a.max_key_file_size(Some(key_size + row + row / 2));
+    let second = a.issue_key("ted").unwrap();
+    assert_eq!(a.issue_key("ted"), Err(DataError::CapacityExceeded));
+    assert_eq!(a.key_count(), 2);
+    
+    /* Removing keys makes room again. */
+    a.remove_key(&second).unwrap();
+    let _ = a.issue_key("ted").unwrap();
+    a.check_key(&key, "ted").unwrap();
+    
+    a.max_pwd_file_size(Some(pwd_size));
+    assert_eq!(a.add_user("bob", "toads", b""), Err(DataError::CapacityExceeded));
+    a.max_pwd_file_size(None);
+    a.add_user("bob", "toads", b"").unwrap();
+}
+
+#[test]
+fn refused_import_leaves_no_trace() {
+    let fx = Fixture::new();
+    let src = PwdAuth::new(&fx.users).unwrap();
+    src.add_user("Ted", "frogs", b"").unwrap();
+    src.set_email("Ted", Some("ted@example.com")).unwrap();
+    let bundle = src.export_user("Ted").unwrap();
+    
+    let mut dst = PwdAuth::new(&fx.keys).unwrap();
+    dst.case_insensitive(true);
+    let size = dst.estimated_file_size();
+    dst.max_file_size(Some(size));
+    assert_eq!(dst.import_user(bundle.clone()), Err(DataError::CapacityExceeded));
+    assert_eq!(dst.find_user(Identifier::Email("ted@example.com")), Err(DataError::NoSuchUser));
+    
+    dst.max_file_size(None);
+    dst.add_user("ted", "toads", b"").unwrap();
+    dst.set_email("ted", Some("TED@example.com")).unwrap();
+    assert_eq!(dst.find_user(Identifier::Email("ted@example.com")).unwrap(), "ted");
+}
+
+#[test]
+fn status_report() {
+    let fx = Fixture::new();
+    let mut a = BothAuth::new(&fx.users, &fx.keys).unwrap();
+    a.max_users(Some(10));
+    a.add_user("ted", "frogs", b"").unwrap();
+    a.add_user("bob", "toads", b"").unwrap();
+    let key = a.issue_key("ted").unwrap();
+    let _ = a.issue_key("bob").unwrap();
+    a.invalidate_key(&key).unwrap();
+    a.save_keys().unwrap();
+    
+    let status = a.status();
+    assert_eq!((status.users, status.active_sessions, status.keys), (2, 1, 2));
+    assert_eq!((status.pwd_dirty, status.key_dirty), (true, false));
+    assert_eq!(status.pwd_file, std::path::PathBuf::from(&fx.users));
+    assert_eq!(status.key_file, std::path::PathBuf::from(&fx.keys));
+    assert!(status.pwd_saved.is_some() && status.key_saved.is_some());
+    assert_eq!((status.max_users, status.max_keys), (Some(10), None));
+    
+    let text = status.to_string();
+    assert!(text.starts_with("users: 2 (limit 10)\nsessions: 1 active, 2 stored (limit unlimited)\n"), "{}", text);
+    assert!(text.contains(", unsaved changes\nkey file: "), "{}", text);
+    assert!(text.contains("\nkey life: 20m, 199 bits of entropy\n"), "{}", text);
+    assert!(text.ends_with("\nwrite-through: off"), "{}", text);
+}
+
+#[test]
+fn health_check() {
+    use std::io::Write;
+    let fx = Fixture::new();
+    let a = BothAuth::new(&fx.users, &fx.keys).unwrap();
+    a.add_user("ted", "frogs", b"").unwrap();
+    a.save_passwords().unwrap();
+    assert_eq!(a.health_check(), Vec::new());
+    
+    let mut f = std::fs::OpenOptions::new().append(true).open(&fx.users).unwrap();
+    f.write_all(b"bob,not,enough\nbob,,,,,,\n").unwrap();
+    drop(f);
+    std::fs::remove_file(&fx.keys).unwrap();
+    let issues = a.health_check();
+    assert_eq!(issues.len(), 2, "{:?}", issues);
+    assert!(matches!(&issues[0], HealthIssue::Corrupt { errors: 2, .. }), "{:?}", issues);
+    assert_eq!(issues[1], HealthIssue::Missing { path: fx.keys.clone().into() });
+    assert_eq!(issues[1].to_string(), format!("{} is missing", fx.keys));
+    
+    /* The probes leave nothing behind (but the password file's .sum file). */
+    let files = std::fs::read_dir(fx.dir.path()).unwrap().count();
+    assert_eq!(files, 2);
+}
+
+#[test]
+fn reader_writer_round_trip() {
+    let fx = Fixture::new();
+    let p = PwdAuth::new(&fx.users).unwrap();
+    p.add_user("ted", "frogs", b"").unwrap();
+    let bytes = p.save_to_writer(Vec::new()).unwrap();
+    assert!(p.is_dirty());
+    
+    let p2 = PwdAuth::from_reader(&bytes[..]).unwrap();
+    p2.check_password("ted", "frogs", b"").unwrap();
+    
+    let k = KeyAuth::new(&fx.keys).unwrap();
+    let key = k.issue_key("ted").unwrap();
+    let bytes = k.save_to_writer(Vec::new()).unwrap();
+    let k2 = KeyAuth::from_reader(&bytes[..]).unwrap();
+    k2.check_key(&key, "ted").unwrap();
+    
+    /* With no file of its own, saving needs somewhere else to go. */
+    let before = std::fs::read_dir(".").unwrap().count();
+    let e = p2.save().unwrap_err();
+    assert_eq!(e.kind, std::io::ErrorKind::InvalidInput);
+    assert_eq!(k2.save().unwrap_err().kind, std::io::ErrorKind::InvalidInput);
+    assert_eq!(std::fs::read_dir(".").unwrap().count(), before);
+    
+    let copy = fx.file("copy.csv");
+    p2.save_to(&copy).unwrap();
+    PwdAuth::open(&copy).unwrap().check_password("ted", "frogs", b"").unwrap();
+}
+
+#[test]
+fn static_csv_is_read_only() {
+    let fx = Fixture::new();
+    let p = PwdAuth::new(&fx.users).unwrap();
+    p.add_user("ted", "frogs", b"").unwrap();
+    /* Stands in for `include_bytes!()`. */
+    let data: &'static [u8] = Box::leak(p.save_to_writer(Vec::new()).unwrap().into_boxed_slice());
+    
+    let a = PwdAuth::from_static_csv(data).unwrap();
+    assert!(a.is_read_only());
+    assert!(!p.is_read_only());
+    a.check_password("ted", "frogs", b"").unwrap();
+    assert_eq!(a.check_password("ted", "toads", b""), Err(DataError::BadPassword));
+    
+    assert_eq!(a.add_user("bob", "toads", b""), Err(DataError::ReadOnly));
+    assert_eq!(a.change_password("ted", "toads", b""), Err(DataError::ReadOnly));
+    assert_eq!(a.set_admin("ted", true), Err(DataError::ReadOnly));
+    assert_eq!(a.delete_user("ted"), Err(DataError::ReadOnly));
+    assert_eq!(a.usernames(), vec!["ted".to_string()]);
+    assert!(!a.is_dirty());
+    assert_eq!(DataError::ReadOnly.suggested_status(), 403);
+    
+    /* A plaintext import is refused without shredding the file. */
+    let plain = fx.file("plain.csv");
+    std::fs::write(&plain, "uname,password\nbob,toads\n").unwrap();
+    let e = a.import_plaintext_csv(&plain, SaltPolicy::Random).unwrap_err();
+    assert_eq!(e.kind, std::io::ErrorKind::PermissionDenied);
+    assert!(std::fs::read_to_string(&plain).unwrap().contains("toads"));
+}
+
+#[test]
+fn suggested_status() {
+    assert_eq!(DataError::BadPassword.suggested_status(), 401);
+    assert_eq!(DataError::BadCredentials.suggested_status(), 401);
+    let expired_for = std::time::Duration::from_secs(60);
+    assert_eq!(DataError::KeyExpired { expired_for }.suggested_status(), 401);
+    assert_eq!(DataError::NotAdmin.suggested_status(), 403);
+    assert_eq!(DataError::NoSuchUser.suggested_status(), 404);
+    assert_eq!(DataError::NoSuchNamespace.suggested_status(), 404);
+    assert_eq!(DataError::UserExists { uname: "ted".into() }.suggested_status(), 409);
+    assert_eq!(DataError::CapacityExceeded.suggested_status(), 503);
+}
+
+#[cfg(feature = "serde")]
+#[test]
+fn serde_errors() {
+    assert_eq!(serde_json::to_string(&DataError::BadPassword).unwrap(), "\"BadPassword\"");
+    let e: DataError = serde_json::from_str("\"KeyRevoked\"").unwrap();
+    assert_eq!(e, DataError::KeyRevoked);
+    let json = serde_json::to_string(&DataError::UserExists { uname: "ted".into() }).unwrap();
+    assert_eq!(json, r#"{"UserExists":{"uname":"ted"}}"#);
+    
+    let fe = FileError::new(
+        Path::new("test/nope.csv"), Op::Read, std::io::ErrorKind::NotFound,
+        String::from("no such file")
+    );
+    let json = serde_json::to_string(&DataError::SaveFailed(fe.clone())).unwrap();
+    assert!(json.contains("\"kind\":\"NotFound\""), "{}", json);
+    assert_eq!(serde_json::from_str::<DataError>(&json).unwrap(), DataError::SaveFailed(fe));
+}
@@ -0,0 +1,86 @@
+use std::collections::{HashMap, VecDeque};
+use std::time::{Duration, SystemTime};
+
+use parking_lot::Mutex;
+
+/**
+Tracks when keys were recently issued to each user, to enforce a limit on
+how many may be issued to any one user within a sliding window of time
+(see `KeyAuth::issue_rate_limit()`).
+
+This is only kept in memory; it starts out empty whenever a database is
+opened.
+*/
+#[derive(Debug, Default)]
+pub(crate) struct IssueThrottle {
+    limit: Option<(usize, Duration)>,
+    issued: Mutex<HashMap<String, VecDeque<SystemTime>>>,
+}
+
+impl IssueThrottle {
+    /** Sets (or removes) the limit of `max` issues per `window`. */
+    pub(crate) fn set_limit(&mut self, limit: Option<(usize, Duration)>) {
+        self.limit = limit;
+    }
+    
+    /**
+    Records an issue to `uname` at `now` and returns `true` if that doesn't
+    exceed the limit; otherwise records nothing and returns `false`.
+    */
+    pub(crate) fn try_acquire(&self, uname: &str, now: SystemTime) -> bool {
+        let (max, window) = match self.limit {
+            Some(limit) => limit,
+            None => { return true; },
+        };
+        
+        let mut issued = self.issued.lock();
+        let times = issued.entry(uname.to_string()).or_default();
+        while let Some(t) = times.front() {
+            if in_window(*t, now, window) { break; }
+            let _ = times.pop_front();
+        }
+        if times.len() >= max { return false; }
+        times.push_back(now);
+        return true;
+    }
+    
+    /**
+    Forgets an issue recorded by `.try_acquire()` at `at`, for when the
+    issue subsequently failed.
+    */
+    pub(crate) fn release(&self, uname: &str, at: SystemTime) {
+        if self.limit.is_none() { return; }
+        let mut issued = self.issued.lock();
+        if let Some(times) = issued.get_mut(uname) {
+            if let Some(i) = times.iter().rposition(|t| *t == at) {
+                let _ = times.remove(i);
+            }
+        }
+    }
+    
+    /** Forgets every issue to `uname`. */
+    pub(crate) fn forget(&self, uname: &str) {
+        let _ = self.issued.lock().remove(uname);
+    }
+    
+    /** Forgets all issues that no longer count against the limit. */
+    pub(crate) fn prune(&self, now: SystemTime) {
+        let window = match self.limit {
+            Some((_, window)) => window,
+            None => Duration::from_secs(0),
+        };
+        let mut issued = self.issued.lock();
+        issued.retain(|_, times| {
+            times.retain(|t| in_window(*t, now, window));
+            !times.is_empty()
+        });
+    }
+}
+
+/** Returns whether the time `t` is within `window` before `now`. */
+fn in_window(t: SystemTime, now: SystemTime, window: Duration) -> bool {
+    match now.duration_since(t) {
+        Ok(age) => age < window,
+        Err(_) => true,
+    }
+}
@@ -0,0 +1,135 @@
+/*!
+A smaller, consistently-named API over the same files, for new code.
+
+The original API grew method by method, so its names are uneven (users
+are "checked" but keys are "issued" and "invalidated"). `Auth` here wraps a
+`BothAuth` and names everything after what it does for a web app:
+passwords are verified, and sessions are created, verified, renewed, and
+ended. It reads and writes exactly the same files, so code can be moved
+over gradually; nothing in the original API is deprecated or changed.
+
+Every constructor returns a `Result` and takes any `impl AsRef<Path>`, and
+everything that changes users or sessions takes `&self`, so an `Auth` can
+be shared between threads in an `Arc`.
+
+```no_run
+use authlite::v2::Auth;
+
+let auth = Auth::open_or_create("data/users.csv", "data/keys.csv").unwrap();
+auth.create_user("ted", "frogs", b"").unwrap();
+let session = auth.create_session("ted", "frogs", b"").unwrap();
+auth.verify_session(&session, "ted").unwrap();
+auth.end_session(&session).unwrap();
+auth.save().unwrap();
+```
+
+Settings (key life, write-through mode, and so on) are made on a
+`BothAuth`, usually from `BothAuth::builder()`, which is then wrapped with
+`Auth::from()`; and `.both()` reaches everything this doesn't cover.
+*/
+use std::path::Path;
+
+use crate::{AuthOk, BothAuth, DataError, FileError, SaveReport};
+
+/** A password and session database; see the module documentation. */
+#[derive(Debug)]
+pub struct Auth {
+    inner: BothAuth,
+}
+
+impl Auth {
+    /** Opens the given existing password and session (key) files. */
+    pub fn open(users_file: impl AsRef<Path>, sessions_file: impl AsRef<Path>)
+    -> Result<Self, FileError> {
+        Ok(Auth { inner: BothAuth::open(users_file, sessions_file)? })
+    }
+    
+    /**
+    Creates new, empty password and session files (and any missing
+    directories above them). Fails if either file already exists.
+    */
+    pub fn create(users_file: impl AsRef<Path>, sessions_file: impl AsRef<Path>)
+    -> Result<Self, FileError> {
+        Ok(Auth { inner: BothAuth::new_with_dirs(users_file, sessions_file)? })
+    }
+    
+    /** Opens the given files, creating whichever of them don't exist. */
+    pub fn open_or_create(users_file: impl AsRef<Path>, sessions_file: impl AsRef<Path>)
+    -> Result<Self, FileError> {
+        Ok(Auth { inner: BothAuth::open_or_new(users_file, sessions_file)? })
+    }
+    
+    /** Returns the underlying `BothAuth`, for everything not covered here. */
+    pub fn both(&self) -> &BothAuth { &self.inner }
+    
+    /** Unwraps the underlying `BothAuth`. */
+    pub fn into_both(self) -> BothAuth { self.inner }
+    
+    /** Adds a new user; see `BothAuth::add_user()`. */
+    pub fn create_user(&self, uname: &str, password: &str, salt: &[u8])
+    -> Result<(), DataError> { self.inner.add_user(uname, password, salt) }
+    
+    /** Removes a user; see `BothAuth::delete_user()`. Their sessions are left alone. */
+    pub fn delete_user(&self, uname: &str)
+    -> Result<(), DataError> { self.inner.delete_user(uname) }
+    
+    pub fn change_password(&self, uname: &str, password: &str, salt: &[u8])
+    -> Result<(), DataError> { self.inner.change_password(uname, password, salt) }
+    
+    /** Checks a user's password; see `BothAuth::check_password()`. */
+    pub fn verify_password(&self, uname: &str, password: &str, salt: &[u8])
+    -> Result<AuthOk, DataError> { self.inner.check_password(uname, password, salt) }
+    
+    pub fn user_exists(&self, uname: &str) -> bool { self.inner.user_exists(uname).is_ok() }
+    
+    pub fn is_admin(&self, uname: &str)
+    -> Result<bool, DataError> { self.inner.is_admin(uname) }
+    
+    pub fn set_admin(&self, uname: &str, is_admin: bool)
+    -> Result<(), DataError> { self.inner.set_admin(uname, is_admin) }
+    
+    /** Returns every user name, sorted. */
+    pub fn usernames(&self) -> Vec<String> { self.inner.usernames() }
+    
+    /**
+    Verifies a user's password and, if it's right, starts a session for
+    them, returning its key; see `BothAuth::check_password_and_issue_key()`.
+    */
+    pub fn create_session(&self, uname: &str, password: &str, salt: &[u8])
+    -> Result<String, DataError> {
+        self.inner.check_password_and_issue_key(uname, password, salt)
+    }
+    
+    /** Checks that `session` is a current session of the given user. */
+    pub fn verify_session(&self, session: &str, uname: &str)
+    -> Result<AuthOk, DataError> { self.inner.check_key(session, uname) }
+    
+    /** Like `.verify_session()`, but also extends the session's life. */
+    pub fn renew_session(&self, session: &str, uname: &str)
+    -> Result<(), DataError> { self.inner.check_and_refresh_key(session, uname) }
+    
+    /**
+    Ends a session (when its user logs out); verifying it afterward returns
+    `DataError::KeyRevoked`. See `BothAuth::invalidate_key()`.
+    */
+    pub fn end_session(&self, session: &str)
+    -> Result<(), DataError> { self.inner.invalidate_key(session) }
+    
+    /** Ends every session of the given user, returning how many there were. */
+    pub fn end_user_sessions(&self, uname: &str)
+    -> Result<usize, DataError> { self.inner.remove_user_keys(uname) }
+    
+    /** Ends every session of every user; see `BothAuth::invalidate_all_sessions()`. */
+    pub fn end_all_sessions(&self)
+    -> Result<(), FileError> { self.inner.invalidate_all_sessions() }
+    
+    /** Whether anything has changed since the files were last saved. */
+    pub fn is_dirty(&self) -> bool { self.inner.pwd_dirty() || self.inner.key_dirty() }
+    
+    /** Saves whichever files have changed; see `BothAuth::save_if_dirty()`. */
+    pub fn save(&self) -> Result<SaveReport, FileError> { self.inner.save_if_dirty() }
+}
+
+impl From<BothAuth> for Auth {
+    fn from(inner: BothAuth) -> Self { Auth { inner } }
+}
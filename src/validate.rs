@@ -0,0 +1,215 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+use serde::de::DeserializeOwned;
+
+use crate::{AuthEvent, FileError, Op};
+
+/** A problem found by `PwdAuth::validate_file()` or
+    `KeyAuth::validate_file()`, or when opening a database (see
+    `PwdAuth::load_report()`).
+    
+    Rows are identified by their line number in the file (the header is
+    line 1).
+*/
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum ValidationIssue {
+    /** The row couldn't be parsed; it would be skipped by `.open()`. */
+    Malformed { line: u64, msg: String },
+    /** The row has the same user name (or key) as an earlier row; `.open()`
+        would keep only one of them. */
+    Duplicate { line: u64, first_line: u64, id: String },
+    /** The key on this row has expired (or was issued before a call to
+        `.invalidate_all()`); `.open()` would skip it. */
+    Expired { line: u64 },
+    /** The key on this row is shorter than is safe to rely on. */
+    ShortKey { line: u64, length: usize },
+    /** The file can be read by users other than its owner. */
+    WorldReadable,
+}
+
+impl ValidationIssue {
+    /** Whether this issue means `.open()` would lose or misread data (as
+        opposed to being a warning about the file's contents or setup). */
+    pub fn is_error(&self) -> bool {
+        matches!(self, ValidationIssue::Malformed { .. } | ValidationIssue::Duplicate { .. })
+    }
+}
+
+impl fmt::Display for ValidationIssue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ValidationIssue::Malformed { line, msg } =>
+                write!(f, "line {}: {}", line, msg),
+            ValidationIssue::Duplicate { line, first_line, id } =>
+                write!(f, "line {}: duplicate entry for \"{}\" (first on line {})",
+                       line, id, first_line),
+            ValidationIssue::Expired { line } =>
+                write!(f, "line {}: key has expired", line),
+            ValidationIssue::ShortKey { line, length } =>
+                write!(f, "line {}: key is only {} characters long", line, length),
+            ValidationIssue::WorldReadable =>
+                write!(f, "file is readable by other users"),
+        }
+    }
+}
+
+/** The results of checking a data file, either without opening it as a
+    database (with `.validate_file()`), or while opening it (see
+    `.load_report()`). */
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ValidationReport {
+    /** The file that was checked. */
+    pub path: PathBuf,
+    /** The number of data rows (not counting the header) in the file. */
+    pub rows: usize,
+    /** Everything questionable about the file, in the order found. */
+    pub issues: Vec<ValidationIssue>,
+}
+
+impl ValidationReport {
+    /** Returns whether any of the issues found is an error. */
+    pub fn has_errors(&self) -> bool { self.issues.iter().any(|i| i.is_error()) }
+    
+    /** An empty report, for a database that wasn't loaded from a file. */
+    pub(crate) fn empty(path: &Path) -> Self {
+        ValidationReport { path: PathBuf::from(path), rows: 0, issues: Vec::new() }
+    }
+    
+    /** Prints a warning to stderr for each issue. */
+    pub(crate) fn warn(&self) {
+        for issue in self.issues.iter() {
+            eprintln!("WARNING: reading {}, {}", self.path.to_string_lossy(), issue);
+        }
+    }
+    
+    /** Adds an issue if the file is readable by anyone but its owner. */
+    pub(crate) fn check_permissions(&mut self) {
+        if is_world_readable(&self.path) { self.issues.push(ValidationIssue::WorldReadable); }
+    }
+    
+    /** Returns an `AuthEvent::DuplicateEntry` for each duplicate found. */
+    pub(crate) fn duplicate_events(&self) -> Vec<AuthEvent> {
+        self.issues.iter().filter_map(|issue| match issue {
+            ValidationIssue::Duplicate { line, first_line, id } => Some(AuthEvent::DuplicateEntry {
+                path: self.path.clone(),
+                line: *line,
+                first_line: *first_line,
+                id: id.clone(),
+            }),
+            _ => None,
+        }).collect()
+    }
+}
+
+/** What `PwdAuth::recover()` salvaged from a damaged file. */
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RecoveryReport {
+    /** The new file the recovered records were written to. */
+    pub path: PathBuf,
+    /** The number of records recovered. */
+    pub recovered: usize,
+    /** Whether the damaged file ended partway through a record, or is
+        shorter than when it was saved, as a file whose writing was cut
+        short is. */
+    pub truncated: bool,
+    /** The records that couldn't be recovered (all `ValidationIssue::Malformed`),
+        identified by their line in the damaged file. Records cut off
+        entirely can't be identified, so aren't listed. */
+    pub lost: Vec<ValidationIssue>,
+    /** How many records the file had when it was saved, if its `.sum`
+        file could be read. */
+    pub expected: Option<usize>,
+    /** Whether the damaged file still matches the checksum it was saved
+        with, if its `.sum` file could be read. */
+    pub checksum_matches: Option<bool>,
+}
+
+impl RecoveryReport {
+    /** Returns whether every record in the damaged file was recovered. */
+    pub fn is_complete(&self) -> bool {
+        return !self.truncated && self.lost.is_empty()
+            && self.expected.is_none_or(|rows| rows == self.recovered)
+            && self.checksum_matches != Some(false);
+    }
+}
+
+/**
+Reads every row of the .csv data in `r` as a `T`, reporting rows that
+don't parse and rows whose `id_of()` repeats an earlier one, and passing
+each good row to `check()` (which can also store it, and report anything
+else wrong with it). `path` is only used to describe errors.
+*/
+pub(crate) fn validate_rows<T, R, I, C>(
+    r: R,
+    path: &Path,
+    id_of: I,
+    mut check: C
+) -> Result<ValidationReport, FileError>
+where
+    T: DeserializeOwned,
+    R: Read,
+    I: Fn(&T) -> &str,
+    C: FnMut(u64, T, &mut Vec<ValidationIssue>),
+{
+    let mut issues: Vec<ValidationIssue> = Vec::new();
+    let mut seen: HashMap<String, u64> = HashMap::new();
+    let mut rows: usize = 0;
+    
+    let mut r = csv::Reader::from_reader(r);
+    let headers = match r.headers() {
+        Ok(h) => h.clone(),
+        Err(e) => { return Err(FileError::from_csv(path, Op::Read, &e)); },
+    };
+    for result in r.records() {
+        rows += 1;
+        let record = match result {
+            Ok(record) => record,
+            /* The reader can't get past an I/O error, so it would just
+               keep returning it. */
+            Err(e) if matches!(e.kind(), csv::ErrorKind::Io(_)) => {
+                return Err(FileError::from_csv(path, Op::Read, &e));
+            },
+            Err(e) => {
+                let line = e.position().map(|p| p.line()).unwrap_or(0);
+                issues.push(ValidationIssue::Malformed { line, msg: e.to_string() });
+                continue;
+            },
+        };
+        let line = record.position().map(|p| p.line()).unwrap_or(0);
+        let row: T = match record.deserialize(Some(&headers)) {
+            Ok(row) => row,
+            Err(e) => {
+                issues.push(ValidationIssue::Malformed { line, msg: e.to_string() });
+                continue;
+            },
+        };
+        
+        let id = id_of(&row);
+        match seen.get(id) {
+            Some(first_line) => issues.push(ValidationIssue::Duplicate {
+                line, first_line: *first_line, id: id.to_string()
+            }),
+            None => { let _ = seen.insert(id.to_string(), line); },
+        }
+        check(line, row, &mut issues);
+    }
+    
+    return Ok(ValidationReport { path: PathBuf::from(path), rows, issues });
+}
+
+/** Whether the file at `path` can be read by anyone but its owner. */
+#[cfg(unix)]
+pub(crate) fn is_world_readable(path: &Path) -> bool {
+    use std::os::unix::fs::PermissionsExt;
+    match std::fs::metadata(path) {
+        Ok(md) => md.permissions().mode() & 0o044 != 0,
+        Err(_) => false,
+    }
+}
+
+#[cfg(not(unix))]
+pub(crate) fn is_world_readable(_path: &Path) -> bool { false }
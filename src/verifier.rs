@@ -0,0 +1,260 @@
+use std::cmp::Ordering;
+use std::convert::TryFrom;
+use std::io::{self, ErrorKind, Write};
+use std::path::{Path, PathBuf};
+
+use crate::{AuthOk, FileError, DataError, InputLimits, Op, open_for_atomic_write, abort_atomic_write,
+            commit_atomic_write};
+use crate::hasher::{HashEncoding, HashFn, PasswordHasher};
+use crate::secret::{Secret, SecretProvider};
+
+/* Identifies the file format (and its version). */
+const MAGIC: &[u8; 8] = b"ALVERIF1";
+
+const ADMIN_FLAG: u8 = 1;
+
+/**
+Writes a verifier file at `path` for the given (name, hash, admin) rows,
+which must be sorted by name.
+
+The file is `MAGIC`, the number of rows as a little-endian `u64`, then
+each row in order: the length of the name as a little-endian `u16`, the
+name, the same for the hash, and a byte of flags.
+*/
+pub(crate) fn write_verifier(
+    path: &Path,
+    rows: &[(&str, &str, bool)]
+) -> Result<(), FileError> {
+    let mut data: Vec<u8> = Vec::new();
+    data.extend_from_slice(MAGIC);
+    data.extend_from_slice(&(rows.len() as u64).to_le_bytes());
+    for (uname, hash, admin) in rows.iter() {
+        for field in [uname, hash].iter() {
+            let len = match u16::try_from(field.len()) {
+                Ok(len) => len,
+                Err(_) => {
+                    return Err(FileError::new(path, Op::Write, ErrorKind::InvalidInput,
+                        format!("field for user \"{}\" is too long", uname)));
+                },
+            };
+            data.extend_from_slice(&len.to_le_bytes());
+            data.extend_from_slice(field.as_bytes());
+        }
+        data.push(if *admin { ADMIN_FLAG } else { 0 });
+    }
+    
+    let (mut f, tmp) = open_for_atomic_write(path)?;
+    if let Err(e) = f.write_all(&data) {
+        return Err(abort_atomic_write(&tmp, FileError::from_io(path, Op::Write, &e)));
+    }
+    return commit_atomic_write(f, &tmp, path);
+}
+
+/** One row of a verifier file, borrowed from its data. */
+struct Row<'a> {
+    uname: &'a [u8],
+    hash: &'a [u8],
+    flags: u8,
+}
+
+/** A compact, read-only snapshot of a password database, written by
+    `PwdAuth::export_verifier()`, that can do nothing but check passwords.
+    
+    The snapshot is a binary file of users sorted by name, so opening it
+    just reads it into memory (and checks that it's intact) without
+    parsing any .csv, and each lookup is a binary search. This suits
+    read-heavy frontends, which can check passwords against a snapshot
+    while changes are made (and new snapshots exported) elsewhere.
+    
+    The same pepper and `PasswordHasher` must be set here as on the
+    `PwdAuth` that exported the snapshot.
+*/
+#[derive(Debug)]
+pub struct Verifier {
+    path: PathBuf,
+    data: Vec<u8>,
+    /* Where each row starts in `data`. */
+    rows: Vec<usize>,
+    pepper: Secret,
+    hasher: HashFn,
+    uniform_errors: bool,
+    limits: InputLimits,
+}
+
+impl Verifier {
+    /**
+    Open the verifier file at the given path. Returns a `FileError` with
+    kind `ErrorKind::InvalidData` if it isn't one, or it has been
+    truncated or corrupted.
+    */
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, FileError> {
+        let path = path.as_ref();
+        let data = match std::fs::read(path) {
+            Ok(data) => data,
+            Err(e) => { return Err(FileError::from_io(path, Op::Read, &e)); },
+        };
+        let rows = match index_rows(&data) {
+            Some(rows) => rows,
+            None => {
+                return Err(FileError::new(path, Op::Read, ErrorKind::InvalidData,
+                                          "not a valid verifier file".to_string()));
+            },
+        };
+        
+        return Ok(Verifier {
+            path: PathBuf::from(path),
+            data,
+            rows,
+            pepper: Secret::default(),
+            hasher: HashFn::default(),
+            uniform_errors: false,
+            limits: InputLimits::default(),
+        });
+    }
+    
+    /** Set the pepper; see `PwdAuth::pepper()`. */
+    pub fn pepper(&mut self, provider: &dyn SecretProvider) -> io::Result<()> {
+        self.pepper = Secret::from_provider(provider)?;
+        return Ok(());
+    }
+    
+    /** Replace the password hash function; see `PwdAuth::password_hasher()`. */
+    pub fn password_hasher<H>(&mut self, hasher: H)
+    where H: PasswordHasher + 'static
+    {
+        self.hasher = HashFn::new(hasher)
+            .normalized(self.hasher.normalizes())
+            .encoded(self.hasher.encoding());
+    }
+    
+    /** Turn Unicode normalization of passwords on or off; see `PwdAuth::normalize_passwords()`. */
+    pub fn normalize_passwords(&mut self, on: bool) {
+        self.hasher = self.hasher.clone().normalized(on);
+    }
+    
+    /** Choose the encoding of hashes that aren't flagged `needs_rehash`; see `PwdAuth::hash_encoding()`. */
+    pub fn hash_encoding(&mut self, encoding: HashEncoding) {
+        self.hasher = self.hasher.clone().encoded(encoding);
+    }
+    
+    /** Turn "uniform errors" mode on or off; see `PwdAuth::uniform_errors()`. */
+    pub fn uniform_errors(&mut self, on: bool) { self.uniform_errors = on; }
+    
+    /** Set the caps on user name and password lengths; see `PwdAuth::input_limits()`. */
+    pub fn input_limits(&mut self, limits: InputLimits) { self.limits = limits; }
+    
+    /** Returns the number of users in the snapshot. */
+    pub fn len(&self) -> usize { self.rows.len() }
+    
+    /** Returns whether the snapshot has no users at all. */
+    pub fn is_empty(&self) -> bool { self.rows.is_empty() }
+    
+    /** Returns the path of the file this snapshot was read from. */
+    pub fn path(&self) -> &Path { &self.path }
+    
+    /**
+    Checks whether the given password/salt combination is correct for the
+    given user, exactly as `PwdAuth::check_password()` does (including
+    taking about as long when the user doesn't exist).
+    */
+    pub fn check_password(
+        &self,
+        uname: &str,
+        password: &str,
+        salt: &[u8]
+    ) -> Result<AuthOk, DataError> {
+        self.limits.check(uname, password)?;
+        let mut salted = salt.to_vec();
+        salted.extend_from_slice(self.pepper.as_bytes());
+        
+        let hash = match self.find(uname) {
+            Some(row) => String::from_utf8_lossy(row.hash),
+            None => {
+                self.hasher.verify_dummy(password, &salted);
+                return Err(self.uniform_or(DataError::NoSuchUser));
+            },
+        };
+        if self.hasher.verify(password, &salted, &hash) {
+            let mut ok = AuthOk::new(uname);
+            ok.needs_rehash = self.hasher.needs_rehash(&hash);
+            Ok(ok)
+        } else {
+            Err(self.uniform_or(DataError::BadPassword))
+        }
+    }
+    
+    /** Returns whether the given user has administrator privileges. */
+    pub fn is_admin(&self, uname: &str) -> Result<bool, DataError> {
+        match self.find(uname) {
+            Some(row) => Ok(row.flags & ADMIN_FLAG != 0),
+            None => Err(DataError::NoSuchUser),
+        }
+    }
+    
+    /** Returns `e`, or `DataError::BadCredentials` in uniform errors mode. */
+    fn uniform_or(&self, e: DataError) -> DataError {
+        if self.uniform_errors { DataError::BadCredentials } else { e }
+    }
+    
+    /** Binary searches for the given user's row. */
+    fn find(&self, uname: &str) -> Option<Row<'_>> {
+        let target = uname.as_bytes();
+        let mut lo = 0;
+        let mut hi = self.rows.len();
+        while lo < hi {
+            let mid = lo + (hi - lo) / 2;
+            /* Every row was checked by `index_rows()`. */
+            let row = read_row(&self.data, self.rows[mid])?.0;
+            match row.uname.cmp(target) {
+                Ordering::Less => { lo = mid + 1; },
+                Ordering::Greater => { hi = mid; },
+                Ordering::Equal => { return Some(row); },
+            }
+        }
+        return None;
+    }
+}
+
+/** Reads the row starting at `at`, returning it and where the next starts. */
+fn read_row(data: &[u8], at: usize) -> Option<(Row<'_>, usize)> {
+    let (uname, at) = read_field(data, at)?;
+    let (hash, at) = read_field(data, at)?;
+    let flags = *data.get(at)?;
+    return Some((Row { uname, hash, flags }, at + 1));
+}
+
+/** Reads a length-prefixed field starting at `at`. */
+fn read_field(data: &[u8], at: usize) -> Option<(&[u8], usize)> {
+    let len = data.get(at..at + 2)?;
+    let len = u16::from_le_bytes([len[0], len[1]]) as usize;
+    let start = at + 2;
+    let field = data.get(start..start + len)?;
+    return Some((field, start + len));
+}
+
+/**
+Returns the offset of each row in the verifier file data, or `None` if the
+data isn't a complete, correctly-sorted verifier file.
+*/
+fn index_rows(data: &[u8]) -> Option<Vec<usize>> {
+    if data.get(..MAGIC.len())? != MAGIC { return None; }
+    let mut at = MAGIC.len();
+    let mut count = [0u8; 8];
+    count.copy_from_slice(data.get(at..at + 8)?);
+    let count = usize::try_from(u64::from_le_bytes(count)).ok()?;
+    at += 8;
+    
+    let mut rows: Vec<usize> = Vec::new();
+    let mut prev: Option<&[u8]> = None;
+    for _ in 0..count {
+        let (row, next) = read_row(data, at)?;
+        if let Some(prev) = prev {
+            if prev >= row.uname { return None; }
+        }
+        prev = Some(row.uname);
+        rows.push(at);
+        at = next;
+    }
+    if at != data.len() { return None; }
+    return Some(rows);
+}
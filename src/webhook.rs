@@ -0,0 +1,156 @@
+use std::io;
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+use hmac::{Hmac, Mac};
+use serde_json::json;
+use sha2::Sha256;
+
+use crate::AuthEvent;
+use crate::secret::Secret;
+
+/** The header carrying the signature of each request's body. */
+pub const SIGNATURE_HEADER: &str = "X-Authlite-Signature";
+
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/** Sends `AuthEvent`s to a URL as JSON, so they can be passed on to a chat
+    service or a log collector without any application code.
+    
+    Each event is POSTed as a JSON object with an `"event"` field holding
+    its `AuthEvent::name()`, plus a field for each of the event's own
+    fields. The body is signed with HMAC-SHA256 using the webhook's
+    secret, and the signature is sent (as `sha256=` followed by hex) in
+    the `X-Authlite-Signature` header so the receiver can check that it
+    came from here.
+    
+    ```no_run
+    # use authlite::{BothAuth, Webhook};
+    let hook = Webhook::new("https://example.com/hooks/auth", b"shared secret")
+        .events(&["key_evicted"]);
+    let auth = BothAuth::builder()
+        .pwd_file("data/users.csv")
+        .key_file("data/keys.csv")
+        .webhook(hook)
+        .build()
+        .unwrap();
+    ```
+    
+    Only available with the `http-hooks` feature.
+*/
+#[derive(Debug, Clone)]
+pub struct Webhook {
+    url: String,
+    secret: Secret,
+    events: Option<Vec<String>>,
+    timeout: Duration,
+}
+
+impl Webhook {
+    /**
+    Create a webhook that sends every event to `url`, signing the requests
+    with `secret`.
+    */
+    pub fn new(url: impl Into<String>, secret: &[u8]) -> Self {
+        return Webhook {
+            url: url.into(),
+            secret: Secret::from_bytes(secret),
+            events: None,
+            timeout: DEFAULT_TIMEOUT,
+        };
+    }
+    
+    /** Only send events with the given names (see `AuthEvent::name()`). */
+    pub fn events(mut self, names: &[&str]) -> Self {
+        self.events = Some(names.iter().map(|n| n.to_string()).collect());
+        self
+    }
+    
+    /** Give up on a request after this long (the default is 10 seconds). */
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+    
+    /** Returns whether this webhook is configured to send the given event. */
+    pub fn wants(&self, event: &AuthEvent) -> bool {
+        match &self.events {
+            None => true,
+            Some(names) => names.iter().any(|n| n == event.name()),
+        }
+    }
+    
+    /** Returns the JSON body that would be sent for the given event. */
+    pub fn payload(event: &AuthEvent) -> String {
+        let value = match event {
+            AuthEvent::KeyEvicted { uname, expiry } => json!({
+                "event": event.name(),
+                "uname": uname,
+                "expiry": timestamp(*expiry),
+            }),
+            AuthEvent::DuplicateEntry { path, line, first_line, id } => json!({
+                "event": event.name(),
+                "path": path.to_string_lossy(),
+                "line": line,
+                "first_line": first_line,
+                "id": id,
+            }),
+        };
+        return value.to_string();
+    }
+    
+    /** Returns the value of the signature header for the given body. */
+    pub fn signature(&self, body: &[u8]) -> String {
+        let mut mac = Hmac::<Sha256>::new_from_slice(self.secret.as_bytes())
+            .expect("HMAC accepts keys of any length");
+        mac.update(body);
+        let digest = mac.finalize().into_bytes();
+        
+        let mut sig = String::from("sha256=");
+        for b in digest.iter() { sig.push_str(&format!("{:02x}", b)); }
+        return sig;
+    }
+    
+    /**
+    POSTs the given event to the webhook's URL (whether or not it's one
+    of the configured events), waiting for the response.
+    
+    Returns an error if the request fails or the server doesn't respond
+    with a 2xx status.
+    */
+    pub fn send(&self, event: &AuthEvent) -> io::Result<()> {
+        let body = Webhook::payload(event);
+        let result = ureq::post(&self.url)
+            .timeout(self.timeout)
+            .set("Content-Type", "application/json")
+            .set(SIGNATURE_HEADER, &self.signature(body.as_bytes()))
+            .send_string(&body);
+        match result {
+            Ok(_) => Ok(()),
+            Err(e) => Err(io::Error::other(e.to_string())),
+        }
+    }
+    
+    /**
+    Turns the webhook into an event handler, for `.on_event()`. Each
+    configured event is sent from a new thread, so the database is never
+    held up waiting for the server; failures are printed to stderr.
+    */
+    pub fn into_handler(self) -> impl Fn(&AuthEvent) + Send + Sync + 'static {
+        move |event: &AuthEvent| {
+            if !self.wants(event) { return; }
+            let hook = self.clone();
+            let event = event.clone();
+            let _ = thread::spawn(move || {
+                if let Err(e) = hook.send(&event) {
+                    eprintln!("WARNING: sending {} event to {}: {}", event.name(), &hook.url, &e);
+                }
+            });
+        }
+    }
+}
+
+/** Formats a time the same way the data files do. */
+fn timestamp(t: SystemTime) -> String {
+    humantime_serde::re::humantime::format_rfc3339(t).to_string()
+}